@@ -0,0 +1,179 @@
+use proc_macro2::{Delimiter, Literal, Spacing, Span, TokenStream, TokenTree};
+use proc_macro_error::abort;
+use quote::quote;
+
+// Parse shell arithmetic expression inside $((...)) to rust code evaluating to io::Result<i64>
+//
+// - operators (from low to high precedence): == !=, < <= > >=, + -, * / %, **, unary + -
+// - all operations are wrapping on i64, dividing by 0 or negative exponent is an error
+// - variables are converted with `cmd_lib::arith_var()` at runtime, failing if not integers
+pub fn parse_arith(input: TokenStream, span: Span) -> TokenStream {
+    let expr = parse_expr(input, span);
+    quote!((|| -> ::std::io::Result<i64> {
+        let value: i64 = #expr;
+        Ok(value)
+    })())
+}
+
+// the expression evaluating to i64, with `?` on the fallible operations
+fn parse_expr(input: TokenStream, span: Span) -> TokenStream {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    if tokens.is_empty() {
+        abort!(span, "empty arithmetic expression");
+    }
+    let mut parser = ArithParser { tokens, pos: 0 };
+    let expr = parser.parse_expr(0);
+    if let Some(tt) = parser.tokens.get(parser.pos) {
+        abort!(tt.span(), "unexpected token in arithmetic expression");
+    }
+    expr
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+impl Op {
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Eq | Op::Ne => 1,
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => 2,
+            Op::Add | Op::Sub => 3,
+            Op::Mul | Op::Div | Op::Rem => 4,
+            Op::Pow => 5,
+        }
+    }
+
+    fn gen(self, l: TokenStream, r: TokenStream) -> TokenStream {
+        match self {
+            Op::Eq => quote!(((#l == #r) as i64)),
+            Op::Ne => quote!(((#l != #r) as i64)),
+            Op::Lt => quote!(((#l < #r) as i64)),
+            Op::Le => quote!(((#l <= #r) as i64)),
+            Op::Gt => quote!(((#l > #r) as i64)),
+            Op::Ge => quote!(((#l >= #r) as i64)),
+            Op::Add => quote!((#l).wrapping_add(#r)),
+            Op::Sub => quote!((#l).wrapping_sub(#r)),
+            Op::Mul => quote!((#l).wrapping_mul(#r)),
+            Op::Div => quote!(::cmd_lib::arith_div(#l, #r)?),
+            Op::Rem => quote!(::cmd_lib::arith_rem(#l, #r)?),
+            Op::Pow => quote!(::cmd_lib::arith_pow(#l, #r)?),
+        }
+    }
+}
+
+struct ArithParser {
+    tokens: Vec<TokenTree>,
+    pos: usize,
+}
+
+impl ArithParser {
+    fn punct_at(&self, pos: usize) -> Option<(char, Spacing)> {
+        match self.tokens.get(pos) {
+            Some(TokenTree::Punct(p)) => Some((p.as_char(), p.spacing())),
+            _ => None,
+        }
+    }
+
+    // returns the binary operator at current position, and its length in tokens
+    fn peek_op(&self) -> Option<(Op, usize)> {
+        let (ch, spacing) = self.punct_at(self.pos)?;
+        let next = if spacing == Spacing::Joint {
+            self.punct_at(self.pos + 1).map(|(c, _)| c)
+        } else {
+            None
+        };
+        let op = match (ch, next) {
+            ('=', Some('=')) => (Op::Eq, 2),
+            ('!', Some('=')) => (Op::Ne, 2),
+            ('<', Some('=')) => (Op::Le, 2),
+            ('>', Some('=')) => (Op::Ge, 2),
+            ('*', Some('*')) => (Op::Pow, 2),
+            ('<', _) => (Op::Lt, 1),
+            ('>', _) => (Op::Gt, 1),
+            ('+', _) => (Op::Add, 1),
+            ('-', _) => (Op::Sub, 1),
+            ('*', _) => (Op::Mul, 1),
+            ('/', _) => (Op::Div, 1),
+            ('%', _) => (Op::Rem, 1),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    fn parse_expr(&mut self, min_precedence: u8) -> TokenStream {
+        let mut lhs = self.parse_unary();
+        while let Some((op, len)) = self.peek_op() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.pos += len;
+            // ** is right associative
+            let next_precedence = if op == Op::Pow {
+                op.precedence()
+            } else {
+                op.precedence() + 1
+            };
+            let rhs = self.parse_expr(next_precedence);
+            lhs = op.gen(lhs, rhs);
+        }
+        lhs
+    }
+
+    fn parse_unary(&mut self) -> TokenStream {
+        match self.punct_at(self.pos) {
+            Some(('-', _)) => {
+                self.pos += 1;
+                let operand = self.parse_unary();
+                return quote!((#operand).wrapping_neg());
+            }
+            Some(('+', _)) => {
+                self.pos += 1;
+                return self.parse_unary();
+            }
+            _ => {}
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> TokenStream {
+        let tt = match self.tokens.get(self.pos) {
+            Some(tt) => tt.clone(),
+            None => abort!(
+                self.tokens[self.pos - 1].span(),
+                "incomplete arithmetic expression"
+            ),
+        };
+        self.pos += 1;
+        match tt {
+            TokenTree::Literal(ref lit) => {
+                let s = lit.to_string();
+                match s.parse::<i64>() {
+                    Ok(n) => {
+                        let n = Literal::i64_suffixed(n);
+                        quote!(#n)
+                    }
+                    Err(_) => abort!(lit.span(), "invalid integer in arithmetic expression"),
+                }
+            }
+            TokenTree::Ident(ref var) => quote!(::cmd_lib::arith_var(&#var)?),
+            TokenTree::Group(ref g) if g.delimiter() == Delimiter::Parenthesis => {
+                let expr = parse_expr(g.stream(), g.span());
+                quote!((#expr))
+            }
+            _ => abort!(tt.span(), "invalid token in arithmetic expression"),
+        }
+    }
+}
@@ -1,5 +1,6 @@
+use crate::arith::parse_arith;
 use crate::parser::{ParseArg, Parser};
-use proc_macro2::{token_stream, Delimiter, Ident, Literal, Span, TokenStream, TokenTree};
+use proc_macro2::{token_stream, Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
 use proc_macro_error::abort;
 use quote::quote;
 use std::ffi::OsString;
@@ -40,9 +41,14 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
 
             seal_last_part(&mut last_part, &mut output);
             let mut with_brace = false;
+            let mut indirect = false;
             if iter.peek() == Some(&'{') {
                 with_brace = true;
                 iter.next();
+                if iter.peek() == Some(&'!') {
+                    indirect = true;
+                    iter.next();
+                }
             }
             let mut var = String::new();
             while let Some(&c) = iter.peek() {
@@ -64,7 +70,13 @@ pub fn scan_str_lit(lit: &Literal) -> TokenStream {
             }
             if !var.is_empty() {
                 let var = syn::parse_str::<Ident>(&var).unwrap();
-                output.extend(quote!(.append(#var.as_os_str())));
+                if indirect {
+                    output.extend(quote!(.append(::cmd_lib::env_var_indirect(&#var))));
+                } else {
+                    output.extend(quote!(.append(#var.as_os_str())));
+                }
+            } else if indirect {
+                abort!(lit.span(), "bad substitution");
             } else {
                 output.extend(quote!(.append("$")));
             }
@@ -93,6 +105,7 @@ pub struct Lexer {
     iter: TokenStreamPeekable<token_stream::IntoIter>,
     args: Vec<ParseArg>,
     last_arg_str: TokenStream,
+    last_arg_interpolated: bool,
     last_redirect: Option<(RedirectFd, Span)>,
     seen_redirect: (bool, bool, bool),
 }
@@ -102,6 +115,7 @@ impl Lexer {
         Self {
             args: vec![],
             last_arg_str: TokenStream::new(),
+            last_arg_interpolated: false,
             last_redirect: None,
             seen_redirect: (false, false, false),
             iter: TokenStreamPeekable {
@@ -138,6 +152,8 @@ impl Lexer {
                         self.scan_ampersand();
                     } else if ch == '$' {
                         self.scan_dollar();
+                    } else if ch == '%' && self.at_cmd_start() {
+                        self.scan_callback();
                     } else {
                         let s = ch.to_string();
                         self.extend_last_arg(quote!(#s));
@@ -175,6 +191,9 @@ impl Lexer {
             if stdouterr {
                 self.args.push(ParseArg::RedirectFd(2, 1));
             }
+        } else if self.last_arg_interpolated {
+            self.args
+                .push(ParseArg::ArgInterpolated(quote!(#last_arg_str)));
         } else if !last_arg_str.is_empty() {
             self.args.push(ParseArg::ArgStr(quote!(#last_arg_str)));
         }
@@ -183,13 +202,19 @@ impl Lexer {
             SepToken::Space => new_redirect = self.seen_redirect,
             SepToken::SemiColon => self.args.push(ParseArg::Semicolon),
             SepToken::Pipe => {
-                Self::check_set_redirect(&mut self.seen_redirect.1, "stdout", token_span);
+                if self.seen_redirect.1 {
+                    abort!(
+                        token_span,
+                        "conflicting stdout: already redirected, so it can't be piped to the next command"
+                    );
+                }
                 self.args.push(ParseArg::Pipe);
                 new_redirect.0 = true;
             }
         }
         self.seen_redirect = new_redirect;
         self.last_arg_str = TokenStream::new();
+        self.last_arg_interpolated = false;
     }
 
     fn extend_last_arg(&mut self, stream: TokenStream) {
@@ -230,6 +255,9 @@ impl Lexer {
         let s = lit.to_string();
         if s.starts_with('\"') || s.starts_with('r') {
             // string literal
+            if self.last_arg_str.is_empty() && s.starts_with("\"$") && !s.starts_with("\"$$") {
+                self.last_arg_interpolated = true;
+            }
             let ss = scan_str_lit(&lit);
             self.extend_last_arg(quote!(#ss.into_os_string()));
         } else {
@@ -343,8 +371,21 @@ impl Lexer {
         let peek_no_gap = self.iter.peek_no_gap().map(|tt| tt.to_owned());
         // let peek_no_gap = None;
         if let Some(TokenTree::Ident(var)) = peek_no_gap {
-            self.extend_last_arg(quote!(#var.as_os_str()));
+            self.interpolate_var(quote!(#var));
+        } else if let Some(TokenTree::Punct(ref p)) = peek_no_gap {
+            if p.as_char() != '%' {
+                abort!(p.span(), "invalid token after $");
+            }
+            self.iter.next();
+            self.scan_flag_map();
+            return;
         } else if let Some(TokenTree::Group(g)) = peek_no_gap {
+            if let Some(expr) = Self::arith_expr(&g) {
+                let expr = parse_arith(expr, g.span());
+                self.extend_last_arg(quote!(::cmd_lib::arith_result(#expr)));
+                self.iter.next();
+                return;
+            }
             if g.delimiter() != Delimiter::Brace && g.delimiter() != Delimiter::Bracket {
                 abort!(
                     g.span(),
@@ -353,14 +394,27 @@ impl Lexer {
                 );
             }
             let mut found_var = false;
+            let mut indirect = false;
             for tt in g.stream() {
                 let span = tt.span();
-                if let TokenTree::Ident(ref var) = tt {
+                if let TokenTree::Punct(ref p) = tt {
+                    // ${!var} for the environment variable named by the value of var
+                    if p.as_char() != '!'
+                        || g.delimiter() != Delimiter::Brace
+                        || found_var
+                        || indirect
+                    {
+                        abort!(span, "invalid grouping: extra tokens");
+                    }
+                    indirect = true;
+                } else if let TokenTree::Ident(ref var) = tt {
                     if found_var {
                         abort!(span, "more than one variable in grouping");
                     }
-                    if g.delimiter() == Delimiter::Brace {
-                        self.extend_last_arg(quote!(#var.as_os_str()));
+                    if indirect {
+                        self.interpolate_var(quote!(::cmd_lib::env_var_indirect(&#var)));
+                    } else if g.delimiter() == Delimiter::Brace {
+                        self.interpolate_var(quote!(#var));
                     } else {
                         if !self.last_arg_str.is_empty() {
                             abort!(span, "vector variable can only be used alone");
@@ -378,6 +432,75 @@ impl Lexer {
         self.iter.next();
     }
 
+    fn interpolate_var(&mut self, var: TokenStream) {
+        if self.last_arg_str.is_empty() {
+            self.last_arg_interpolated = true;
+        }
+        self.extend_last_arg(quote!(#var.as_os_str()));
+    }
+
+    // $%{map} for expanding a map to flag arguments
+    fn scan_flag_map(&mut self) {
+        let var = match self.iter.peek_no_gap() {
+            Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => {
+                let mut iter = g.stream().into_iter();
+                match (iter.next(), iter.next()) {
+                    (Some(TokenTree::Ident(var)), None) => var,
+                    _ => abort!(g.span(), "expect a single variable in $%{...}"),
+                }
+            }
+            _ => abort!(self.iter.span(), "expect {...} after $%"),
+        };
+        if !self.last_arg_str.is_empty() {
+            abort!(var.span(), "map variable can only be used alone");
+        }
+        self.args.push(ParseArg::ArgVec(
+            quote!(::cmd_lib::FlagArgs::flag_args(&#var)),
+        ));
+        self.iter.next();
+    }
+
+    // whether no argument has been seen yet in the current statement
+    fn at_cmd_start(&self) -> bool {
+        self.last_arg_str.is_empty()
+            && self.last_redirect.is_none()
+            && matches!(self.args.last(), None | Some(ParseArg::Semicolon))
+    }
+
+    // %{ |env| ... } statement, running rust closure with the command environment
+    fn scan_callback(&mut self) {
+        if let Some(TokenTree::Group(g)) = self.iter.peek_no_gap() {
+            if g.delimiter() == Delimiter::Brace {
+                let f = g.stream();
+                self.args.push(ParseArg::Callback(f));
+                self.iter.next();
+                match self.iter.peek() {
+                    Some(TokenTree::Punct(p)) if p.as_char() == ';' => {}
+                    None => {}
+                    Some(tt) => abort!(tt.span(), "expect ';' after %{...} statement"),
+                }
+                return;
+            }
+        }
+        self.extend_last_arg(quote!("%"));
+    }
+
+    // $((expr)) for arithmetic expansion
+    fn arith_expr(g: &Group) -> Option<TokenStream> {
+        if g.delimiter() != Delimiter::Parenthesis {
+            return None;
+        }
+        let mut iter = g.stream().into_iter();
+        match (iter.next(), iter.next()) {
+            (Some(TokenTree::Group(inner)), None)
+                if inner.delimiter() == Delimiter::Parenthesis =>
+            {
+                Some(inner.stream())
+            }
+            _ => None,
+        }
+    }
+
     fn check_append(&mut self) -> bool {
         let mut append = false;
         if let Some(TokenTree::Punct(p)) = self.iter.peek_no_gap() {
@@ -84,9 +84,11 @@ pub fn use_custom_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
 /// ```
 /// # use cmd_lib::*;
 /// use_builtin_cmd!(info); // import only one builtin command
-/// use_builtin_cmd!(echo, info, warn, err, die, cat); // import all the builtins
+/// // import all the builtins
+/// use_builtin_cmd!(echo, trace, debug, info, warn, error, die, cat, env, pathmunge);
 /// ```
-/// `cd` builtin command is always enabled without importing it.
+/// `cd` builtin command is always enabled without importing it, and `grep` needs the `grep`
+/// feature.
 #[proc_macro]
 #[proc_macro_error]
 pub fn use_builtin_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -168,6 +170,50 @@ pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     .into()
 }
 
+/// Run commands like `run_fun!`, also returning the time elapsed for running them
+/// ```no_run
+/// # use cmd_lib::run_fun_timed;
+/// let (output, took) = run_fun_timed!(make -j)?;
+/// println!("make took {:?}", took);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_fun_timed(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.run_fun_timed()
+    })
+    .into()
+}
+
+/// Checks the commands would run, without running them
+///
+/// It checks every program can be found, and every `cd` target and redirected file can be
+/// accessed, after applying the same hooks and `Process` options as running the commands:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let file = "/tmp/out.txt";
+/// let report = validate_cmd!(cargo build; ls -l target > $file)?;
+/// for cmd in report.commands {
+///     println!("{} -> {:?}", cmd.command, cmd.program);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Variables are expanded as usual, but `cd` targets are only checked against the directories
+/// which exist before running anything.
+#[proc_macro]
+#[proc_macro_error]
+pub fn validate_cmd(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.validate()
+    })
+    .into()
+}
+
 /// Run commands with/without pipes as a child process, returning a handle to check the final
 /// result
 /// ```
@@ -190,6 +236,31 @@ pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     .into()
 }
 
+/// Replace the current process with the command, like the `exec` builtin of shells
+///
+/// It never returns if the command starts running, since the current process is gone. The
+/// environment variables, `cd` directory, redirects and `Process` options are set up before
+/// the command replaces the current process, and the standard streams without redirects are
+/// inherited. Only a single external command is allowed, and on failure the error is returned:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let config = "/etc/app.conf";
+/// let err = exec!(APP_CONFIG=$config my_app --foreground < /dev/null);
+/// panic!("{}", err);
+/// ```
+/// On Windows the command is spawned and waited for instead, exiting the current process with
+/// the same exit code.
+#[proc_macro]
+#[proc_macro_error]
+pub fn exec(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #cmds.exec()
+    })
+    .into()
+}
+
 /// Run commands with/without pipes as a child process, returning a handle to capture the
 /// final output
 /// ```
@@ -354,5 +425,6 @@ fn parse_msg(input: TokenStream) -> TokenStream {
     output
 }
 
+mod arith;
 mod lexer;
 mod parser;
@@ -9,7 +9,9 @@ pub enum ParseArg {
     RedirectFd(i32, i32),                 // fd1, fd2
     RedirectFile(i32, TokenStream, bool), // fd1, file, append?
     ArgStr(TokenStream),
+    ArgInterpolated(TokenStream), // argument starting with an interpolated variable
     ArgVec(TokenStream),
+    Callback(TokenStream),
 }
 
 pub struct Parser<I: Iterator<Item = ParseArg>> {
@@ -82,9 +84,15 @@ impl<I: Iterator<Item = ParseArg>> Parser<I> {
                 ParseArg::ArgStr(opt) => {
                     ret.extend(quote!(.add_arg(#opt.into_os_string())));
                 }
+                ParseArg::ArgInterpolated(opt) => {
+                    ret.extend(quote!(.add_interpolated_arg(#opt.into_os_string())));
+                }
                 ParseArg::ArgVec(opts) => {
                     ret.extend(quote! (.add_args(#opts.iter().map(|s| ::std::ffi::OsString::from(s)).collect())));
                 }
+                ParseArg::Callback(f) => {
+                    ret.extend(quote!(.add_callback(#f)));
+                }
                 ParseArg::Pipe | ParseArg::Semicolon => break,
             }
             self.iter.next();
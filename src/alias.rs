@@ -0,0 +1,180 @@
+use crate::config;
+use crate::process::debug_enabled;
+use crate::registry::{self, CmdRegistry};
+use crate::script::parse_words;
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, ErrorKind, Result};
+
+#[derive(Clone)]
+pub(crate) enum Alias {
+    // command lines to choose from
+    First(Vec<Vec<String>>),
+    // command line with variables to expand
+    Template(Vec<String>),
+}
+
+impl Alias {
+    pub(crate) fn template(name: &str, template: &str) -> Result<Self> {
+        let words = parse_words(template)?;
+        if words.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Alias {:?} has an empty template", name),
+            ));
+        }
+        Ok(Alias::Template(words))
+    }
+
+    pub(crate) fn first<I, S>(alternatives: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let alternatives = alternatives
+            .into_iter()
+            .map(|alt| {
+                alt.as_ref()
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|alt| !alt.is_empty())
+            .collect();
+        Alias::First(alternatives)
+    }
+}
+
+/// Registers `name` as an alias for the command line in `template`
+///
+/// When a command starts with `name`, it is replaced by the template, and the arguments at call
+/// site are appended:
+/// ```no_run
+/// # use cmd_lib::*;
+/// alias("k", "kubectl --context $CTX --namespace ${NS}")?;
+/// run_cmd!(k get pods)?; // kubectl --context prod --namespace web get pods
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The template is split into words like `parse_cmd_line()`, but it must be a single command,
+/// without pipes, `;` or redirects. `$NAME` and `${NAME}` in the words are replaced by the
+/// environment variables every time the alias is run, unset ones by empty strings, and `$$` by
+/// `$`, except in single quotes or escaped as `\$`, where `$` is kept as it is. Templates can
+/// start with other aliases, which are expanded in turn, while an alias expanding to itself again
+/// or nested deeper than `set_max_alias_depth()` fails with an error of kind `InvalidInput` when
+/// run. The expanded command is used for logging and errors.
+pub fn alias(name: &str, template: &str) -> Result<()> {
+    CmdRegistry::global().alias(name, template)
+}
+
+/// Registers `name` as an alias for the first of `alternatives` which exists
+///
+/// Each alternative is a program with its arguments, separated by whitespace. When a command
+/// starts with `name`, it is replaced by the first alternative whose program is a registered
+/// builtin or custom command, or is found in `PATH`, and the arguments at call site are appended:
+/// ```no_run
+/// # use cmd_lib::*;
+/// alias_first("search", ["rg", "grep -r"]);
+/// let pattern = "TODO";
+/// run_cmd!(search $pattern src)?; // rg TODO src, or grep -r TODO src without rg
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The alternative is chosen again every time the alias is run, and logged in debug mode. If none
+/// of them exists, running the alias fails with an error of kind `NotFound`, naming all the
+/// alternatives tried. Registering the same name again replaces the alternatives.
+pub fn alias_first<I, S>(name: &str, alternatives: I)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    CmdRegistry::global().alias_first(name, alternatives)
+}
+
+// Returns the command line expanded from alias `name`, choosing the alternatives with `exists`
+// true for their programs, or `None` if `name` is not an alias
+pub(crate) fn resolve_alias(
+    name: &OsStr,
+    exists: impl Fn(&OsStr) -> bool,
+) -> Option<Result<Vec<OsString>>> {
+    let max_depth = config::get(|c| c.max_alias_depth);
+    let mut argv = vec![name.to_os_string()];
+    let mut expanded: Vec<OsString> = vec![];
+    while let Some(alias) = registry::find_alias(&argv[0]) {
+        let recursive = expanded.contains(&argv[0]);
+        if recursive || expanded.len() == max_depth {
+            expanded.push(argv.remove(0));
+            let chain: Vec<_> = expanded.iter().map(|name| name.to_string_lossy()).collect();
+            let msg = if recursive {
+                format!("Recursive alias: {}", chain.join(" -> "))
+            } else {
+                format!(
+                    "Alias nested deeper than the limit of {}: {}, see set_max_alias_depth()",
+                    max_depth,
+                    chain.join(" -> ")
+                )
+            };
+            return Some(Err(Error::new(ErrorKind::InvalidInput, msg)));
+        }
+        let words: Vec<OsString> = match alias {
+            Alias::First(alternatives) => {
+                match alternatives.iter().find(|alt| exists(OsStr::new(&alt[0]))) {
+                    Some(alt) => alt.iter().map(OsString::from).collect(),
+                    None => {
+                        let tried: Vec<String> =
+                            alternatives.iter().map(|alt| alt.join(" ")).collect();
+                        return Some(Err(Error::new(
+                            ErrorKind::NotFound,
+                            format!(
+                                "No alternative of alias {:?} found, tried: {}",
+                                argv[0],
+                                tried.join(", ")
+                            ),
+                        )));
+                    }
+                }
+            }
+            Alias::Template(words) => words.iter().map(|word| expand_vars(word)).collect(),
+        };
+        expanded.push(argv.remove(0));
+        argv.splice(0..0, words);
+    }
+    if expanded.is_empty() {
+        return None;
+    }
+    if debug_enabled() {
+        debug!("Expanded alias {:?} to {:?}", name, argv);
+    }
+    Some(Ok(argv))
+}
+
+// replaces `$NAME` and `${NAME}` with the environment variables, and `$$` with `$`
+fn expand_vars(word: &str) -> OsString {
+    let mut ret = OsString::new();
+    let mut rest = word;
+    while let Some(i) = rest.find('$') {
+        ret.push(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, len) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            }
+        } else if rest.starts_with('$') {
+            ret.push("$");
+            rest = &rest[1..];
+            continue;
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        if name.is_empty() {
+            ret.push("$");
+            continue;
+        }
+        ret.push(std::env::var_os(name).unwrap_or_default());
+        rest = &rest[len..];
+    }
+    ret.push(rest);
+    ret
+}
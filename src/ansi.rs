@@ -0,0 +1,59 @@
+// Strips ANSI escape sequences, for `Process::strip_ansi()`
+//
+// Removed are CSI sequences like colors `ESC [ 1;31 m` and cursor movements, OSC sequences like
+// window titles and hyperlinks `ESC ] ... BEL` or `ESC ] ... ESC \`, and the other escapes like
+// `ESC ( B` or `ESC =`. A sequence cut at the end of the text is removed as well.
+pub(crate) fn strip_ansi(s: &str) -> String {
+    if !s.contains('\x1b') {
+        return s.into();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameter and intermediate bytes, then a final byte
+            Some('[') => {
+                while chars.next_if(|c| ('\x20'..='\x3f').contains(c)).is_some() {}
+                chars.next_if(|c| ('\x40'..='\x7e').contains(c));
+            }
+            // OSC: a string ended by BEL or ST
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' {
+                        chars.next_if_eq(&'\\');
+                        break;
+                    }
+                }
+            }
+            // other escapes: intermediate bytes, then a final byte
+            Some(c) if ('\x20'..='\x2f').contains(&c) => {
+                while chars.next_if(|c| ('\x20'..='\x2f').contains(c)).is_some() {}
+                chars.next_if(|c| ('\x30'..='\x7e').contains(c));
+            }
+            Some(_) | None => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("plain"), "plain");
+        assert_eq!(strip_ansi("\x1b[1;31merror\x1b[0m: x"), "error: x");
+        assert_eq!(strip_ansi("\x1b[38;5;208mo\x1b[m\x1b[2K\x1b[1A"), "o");
+        assert_eq!(strip_ansi("\x1b]0;title\x07a\x1b]8;;url\x1b\\b"), "ab");
+        assert_eq!(strip_ansi("\x1b(Bx\x1b=y\x1b[31"), "xy");
+        assert_eq!(strip_ansi("\x1b[31m\n\x1b[0m"), "\n");
+    }
+}
@@ -0,0 +1,59 @@
+use crate::FunResult;
+
+/// Asserts the output of `run_fun!()` is `expected`, panicking with a line diff if not
+///
+/// It also panics if the command failed.
+/// ```
+/// # use cmd_lib::*;
+/// assert_output(run_fun!(echo hello), "hello");
+/// ```
+#[track_caller]
+pub fn assert_output(result: FunResult, expected: &str) {
+    let actual = match result {
+        Ok(actual) => actual,
+        Err(e) => panic!("command failed: {}", e),
+    };
+    if actual != expected {
+        panic!(
+            "command output mismatch (-expected +actual):\n{}",
+            diff_lines(expected, &actual)
+        );
+    }
+}
+
+// line diff based on the longest common subsequence, good enough for short outputs
+fn diff_lines(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ret = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ret += &format!(" {}\n", old[i]);
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ret += &format!("-{}\n", old[i]);
+            i += 1;
+        } else {
+            ret += &format!("+{}\n", new[j]);
+            j += 1;
+        }
+    }
+    // same lines, but different trailing newlines
+    if ret.lines().all(|line| line.starts_with(' ')) {
+        ret += &format!("expected: {:?}\n  actual: {:?}\n", expected, actual);
+    }
+    ret
+}
@@ -1,12 +1,19 @@
+use crate::pathlike::{self, SEPARATOR};
 use crate::{CmdEnv, CmdResult};
 use log::*;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
 
 #[doc(hidden)]
 pub fn builtin_echo(env: &mut CmdEnv) -> CmdResult {
-    let msg = env.args()[1..].join(" ");
-    writeln!(env.stdout(), "{}", msg)
+    let no_newline = env.args().get(1).is_some_and(|arg| arg == "-n");
+    let msg = env.args()[if no_newline { 2 } else { 1 }..].join(" ");
+    if no_newline {
+        write!(env.stdout(), "{}", msg)
+    } else {
+        writeln!(env.stdout(), "{}", msg)
+    }
 }
 
 #[doc(hidden)]
@@ -48,16 +55,136 @@ pub fn builtin_trace(env: &mut CmdEnv) -> CmdResult {
 #[doc(hidden)]
 pub fn builtin_cat(env: &mut CmdEnv) -> CmdResult {
     if env.args().len() == 1 {
-        let mut buf = vec![];
-        env.stdin().read_to_end(&mut buf)?;
-        env.stdout().write_all(&buf)?;
-        return Ok(());
+        // copy in chunks, since stdin and stdout can't be borrowed at the same time
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = match env.stdin().read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            env.stdout().write_all(&buf[..n])?;
+        }
+    }
+
+    let file = env.resolve_path(&env.args()[1]);
+    std::io::copy(&mut File::open(file)?, &mut env.stdout())?;
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn builtin_env(env: &mut CmdEnv) -> CmdResult {
+    if env.args().len() > 1 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "builtin env takes no arguments",
+        ));
+    }
+    // sorted for stable output
+    let vars = env.env_vars();
+    let mut out = env.stdout();
+    for (k, v) in vars {
+        writeln!(out, "{}={}", k, v)?;
+    }
+    Ok(())
+}
+
+/// Adds a directory to a PATH-like variable for the commands after it in the same block
+///
+/// `pathmunge NAME DIR` prepends `DIR` to the variable `NAME`, and `pathmunge NAME DIR after`
+/// appends it, like `prepend_pathlike()` and `append_pathlike()` with the value the command
+/// would get, including the variables exported by the commands before it:
+/// ```no_run
+/// # use cmd_lib::*;
+/// use_builtin_cmd!(pathmunge);
+/// let dir = "/opt/app/lib";
+/// run_cmd! {
+///     pathmunge PYTHONPATH $dir;
+///     pathmunge LD_LIBRARY_PATH $dir after;
+///     python3 -m app;
+/// }?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The new value is set with `CmdEnv::export()`.
+pub fn builtin_pathmunge(env: &mut CmdEnv) -> CmdResult {
+    let (name, dir, prepend) = match env.args() {
+        [_, name, dir] => (name.clone(), dir.clone(), true),
+        [_, name, dir, after] if after == "after" => (name.clone(), dir.clone(), false),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "usage: pathmunge NAME DIR [after]",
+            ))
+        }
+    };
+    let value = env.env_var(&name).unwrap_or_default();
+    let value = pathlike::munge(&name, &value, Path::new(&dir), prepend, SEPARATOR)?;
+    env.export(name, value);
+    Ok(())
+}
+
+#[doc(hidden)]
+#[cfg(feature = "grep")]
+pub fn builtin_grep(env: &mut CmdEnv) -> CmdResult {
+    use std::io::{BufRead, BufReader};
+
+    let mut case_insensitive = false;
+    let mut invert = false;
+    let mut args = env.args()[1..].iter().peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+        if arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'i' => case_insensitive = true,
+                'v' => invert = true,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("builtin grep: unknown option -{}", flag),
+                    ))
+                }
+            }
+        }
     }
+    let (pattern, file) = match (args.next(), args.next(), args.next()) {
+        (Some(pattern), file, None) => (pattern, file),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "usage: grep [-i] [-v] PATTERN [FILE]",
+            ))
+        }
+    };
+    let regex = regex::RegexBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("builtin grep: {}", e)))?;
 
-    let mut file = PathBuf::from(env.args()[1].to_owned());
-    if file.is_relative() {
-        file = PathBuf::from(env.current_dir()).join(file);
+    let file = file.map(|file| env.resolve_path(file));
+    let (stdin, mut stdout, _) = env.take_stdio();
+    let input: Box<dyn Read> = match file {
+        Some(file) => Box::new(File::open(file)?),
+        None => Box::new(stdin),
+    };
+    let mut input = BufReader::new(input);
+    let mut line = vec![];
+    let mut selected = false;
+    while input.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        if regex.is_match(text.trim_end_matches(['\n', '\r'])) != invert {
+            stdout.write_all(&line)?;
+            selected = true;
+        }
+        line.clear();
+    }
+    if !selected {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "builtin grep: no lines selected",
+        ));
     }
-    env.stdout().write_all(&std::fs::read(file)?)?;
     Ok(())
 }
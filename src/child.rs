@@ -1,9 +1,25 @@
+use crate::ansi;
+use crate::diagnostic::{self, Diagnostic, DiagnosticKind, FnClassify};
+use crate::error::{CmdError, PartialOutput};
+use crate::handle::ProcessHandle;
+use crate::io::{self, PipeCounter};
+use crate::reaper::{self, Reapable};
+use crate::spec::CmdSpec;
+use crate::sys;
 use crate::{process, CmdResult, FunResult};
-use log::{info, warn};
+use log::{log, warn, Level};
 use os_pipe::PipeReader;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
 use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 /// Representation of running or exited children processes, connected with pipes
 /// optionally.
@@ -12,132 +28,2197 @@ use std::thread::JoinHandle;
 pub struct CmdChildren {
     children: Vec<Result<CmdChild>>,
     ignore_error: bool,
+    reapable: Option<Arc<Mutex<Reapable>>>,
+    stats: StatsCollector,
+    started: Instant,
+    timeout: Option<Duration>,
+    last_record: Option<ExecutionRecord>,
+    spec: Option<CmdSpec>,
+}
+
+/// What ran as the last command of a pipeline, and how it exited, for audit logging
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ExecutionRecord {
+    /// Command name and arguments, as passed to the program
+    pub argv: Vec<String>,
+    /// Working directory of the command
+    pub current_dir: PathBuf,
+    /// Environment variables set for this command only, like `FOO=1 cmd`
+    pub env: BTreeMap<String, String>,
+    /// Whether the command succeeded, even if its error is ignored
+    pub success: bool,
+    /// Exit code of the process, which is 0 for succeeded builtin and custom commands
+    pub exit_code: Option<i32>,
+    /// Signal terminating the process, on unix only
+    pub signal: Option<i32>,
+    /// Time elapsed from spawning the pipeline to the command exiting
+    pub duration: Duration,
+}
+
+impl ExecutionRecord {
+    pub(crate) fn new(
+        argv: Vec<String>,
+        current_dir: PathBuf,
+        env: BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            argv,
+            current_dir,
+            env,
+            success: false,
+            exit_code: None,
+            signal: None,
+            duration: Duration::ZERO,
+        }
+    }
+
+    fn finish(&mut self, ret: &CmdResult, duration: Duration) {
+        self.success = ret.is_ok();
+        match ret {
+            Ok(()) => self.exit_code = Some(0),
+            Err(e) => {
+                if let Some(err) = CmdError::from_io_error(e) {
+                    self.exit_code = err.exit_code;
+                    self.signal = err.signal;
+                }
+            }
+        }
+        self.duration = duration;
+    }
+
+    /// Renders the record as a single line JSON object
+    ///
+    /// The field names are `argv`, `current_dir`, `env`, `success`, `exit_code`, `signal` and
+    /// `duration_ms`, and they are guaranteed not to change without a major version bump.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Json<'a> {
+            argv: &'a [String],
+            current_dir: String,
+            env: &'a BTreeMap<String, String>,
+            success: bool,
+            exit_code: Option<i32>,
+            signal: Option<i32>,
+            duration_ms: u64,
+        }
+
+        let json = Json {
+            argv: &self.argv,
+            current_dir: self.current_dir.to_string_lossy().into(),
+            env: &self.env,
+            success: self.success,
+            exit_code: self.exit_code,
+            signal: self.signal,
+            duration_ms: self.duration.as_millis() as u64,
+        };
+        // serializing plain strings and numbers can't fail
+        serde_json::to_string(&json).unwrap()
+    }
+}
+
+/// Statistics of the pipeline, available after waiting for the children
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct PipelineStats {
+    /// Statistics for each stage, in pipeline order
+    pub stages: Vec<StageStats>,
+}
+
+/// Statistics of one pipeline stage
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct StageStats {
+    /// Bytes written to stdout, only counted with `Process::count_pipe_bytes()`, and for the last
+    /// stage only when its output is captured
+    pub stdout_bytes: Option<u64>,
+    /// Estimated time blocked writing to stdout while the next stage didn't read fast enough,
+    /// only measured with `Process::count_pipe_bytes()`, and not for the last stage
+    ///
+    /// It is the time the relay between the stages spent writing to the next stage, which the
+    /// stage spends blocked too once the pipe to the relay is full. When it is a large part of
+    /// the duration of the pipeline, a downstream stage is the bottleneck.
+    pub stdout_blocked: Option<Duration>,
+    /// Error of the stage when it failed without failing the pipeline, as pipefail is disabled
+    pub masked_error: Option<String>,
+    /// Whether the stage succeeded, or `None` if it was not waited for
+    pub success: Option<bool>,
+    /// Exit code of the process, which is 0 for succeeded builtin and custom commands
+    pub exit_code: Option<i32>,
+    /// Signal terminating the process, on unix only
+    pub signal: Option<i32>,
+    /// Whether the stage failed, but its error was ignored with `ignore` or `ignore_errors()`
+    pub error_ignored: bool,
+}
+
+/// Progress of copying the output, passed to the callback of `FunChildren::progress()`
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Progress {
+    /// Bytes read from the last stage so far
+    pub bytes: u64,
+    /// Time elapsed since spawning the children
+    pub elapsed: Duration,
+    /// Bytes per second read from the last stage since the previous report
+    pub bytes_per_sec: f64,
+    /// Bytes written to stdout by the other stages so far, in pipeline order, only counted with
+    /// `Process::count_pipe_bytes()`
+    pub stage_bytes: Vec<u64>,
+}
+
+type FnProgress = Box<dyn FnMut(Progress) + Send>;
+// the value a builtin or custom command sets with `CmdEnv::set_result()`
+pub(crate) type TypedResult = Arc<Mutex<Option<Box<dyn Any + Send>>>>;
+
+type FnSuccess =
+    Box<dyn Fn(&CmdResult, &[String], &[String]) -> std::result::Result<(), String> + Send>;
+
+// reports the progress of the output copied, at most once per `every`, and a last time when
+// dropped
+struct ProgressMeter {
+    every: Duration,
+    f: FnProgress,
+    started: Instant,
+    stage_counts: Vec<Arc<AtomicU64>>,
+    bytes: u64,
+    last_bytes: u64,
+    last_report: Instant,
+}
+
+impl ProgressMeter {
+    fn new(every: Duration, f: FnProgress, started: Instant, counters: &[PipeCounter]) -> Self {
+        Self {
+            every,
+            f,
+            started,
+            stage_counts: counters.iter().map(PipeCounter::shared_count).collect(),
+            bytes: 0,
+            last_bytes: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn add(&mut self, n: usize) {
+        self.bytes += n as u64;
+        if self.last_report.elapsed() >= self.every {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        let now = Instant::now();
+        let secs = now.duration_since(self.last_report).as_secs_f64();
+        let bytes_per_sec = if secs > 0.0 {
+            (self.bytes - self.last_bytes) as f64 / secs
+        } else {
+            0.0
+        };
+        (self.f)(Progress {
+            bytes: self.bytes,
+            elapsed: self.started.elapsed(),
+            bytes_per_sec,
+            stage_bytes: self
+                .stage_counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+        });
+        self.last_bytes = self.bytes;
+        self.last_report = now;
+    }
+}
+
+impl Drop for ProgressMeter {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
+
+struct ProgressWriter<'a> {
+    inner: &'a mut dyn Write,
+    meter: ProgressMeter,
+}
+
+impl Write for ProgressWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.meter.add(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+// tells whether the output was read to the end
+struct EofReader {
+    inner: PipeReader,
+    eof: Arc<AtomicBool>,
+}
+
+impl Read for EofReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.eof.store(true, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+// buffered output handed to `wait_with_buf_pipe()`, counting the bytes consumed
+struct BufPipe {
+    inner: BufReader<Box<dyn Read>>,
+    consumed: Arc<AtomicU64>,
+}
+
+impl Read for BufPipe {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl BufRead for BufPipe {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.consumed.fetch_add(amt as u64, Ordering::Relaxed);
+    }
+}
+
+struct ProgressReader {
+    inner: Box<dyn Read>,
+    meter: ProgressMeter,
+}
+
+impl Read for ProgressReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.meter.add(n);
+        Ok(n)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct StatsCollector {
+    count_bytes: bool,
+    // the errors of the whole pipeline are ignored
+    ignore_error: bool,
+    counters: Vec<PipeCounter>,
+    stats: PipelineStats,
+}
+
+impl StatsCollector {
+    pub(crate) fn new(stages: usize, count_bytes: bool, counters: Vec<PipeCounter>) -> Self {
+        Self {
+            count_bytes,
+            ignore_error: false,
+            counters,
+            stats: PipelineStats {
+                stages: vec![StageStats::default(); stages],
+            },
+        }
+    }
+
+    fn record(&mut self, stage: usize, ret: &CmdResult, stage_ignored: bool) {
+        let stats = match self.stats.stages.get_mut(stage) {
+            Some(stats) => stats,
+            None => return,
+        };
+        stats.success = Some(ret.is_ok());
+        match ret {
+            Ok(()) => stats.exit_code = Some(0),
+            Err(e) => {
+                if let Some(err) = CmdError::from_io_error(e) {
+                    stats.exit_code = err.exit_code;
+                    stats.signal = err.signal;
+                }
+                stats.error_ignored = stage_ignored || self.ignore_error;
+            }
+        }
+    }
+
+    // takes the statuses recorded by the background reaper
+    fn merge_reaped(&mut self, reaped: PipelineStats) {
+        for (stats, reaped) in self.stats.stages.iter_mut().zip(reaped.stages) {
+            stats.masked_error = reaped.masked_error;
+            stats.success = reaped.success;
+            stats.exit_code = reaped.exit_code;
+            stats.signal = reaped.signal;
+            stats.error_ignored = reaped.error_ignored;
+        }
+    }
+
+    fn mask_error(&mut self, stage: usize, err: &Error) {
+        if process::pipefail_warn_enabled() {
+            // not a warning when the caller ignores the errors anyway
+            let level = if self.ignore_error {
+                Level::Debug
+            } else {
+                Level::Warn
+            };
+            log!(level, "Ignoring failure as pipefail is disabled: {}", err);
+        }
+        if let Some(stats) = self.stats.stages.get_mut(stage) {
+            stats.masked_error = Some(err.to_string());
+        }
+    }
+
+    fn finish(&mut self, last_stdout_bytes: Option<u64>) {
+        for counter in self.counters.drain(..) {
+            let stage = counter.stage;
+            let (bytes, blocked) = counter.finish_timed();
+            self.stats.stages[stage].stdout_bytes = Some(bytes);
+            self.stats.stages[stage].stdout_blocked = Some(blocked);
+        }
+        if let Some(last) = self.stats.stages.last_mut() {
+            if last_stdout_bytes.is_some() {
+                last.stdout_bytes = last_stdout_bytes;
+            }
+        }
+    }
 }
 
 impl CmdChildren {
     pub(crate) fn new(children: Vec<Result<CmdChild>>, ignore_error: bool) -> Self {
+        let stages = children.len();
         Self {
             children,
             ignore_error,
+            reapable: None,
+            stats: StatsCollector::new(stages, false, vec![]),
+            started: Instant::now(),
+            timeout: process::Process::default_timeout(),
+            last_record: None,
+            spec: None,
+        }
+    }
+
+    pub(crate) fn started_at(mut self, started: Instant) -> Self {
+        self.started = started;
+        self
+    }
+
+    pub(crate) fn with_stats(mut self, stats: StatsCollector) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub(crate) fn with_spec(mut self, spec: Option<CmdSpec>) -> Self {
+        self.spec = spec;
+        self
+    }
+
+    /// Returns the identity of the process of the stage at index `stage`
+    ///
+    /// See `ProcessHandle`. It is `None` for builtin and custom commands, which run in this
+    /// process.
+    pub fn stage_handle(&self, stage: usize) -> Option<ProcessHandle> {
+        let pid = match self.reapable {
+            Some(ref reapable) => CmdChild::stage_pid(&reapable.lock().unwrap().children, stage),
+            None => CmdChild::stage_pid(&self.children, stage),
+        };
+        pid.map(ProcessHandle::new)
+    }
+
+    /// Returns the spec of the pipeline, for spawning it again with modifications
+    ///
+    /// See `CmdSpec` for the pipelines without one.
+    pub fn spec(&self) -> Option<CmdSpec> {
+        self.spec.clone()
+    }
+
+    /// Spawns the pipeline again, as it was spawned
+    ///
+    /// It fails with an error of kind `InvalidInput` for the pipelines without a spec.
+    pub fn respawn(&self) -> Result<CmdChildren> {
+        respawn_spec(&self.spec)?.spawn()
+    }
+
+    /// Returns the statistics of the pipeline, which are complete after waiting
+    pub fn stats(&self) -> &PipelineStats {
+        &self.stats.stats
+    }
+
+    /// Returns the record of the last command in the pipeline, after waiting
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let mut children = spawn!(make -j)?;
+    /// let ret = children.wait();
+    /// let record = children.last_execution_record().unwrap();
+    /// println!("{:?} exited with {:?}", record.argv, record.exit_code);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn last_execution_record(&self) -> Option<&ExecutionRecord> {
+        self.last_record.as_ref()
+    }
+
+    // hand over the children to the background reaper, if it is enabled
+    pub(crate) fn auto_reap(mut self) -> Self {
+        // nothing else takes stderr of spawned children, so log it right away
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        if reaper::auto_reap_enabled() {
+            self.reapable = Some(reaper::register(Reapable {
+                children: std::mem::take(&mut self.children),
+                ignore_error: self.ignore_error,
+                started: self.started,
+                result: None,
+                record: None,
+                stats: None,
+            }));
+        }
+        self
+    }
+
+    pub(crate) fn into_fun_children(self) -> FunChildren {
+        FunChildren {
+            children: self.children,
+            ignore_error: self.ignore_error,
+            ignore_broken_pipe: false,
+            ignore_sink_errors: false,
+            chunk_read_timeout: None,
+            progress: None,
+            strip_bom: process::Process::strip_bom_enabled(),
+            strip_ansi: process::Process::strip_ansi_enabled(),
+            stats: self.stats,
+            started: self.started,
+            timeout: self.timeout,
+            spec: self.spec,
+        }
+    }
+
+    /// Ignores the errors of all the stages when waiting, like the `ignore` command at call site
+    ///
+    /// The stderr lines of the children are logged at the debug level instead of the info level
+    /// from then on, and so are the failures masked without pipefail. The children handed over
+    /// to the reaper of `enable_auto_reap()` are switched too, and so are their statistics when
+    /// they are reaped already.
+    pub fn ignore_errors(mut self) -> Self {
+        self.ignore_error = true;
+        match self.reapable {
+            Some(ref reapable) => {
+                let mut reapable = reapable.lock().unwrap();
+                reapable.ignore_error = true;
+                CmdChild::quiet_stderr_all(&mut reapable.children);
+                if let Some(ref mut stats) = reapable.stats {
+                    for stage in stats.stages.iter_mut() {
+                        stage.error_ignored |= stage.success == Some(false);
+                    }
+                }
+            }
+            None => CmdChild::quiet_stderr_all(&mut self.children),
+        }
+        self
+    }
+
+    /// Decides whether the last stage succeeded with `f`, after the default checks
+    ///
+    /// `f` gets the result of the default checks, the last lines of stdout and the last lines of
+    /// stderr of the last stage, and returns `Err` with a message to fail it, or `Ok` to let it
+    /// succeed even when it exited with an error:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// spawn!(terraform apply -auto-approve)?
+    ///     .success_when(|ret, _, stderr_tail| match ret {
+    ///         Ok(()) if stderr_tail.iter().any(|line| line.starts_with("ERROR:")) => {
+    ///             Err("errors were reported".into())
+    ///         }
+    ///         _ => ret.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    ///     })
+    ///     .wait()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The tails hold at most the last 10 lines, like `CmdError::stderr_tail`. Stdout is not
+    /// captured by `CmdChildren`, so its tail is empty here, and the stderr tail is empty when
+    /// stderr is not logged, as with `Process::interactive()` or `Process::stderr_log()`, or
+    /// redirected. It has no effect on a pipeline already reaped by `enable_auto_reap()`, so it
+    /// is set right after spawning.
+    pub fn success_when<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&CmdResult, &[String], &[String]) -> std::result::Result<(), String> + Send + 'static,
+    {
+        match self.reapable {
+            Some(ref reapable) => {
+                CmdChild::check_success(&mut reapable.lock().unwrap().children, Box::new(f))
+            }
+            None => CmdChild::check_success(&mut self.children, Box::new(f)),
+        }
+        self
+    }
+
+    pub fn wait(&mut self) -> CmdResult {
+        self.wait_timed().map(|_| ())
+    }
+
+    /// Kills all the processes in the pipeline, which still need to be waited for
+    ///
+    /// The commands started in a process group of their own by `Process::process_group()` are
+    /// killed with the whole group. Builtin and custom commands running in threads can't be
+    /// killed.
+    pub fn kill(&mut self) -> CmdResult {
+        if let Some(ref reapable) = self.reapable {
+            return CmdChild::kill_all(&mut reapable.lock().unwrap().children);
+        }
+        CmdChild::kill_all(&mut self.children)
+    }
+
+    /// Waits for the children like `wait()`, killing them if they run longer than `timeout`
+    ///
+    /// The timeout is measured from calling it, like `FunChildren::wait_with_output_timeout()`,
+    /// and replaces the default one from `Process::timeout()`. On timeout, it returns an error of
+    /// kind `TimedOut`, even with `ignore_errors()`. Builtin and custom commands running in
+    /// threads can't be killed, so they are still waited for after timing out.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> CmdResult {
+        self.timeout = None;
+        let (ret, _) = self.wait_result_until(Some((Instant::now() + timeout, timeout)));
+        self.ignore_result(ret)
+    }
+
+    /// Waits for the children like `wait()`, calling `heartbeat` every `every` while they run
+    ///
+    /// It is for threads which can't block for long, like a GUI thread pumping its events:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::ops::ControlFlow;
+    /// # use std::time::Duration;
+    /// # let mut cancelled = || false;
+    /// let mut children = spawn!(cargo build --release)?;
+    /// let ret = children.wait_with_heartbeat(Duration::from_millis(50), || {
+    ///     // pump the GUI events here
+    ///     if cancelled() {
+    ///         ControlFlow::Break(())
+    ///     } else {
+    ///         ControlFlow::Continue(())
+    ///     }
+    /// });
+    /// if ret.is_err() && cancelled() {
+    ///     children.kill()?;
+    ///     let _ = children.wait();
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Returning `ControlFlow::Break` stops waiting with an error of kind `Interrupted`, even
+    /// with `ignore_errors()`, and leaves the children running, to be killed or waited for again.
+    /// The default timeout from `Process::timeout()` still applies, while the time spent in
+    /// `heartbeat` delays the next check of the children.
+    pub fn wait_with_heartbeat(
+        &mut self,
+        every: Duration,
+        mut heartbeat: impl FnMut() -> ControlFlow<()>,
+    ) -> CmdResult {
+        let limit = self
+            .timeout
+            .map(|timeout| (self.started + timeout, timeout));
+        let (ret, _) = self.wait_result_until_beating(limit, Some((every, &mut heartbeat)));
+        match ret {
+            Err(e) if e.kind() == ErrorKind::Interrupted && !self.children.is_empty() => Err(e),
+            ret => {
+                self.timeout = None;
+                self.ignore_result(ret)
+            }
+        }
+    }
+
+    /// Waits for the children like `wait()`, returning the time elapsed since spawning them
+    pub fn wait_timed(&mut self) -> Result<Duration> {
+        let (ret, elapsed) = self.wait_result();
+        self.ignore_result(ret).map(|_| elapsed)
+    }
+
+    fn ignore_result(&self, ret: CmdResult) -> CmdResult {
+        match ret {
+            Err(e) if self.ignore_error && e.kind() != ErrorKind::TimedOut => Ok(()),
+            ret => ret,
+        }
+    }
+
+    // waits and returns the error even if ignored, for tracking the last status in a group
+    pub(crate) fn wait_result(&mut self) -> (CmdResult, Duration) {
+        // the default timeout is measured from spawning
+        let limit = self
+            .timeout
+            .take()
+            .map(|timeout| (self.started + timeout, timeout));
+        self.wait_result_until(limit)
+    }
+
+    fn wait_result_until(&mut self, limit: Option<(Instant, Duration)>) -> (CmdResult, Duration) {
+        self.wait_result_until_beating(limit, None)
+    }
+
+    fn wait_result_until_beating(
+        &mut self,
+        limit: Option<(Instant, Duration)>,
+        mut heartbeat: Option<(Duration, &mut dyn FnMut() -> ControlFlow<()>)>,
+    ) -> (CmdResult, Duration) {
+        if let Some(reapable) = self.reapable.take() {
+            let mut reapable = reapable.lock().unwrap();
+            if let Some(ret) = reapable.result.take() {
+                self.last_record = reapable.record.take();
+                if let Some(stats) = reapable.stats.take() {
+                    self.stats.merge_reaped(stats);
+                }
+                return ret;
+            }
+            self.children = std::mem::take(&mut reapable.children);
+        }
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        let mut next_beat = heartbeat.as_ref().map(|(every, _)| Instant::now() + *every);
+        while let Some(beat) = next_beat {
+            let until = limit.map_or(beat, |(deadline, _)| deadline.min(beat));
+            if self.wait_deadline(until) || Instant::now() < beat {
+                break;
+            }
+            let (every, f) = heartbeat.as_mut().unwrap();
+            if f().is_break() {
+                let e = Error::new(ErrorKind::Interrupted, "waiting stopped by the heartbeat");
+                return (Err(e), self.started.elapsed());
+            }
+            next_beat = Some(Instant::now() + *every);
+        }
+        if let Some((deadline, timeout)) = limit {
+            if !self.wait_deadline(deadline) {
+                let _ = CmdChild::kill_all(&mut self.children);
+                let pipeline = match self.children.last() {
+                    Some(Ok(child)) => child.info.pipeline.clone(),
+                    _ => String::new(),
+                };
+                let _ = self.wait_all();
+                self.stats.finish(None);
+                let e = Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Running {} timed out after {:?}", pipeline, timeout),
+                );
+                return (Err(e), self.started.elapsed());
+            }
+        }
+        let ret = self.wait_all();
+        self.stats.finish(None);
+        (ret, self.started.elapsed())
+    }
+
+    pub(crate) fn take_last_record(&mut self) -> Option<ExecutionRecord> {
+        self.last_record.take()
+    }
+
+    /// Returns true if any stage failed with its error ignored, after waiting
+    ///
+    /// The errors are ignored with `ignore` in the commands or `ignore_errors()`, and see
+    /// `stats()` for the status of each stage.
+    pub fn had_ignored_failures(&self) -> bool {
+        self.stats().stages.iter().any(|stage| stage.error_ignored)
+    }
+
+    // waits for the children to all exit, or returns false at `deadline`
+    fn wait_deadline(&mut self, deadline: Instant) -> bool {
+        loop {
+            // the first child still running, with its pid if it's a process
+            let running = self
+                .children
+                .iter_mut()
+                .flatten()
+                .find_map(|child| (!child.has_exited()).then(|| child.pid()));
+            let pid = match running {
+                None => return true,
+                Some(pid) => pid,
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            // sleep until the process exits where the platform supports it, otherwise poll
+            let waited = pid.map(|pid| sys::wait_exit(pid, deadline - now));
+            if !matches!(waited, Some(Ok(_))) {
+                std::thread::sleep((deadline - now).min(Duration::from_millis(10)));
+            }
+        }
+    }
+
+    fn wait_all(&mut self) -> CmdResult {
+        self.stats.ignore_error = self.ignore_error;
+        let last = match self.children.pop().unwrap() {
+            Err(e) => Err(e),
+            Ok(mut handle) => {
+                let record = handle.record.take();
+                let ret = handle.wait(true, &mut self.stats);
+                if let Some(mut record) = record {
+                    record.finish(&ret, self.started.elapsed());
+                    self.last_record = Some(record);
+                }
+                ret
+            }
+        };
+        Self::wait_upstream(last, &mut self.children, &mut self.stats)
+    }
+
+    // The wait algorithm shared by all the wait methods. The last stage is waited for first,
+    // giving `last`, then all the upstream stages are reaped in pipeline order, whatever `last`
+    // is. The error of the earliest failed stage is returned, where a failed upstream stage only
+    // counts with pipefail enabled, or if it failed to spawn, while the status of every stage
+    // is recorded in `stats`.
+    fn wait_upstream(
+        last: CmdResult,
+        upstream: &mut Vec<Result<CmdChild>>,
+        stats: &mut StatsCollector,
+    ) -> CmdResult {
+        let mut ret = Ok(());
+        for child in upstream.drain(..) {
+            let res = match child {
+                Err(e) => Err(e),
+                Ok(child) => child.wait(false, stats),
+            };
+            if ret.is_ok() {
+                ret = res;
+            }
+        }
+        ret.and(last)
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Representation of running or exited children processes with output, connected with pipes
+/// optionally.
+///
+/// Calling `spawn_with_output!` macro will return `Result<FunChildren>`
+pub struct FunChildren {
+    children: Vec<Result<CmdChild>>,
+    ignore_error: bool,
+    ignore_broken_pipe: bool,
+    ignore_sink_errors: bool,
+    chunk_read_timeout: Option<Duration>,
+    progress: Option<(Duration, FnProgress)>,
+    strip_bom: bool,
+    strip_ansi: bool,
+    stats: StatsCollector,
+    started: Instant,
+    timeout: Option<Duration>,
+    spec: Option<CmdSpec>,
+}
+
+impl FunChildren {
+    /// Ignores the errors of all the stages when waiting, like the `ignore` command at call site
+    ///
+    /// The stderr of the children is logged at the debug level, like for
+    /// `CmdChildren::ignore_errors()`.
+    pub fn ignore_errors(mut self) -> Self {
+        self.ignore_error = true;
+        CmdChild::quiet_stderr_all(&mut self.children);
+        self
+    }
+
+    /// Kills all the processes in the pipeline, which still need to be waited for
+    ///
+    /// The commands started in a process group of their own by `Process::process_group()` are
+    /// killed with the whole group. Builtin and custom commands running in threads can't be
+    /// killed.
+    pub fn kill(&mut self) -> CmdResult {
+        CmdChild::kill_all(&mut self.children)
+    }
+
+    /// Returns `Ok` instead of a `BrokenPipe` error when the writer closes early
+    ///
+    /// When writing to the writer of `wait_to_writer()` fails with `BrokenPipe`, e.g. a network
+    /// client disconnects, the children are killed and an error of kind `BrokenPipe` is returned
+    /// by default.
+    pub fn ignore_broken_pipe(mut self) -> Self {
+        self.ignore_broken_pipe = true;
+        self
+    }
+
+    /// Keeps writing to the other sinks of `wait_with_tees()` when one of them fails
+    ///
+    /// The failed sinks are logged as warnings and skipped for the rest of the output. Without
+    /// it, the first failed sink aborts the copying with its error.
+    pub fn ignore_sink_errors(mut self) -> Self {
+        self.ignore_sink_errors = true;
+        self
+    }
+
+    /// Fails reading the output if no new data arrives within `timeout`, killing the children
+    ///
+    /// Unlike `wait_with_output_timeout()`, a command can run as long as it keeps writing, so it
+    /// catches a command hanging in the middle of its output:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::time::Duration;
+    /// let url = "https://example.com/large.json";
+    /// let output = spawn_with_output!(curl -s $url)?
+    ///     .chunk_read_timeout(Duration::from_secs(30))
+    ///     .wait_with_output()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// It applies to `wait_with_output()`, `wait_to_writer()` and the other methods copying the
+    /// output while running, and to `stdout_lines_with_summary()`, returning an error of kind
+    /// `TimedOut`. `wait_with_output()` carries the output read so far in the error, like
+    /// `wait_with_output_timeout()`.
+    pub fn chunk_read_timeout(mut self, timeout: Duration) -> Self {
+        self.chunk_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Calls `f` with the progress of copying the output, at most once per `every`
+    ///
+    /// It is meant for long transfers, like streaming a large output to an uploader:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::time::Duration;
+    /// # let mut uploader = std::io::sink();
+    /// spawn_with_output!(tar -cz /data)?
+    ///     .progress(Duration::from_secs(5), |p| {
+    ///         eprintln!("{} bytes sent, {:.0} B/s", p.bytes, p.bytes_per_sec);
+    ///     })
+    ///     .wait_to_writer(&mut uploader)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The progress is reported as the output is copied, so a slow writer slows down the reports
+    /// along with the pipeline, and nothing is reported while there is no output. A last report
+    /// is made after the output ends. It applies to all the methods reading the output, like
+    /// `wait_with_output()`, `wait_to_writer()`, `wait_with_buf_pipe()`, `wait_split_lines()`,
+    /// `stdout_lines_with_summary()` and `stdout_chunks()`, with or without a timeout. With
+    /// `stdout_chunks()`, `f` is called on the thread reading the output, as it is read rather
+    /// than taken from the channel. `wait_until_line()` leaves it to the wait after it.
+    pub fn progress(mut self, every: Duration, f: impl FnMut(Progress) + Send + 'static) -> Self {
+        self.progress = Some((every, Box::new(f)));
+        self
+    }
+
+    /// Decides whether the last stage succeeded with `f`, after the default checks
+    ///
+    /// Like `CmdChildren::success_when()`, with the tail of stdout holding the last lines of the
+    /// output copied by `wait_with_output()`, `wait_to_writer()` and the other methods copying
+    /// it while running. It is empty for the methods handing stdout over, like
+    /// `wait_with_pipe()` and `stdout_lines_with_summary()`.
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let output = spawn_with_output!(legacy-tool --export)?
+    ///     .success_when(|ret, stdout_tail, _| match ret {
+    ///         Ok(()) if stdout_tail.iter().any(|line| line.starts_with("ERROR:")) => {
+    ///             Err("errors in the output".into())
+    ///         }
+    ///         _ => ret.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    ///     })
+    ///     .wait_with_output()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn success_when<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&CmdResult, &[String], &[String]) -> std::result::Result<(), String> + Send + 'static,
+    {
+        CmdChild::check_success(&mut self.children, Box::new(f));
+        self
+    }
+
+    /// Returns the spec of the pipeline, like `CmdChildren::spec()`
+    pub fn spec(&self) -> Option<CmdSpec> {
+        self.spec.clone()
+    }
+
+    /// Spawns the pipeline again, as it was spawned, like `CmdChildren::respawn()`
+    pub fn respawn(&self) -> Result<FunChildren> {
+        respawn_spec(&self.spec)?.spawn_with_output()
+    }
+
+    pub fn wait_with_output(&mut self) -> FunResult {
+        self.wait_with_output_timed().map(|(output, _)| output)
+    }
+
+    /// Waits for the output like `wait_with_output()`, also returning the time elapsed since
+    /// spawning the children
+    pub fn wait_with_output_timed(&mut self) -> Result<(String, Duration)> {
+        let output = self.wait_with_raw_output()?;
+        Ok((self.output_string(&output), self.started.elapsed()))
+    }
+
+    /// Waits for the output like `wait_with_output()`, returning `None` if the last stage wrote
+    /// no bytes at all
+    ///
+    /// `wait_with_output()` returns an empty string for both no output and a single newline,
+    /// while here the former is `None` and the latter `Some("")`:
+    /// ```
+    /// # use cmd_lib::*;
+    /// assert_eq!(spawn_with_output!(true)?.wait_with_output_opt()?, None);
+    /// assert_eq!(spawn_with_output!(echo)?.wait_with_output_opt()?, Some("".into()));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The output is checked before stripping anything, so a lone BOM with `Process::strip_bom()`
+    /// or escape sequences with `Process::strip_ansi()` are `Some("")` too.
+    pub fn wait_with_output_opt(&mut self) -> Result<Option<String>> {
+        let output = self.wait_with_raw_output()?;
+        Ok((!output.is_empty()).then(|| self.output_string(&output)))
+    }
+
+    /// Waits for the output like `wait_with_output()`, failing if `validate` rejects it
+    ///
+    /// It catches malformed output of a tool before it is used, like checking it is JSON:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # fn parse_json(_: &[u8]) -> Result<(), String> { Ok(()) }
+    /// let report = spawn_with_output!(cargo audit --json)?
+    ///     .wait_validated(|output| parse_json(output).map_err(|e| e.to_string()))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// `validate` gets the output as written by the last stage, before stripping anything, and
+    /// returns `Err` with a message to reject it, which fails with a `CmdError` of the last stage
+    /// caused by an error of kind `InvalidData`. The output is only validated when the children
+    /// succeeded, or their errors are ignored, as the output of a failed command is not expected
+    /// to be valid, so an exit status error is returned as it is.
+    pub fn wait_validated<F>(&mut self, validate: F) -> FunResult
+    where
+        F: Fn(&[u8]) -> std::result::Result<(), String>,
+    {
+        let err = match self.children.last() {
+            Some(Ok(child)) => Some(child.info.error()),
+            _ => None,
+        };
+        let output = self.wait_with_raw_output()?;
+        if let Err(msg) = validate(&output) {
+            let cause = Error::new(ErrorKind::InvalidData, format!("invalid output: {}", msg));
+            return Err(self.last_stage_error(err, cause));
+        }
+        Ok(self.output_string(&output))
+    }
+
+    /// Waits for the children, returning the value set by the last stage with
+    /// `CmdEnv::set_result()`, instead of its output
+    ///
+    /// It lets a custom command at the end of a pipeline return what it parsed as it is, without
+    /// printing and parsing it again:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::io::BufRead;
+    /// #[export_cmd(count_users)]
+    /// fn count_users(env: &mut CmdEnv) -> CmdResult {
+    ///     let users = std::io::BufReader::new(env.stdin()).lines().count();
+    ///     env.set_result(Box::new(users));
+    ///     Ok(())
+    /// }
+    /// use_custom_cmd!(count_users);
+    /// let users: usize = spawn_with_output!(cat /etc/passwd | count_users)?.wait_fun_typed()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The output of the last stage is read and dropped, and the children are waited for and
+    /// checked like with `wait_with_output()`. When the last stage is an external command, or
+    /// it set no value, or a value of another type than `T`, it fails with a `CmdError` of the
+    /// last stage caused by an error of kind `InvalidData`; use `wait_with_output()` to get the
+    /// output as text instead.
+    pub fn wait_fun_typed<T: Any>(&mut self) -> Result<T> {
+        let (err, typed_result) = match self.children.last() {
+            Some(Ok(child)) => (Some(child.info.error()), child.typed_result.clone()),
+            _ => (None, None),
+        };
+        self.wait_with_raw_output()?;
+        let value = typed_result.and_then(|slot| slot.lock().unwrap().take());
+        let cause = match value.map(|value| value.downcast::<T>()) {
+            Some(Ok(value)) => return Ok(*value),
+            Some(Err(_)) => format!(
+                "the result of the last stage is not a {}",
+                std::any::type_name::<T>()
+            ),
+            None => "no result set by the last stage".to_string(),
+        };
+        let cause = Error::new(ErrorKind::InvalidData, cause);
+        Err(self.last_stage_error(err, cause))
+    }
+
+    // an error of the last stage, as captured before waiting for it, caused by `cause`
+    fn last_stage_error(&self, err: Option<CmdError>, cause: Error) -> Error {
+        match err {
+            Some(mut err) => {
+                err.duration = self.started.elapsed();
+                err.with_cause(cause).into()
+            }
+            None => cause,
+        }
+    }
+
+    // waits for the output of the last stage, as written
+    fn wait_with_raw_output(&mut self) -> Result<Vec<u8>> {
+        if let Some(timeout) = self.timeout {
+            let remaining = timeout.saturating_sub(self.started.elapsed());
+            return self.wait_with_raw_output_timeout(remaining);
+        }
+        let pipeline = match self.children.last() {
+            Some(Ok(child)) => child.info.pipeline.clone(),
+            _ => String::new(),
+        };
+        let mut buf = vec![];
+        if let Err(e) = self.wait_to_writer(&mut buf) {
+            return match self.chunk_read_timeout {
+                Some(timeout) if e.kind() == ErrorKind::TimedOut => {
+                    Err(PartialOutput::new(&pipeline, timeout, &buf).into())
+                }
+                _ => Err(e),
+            };
+        }
+        Ok(buf)
+    }
+
+    /// Waits for the output like `wait_with_output()`, killing the children if it takes longer
+    /// than `timeout`
+    ///
+    /// On timeout, it returns an error of kind `TimedOut`, carrying the output read so far:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::time::Duration;
+    /// let mut children = spawn_with_output!(tail -f /var/log/syslog)?;
+    /// let output = match children.wait_with_output_timeout(Duration::from_secs(5)) {
+    ///     Ok(output) => output,
+    ///     Err(e) => match PartialOutput::from_io_error(&e) {
+    ///         Some(partial) => partial.output.clone(),
+    ///         None => return Err(e),
+    ///     },
+    /// };
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The timeout replaces the default one from `Process::timeout()`. Builtin and custom
+    /// commands running in threads can't be killed, so they are still waited for after timing
+    /// out.
+    pub fn wait_with_output_timeout(&mut self, timeout: Duration) -> FunResult {
+        let output = self.wait_with_raw_output_timeout(timeout)?;
+        Ok(self.output_string(&output))
+    }
+
+    fn wait_with_raw_output_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        // replaces the default timeout
+        self.timeout = None;
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        let child = match self.children.last_mut() {
+            Some(Ok(child)) => child,
+            _ => return self.wait_with_raw_output(),
+        };
+        let stdout = match child.stdout.take() {
+            Some(stdout) => stdout,
+            None => return self.wait_with_raw_output(),
+        };
+        let pipeline = child.info.pipeline.clone();
+
+        let (tx, rx) = mpsc::channel();
+        let reader = io::drain_chunks("cmd_lib stdout", stdout, move |chunk| {
+            tx.send(chunk.to_vec()).is_ok()
+        })?;
+
+        let mut meter = self.progress_meter();
+        let deadline = Instant::now() + timeout;
+        let mut output = vec![];
+        let timed_out = loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(data) => {
+                    if let Some(ref mut meter) = meter {
+                        meter.add(data.len());
+                    }
+                    output.extend(data);
+                }
+                Err(RecvTimeoutError::Disconnected) => break false,
+                Err(RecvTimeoutError::Timeout) => break true,
+            }
+        };
+        // the last report
+        drop(meter);
+        let read = if timed_out {
+            let _ = self.kill();
+            Ok(())
+        } else {
+            // the reading is done, failed with the error from the thread if any
+            reader.join().unwrap_or_else(|e| {
+                Err(Error::new(
+                    ErrorKind::Other,
+                    format!("Reading output thread joined with error: {:?}", e),
+                ))
+            })
+        };
+        if let Some(Ok(child)) = self.children.last_mut() {
+            if let Some(ref mut check) = child.success_check {
+                check.stdout_tail.push(&output);
+            }
+        }
+        // the output is already read, so this only waits for the children
+        let ret = self.wait_to_writer_inner(&mut std::io::sink());
+        let count = self.stats.count_bytes.then_some(output.len() as u64);
+        self.stats.finish(count);
+        if timed_out {
+            return Err(PartialOutput::new(&pipeline, timeout, &output).into());
+        }
+        read?;
+        ret?;
+        Ok(output)
+    }
+
+    fn output_string(&self, buf: &[u8]) -> String {
+        let mut output = buf;
+        if self.strip_bom {
+            output = output.strip_prefix(UTF8_BOM).unwrap_or(output);
+        }
+        let mut s = String::from_utf8_lossy(output).to_string();
+        if self.strip_ansi {
+            s = ansi::strip_ansi(&s);
+        }
+        if s.ends_with('\n') {
+            s.pop();
+        }
+        s
+    }
+
+    /// Classifies the stderr lines of all the stages with `classify`, sending them to the channel
+    ///
+    /// It is meant for presenting the errors and warnings of tools, like compilers, apart from the
+    /// rest of their output:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let mut children = spawn_with_output!(cargo build --message-format short)?;
+    /// let diagnostics = children.wait_with_diagnostics(|line| match line {
+    ///     line if line.contains("error") => DiagnosticKind::Error,
+    ///     line if line.contains("warning") => DiagnosticKind::Warning,
+    ///     _ => DiagnosticKind::Other,
+    /// });
+    /// let output = children.wait_with_output();
+    /// for diagnostic in diagnostics.iter().filter(|d| d.kind != DiagnosticKind::Other) {
+    ///     println!("{:?}: {}", diagnostic.kind, diagnostic.line);
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Stderr is read on a thread per stage, with the lines sent as they arrive, instead of
+    /// being logged, so it is not in `CmdError::stderr_tail`. The children are still waited for
+    /// with the other methods, and the channel is closed after all the stages close stderr, so
+    /// iterating it before waiting for the output may block on a full stdout pipe. Lines are
+    /// split on `\n` and decoded lossily, like the logged ones.
+    pub fn wait_with_diagnostics(
+        &mut self,
+        classify: impl Fn(&str) -> DiagnosticKind + Send + Sync + 'static,
+    ) -> mpsc::Receiver<Diagnostic> {
+        let classify: FnClassify = Arc::new(classify);
+        let (tx, rx) = mpsc::channel();
+        for child in self.children.iter_mut().flatten() {
+            if let Some(stderr) = child.stderr.take() {
+                let command = child.info.cmd.clone();
+                let stage_index = child.info.stage_index;
+                let classify = classify.clone();
+                let tx = tx.clone();
+                // without a thread stderr is closed, like with the stderr logging
+                let _ = diagnostic::relay(stderr, command, stage_index, classify, tx);
+            }
+        }
+        rx
+    }
+
+    /// Waits for the children, returning the stdout lines and the stderr lines of all the stages
+    ///
+    /// Stderr is captured instead of logged, with the stages in pipeline order. Lines are split on
+    /// `\n`, so empty lines are kept, but a trailing newline doesn't produce an empty last line.
+    pub fn wait_split_lines(&mut self) -> Result<(Vec<String>, Vec<String>)> {
+        // drain stderr concurrently, or the children could block on a full pipe
+        let stderr_threads: Vec<JoinHandle<Vec<u8>>> = self
+            .children
+            .iter_mut()
+            .flatten()
+            .filter_map(|child| child.stderr.take())
+            .map(|mut stderr| {
+                std::thread::spawn(move || {
+                    let mut buf = vec![];
+                    let _ = stderr.read_to_end(&mut buf);
+                    buf
+                })
+            })
+            .collect();
+        let mut stdout = vec![];
+        let ret = self.wait_to_writer(&mut stdout);
+        let mut stderr = vec![];
+        for thread in stderr_threads {
+            stderr.extend(thread.join().unwrap_or_default());
+        }
+        ret?;
+        let (mut stdout, mut stderr) = (split_lines(&stdout), split_lines(&stderr));
+        if self.strip_ansi {
+            for line in stdout.iter_mut().chain(stderr.iter_mut()) {
+                *line = ansi::strip_ansi(line);
+            }
+        }
+        Ok((stdout, stderr))
+    }
+
+    /// Reads the output until a line matching `pred`, leaving the children running
+    ///
+    /// It returns the matched line, or a `TimedOut` error if no line matches within `timeout`, or
+    /// an `UnexpectedEof` error if the output is closed before that. The output after the matched
+    /// line is discarded, so the children won't block on a full pipe, and they can be waited for
+    /// later as usual.
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::time::Duration;
+    /// let mut server = spawn_with_output!(my_server --port 8080)?;
+    /// server.wait_until_line(|line| line.contains("ready"), Duration::from_secs(10))?;
+    /// run_cmd!(curl localhost:8080)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn wait_until_line(
+        &mut self,
+        pred: impl Fn(&str) -> bool,
+        timeout: Duration,
+    ) -> Result<String> {
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        let child = match self.children.last_mut() {
+            Some(Ok(child)) => child,
+            _ => return Err(self.children.pop().unwrap().err().unwrap()),
+        };
+        let stdout = match child.stdout.take() {
+            Some(stdout) => stdout,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("Output of {} is already read", child.info.cmd),
+                ))
+            }
+        };
+
+        let (tx, rx) = mpsc::channel();
+        io::drain_lines("cmd_lib stdout", stdout, move |line| {
+            tx.send(io::line_to_string(line)).is_ok()
+        })?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) if pred(&line) => return Ok(line),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("Timed out waiting for the line from {}", child.info.cmd),
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("Output of {} closed before the line", child.info.cmd),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Returns the statistics of the pipeline, which are complete after waiting
+    pub fn stats(&self) -> &PipelineStats {
+        &self.stats.stats
+    }
+
+    /// Waits for the children, copying the output to `writer` while running
+    pub fn wait_to_writer(&mut self, writer: &mut dyn Write) -> CmdResult {
+        if let Some(meter) = self.progress_meter() {
+            let mut writer = ProgressWriter {
+                inner: writer,
+                meter,
+            };
+            return self.wait_to_writer(&mut writer);
+        }
+        if self.stats.count_bytes {
+            let mut writer = CountingWriter::new(writer);
+            let ret = self.wait_to_writer_inner(&mut writer);
+            self.stats.finish(Some(writer.count));
+            ret
+        } else {
+            let ret = self.wait_to_writer_inner(writer);
+            self.stats.finish(None);
+            ret
+        }
+    }
+
+    /// Waits for the children, calling `f` with each record of `size` bytes in the output while
+    /// running, and returns the trailing partial record
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let mut children = spawn_with_output!(tar -cf - src)?;
+    /// let rest = children.wait_records(512, &mut |block| {
+    ///     // parse a tar block
+    ///     Ok(())
+    /// })?;
+    /// assert!(rest.is_empty(), "tar output is made of whole blocks");
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The records are complete however the output is split in reads. When the output is not a
+    /// multiple of `size`, the bytes after the last complete record are returned, and an empty
+    /// vector otherwise, so it is up to the caller whether a partial record is an error. An error
+    /// returned by `f` stops reading like a failing writer in `wait_to_writer()`. A zero `size`
+    /// fails with an error of kind `InvalidInput`, after killing and waiting for the children.
+    pub fn wait_records(
+        &mut self,
+        size: usize,
+        f: &mut dyn FnMut(&[u8]) -> CmdResult,
+    ) -> Result<Vec<u8>> {
+        if size == 0 {
+            // none of the children is left running
+            let _ = self.kill();
+            let _ = self.wait_to_writer(&mut std::io::sink());
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "record size must not be zero",
+            ));
+        }
+        let mut writer = RecordWriter {
+            size,
+            buf: Vec::with_capacity(size),
+            f,
+        };
+        self.wait_to_writer(&mut writer)?;
+        Ok(writer.buf)
+    }
+
+    /// Waits for the children, calling `f` with each record of the output starting at a line
+    /// matching `re`, and returns the text before the first record
+    ///
+    /// It splits output like logs, where an entry spans several lines and starts with a
+    /// timestamp:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let start = cmd_lib::regex::Regex::new(r"^\d{4}-\d{2}-\d{2} ").unwrap();
+    /// let mut children = spawn_with_output!(cat /var/log/app.log)?;
+    /// children.wait_records_regex(&start, &mut |entry| {
+    ///     if entry.contains("panicked") {
+    ///         eprint!("{}", entry);
+    ///     }
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Each line is matched without its line ending, and a matching line starts a new record,
+    /// which runs up to the next matching line, or to the end of the output for the last one.
+    /// The records keep their line endings, so the returned text followed by the records is the
+    /// whole output. The lines before the first matching one, a partial record cut off by the
+    /// start of the output, are returned rather than passed to `f`, and the text is empty when
+    /// the output starts with a record. Invalid UTF-8 is replaced like in `wait_with_output()`.
+    /// An error returned by `f` stops reading like a failing writer in `wait_to_writer()`. It
+    /// needs the `regex` feature, which re-exports the crate as `cmd_lib::regex`.
+    #[cfg(feature = "regex")]
+    pub fn wait_records_regex(
+        &mut self,
+        re: &regex::Regex,
+        f: &mut dyn FnMut(&str) -> CmdResult,
+    ) -> Result<String> {
+        let mut writer = RegexRecordWriter {
+            re,
+            line: vec![],
+            record: None,
+            leading: String::new(),
+            f,
+        };
+        self.wait_to_writer(&mut writer)?;
+        writer.finish()?;
+        Ok(writer.leading)
+    }
+
+    /// Waits for the children, copying the output to all the `sinks` while running, and returns
+    /// the number of bytes copied
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let mut log = std::fs::File::create("build.log")?;
+    /// let mut children = spawn_with_output!(make)?;
+    /// children.wait_with_tees(&mut [&mut log, &mut std::io::stdout()])?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Each chunk of the output is written to the sinks in order, so the command only runs once.
+    /// See `ignore_sink_errors()` for continuing with the rest of the sinks when one fails.
+    pub fn wait_with_tees(&mut self, sinks: &mut [&mut dyn Write]) -> Result<u64> {
+        let mut tee = TeeWriter::new(sinks, self.ignore_sink_errors);
+        self.wait_to_writer(&mut tee)?;
+        tee.flush()?;
+        Ok(tee.count)
+    }
+
+    // the meter of `progress()`, which is reported to by the first wait reading the output
+    fn progress_meter(&mut self) -> Option<ProgressMeter> {
+        let (every, f) = self.progress.take()?;
+        Some(ProgressMeter::new(
+            every,
+            f,
+            self.started,
+            &self.stats.counters,
+        ))
+    }
+
+    fn wait_to_writer_inner(&mut self, writer: &mut dyn Write) -> CmdResult {
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        self.stats.ignore_error = self.ignore_error;
+        let last = match self.children.pop().unwrap() {
+            Err(e) => Err(e),
+            Ok(handle) => handle.wait_with_writer(
+                writer,
+                self.ignore_error,
+                self.chunk_read_timeout,
+                &mut self.stats,
+            ),
+        };
+        if let Err(ref e) = last {
+            if matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::TimedOut) {
+                // the upstream stages are killed, so their failures are not theirs
+                let _ = CmdChild::kill_all(&mut self.children);
+                let _ = CmdChildren::wait_upstream(Ok(()), &mut self.children, &mut self.stats);
+                return if e.kind() == ErrorKind::BrokenPipe && self.ignore_broken_pipe {
+                    Ok(())
+                } else {
+                    last
+                };
+            }
+        }
+        let ret = CmdChildren::wait_upstream(last, &mut self.children, &mut self.stats);
+        if self.ignore_error {
+            return Ok(());
+        }
+        ret
+    }
+
+    /// Waits for the children, spilling the output to an unlinked temp file and mapping it
+    ///
+    /// The temp file is created in `std::env::temp_dir()`, and it is removed once the returned
+    /// map is dropped.
+    #[cfg(feature = "mmap")]
+    pub fn wait_to_mmap(&mut self) -> Result<memmap2::Mmap> {
+        let mut file = process::temp_file()?;
+        self.wait_to_writer(&mut file)?;
+        // safety: the file is private to us, since it is already unlinked
+        unsafe { memmap2::Mmap::map(&file) }
+    }
+
+    /// Streams the output lines, with a summary of the pipeline after the lines are exhausted
+    ///
+    /// The stderr of all the stages is still logged, with the lines counted for the summary.
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let mut lines = spawn_with_output!(cargo build --message-format short)?
+    ///     .stdout_lines_with_summary()?;
+    /// for line in lines.by_ref() {
+    ///     println!("{}", line);
+    /// }
+    /// let summary = lines.finish()?;
+    /// println!("{} lines, exit code {:?}", summary.stdout_lines, summary.exit_code);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn stdout_lines_with_summary(self) -> Result<StdoutLines> {
+        self.stdout_lines(None)
+    }
+
+    /// Iterates over the output lines like `stdout_lines_with_summary()`, for up to `deadline`
+    ///
+    /// It is meant for following a command for a while, like tailing logs for 30 seconds:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::time::Duration;
+    /// let mut lines = spawn_with_output!(journalctl -f -u nginx)?
+    ///     .stdout_lines_until(Duration::from_secs(30))?;
+    /// let mut logs = vec![];
+    /// for line in lines.by_ref() {
+    ///     match line {
+    ///         Ok(line) => logs.push(line),
+    ///         Err(e) => eprintln!("stopped following: {}", e),
+    ///     }
+    /// }
+    /// lines.finish()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The deadline counts from this call, and is checked while waiting for the next chunk of
+    /// output, without polling. When it passes, the children are killed, and the iterator ends
+    /// with an error of kind `TimedOut`. A read error, including the one of
+    /// `chunk_read_timeout()`, also ends it as an error item, after the partial line read before
+    /// it, if any.
+    pub fn stdout_lines_until(self, deadline: Duration) -> Result<StdoutLinesUntil> {
+        let lines = self.stdout_lines(Some((Instant::now() + deadline, deadline)))?;
+        Ok(StdoutLinesUntil { lines })
+    }
+
+    fn stdout_lines(mut self, deadline: Option<(Instant, Duration)>) -> Result<StdoutLines> {
+        let mut stderr_counters = vec![];
+        for (i, child) in self.children.iter_mut().enumerate() {
+            if let Ok(child) = child {
+                if let Some(stderr) = child.stderr.take() {
+                    let (relay, counter) = PipeCounter::relay_lines(i, stderr)?;
+                    child.stderr = Some(relay);
+                    stderr_counters.push(counter);
+                }
+            }
+        }
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        let timeout = self.chunk_read_timeout;
+        let stdout = match self.children.last_mut() {
+            Some(Ok(child)) => child
+                .stdout
+                .take()
+                .map(|stdout| ChunkTimeoutReader::wrap(stdout, timeout, deadline, &child.info.cmd))
+                .transpose()?
+                .map(BufReader::new),
+            _ => None,
+        };
+        let meter = self.progress_meter();
+        Ok(StdoutLines {
+            children: self,
+            stdout,
+            meter,
+            stdout_lines: 0,
+            stderr_counters,
+            read_error: None,
+        })
+    }
+
+    /// Streams the output in chunks, through a channel holding at most `capacity` chunks
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::io::Write;
+    /// # let mut upload = std::io::sink();
+    /// let mut chunks = spawn_with_output!(pg_dump mydb)?.stdout_chunks(4);
+    /// for chunk in chunks.by_ref() {
+    ///     upload.write_all(&chunk)?;
+    /// }
+    /// chunks.finish()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The output is read on a thread, in chunks of up to 64 KiB. When the channel is full, the
+    /// thread stops reading until a chunk is taken, so a slow consumer blocks the producer once
+    /// the pipe buffer is full too, instead of the output piling up in memory. With `capacity` 0,
+    /// each chunk is handed over directly.
+    pub fn stdout_chunks(mut self, capacity: usize) -> StdoutChunks {
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        let stdout = match self.children.last_mut() {
+            Some(Ok(child)) => child.stdout.take(),
+            _ => None,
+        };
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        let mut meter = self.progress_meter();
+        let reader = stdout.map(|stdout| {
+            io::drain_chunks("cmd_lib stdout", stdout, move |chunk| {
+                if let Some(ref mut meter) = meter {
+                    meter.add(chunk.len());
+                }
+                // blocks while the channel is full
+                tx.send(chunk.to_vec()).is_ok()
+            })
+        });
+        StdoutChunks {
+            children: self,
+            rx,
+            reader,
+        }
+    }
+
+    pub fn wait_with_pipe(&mut self, f: &mut dyn FnMut(Box<dyn Read>)) -> CmdResult {
+        self.wait_with_buf_pipe_inner(|stdout| {
+            f(Box::new(stdout));
+            Ok(())
+        })
+    }
+
+    /// Waits for the children, with `f` reading the output from a buffered reader
+    ///
+    /// Like `wait_with_pipe()`, without wrapping the reader in a `BufReader`:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::io::BufRead;
+    /// spawn_with_output!(journalctl)?.wait_with_buf_pipe(|pipe| {
+    ///     for line in pipe.lines().take(10) {
+    ///         println!("{}", line?);
+    ///     }
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// When `f` returns before the end of the output, the stages still running are killed instead
+    /// of waited for, and their failures are not reported. An error returned by `f` is returned
+    /// instead of the error of the pipeline, even when its errors are ignored. With
+    /// `Process::count_pipe_bytes()`, the bytes consumed by `f` are counted in `stats()` as the
+    /// output of the last stage.
+    pub fn wait_with_buf_pipe(
+        &mut self,
+        f: impl FnOnce(&mut dyn BufRead) -> Result<()>,
+    ) -> CmdResult {
+        self.wait_with_buf_pipe_inner(|mut stdout| f(&mut stdout))
+    }
+
+    fn wait_with_buf_pipe_inner(&mut self, f: impl FnOnce(BufPipe) -> Result<()>) -> CmdResult {
+        let mut meter = self.progress_meter();
+        CmdChild::start_stderr_logging_all(&mut self.children);
+        self.stats.ignore_error = self.ignore_error;
+        let consumed = Arc::new(AtomicU64::new(0));
+        let mut read = Ok(());
+        let mut stopped_early = false;
+        let last = match self.children.pop().unwrap() {
+            Err(e) => Err(e),
+            Ok(mut child) => {
+                let eof = Arc::new(AtomicBool::new(true));
+                if let Some(stdout) = child.stdout.take() {
+                    eof.store(false, Ordering::Relaxed);
+                    let mut stdout: Box<dyn Read> = Box::new(EofReader {
+                        inner: stdout,
+                        eof: eof.clone(),
+                    });
+                    if let Some(meter) = meter.take() {
+                        stdout = Box::new(ProgressReader {
+                            inner: stdout,
+                            meter,
+                        });
+                    }
+                    read = f(BufPipe {
+                        inner: BufReader::new(stdout),
+                        consumed: consumed.clone(),
+                    });
+                }
+                // `f` may stop reading early, so the stages still running are stopped, and not
+                // blamed for it, while threads can't be stopped and are left running
+                stopped_early = !eof.load(Ordering::Relaxed);
+                if !stopped_early || child.has_exited() {
+                    child.wait(true, &mut self.stats)
+                } else {
+                    let stderr_logging = child.take_stderr_logging();
+                    if let CmdChildHandle::Proc(mut proc) = child.handle {
+                        let _ = sys::kill_tree(&mut proc);
+                        let _ = proc.wait();
+                    }
+                    stderr_logging.finish();
+                    CmdChild::finish_log_relays(child.log_relays);
+                    Ok(())
+                }
+            }
+        };
+        let ret = if stopped_early {
+            let _ = CmdChild::kill_all(&mut self.children);
+            let _ = CmdChildren::wait_upstream(Ok(()), &mut self.children, &mut self.stats);
+            last
+        } else {
+            CmdChildren::wait_upstream(last, &mut self.children, &mut self.stats)
+        };
+        let count = self
+            .stats
+            .count_bytes
+            .then(|| consumed.load(Ordering::Relaxed));
+        self.stats.finish(count);
+        read?;
+        if self.ignore_error {
+            return Ok(());
+        }
+        ret
+    }
+}
+
+/// Iterator of the output lines from `FunChildren::stdout_lines_with_summary()`
+///
+/// Lines are split on `\n` like `wait_split_lines()`. Reading stops at the first read error,
+/// which is returned by `finish()`.
+pub struct StdoutLines {
+    children: FunChildren,
+    stdout: Option<BufReader<Box<dyn Read + Send>>>,
+    meter: Option<ProgressMeter>,
+    stdout_lines: u64,
+    stderr_counters: Vec<PipeCounter>,
+    read_error: Option<Error>,
+}
+
+/// Iterator of the output lines from `FunChildren::stdout_lines_until()`
+///
+/// Like `StdoutLines`, with the error ending the iteration returned as the last item.
+pub struct StdoutLinesUntil {
+    lines: StdoutLines,
+}
+
+impl StdoutLinesUntil {
+    /// Waits for the children after reading the rest of the output, returning the summary
+    ///
+    /// Like `StdoutLines::finish()`, with the children killed after the deadline reported as
+    /// failed. The error ending the iteration is not returned again.
+    pub fn finish(self) -> Result<PipelineSummary> {
+        self.lines.finish()
+    }
+}
+
+impl Iterator for StdoutLinesUntil {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        match self.lines.next() {
+            Some(line) => Some(Ok(line)),
+            None => self.lines.read_error.take().map(Err),
         }
     }
+}
 
-    pub(crate) fn into_fun_children(self) -> FunChildren {
-        FunChildren {
-            children: self.children,
-            ignore_error: self.ignore_error,
+/// Iterator of the output chunks from `FunChildren::stdout_chunks()`
+pub struct StdoutChunks {
+    children: FunChildren,
+    rx: mpsc::Receiver<Vec<u8>>,
+    reader: Option<Result<JoinHandle<Result<()>>>>,
+}
+
+impl StdoutChunks {
+    /// Waits for the children after reading the rest of the output
+    ///
+    /// An error reading the output is returned after waiting for the children.
+    pub fn finish(mut self) -> CmdResult {
+        // drain the rest, so the children won't block on a full pipe
+        for _ in self.by_ref() {}
+        let read = match self.reader.take() {
+            Some(Ok(reader)) => reader.join().unwrap_or_else(|e| {
+                Err(Error::new(
+                    ErrorKind::Other,
+                    format!("Reading output thread joined with error: {:?}", e),
+                ))
+            }),
+            Some(Err(e)) => Err(e),
+            None => Ok(()),
+        };
+        let ret = self.children.wait_to_writer(&mut std::io::sink());
+        read?;
+        ret
+    }
+}
+
+impl Iterator for StdoutChunks {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Summary of a pipeline streamed by `StdoutLines`
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PipelineSummary {
+    /// Whether the pipeline succeeded, which is always true when its errors are ignored
+    pub success: bool,
+    /// Exit code of the failed stage, or 0 if succeeded
+    pub exit_code: Option<i32>,
+    /// Signal terminating the failed stage, on unix only
+    pub signal: Option<i32>,
+    /// Index of the failed stage in the pipeline
+    pub failed_stage: Option<usize>,
+    /// Time elapsed since spawning the pipeline
+    pub duration: Duration,
+    /// Lines written to stdout by the last stage
+    pub stdout_lines: u64,
+    /// Lines written to stderr by all the stages, and relayed to the logger
+    pub stderr_lines: u64,
+}
+
+impl StdoutLines {
+    /// Waits for the children after reading the rest of the output, returning the summary
+    ///
+    /// A stage exiting with failure is reported in the summary instead of an error, which is
+    /// only returned if the output can't be read or a command can't be run or waited for.
+    pub fn finish(mut self) -> Result<PipelineSummary> {
+        // drain the rest, so the children won't block on a full pipe
+        for _ in self.by_ref() {}
+        let ret = self.children.wait_to_writer(&mut std::io::sink());
+        let stderr_lines = self
+            .stderr_counters
+            .drain(..)
+            .map(PipeCounter::finish)
+            .sum();
+        if let Some(e) = self.read_error.take() {
+            return Err(e);
+        }
+        let mut summary = PipelineSummary {
+            success: ret.is_ok(),
+            exit_code: Some(0),
+            signal: None,
+            failed_stage: None,
+            duration: self.children.started.elapsed(),
+            stdout_lines: self.stdout_lines,
+            stderr_lines,
+        };
+        if let Err(e) = ret {
+            match CmdError::from_io_error(&e) {
+                Some(err) if err.exit_code.is_some() || err.signal.is_some() => {
+                    summary.exit_code = err.exit_code;
+                    summary.signal = err.signal;
+                    summary.failed_stage = Some(err.stage_index);
+                }
+                _ => return Err(e),
+            }
         }
+        Ok(summary)
     }
 
-    pub fn wait(&mut self) -> CmdResult {
-        // wait for the last child result
-        let handle = self.children.pop().unwrap();
-        match handle {
-            Err(e) => {
-                let _ = Self::wait_children(&mut self.children);
-                return Err(e);
+    fn take_line(&mut self, mut line: Vec<u8>) -> String {
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        let mut line = &line[..];
+        if self.stdout_lines == 0 && self.children.strip_bom {
+            line = line.strip_prefix(UTF8_BOM).unwrap_or(line);
+        }
+        self.stdout_lines += 1;
+        let line = String::from_utf8_lossy(line);
+        if self.children.strip_ansi {
+            ansi::strip_ansi(&line)
+        } else {
+            line.to_string()
+        }
+    }
+
+    // waits like `finish()`, with the failure of a stage returned as the error
+    pub(crate) fn finish_result(mut self) -> CmdResult {
+        for _ in self.by_ref() {}
+        let ret = self.children.wait_to_writer(&mut std::io::sink());
+        for counter in self.stderr_counters.drain(..) {
+            counter.finish();
+        }
+        match self.read_error.take() {
+            Some(e) => Err(e),
+            None => ret,
+        }
+    }
+}
+
+impl Iterator for StdoutLines {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let stdout = self.stdout.as_mut()?;
+        let mut line = vec![];
+        match stdout.read_until(b'\n', &mut line) {
+            Ok(0) => {
+                self.stdout = None;
+                // the last report
+                self.meter = None;
+                None
             }
-            Ok(handle) => {
-                if let Err(e) = handle.wait(true) {
-                    let _ = Self::wait_children(&mut self.children);
-                    return Err(e);
+            Ok(n) => {
+                if let Some(ref mut meter) = self.meter {
+                    meter.add(n);
+                }
+                Some(self.take_line(line))
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::TimedOut {
+                    let _ = self.children.kill();
                 }
+                self.read_error = Some(e);
+                self.stdout = None;
+                self.meter = None;
+                // the partial line read before the error, which ends the iteration after it
+                (!line.is_empty()).then(|| self.take_line(line))
             }
         }
-        Self::wait_children(&mut self.children)
     }
+}
 
-    fn wait_children(children: &mut Vec<Result<CmdChild>>) -> CmdResult {
-        let mut ret = Ok(());
-        while !children.is_empty() {
-            let child_handle = children.pop().unwrap();
-            match child_handle {
-                Err(e) => ret = Err(e),
-                Ok(child_handle) => {
-                    if let Err(e) = child_handle.wait(false) {
-                        ret = Err(e);
+// Reads on a thread, failing when no chunk arrives within `timeout`, or after the instant of
+// `deadline`, which also holds the total time allowed for the error message
+struct ChunkTimeoutReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    reader: Option<JoinHandle<Result<()>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    timeout: Option<Duration>,
+    deadline: Option<(Instant, Duration)>,
+    cmd: String,
+}
+
+impl ChunkTimeoutReader {
+    fn wrap(
+        stdout: PipeReader,
+        timeout: Option<Duration>,
+        deadline: Option<(Instant, Duration)>,
+        cmd: &str,
+    ) -> Result<Box<dyn Read + Send>> {
+        if timeout.is_none() && deadline.is_none() {
+            return Ok(Box::new(stdout));
+        }
+        let (tx, rx) = mpsc::sync_channel(1);
+        let reader = io::drain_chunks("cmd_lib stdout", stdout, move |chunk| {
+            tx.send(chunk.to_vec()).is_ok()
+        })?;
+        Ok(Box::new(Self {
+            rx,
+            reader: Some(reader),
+            chunk: vec![],
+            pos: 0,
+            timeout,
+            deadline,
+            cmd: cmd.into(),
+        }))
+    }
+
+    fn timed_out(&self) -> Error {
+        match self.deadline {
+            Some((at, total)) if Instant::now() >= at => Error::new(
+                ErrorKind::TimedOut,
+                format!("{} still running after {:?}", self.cmd, total),
+            ),
+            _ => Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "No output from {} within {:?}",
+                    self.cmd,
+                    self.timeout.unwrap_or_default()
+                ),
+            ),
+        }
+    }
+}
+
+impl Read for ChunkTimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos == self.chunk.len() {
+            let left = self
+                .deadline
+                .map(|(at, _)| at.saturating_duration_since(Instant::now()));
+            let wait = match (self.timeout, left) {
+                (Some(timeout), Some(left)) => timeout.min(left),
+                (timeout, left) => timeout.or(left).unwrap_or_default(),
+            };
+            match self.rx.recv_timeout(wait) {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // the reading is done, failed with the error from the thread if any
+                Err(RecvTimeoutError::Disconnected) => {
+                    return match self.reader.take().map(JoinHandle::join) {
+                        Some(Ok(Err(e))) => Err(e),
+                        _ => Ok(0),
                     }
                 }
+                Err(RecvTimeoutError::Timeout) => return Err(self.timed_out()),
             }
         }
-        ret
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
     }
 }
 
-/// Representation of running or exited children processes with output, connected with pipes
-/// optionally.
-///
-/// Calling `spawn_with_output!` macro will return `Result<FunChildren>`
-pub struct FunChildren {
-    children: Vec<Result<CmdChild>>,
-    ignore_error: bool,
+fn respawn_spec(spec: &Option<CmdSpec>) -> Result<&CmdSpec> {
+    spec.as_ref().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "no spec to spawn the pipeline again, see CmdSpec",
+        )
+    })
 }
 
-impl FunChildren {
-    pub fn wait_with_output(&mut self) -> FunResult {
-        // wait for the last child result
-        let handle = self.children.pop().unwrap();
-        match handle {
-            Err(e) => {
-                let _ = CmdChildren::wait_children(&mut self.children);
-                Err(e)
-            }
-            Ok(handle) => {
-                let wait_last = handle.wait_with_output(self.ignore_error);
-                match wait_last {
-                    Err(e) => {
-                        let _ = CmdChildren::wait_children(&mut self.children);
-                        Err(e)
-                    }
-                    Ok(output) => {
-                        let mut s = String::from_utf8_lossy(&output).to_string();
-                        if s.ends_with('\n') {
-                            s.pop();
-                        }
-                        let ret = CmdChildren::wait_children(&mut self.children);
-                        if let Err(e) = ret {
-                            if !self.ignore_error {
-                                return Err(e);
-                            }
-                        }
-                        Ok(s)
-                    }
-                }
+fn split_lines(buf: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(buf)
+        .split_terminator('\n')
+        .map(String::from)
+        .collect()
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    count: u64,
+}
+
+impl<'a> CountingWriter<'a> {
+    fn new(inner: &'a mut dyn Write) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+// splits the output in records for `FunChildren::wait_records()`
+struct RecordWriter<'a> {
+    size: usize,
+    // the partial record so far
+    buf: Vec<u8>,
+    f: &'a mut dyn FnMut(&[u8]) -> CmdResult,
+}
+
+impl Write for RecordWriter<'_> {
+    fn write(&mut self, mut data: &[u8]) -> Result<usize> {
+        let len = data.len();
+        if !self.buf.is_empty() {
+            let n = (self.size - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() < self.size {
+                return Ok(len);
             }
+            (self.f)(&self.buf)?;
+            self.buf.clear();
+        }
+        let mut records = data.chunks_exact(self.size);
+        for record in records.by_ref() {
+            (self.f)(record)?;
         }
+        self.buf.extend_from_slice(records.remainder());
+        Ok(len)
     }
 
-    pub fn wait_with_pipe(&mut self, f: &mut dyn FnMut(Box<dyn Read>)) -> CmdResult {
-        let child = self.children.pop().unwrap()?;
-        let polling_stderr = StderrLogging::new(&child.cmd, child.stderr);
-        match child.handle {
-            CmdChildHandle::Proc(mut proc) => {
-                if let Some(stdout) = child.stdout {
-                    f(Box::new(stdout));
-                    let _ = proc.kill();
-                }
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "regex")]
+struct RegexRecordWriter<'a> {
+    re: &'a regex::Regex,
+    // the partial line so far
+    line: Vec<u8>,
+    // the record so far, once a line matched
+    record: Option<String>,
+    leading: String,
+    f: &'a mut dyn FnMut(&str) -> CmdResult,
+}
+
+#[cfg(feature = "regex")]
+impl RegexRecordWriter<'_> {
+    fn push_line(&mut self) -> CmdResult {
+        let line = String::from_utf8_lossy(&self.line).to_string();
+        self.line.clear();
+        if self.re.is_match(line.trim_end_matches(['\n', '\r'])) {
+            if let Some(record) = self.record.take() {
+                (self.f)(&record)?;
             }
-            CmdChildHandle::Thread(_) => {
-                if let Some(stdout) = child.stdout {
-                    f(Box::new(stdout));
-                }
+            self.record = Some(line);
+        } else {
+            match self.record {
+                Some(ref mut record) => record.push_str(&line),
+                None => self.leading.push_str(&line),
+            }
+        }
+        Ok(())
+    }
+
+    // the last line may have no line ending, and the last record ends with the output
+    fn finish(&mut self) -> CmdResult {
+        if !self.line.is_empty() {
+            self.push_line()?;
+        }
+        match self.record.take() {
+            Some(record) => (self.f)(&record),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Write for RegexRecordWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            self.line.extend_from_slice(line);
+            if line.ends_with(b"\n") {
+                self.push_line()?;
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct TeeWriter<'a, 'b> {
+    sinks: &'a mut [&'b mut dyn Write],
+    failed: Vec<bool>,
+    ignore_errors: bool,
+    count: u64,
+}
+
+impl<'a, 'b> TeeWriter<'a, 'b> {
+    fn new(sinks: &'a mut [&'b mut dyn Write], ignore_errors: bool) -> Self {
+        let failed = vec![false; sinks.len()];
+        Self {
+            sinks,
+            failed,
+            ignore_errors,
+            count: 0,
+        }
+    }
+
+    fn for_each_sink(&mut self, mut f: impl FnMut(&mut dyn Write) -> Result<()>) -> Result<()> {
+        let mut last_err = None;
+        for (i, sink) in self.sinks.iter_mut().enumerate() {
+            if self.failed[i] {
+                continue;
             }
-            CmdChildHandle::SyncFn(_) => {
-                if let Some(stdout) = child.stdout {
-                    f(Box::new(stdout));
+            if let Err(e) = f(&mut **sink) {
+                if !self.ignore_errors {
+                    return Err(e);
                 }
+                warn!("Writing to sink {} failed, skipping it: {}", i, e);
+                self.failed[i] = true;
+                last_err = Some(e);
             }
-        };
-        drop(polling_stderr);
-        CmdChildren::wait_children(&mut self.children)
+        }
+        // stop copying once there is no sink left
+        match last_err {
+            Some(e) if self.failed.iter().all(|failed| *failed) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for TeeWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.for_each_sink(|sink| sink.write_all(buf))?;
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.for_each_sink(|sink| sink.flush())
     }
 }
 
 pub(crate) struct CmdChild {
     handle: CmdChildHandle,
-    cmd: String,
+    info: ChildInfo,
+    record: Option<ExecutionRecord>,
     stdout: Option<PipeReader>,
     stderr: Option<PipeReader>,
+    stderr_logging: Option<StderrLogging>,
+    // stderr is logged at the debug level instead of the info level, as the errors are ignored,
+    // read by the logging thread for each line
+    stderr_quiet: Arc<AtomicBool>,
+    success_check: Option<SuccessCheck>,
+    // the thread compressing the stdout redirected to a file
+    stdout_relay: Option<JoinHandle<CmdResult>>,
+    // the threads copying the output to the log files of `Process::stdout_log()` and
+    // `Process::stderr_log()`
+    log_relays: Vec<JoinHandle<Result<()>>>,
+    typed_result: Option<TypedResult>,
+    ignore_error: bool,
+}
+
+// what the errors need to know about the child
+struct ChildInfo {
+    cmd: String,
+    argv: Vec<String>,
+    pipeline: String,
+    stage_index: usize,
+    started: Instant,
+}
+
+impl ChildInfo {
+    fn error(&self) -> CmdError {
+        let mut err = CmdError::new(
+            &self.cmd,
+            &self.pipeline,
+            self.stage_index,
+            self.started.elapsed(),
+        );
+        err.argv = self.argv.clone();
+        err
+    }
 }
 
 impl CmdChild {
@@ -149,43 +2230,266 @@ impl CmdChild {
     ) -> Self {
         Self {
             handle,
-            cmd,
+            info: ChildInfo {
+                pipeline: cmd.clone(),
+                cmd,
+                argv: vec![],
+                stage_index: 0,
+                started: Instant::now(),
+            },
+            record: None,
             stdout,
             stderr,
+            stderr_logging: None,
+            stderr_quiet: Arc::default(),
+            success_check: None,
+            stdout_relay: None,
+            log_relays: vec![],
+            typed_result: None,
+            ignore_error: false,
+        }
+    }
+
+    // Logs stderr on a thread from now on, unless it's taken or logged already. Every wait
+    // method starts it for all the stages before blocking on any of them, as a stage blocked on
+    // a full stderr pipe could block the others, and each stage joins it once waited for.
+    fn start_stderr_logging(&mut self) {
+        if self.stderr_logging.is_none() {
+            self.stderr_logging = Some(StderrLogging::new(
+                &self.info.cmd,
+                self.stderr.take(),
+                self.stderr_quiet.clone(),
+            ));
+        }
+    }
+
+    fn quiet_stderr_all(children: &mut [Result<CmdChild>]) {
+        for child in children.iter_mut().flatten() {
+            child.stderr_quiet.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn start_stderr_logging_all(children: &mut [Result<CmdChild>]) {
+        children
+            .iter_mut()
+            .flatten()
+            .for_each(CmdChild::start_stderr_logging);
+    }
+
+    fn take_stderr_logging(&mut self) -> StderrLogging {
+        self.start_stderr_logging();
+        self.stderr_logging.take().unwrap()
+    }
+
+    pub(crate) fn in_pipeline(
+        mut self,
+        stage_index: usize,
+        pipeline: &str,
+        argv: Vec<String>,
+    ) -> Self {
+        self.info.stage_index = stage_index;
+        self.info.pipeline = pipeline.into();
+        self.info.argv = argv;
+        self
+    }
+
+    // ignores the errors of this stage only, with `ignore` not starting the pipeline
+    pub(crate) fn ignore_error(mut self, ignore_error: bool) -> Self {
+        self.ignore_error = ignore_error;
+        self
+    }
+
+    pub(crate) fn with_record(mut self, record: ExecutionRecord) -> Self {
+        self.record = Some(record);
+        self
+    }
+
+    pub(crate) fn with_stdout_relay(mut self, relay: Option<JoinHandle<CmdResult>>) -> Self {
+        self.stdout_relay = relay;
+        self
+    }
+
+    pub(crate) fn with_log_relays(mut self, relays: Vec<JoinHandle<Result<()>>>) -> Self {
+        self.log_relays = relays;
+        self
+    }
+
+    pub(crate) fn with_typed_result(mut self, typed_result: TypedResult) -> Self {
+        self.typed_result = Some(typed_result);
+        self
+    }
+
+    // waits for the output to be logged, until the stage and any process it left running close it
+    fn finish_log_relays(relays: Vec<JoinHandle<Result<()>>>) {
+        for relay in relays {
+            let _ = relay.join();
+        }
+    }
+
+    // waits for the compressing of stdout to finish, a failure of which fails the stage
+    fn finish_stdout_relay(&mut self, res: CmdResult) -> CmdResult {
+        CmdChild::finish_log_relays(std::mem::take(&mut self.log_relays));
+        let relayed = match self.stdout_relay.take() {
+            Some(relay) => relay.join().unwrap_or_else(|_| {
+                Err(Error::new(
+                    ErrorKind::Other,
+                    "compressing the output panicked",
+                ))
+            }),
+            None => Ok(()),
+        };
+        // a command writing to a closed pipe fails too, which is not the cause
+        match relayed {
+            Err(e) => Err(self.info.error().with_cause(e).into()),
+            Ok(()) => res,
+        }
+    }
+
+    // checks the result of the last stage with `f`, from `success_when()`
+    fn check_success(children: &mut [Result<CmdChild>], f: FnSuccess) {
+        if let Some(Ok(child)) = children.last_mut() {
+            child.success_check = Some(SuccessCheck {
+                f,
+                stdout_tail: TailLines::default(),
+            });
+        }
+    }
+
+    fn kill_all(children: &mut [Result<CmdChild>]) -> CmdResult {
+        let mut ret = Ok(());
+        for child in children.iter_mut().flatten() {
+            if let CmdChildHandle::Proc(ref mut proc) = child.handle {
+                if let Err(e) = sys::kill_tree(proc) {
+                    ret = Err(child.info.error().with_cause(e).into());
+                }
+            }
+        }
+        ret
+    }
+
+    // kills and reaps the stages spawned before a later one could not be set up, without
+    // logging or reporting anything, as the pipeline is not returned
+    pub(crate) fn abort_all(children: Vec<Result<CmdChild>>) {
+        // the pipes of the children are dropped with them first, so the builtin and custom
+        // commands running in threads, which can't be killed, fail writing instead of blocking
+        let mut handles: Vec<CmdChildHandle> = children
+            .into_iter()
+            .flatten()
+            .map(|child| child.handle)
+            .collect();
+        for handle in handles.iter_mut() {
+            handle.kill();
+        }
+        for handle in handles {
+            match handle {
+                CmdChildHandle::Proc(mut proc) => {
+                    let _ = proc.wait();
+                }
+                CmdChildHandle::Thread(thread) => {
+                    let _ = thread.join();
+                }
+                CmdChildHandle::SyncFn(()) => {}
+            }
+        }
+    }
+
+    fn stage_pid(children: &[Result<CmdChild>], stage: usize) -> Option<u32> {
+        children
+            .iter()
+            .flatten()
+            .find(|child| child.info.stage_index == stage)
+            .and_then(CmdChild::pid)
+    }
+
+    fn pid(&self) -> Option<u32> {
+        match self.handle {
+            CmdChildHandle::Proc(ref proc) => Some(proc.id()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn has_exited(&mut self) -> bool {
+        match self.handle {
+            CmdChildHandle::Proc(ref mut proc) => !matches!(proc.try_wait(), Ok(None)),
+            CmdChildHandle::Thread(ref thread) => thread.is_finished(),
+            CmdChildHandle::SyncFn(_) => true,
         }
     }
 
-    fn wait(self, is_last: bool) -> CmdResult {
-        let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
+    fn wait(mut self, is_last: bool, stats: &mut StatsCollector) -> CmdResult {
+        let stderr_logging = self.take_stderr_logging();
+        let check = self.success_check.take();
+        let handle = std::mem::replace(&mut self.handle, CmdChildHandle::SyncFn(()));
+        let res = handle.wait_with_stderr(stderr_logging, &self.info, check);
+        let res = self.finish_stdout_relay(res);
+        stats.record(self.info.stage_index, &res, self.ignore_error);
         if let Err(e) = res {
+            if self.ignore_error {
+                return Ok(());
+            }
             if is_last || process::pipefail_enabled() {
                 return Err(e);
             }
+            stats.mask_error(self.info.stage_index, &e);
         }
         Ok(())
     }
 
-    fn wait_with_output(self, ignore_error: bool) -> Result<Vec<u8>> {
-        let buf = {
-            if let Some(mut out) = self.stdout {
-                let mut buf = vec![];
-                if let Err(e) = out.read_to_end(&mut buf) {
-                    if !ignore_error {
-                        return Err(CmdChildHandle::cmd_io_error(e, &self.cmd, false));
-                    }
+    fn wait_with_writer(
+        mut self,
+        writer: &mut dyn Write,
+        ignore_error: bool,
+        chunk_read_timeout: Option<Duration>,
+        stats: &mut StatsCollector,
+    ) -> CmdResult {
+        let ignore_error = ignore_error || self.ignore_error;
+        if let Some(out) = self.stdout.take() {
+            let mut out = ChunkTimeoutReader::wrap(out, chunk_read_timeout, None, &self.info.cmd)?;
+            let mut writer = TailWriter {
+                inner: writer,
+                tail: self
+                    .success_check
+                    .as_mut()
+                    .map(|check| &mut check.stdout_tail),
+            };
+            if let Err(e) = std::io::copy(&mut out, &mut writer) {
+                if e.kind() == ErrorKind::BrokenPipe {
+                    // the sink is closed, stop the producer instead of waiting for it
+                    self.handle.kill();
+                    let stderr_logging = self.take_stderr_logging();
+                    let _ = self
+                        .handle
+                        .wait_with_stderr(stderr_logging, &self.info, None);
+                    return Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        format!("Output sink of {} closed", self.info.cmd),
+                    ));
+                }
+                if e.kind() == ErrorKind::TimedOut {
+                    self.handle.kill();
+                    let stderr_logging = self.take_stderr_logging();
+                    let _ = self
+                        .handle
+                        .wait_with_stderr(stderr_logging, &self.info, None);
+                    return Err(e);
+                }
+                if !ignore_error {
+                    return Err(self.info.error().with_cause(e).into());
                 }
-                buf
-            } else {
-                vec![]
             }
-        };
-        let res = self.handle.wait_with_stderr(self.stderr, &self.cmd);
+        }
+        let stderr_logging = self.take_stderr_logging();
+        let check = self.success_check.take();
+        let handle = std::mem::replace(&mut self.handle, CmdChildHandle::SyncFn(()));
+        let res = handle.wait_with_stderr(stderr_logging, &self.info, check);
+        let res = self.finish_stdout_relay(res);
+        stats.record(self.info.stage_index, &res, self.ignore_error);
         if let Err(e) = res {
             if !ignore_error {
                 return Err(e);
             }
         }
-        Ok(buf)
+        Ok(())
     }
 }
 
@@ -196,101 +2500,198 @@ pub(crate) enum CmdChildHandle {
 }
 
 impl CmdChildHandle {
-    fn wait_with_stderr(self, stderr: Option<PipeReader>, cmd: &str) -> CmdResult {
-        let polling_stderr = StderrLogging::new(cmd, stderr);
-        match self {
-            CmdChildHandle::Proc(mut proc) => {
-                let status = proc.wait();
-                match status {
-                    Err(e) => return Err(CmdChildHandle::cmd_io_error(e, cmd, false)),
-                    Ok(status) => {
-                        if !status.success() {
-                            return Err(Self::status_to_io_error(
-                                status,
-                                &format!("Running {} exited with error", cmd),
-                            ));
-                        }
-                    }
-                }
-            }
-            CmdChildHandle::Thread(thread) => {
-                let status = thread.join();
-                match status {
-                    Ok(result) => {
-                        if let Err(e) = result {
-                            return Err(CmdChildHandle::cmd_io_error(e, cmd, false));
-                        }
-                    }
-                    Err(e) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!("Running {} thread joined with error: {:?}", cmd, e),
-                        ))
-                    }
-                }
+    fn kill(&mut self) {
+        if let CmdChildHandle::Proc(proc) = self {
+            let _ = sys::kill_tree(proc);
+        }
+    }
+
+    fn wait_with_stderr(
+        self,
+        stderr_logging: StderrLogging,
+        info: &ChildInfo,
+        check: Option<SuccessCheck>,
+    ) -> CmdResult {
+        let err = match self {
+            CmdChildHandle::Proc(mut proc) => match proc.wait() {
+                Err(e) => Some(info.error().with_cause(e)),
+                Ok(status) if !status.success() => Some(Self::status_to_cmd_error(status, info)),
+                Ok(_) => None,
+            },
+            CmdChildHandle::Thread(thread) => match thread.join() {
+                Ok(Err(e)) => Some(info.error().with_cause(e)),
+                Ok(Ok(())) => None,
+                Err(e) => Some(info.error().with_cause(Error::new(
+                    ErrorKind::Other,
+                    format!("thread joined with error: {:?}", e),
+                ))),
+            },
+            CmdChildHandle::SyncFn(_) => None,
+        };
+        let stderr_tail = stderr_logging.finish();
+        let ret = match err {
+            Some(mut err) => {
+                err.stderr_tail = stderr_tail.clone();
+                Err(err.into())
             }
-            CmdChildHandle::SyncFn(_) => {}
+            None => Ok(()),
+        };
+        match check {
+            Some(check) => check.apply(ret, stderr_tail, info),
+            None => ret,
         }
-        drop(polling_stderr);
-        Ok(())
     }
 
-    fn cmd_io_error(e: Error, command: &str, spawning: bool) -> Error {
-        Error::new(
-            e.kind(),
-            format!(
-                "{} {} failed: {}",
-                if spawning { "Spawning" } else { "Running" },
-                command,
-                e
-            ),
-        )
+    fn status_to_cmd_error(status: ExitStatus, info: &ChildInfo) -> CmdError {
+        let mut err = info.error();
+        err.exit_code = status.code();
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            err.signal = status.signal();
+        }
+        err
+    }
+}
+
+const TAIL_LINES: usize = 10;
+// longer lines are truncated in the tails of `success_when()`
+const TAIL_LINE_LEN: usize = 4096;
+
+// the evaluator of `success_when()`, with the stdout tail of the last stage
+struct SuccessCheck {
+    f: FnSuccess,
+    stdout_tail: TailLines,
+}
+
+impl SuccessCheck {
+    fn apply(self, ret: CmdResult, stderr_tail: Vec<String>, info: &ChildInfo) -> CmdResult {
+        let stdout_tail = self.stdout_tail.finish();
+        let msg = match (self.f)(&ret, &stdout_tail, &stderr_tail) {
+            Ok(()) => return Ok(()),
+            Err(msg) => msg,
+        };
+        let mut err = info.error();
+        if let Some(prev) = ret.as_ref().err().and_then(CmdError::from_io_error) {
+            err.exit_code = prev.exit_code;
+            err.signal = prev.signal;
+        }
+        err.stderr_tail = stderr_tail;
+        Err(err.with_cause(Error::new(ErrorKind::Other, msg)).into())
+    }
+}
+
+// the last lines of the data pushed
+#[derive(Default)]
+struct TailLines {
+    lines: VecDeque<String>,
+    partial: Vec<u8>,
+}
+
+impl TailLines {
+    fn push(&mut self, mut data: &[u8]) {
+        while let Some(pos) = data.iter().position(|&b| b == b'\n') {
+            self.push_partial(&data[..pos]);
+            self.push_line();
+            data = &data[pos + 1..];
+        }
+        self.push_partial(data);
     }
 
-    fn status_to_io_error(status: ExitStatus, command: &str) -> Error {
-        if let Some(code) = status.code() {
-            Error::new(
-                ErrorKind::Other,
-                format!("{}; status code: {}", command, code),
-            )
-        } else {
-            Error::new(
-                ErrorKind::Other,
-                format!("{}; terminated by {}", command, status),
-            )
+    fn push_partial(&mut self, data: &[u8]) {
+        let room = TAIL_LINE_LEN.saturating_sub(self.partial.len());
+        self.partial
+            .extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    fn push_line(&mut self) {
+        if self.lines.len() == TAIL_LINES {
+            self.lines.pop_front();
         }
+        let line = String::from_utf8_lossy(&self.partial).to_string();
+        self.lines.push_back(line);
+        self.partial.clear();
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if !self.partial.is_empty() {
+            self.push_line();
+        }
+        self.lines.into()
+    }
+}
+
+// keeps the tail of the data written to `inner`, if any
+struct TailWriter<'a> {
+    inner: &'a mut dyn Write,
+    tail: Option<&'a mut TailLines>,
+}
+
+impl Write for TailWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(ref mut tail) = self.tail {
+            tail.push(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
     }
 }
 
+// Logging of the stderr lines on a thread, joined by `finish()`. When dropped without it, like
+// with the children left running, the thread is detached and exits once stderr is closed.
 struct StderrLogging {
-    thread: Option<JoinHandle<()>>,
+    thread: Option<JoinHandle<Result<()>>>,
     cmd: String,
+    tail: Arc<Mutex<VecDeque<String>>>,
 }
 
 impl StderrLogging {
-    fn new(cmd: &str, stderr: Option<PipeReader>) -> Self {
+    fn new(cmd: &str, stderr: Option<PipeReader>, quiet: Arc<AtomicBool>) -> Self {
+        let tail = Arc::new(Mutex::new(VecDeque::new()));
         if let Some(stderr) = stderr {
-            let thread = std::thread::spawn(move || {
-                BufReader::new(stderr)
-                    .lines()
-                    .filter_map(|line| line.ok())
-                    .for_each(|line| info!("{}", line))
+            let lines = tail.clone();
+            let thread = io::drain_lines("cmd_lib stderr", stderr, move |line| {
+                let line_str = io::line_to_string(line);
+                let level = if quiet.load(Ordering::Relaxed) {
+                    Level::Debug
+                } else {
+                    Level::Info
+                };
+                log!(level, "{}", line_str);
+                let mut lines = lines.lock().unwrap();
+                if lines.len() == TAIL_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line_str);
+                true
             });
             Self {
                 cmd: cmd.into(),
-                thread: Some(thread),
+                // without a thread stderr is closed, so it's written to a broken pipe instead
+                thread: thread.ok(),
+                tail,
             }
         } else {
             Self {
                 cmd: cmd.into(),
                 thread: None,
+                tail,
             }
         }
     }
-}
 
-impl Drop for StderrLogging {
-    fn drop(&mut self) {
+    // waits for the logging to finish, returning the last lines
+    fn finish(mut self) -> Vec<String> {
+        self.join();
+        let tail = std::mem::take(&mut *self.tail.lock().unwrap());
+        tail.into()
+    }
+
+    fn join(&mut self) {
         if let Some(thread) = self.thread.take() {
             if let Err(e) = thread.join() {
                 warn!("{} logging thread exited with error: {:?}", self.cmd, e);
@@ -0,0 +1,44 @@
+use os_pipe::{PipeReader, PipeWriter};
+use std::fs::File;
+use std::io::{Result, Write};
+use std::thread::{self, JoinHandle};
+
+/// Compression of the output redirected to files, see `Process::compress_output()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Codec {
+    /// gzip at the default level, with the `gzip` feature
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// zstd at the default level, with the `zstd` feature
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    fn copy(self, mut pipe: PipeReader, file: File) -> Result<()> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                std::io::copy(&mut pipe, &mut encoder)?;
+                encoder.finish()?.flush()
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(file, 0)?;
+                std::io::copy(&mut pipe, &mut encoder)?;
+                encoder.finish()?.flush()
+            }
+        }
+    }
+}
+
+// returns the pipe to write to `file` through a thread compressing with `codec`, which ends
+// once all the writers are closed
+pub(crate) fn relay(codec: Codec, file: File) -> Result<(PipeWriter, JoinHandle<Result<()>>)> {
+    let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+    let handle = thread::Builder::new().spawn(move || codec.copy(pipe_reader, file))?;
+    Ok((pipe_writer, handle))
+}
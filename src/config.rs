@@ -0,0 +1,326 @@
+//! Snapshots of the global settings, and overrides of them for the current thread
+//!
+//! The settings of `set_debug()`, `set_pipefail()`, `set_pipefail_warn()`, `set_max_cmd_len()`,
+//! `set_max_pipeline_len()`, `set_max_alias_depth()`, `set_history_expansion()`,
+//! `harden_operands()` and `set_timeout()` are process-global, so tests changing them can
+//! interfere with each other when run in parallel. `snapshot()` and `restore()` put them back
+//! after a change, while `with_config()` overrides them for the current thread only:
+//! ```
+//! # use cmd_lib::*;
+//! let guard = config::with_config(|cfg| cfg.pipefail = Some(false));
+//! run_cmd!(false | true)?;
+//! drop(guard);
+//! assert!(run_cmd!(false | true).is_err());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//! Builtin and custom commands in pipelines run on their own threads, where the overrides don't
+//! apply, while the settings are read by the thread running or waiting for the commands.
+//!
+//! Each setting can also be set by an environment variable, for changing it without
+//! recompiling, like `CMD_LIB_DEBUG=1 ./deploy`:
+//!
+//! | Setting             | Variable                    | Default |
+//! |---------------------|-----------------------------|---------|
+//! | `debug`             | `CMD_LIB_DEBUG`             | false   |
+//! | `pipefail`          | `CMD_LIB_PIPEFAIL`          | true    |
+//! | `pipefail_warn`     | `CMD_LIB_PIPEFAIL_WARN`     | true    |
+//! | `max_cmd_len`       | `CMD_LIB_MAX_CMD_LEN`       | 4096    |
+//! | `max_pipeline_len`  | `CMD_LIB_MAX_PIPELINE_LEN`  | 256     |
+//! | `max_alias_depth`   | `CMD_LIB_MAX_ALIAS_DEPTH`   | 8       |
+//! | `history_expansion` | `CMD_LIB_HISTORY_EXPANSION` | false   |
+//! | `harden_operands`   | `CMD_LIB_HARDEN_OPERANDS`   | false   |
+//! | `timeout`           | `CMD_LIB_TIMEOUT`           | 0s      |
+//!
+//! Booleans are `1`, `true`, `yes` or `on`, and `0`, `false`, `no` or `off`, in any case. Sizes
+//! are a number of bytes, with an optional `K`, `M` or `G` suffix for powers of 1024, like `64K`.
+//! Durations are a number with a `ms`, `s`, `m` or `h` unit, like `500ms` or `5m`, and a number
+//! alone is in seconds. Invalid values are ignored with a warning.
+//!
+//! The variables are read once, when a setting is first needed, or again by `init()`. A setting
+//! is taken from the first of these places setting it: `with_config()` on the current thread,
+//! the functions like `set_debug()`, the variables, and the defaults. `describe()` tells which
+//! values are in effect and where each came from.
+//!
+//! Some state is inherently process-global, and is neither in the snapshots nor overridable:
+//! the registered custom commands, aliases, hooks and launcher, the commands added by
+//! `harden_operands_for()`, the auto reaping, the logger, the process working directory and
+//! environment variables, and the signal dispositions inherited by the children.
+use lazy_static::lazy_static;
+use log::warn;
+use std::cell::RefCell;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// the settings, with the environment variables setting them
+const SETTINGS: [(&str, &str); 9] = [
+    ("debug", "CMD_LIB_DEBUG"),
+    ("pipefail", "CMD_LIB_PIPEFAIL"),
+    ("pipefail_warn", "CMD_LIB_PIPEFAIL_WARN"),
+    ("max_cmd_len", "CMD_LIB_MAX_CMD_LEN"),
+    ("max_pipeline_len", "CMD_LIB_MAX_PIPELINE_LEN"),
+    ("max_alias_depth", "CMD_LIB_MAX_ALIAS_DEPTH"),
+    ("history_expansion", "CMD_LIB_HISTORY_EXPANSION"),
+    ("harden_operands", "CMD_LIB_HARDEN_OPERANDS"),
+    ("timeout", "CMD_LIB_TIMEOUT"),
+];
+
+/// Global settings taken by `snapshot()`, to be put back with `restore()`
+#[derive(Debug, Clone)]
+pub struct ConfigSnapshot {
+    env: Option<Config>,
+    set: Config,
+}
+
+/// Takes a snapshot of the global settings
+pub fn snapshot() -> ConfigSnapshot {
+    ConfigSnapshot {
+        env: ENV_CONFIG.lock().unwrap().clone(),
+        set: SET_CONFIG.lock().unwrap().clone(),
+    }
+}
+
+/// Restores the global settings of a snapshot, including the ones not set at that time
+///
+/// The settings read from the environment variables are put back as they were read, while the
+/// variables themselves are left as they are.
+pub fn restore(snapshot: ConfigSnapshot) {
+    *ENV_CONFIG.lock().unwrap() = snapshot.env;
+    *SET_CONFIG.lock().unwrap() = snapshot.set;
+}
+
+/// Settings overriding the global ones on the current thread, `None` for the global setting
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Config {
+    /// Overrides `set_debug()`
+    pub debug: Option<bool>,
+    /// Overrides `set_pipefail()`
+    pub pipefail: Option<bool>,
+    /// Overrides `set_pipefail_warn()`
+    pub pipefail_warn: Option<bool>,
+    /// Overrides `set_max_cmd_len()`
+    pub max_cmd_len: Option<usize>,
+    /// Overrides `set_max_pipeline_len()`
+    pub max_pipeline_len: Option<usize>,
+    /// Overrides `set_max_alias_depth()`
+    pub max_alias_depth: Option<usize>,
+    /// Overrides `set_history_expansion()`
+    pub history_expansion: Option<bool>,
+    /// Overrides `harden_operands()`
+    pub harden_operands: Option<bool>,
+    /// Overrides `set_timeout()`, with zero for no timeout
+    pub timeout: Option<Duration>,
+}
+
+impl Config {
+    fn defaults() -> Self {
+        Self {
+            debug: Some(false),
+            pipefail: Some(true),
+            pipefail_warn: Some(true),
+            max_cmd_len: Some(4096),
+            max_pipeline_len: Some(256),
+            max_alias_depth: Some(8),
+            history_expansion: Some(false),
+            harden_operands: Some(false),
+            timeout: Some(Duration::ZERO),
+        }
+    }
+
+    // reads the environment variables, ignoring the invalid values with a warning
+    fn from_env() -> Self {
+        let mut config = Self::default();
+        for (name, var) in SETTINGS {
+            let value = match std::env::var(var) {
+                Ok(value) => value,
+                Err(std::env::VarError::NotPresent) => continue,
+                Err(e) => {
+                    warn!("Ignoring {}: {}", var, e);
+                    continue;
+                }
+            };
+            if let Err(e) = config.parse(name, value.trim()) {
+                warn!("Ignoring {}={:?}: {}", var, value, e);
+            }
+        }
+        config
+    }
+
+    fn parse(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "debug" => self.debug = Some(parse_bool(value)?),
+            "pipefail" => self.pipefail = Some(parse_bool(value)?),
+            "pipefail_warn" => self.pipefail_warn = Some(parse_bool(value)?),
+            "max_cmd_len" => self.max_cmd_len = Some(parse_size(value)?),
+            "max_pipeline_len" => self.max_pipeline_len = Some(parse_size(value)?),
+            "max_alias_depth" => self.max_alias_depth = Some(parse_size(value)?),
+            "history_expansion" => self.history_expansion = Some(parse_bool(value)?),
+            "harden_operands" => self.harden_operands = Some(parse_bool(value)?),
+            "timeout" => self.timeout = Some(parse_duration(value)?),
+            _ => unreachable!("unknown setting {}", name),
+        }
+        Ok(())
+    }
+
+    // the value of the setting `name` as text, if it is set
+    fn value(&self, name: &str) -> Option<String> {
+        match name {
+            "debug" => self.debug.map(|v| v.to_string()),
+            "pipefail" => self.pipefail.map(|v| v.to_string()),
+            "pipefail_warn" => self.pipefail_warn.map(|v| v.to_string()),
+            "max_cmd_len" => self.max_cmd_len.map(|v| v.to_string()),
+            "max_pipeline_len" => self.max_pipeline_len.map(|v| v.to_string()),
+            "max_alias_depth" => self.max_alias_depth.map(|v| v.to_string()),
+            "history_expansion" => self.history_expansion.map(|v| v.to_string()),
+            "harden_operands" => self.harden_operands.map(|v| v.to_string()),
+            "timeout" => self.timeout.map(|v| format!("{:?}", v)),
+            _ => unreachable!("unknown setting {}", name),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err("not a boolean".into()),
+    }
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("unknown size suffix {:?}", unit)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| "not a size".into())
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let secs_per_unit = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown duration unit {:?}", unit)),
+    };
+    digits
+        .parse::<f64>()
+        .ok()
+        .and_then(|n| Duration::try_from_secs_f64(n * secs_per_unit).ok())
+        .ok_or_else(|| "not a duration".into())
+}
+
+lazy_static! {
+    // the settings of the environment variables, read once
+    static ref ENV_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+    // the settings of the functions like `set_debug()`
+    static ref SET_CONFIG: Mutex<Config> = Mutex::new(Config::default());
+}
+
+thread_local! {
+    static THREAD_CONFIG: RefCell<Config> = RefCell::new(Config::default());
+}
+
+/// Reads the settings of the environment variables again
+///
+/// They are read when a setting is first needed, so it is only needed after changing the
+/// variables afterwards, like in tests.
+pub fn init() {
+    *ENV_CONFIG.lock().unwrap() = Some(Config::from_env());
+}
+
+/// Describes the settings in effect on the current thread, one per line, with where each came
+/// from
+///
+/// It is meant for debugging, like `pipefail = false (CMD_LIB_PIPEFAIL)`, and the format may
+/// change.
+pub fn describe() -> String {
+    let thread = thread_config();
+    let set = SET_CONFIG.lock().unwrap().clone();
+    let env = env_config();
+    let defaults = Config::defaults();
+    let mut lines = vec![];
+    for (name, var) in SETTINGS {
+        let layers = [
+            (&thread, "with_config()"),
+            (&set, "set by function"),
+            (&env, var),
+            (&defaults, "default"),
+        ];
+        let (value, source) = layers
+            .iter()
+            .find_map(|(config, source)| config.value(name).map(|value| (value, *source)))
+            .unwrap();
+        lines.push(format!("{} = {} ({})", name, value, source));
+    }
+    lines.join("\n")
+}
+
+/// Overrides the settings on the current thread until the returned guard is dropped
+///
+/// `f` changes a copy of the current overrides, so nested calls add to the outer ones.
+pub fn with_config(f: impl FnOnce(&mut Config)) -> ConfigGuard {
+    // not borrowed while calling `f`, which may read the settings
+    let prev = THREAD_CONFIG.with(|config| config.borrow().clone());
+    let mut config = prev.clone();
+    f(&mut config);
+    THREAD_CONFIG.with(|current| *current.borrow_mut() = config);
+    ConfigGuard { prev: Some(prev) }
+}
+
+/// Guard returned by `with_config()`, restoring the previous overrides when dropped
+#[must_use = "the overrides are removed when the guard is dropped"]
+pub struct ConfigGuard {
+    prev: Option<Config>,
+}
+
+impl Drop for ConfigGuard {
+    fn drop(&mut self) {
+        if let Some(prev) = self.prev.take() {
+            THREAD_CONFIG.with(|config| *config.borrow_mut() = prev);
+        }
+    }
+}
+
+fn env_config() -> Config {
+    ENV_CONFIG
+        .lock()
+        .unwrap()
+        .get_or_insert_with(Config::from_env)
+        .clone()
+}
+
+// sets a setting for the functions like `set_debug()`
+pub(crate) fn set(f: impl FnOnce(&mut Config)) {
+    f(&mut SET_CONFIG.lock().unwrap());
+}
+
+// the overrides of `with_config()` on the current thread, for carrying them to another one
+pub(crate) fn thread_config() -> Config {
+    THREAD_CONFIG.with(|config| config.borrow().clone())
+}
+
+// the setting in effect on the current thread
+pub(crate) fn get<T>(f: impl Fn(&Config) -> Option<T>) -> T {
+    THREAD_CONFIG
+        .with(|config| f(&config.borrow()))
+        .or_else(|| f(&SET_CONFIG.lock().unwrap()))
+        .or_else(|| f(&env_config()))
+        .or_else(|| f(&Config::defaults()))
+        .unwrap()
+}
@@ -0,0 +1,83 @@
+use crate::CmdResult;
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+
+type FnMatch = Box<dyn Fn(&[OsString]) -> bool>;
+
+/// Asks the user on the terminal before running the matched commands
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let confirm = Confirm::new(|args| args[0] == "rm");
+/// Process::new()
+///     .confirm(confirm)
+///     .run(|| run_cmd!(rm -rf /tmp/build))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Before spawning a pipeline with any matched command, it prompts `Run <pipeline>? [y/N]` on
+/// the terminal, and returns an error without spawning anything if the answer is not `y` or
+/// `yes`. Without a terminal, like in cron jobs, the commands are declined by default.
+pub struct Confirm {
+    matches: FnMatch,
+    without_tty: bool,
+    input: Option<RefCell<Box<dyn BufRead>>>,
+}
+
+impl Confirm {
+    /// Confirms the commands whose arguments, including the program, match `f`
+    pub fn new(f: impl Fn(&[OsString]) -> bool + 'static) -> Self {
+        Self {
+            matches: Box::new(f),
+            without_tty: false,
+            input: None,
+        }
+    }
+
+    /// Runs the matched commands without asking when there is no terminal, false by default
+    pub fn proceed_without_tty(mut self, proceed: bool) -> Self {
+        self.without_tty = proceed;
+        self
+    }
+
+    /// Reads the answers from `input` instead of the terminal, with the prompts written to stderr
+    pub fn input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = Some(RefCell::new(Box::new(input)));
+        self
+    }
+
+    pub(crate) fn matches(&self, args: &[OsString]) -> bool {
+        (self.matches)(args)
+    }
+
+    pub(crate) fn ask(&self, cmd: &str) -> CmdResult {
+        let prompt = format!("Run {}? [y/N] ", cmd);
+        let mut answer = String::new();
+        if let Some(ref input) = self.input {
+            eprint!("{}", prompt);
+            input.borrow_mut().read_line(&mut answer)?;
+        } else {
+            match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+                Ok(mut tty) => {
+                    tty.write_all(prompt.as_bytes())?;
+                    BufReader::new(tty).read_line(&mut answer)?;
+                }
+                Err(_) if self.without_tty => return Ok(()),
+                Err(_) => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("Running {} declined: no terminal to confirm", cmd),
+                    ))
+                }
+            }
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                format!("Running {} declined", cmd),
+            )),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::io;
+use os_pipe::PipeReader;
+use std::io::Result;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+pub(crate) type FnClassify = Arc<dyn Fn(&str) -> DiagnosticKind + Send + Sync>;
+
+/// Kind of a stderr line, as decided by the classifier of `FunChildren::wait_with_diagnostics()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticKind {
+    Error,
+    Warning,
+    Note,
+    /// Lines which are none of the above, like progress or context lines
+    Other,
+}
+
+/// A stderr line of a command, classified by `FunChildren::wait_with_diagnostics()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// The line, without the trailing newline
+    pub line: String,
+    /// Command writing the line
+    pub command: String,
+    /// Index of the command in the pipeline
+    pub stage_index: usize,
+}
+
+// sends the classified lines of `stderr` on a thread until it is closed, draining the rest
+// after the receiver is gone, so the command won't block on stderr
+pub(crate) fn relay(
+    stderr: PipeReader,
+    command: String,
+    stage_index: usize,
+    classify: FnClassify,
+    tx: Sender<Diagnostic>,
+) -> Result<JoinHandle<Result<()>>> {
+    io::drain_lines("cmd_lib stderr", stderr, move |line| {
+        let line = io::line_to_string(line);
+        let diagnostic = Diagnostic {
+            kind: classify(&line),
+            line,
+            command: command.clone(),
+            stage_index,
+        };
+        tx.send(diagnostic).is_ok()
+    })
+}
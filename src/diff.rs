@@ -0,0 +1,136 @@
+use crate::child::{FunChildren, StdoutLines};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io::Result;
+
+// the hunks reported at most
+const MAX_HUNKS: usize = 10;
+// the lines read ahead on each side to find where the outputs match again
+const RESYNC_WINDOW: usize = 256;
+
+/// Compares the output lines of two pipelines, like `diff <(cmd1) <(cmd2)` in bash
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let live = spawn_with_output!(ssh web1 cat /etc/nginx/nginx.conf)?;
+/// let repo = spawn_with_output!(git show HEAD:nginx.conf)?;
+/// if let Some(diff) = diff_outputs(live, repo)? {
+///     eprintln!("nginx.conf drifted:\n{}", diff);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Both pipelines run at the same time, and their outputs are compared while read, keeping only
+/// the lines around a difference in memory. It returns `None` when the lines are the same, and
+/// the differences otherwise, in the format of `diff -U0` without the file names, for the first
+/// 10 hunks, followed by `...` when there are more. After a difference, the outputs are expected
+/// to match again within 256 lines, otherwise the lines read so far are reported as changed,
+/// so the hunks may be longer than the ones of `diff` for outputs changed that much. A missing
+/// newline at the end of an output is not a difference.
+///
+/// Both pipelines are waited for, and a failure of either one is returned as the error, even
+/// when the outputs differ.
+pub fn diff_outputs(a: FunChildren, b: FunChildren) -> Result<Option<String>> {
+    let mut a = Side::new(a.stdout_lines_with_summary()?);
+    let mut b = match b.stdout_lines_with_summary() {
+        Ok(lines) => Side::new(lines),
+        Err(e) => {
+            let _ = a.lines.finish_result();
+            return Err(e);
+        }
+    };
+    let mut diff = String::new();
+    let mut hunks = 0;
+    loop {
+        a.fill(1);
+        b.fill(1);
+        match (a.buf.front(), b.buf.front()) {
+            (None, None) => break,
+            (Some(line_a), Some(line_b)) if line_a == line_b => {
+                a.advance(1);
+                b.advance(1);
+                continue;
+            }
+            _ => {}
+        }
+        if hunks == MAX_HUNKS {
+            diff += "...\n";
+            break;
+        }
+        a.fill(RESYNC_WINDOW);
+        b.fill(RESYNC_WINDOW);
+        let (removed, added) = resync(&a.buf, &b.buf);
+        let _ = writeln!(diff, "@@ -{} +{} @@", a.range(removed), b.range(added));
+        for line in a.buf.iter().take(removed) {
+            let _ = writeln!(diff, "-{}", line);
+        }
+        for line in b.buf.iter().take(added) {
+            let _ = writeln!(diff, "+{}", line);
+        }
+        a.advance(removed);
+        b.advance(added);
+        hunks += 1;
+    }
+    // the rest of the outputs is read by `finish_result()`
+    let ret_a = a.lines.finish_result();
+    let ret_b = b.lines.finish_result();
+    ret_a?;
+    ret_b?;
+    Ok((hunks > 0).then_some(diff))
+}
+
+// the lines to take from `a` and `b` until the first line found in both, or all of them
+fn resync(a: &VecDeque<String>, b: &VecDeque<String>) -> (usize, usize) {
+    if a.is_empty() || b.is_empty() {
+        return (a.len(), b.len());
+    }
+    // the nearest match, with the fewest lines skipped on both sides
+    for skipped in 1..a.len() + b.len() {
+        for i in skipped.saturating_sub(b.len() - 1)..=skipped.min(a.len() - 1) {
+            if a[i] == b[skipped - i] {
+                return (i, skipped - i);
+            }
+        }
+    }
+    (a.len(), b.len())
+}
+
+// an output being compared, with the lines read ahead
+struct Side {
+    lines: StdoutLines,
+    buf: VecDeque<String>,
+    // the lines compared before the buffered ones
+    line_no: usize,
+}
+
+impl Side {
+    fn new(lines: StdoutLines) -> Self {
+        Self {
+            lines,
+            buf: VecDeque::new(),
+            line_no: 0,
+        }
+    }
+
+    fn fill(&mut self, len: usize) {
+        while self.buf.len() < len {
+            match self.lines.next() {
+                Some(line) => self.buf.push_back(line),
+                None => break,
+            }
+        }
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.buf.drain(..len);
+        self.line_no += len;
+    }
+
+    // the range of the next `len` lines, as in a hunk header of `diff -U0`
+    fn range(&self, len: usize) -> String {
+        match len {
+            0 => format!("{},0", self.line_no),
+            1 => format!("{}", self.line_no + 1),
+            _ => format!("{},{}", self.line_no + 1, len),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::pathlike::{self, SEPARATOR};
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::path::Path;
+use std::process::Command;
+
+/// Environment variables for the commands, composed from layered sources
+///
+/// Each layer sets or removes variables, and can clear the environment inherited from this
+/// process. Layers are combined with `merge()` and `with_overrides()`, where the later one wins:
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::collections::HashMap;
+/// let base = Env::new().set("RUST_LOG", "info").set("PROFILE", "dev");
+/// let profile = Env::new().set("PROFILE", "release").remove("RUST_BACKTRACE");
+/// let overrides = HashMap::from([("RUST_LOG", "debug")]);
+/// let env = base.merge(&profile).with_overrides(overrides);
+/// Process::new().env(env).run(|| run_cmd!(cargo build))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// A variable set in a later layer replaces the value or the removal from the earlier ones, and a
+/// variable removed in a later layer is removed whatever the earlier ones set. A later layer with
+/// `clear()` drops the inherited environment and everything set by the earlier layers, keeping
+/// only its own variables set after clearing.
+#[derive(Debug, Clone, Default)]
+pub struct Env {
+    clear: bool,
+    // `None` for removed variables
+    vars: BTreeMap<String, Option<String>>,
+}
+
+impl Env {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key` to `value`
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(key.into(), Some(value.into()));
+        self
+    }
+
+    /// Removes `key`, whether it is inherited or set by an earlier layer
+    pub fn remove(mut self, key: impl Into<String>) -> Self {
+        self.vars.insert(key.into(), None);
+        self
+    }
+
+    /// Clears the inherited environment and the variables set so far
+    pub fn clear(mut self) -> Self {
+        self.clear = true;
+        self.vars.clear();
+        self
+    }
+
+    /// Adds the layer `other` on top of this one
+    pub fn merge(mut self, other: &Env) -> Self {
+        if other.clear {
+            self.clear = true;
+            self.vars.clear();
+        }
+        for (key, value) in other.vars.iter() {
+            self.vars.insert(key.clone(), value.clone());
+        }
+        self
+    }
+
+    /// Adds a layer setting all the variables in `vars` on top of this one
+    pub fn with_overrides<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        for (key, value) in vars {
+            self = self.set(key, value);
+        }
+        self
+    }
+
+    /// Sets the PATH-like variable `key` to its final value with `dir` appended
+    ///
+    /// Like `append_pathlike()`, with the value read from this layer, or from the inherited
+    /// environment if it's not set here:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let env = Env::new()
+    ///     .append_pathlike("LD_LIBRARY_PATH", "/opt/app/lib")?
+    ///     .prepend_pathlike("PYTHONPATH", "/opt/app/python")?;
+    /// Process::new().env(env).run(|| run_cmd!(python3 -m app))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn append_pathlike(self, key: &str, dir: impl AsRef<Path>) -> Result<Self> {
+        self.munge_pathlike(key, dir.as_ref(), false)
+    }
+
+    /// Sets the PATH-like variable `key` to its final value with `dir` prepended
+    ///
+    /// See `append_pathlike()` for the details.
+    pub fn prepend_pathlike(self, key: &str, dir: impl AsRef<Path>) -> Result<Self> {
+        self.munge_pathlike(key, dir.as_ref(), true)
+    }
+
+    fn munge_pathlike(self, key: &str, dir: &Path, prepend: bool) -> Result<Self> {
+        let value = self.get(key).unwrap_or_default();
+        let value = pathlike::munge(key, &value, dir, prepend, SEPARATOR)?;
+        Ok(self.set(key, value))
+    }
+
+    /// Returns the final value of `key`, as the commands would get it
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.vars.get(key) {
+            Some(value) => value.clone(),
+            None if self.clear => None,
+            None => std::env::var(key).ok(),
+        }
+    }
+
+    // the variables the commands would get, from the environment of this process
+    pub(crate) fn resolve(&self, vars: &mut BTreeMap<String, String>) {
+        if self.clear {
+            vars.clear();
+        }
+        for (key, value) in self.vars.iter() {
+            match value {
+                Some(value) => vars.insert(key.clone(), value.clone()),
+                None => vars.remove(key),
+            };
+        }
+    }
+
+    pub(crate) fn apply(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+        }
+        for (key, value) in self.vars.iter() {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+    }
+}
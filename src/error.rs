@@ -0,0 +1,223 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Details of a failed command, carried by the `std::io::Error` returned from the macros
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// if let Err(e) = run_cmd!(make -j) {
+///     if let Some(err) = CmdError::from_io_error(&e) {
+///         eprintln!("stage {} exited with {:?}", err.stage_index, err.exit_code);
+///     }
+/// }
+/// ```
+/// The underlying error, like the one returned from a custom command, is its `source()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct CmdError {
+    /// The failed command, truncated if longer than the limit set by `set_max_cmd_len()`
+    pub command: String,
+    /// Full name and arguments of the failed command
+    pub argv: Vec<String>,
+    /// The whole pipeline containing the failed command, with each command truncated like
+    /// `command`
+    pub pipeline: String,
+    /// Index of the failed command in the pipeline
+    pub stage_index: usize,
+    /// Exit code of the process, if it exited normally
+    pub exit_code: Option<i32>,
+    /// Signal terminating the process, on unix only
+    pub signal: Option<i32>,
+    /// Kind of the error, which is also the kind of the carrying `std::io::Error`
+    pub kind: ErrorKind,
+    /// Time elapsed since spawning the command
+    pub duration: Duration,
+    /// Last lines written to stderr by the command, unless its stderr is captured or redirected
+    pub stderr_tail: Vec<String>,
+    cause: Option<Error>,
+}
+
+impl CmdError {
+    pub(crate) fn new(
+        command: &str,
+        pipeline: &str,
+        stage_index: usize,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            command: command.into(),
+            argv: vec![],
+            pipeline: pipeline.into(),
+            stage_index,
+            exit_code: None,
+            signal: None,
+            kind: ErrorKind::Other,
+            duration,
+            stderr_tail: vec![],
+            cause: None,
+        }
+    }
+
+    pub(crate) fn with_cause(mut self, cause: Error) -> Self {
+        self.kind = cause.kind();
+        self.cause = Some(cause);
+        self
+    }
+
+    /// Returns the details if `e` is returned from a failed command
+    pub fn from_io_error(e: &Error) -> Option<&CmdError> {
+        find_source(e)
+    }
+
+    /// Renders the error as a single line JSON object
+    ///
+    /// The field names are `command`, `pipeline`, `stage_index`, `exit_code`, `signal`, `kind`,
+    /// `duration_ms` and `stderr_tail`, and they are guaranteed not to change without a major
+    /// version bump. `kind` is the name of the `std::io::ErrorKind` variant.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Json<'a> {
+            command: &'a str,
+            pipeline: &'a str,
+            stage_index: usize,
+            exit_code: Option<i32>,
+            signal: Option<i32>,
+            kind: String,
+            duration_ms: u64,
+            stderr_tail: &'a [String],
+        }
+
+        let json = Json {
+            command: &self.command,
+            pipeline: &self.pipeline,
+            stage_index: self.stage_index,
+            exit_code: self.exit_code,
+            signal: self.signal,
+            kind: format!("{:?}", self.kind),
+            duration_ms: self.duration.as_millis() as u64,
+            stderr_tail: &self.stderr_tail,
+        };
+        // serializing plain strings and numbers can't fail
+        serde_json::to_string(&json).unwrap()
+    }
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref cause) = self.cause {
+            return write!(f, "Running {} failed: {}", self.command, cause);
+        }
+        write!(
+            f,
+            "Running {} exited with error after {:.2?}",
+            self.command, self.duration
+        )?;
+        if let Some(code) = self.exit_code {
+            write!(f, "; status code: {}", code)
+        } else if let Some(signal) = self.signal {
+            write!(f, "; terminated by signal {}", signal)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl std::error::Error for CmdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause.as_ref().map(|e| e as _)
+    }
+}
+
+impl From<CmdError> for Error {
+    fn from(e: CmdError) -> Self {
+        Error::new(e.kind, e)
+    }
+}
+
+/// Output read before timing out, carried by the `TimedOut` error returned from
+/// `FunChildren::wait_with_output_timeout()`
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PartialOutput {
+    /// The pipeline which timed out
+    pub pipeline: String,
+    /// The timeout
+    pub timeout: Duration,
+    /// Output read so far as it is, without the trailing newline removed
+    pub output: String,
+}
+
+impl PartialOutput {
+    pub(crate) fn new(pipeline: &str, timeout: Duration, output: &[u8]) -> Self {
+        Self {
+            pipeline: pipeline.into(),
+            timeout,
+            output: String::from_utf8_lossy(output).into(),
+        }
+    }
+
+    /// Returns the partial output if `e` is returned from a timed out command
+    pub fn from_io_error(e: &Error) -> Option<&PartialOutput> {
+        find_source(e)
+    }
+}
+
+impl fmt::Display for PartialOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Running {} timed out after {:?}",
+            self.pipeline, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for PartialOutput {}
+
+impl From<PartialOutput> for Error {
+    fn from(e: PartialOutput) -> Self {
+        Error::new(ErrorKind::TimedOut, e)
+    }
+}
+
+// An error with a message on what failed, keeping the underlying error as its source
+#[derive(Debug)]
+struct ContextError {
+    context: String,
+    source: Error,
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl std::error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// the first error of type `T` wrapped in `e` or in its sources, like the ones of `with_context()`
+fn find_source<T: std::error::Error + 'static>(e: &Error) -> Option<&T> {
+    let mut err: &(dyn std::error::Error + 'static) = e.get_ref()?;
+    loop {
+        if let Some(found) = err.downcast_ref() {
+            return Some(found);
+        }
+        let source = err.source()?;
+        // the source of an `io::Error` is the one of the error it wraps, so it is unwrapped here
+        err = match source.downcast_ref::<Error>() {
+            Some(e) => e.get_ref()?,
+            None => source,
+        };
+    }
+}
+
+// Wraps `e` with the same kind, like "Spawning ... failed: {e}", and `e` as the source
+pub(crate) fn with_context(e: Error, context: String) -> Error {
+    Error::new(e.kind(), ContextError { context, source: e })
+}
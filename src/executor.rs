@@ -0,0 +1,66 @@
+use crate::{CmdEnv, CmdResult};
+use std::io::{Error, ErrorKind};
+use std::process::Command;
+
+/// Runs the external commands spawned inside `Process::run()`
+///
+/// It is the spawn boundary of external commands, which can be replaced to test the code using
+/// commands without spawning any process. Like custom commands, any
+/// `Fn(&mut CmdEnv) -> CmdResult` closure is an executor:
+/// ```
+/// # use cmd_lib::*;
+/// # use std::io::Write;
+/// let mock = |env: &mut CmdEnv| {
+///     if env.args()[0] == "git" {
+///         writeln!(env.stdout(), "0123abc")
+///     } else {
+///         Err(std::io::Error::new(std::io::ErrorKind::Other, "unexpected command"))
+///     }
+/// };
+/// let head = Process::new()
+///     .executor(mock)
+///     .run(|| run_fun!(git rev-parse HEAD))?;
+/// assert_eq!(head, "0123abc");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Builtin and custom commands are not passed to the executor.
+pub trait Executor: Send + Sync {
+    /// Runs the command with the arguments, environment variables, current directory and
+    /// standard streams in `env`
+    fn execute(&self, env: &mut CmdEnv) -> CmdResult;
+}
+
+impl<F> Executor for F
+where
+    F: Fn(&mut CmdEnv) -> CmdResult + Send + Sync,
+{
+    fn execute(&self, env: &mut CmdEnv) -> CmdResult {
+        self(env)
+    }
+}
+
+/// The executor spawning real processes, for other executors to delegate to
+///
+/// Unlike commands spawned without an executor, the arguments are passed as lossy UTF-8 strings.
+pub struct DefaultExecutor;
+
+impl Executor for DefaultExecutor {
+    fn execute(&self, env: &mut CmdEnv) -> CmdResult {
+        let mut cmd = Command::new(&env.args()[0]);
+        if let Some(process_env) = env.process_env() {
+            process_env.apply(&mut cmd);
+        }
+        cmd.args(&env.args()[1..])
+            .envs(env.vars())
+            .current_dir(env.current_dir());
+        let (stdin, stdout, stderr) = env.take_stdio();
+        let status = cmd.stdin(stdin).stdout(stdout).stderr(stderr).status()?;
+        if !status.success() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("exited with {}", status),
+            ));
+        }
+        Ok(())
+    }
+}
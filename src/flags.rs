@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+/// Maps expanded to flag arguments by `$%{map}` interpolation
+///
+/// Each entry becomes `--key value`, as two arguments, sorted by key for reproducible command
+/// lines. Empty maps expand to nothing. Use `Flags` for other formats:
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::collections::BTreeMap;
+/// let opts = BTreeMap::from([("region", "us-east-1"), ("profile", "ci")]);
+/// // aws s3 ls --profile ci --region us-east-1
+/// run_cmd!(aws s3 ls $%{opts})?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub trait FlagArgs {
+    /// Returns the flag arguments
+    fn flag_args(&self) -> Vec<String>;
+}
+
+impl<T: FlagArgs + ?Sized> FlagArgs for &T {
+    fn flag_args(&self) -> Vec<String> {
+        (**self).flag_args()
+    }
+}
+
+impl<K: Display, V: Display, S> FlagArgs for HashMap<K, V, S> {
+    fn flag_args(&self) -> Vec<String> {
+        Flags::new(self).flag_args()
+    }
+}
+
+impl<K: Display, V: Display> FlagArgs for BTreeMap<K, V> {
+    fn flag_args(&self) -> Vec<String> {
+        Flags::new(self).flag_args()
+    }
+}
+
+/// Flag arguments with a configurable format, for `$%{flags}` interpolation
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::collections::HashMap;
+/// let opts = HashMap::from([("jobs", 4), ("load-average", 2)]);
+/// // make -jobs=4 -load-average=2
+/// let flags = Flags::new(&opts).prefix("-").join_with("=");
+/// run_cmd!(make $%{flags})?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Flags {
+    entries: Vec<(String, String)>,
+    prefix: String,
+    separator: Option<String>,
+}
+
+impl Flags {
+    /// Collects the entries of a map, or any other key value pairs
+    pub fn new<K: Display, V: Display>(entries: impl IntoIterator<Item = (K, V)>) -> Self {
+        let mut entries: Vec<(String, String)> = entries
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        entries.sort();
+        Self {
+            entries,
+            prefix: "--".into(),
+            separator: None,
+        }
+    }
+
+    /// Sets the prefix of the keys, `--` by default
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Joins each key and value into one argument with `separator`, like `--key=value`
+    pub fn join_with(mut self, separator: &str) -> Self {
+        self.separator = Some(separator.into());
+        self
+    }
+}
+
+impl FlagArgs for Flags {
+    fn flag_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for (k, v) in self.entries.iter() {
+            let key = format!("{}{}", self.prefix, k);
+            match self.separator {
+                Some(ref sep) => args.push(format!("{}{}{}", key, sep, v)),
+                None => args.extend([key, v.clone()]),
+            }
+        }
+        args
+    }
+}
@@ -0,0 +1,341 @@
+use crate::process::Process;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// Options for `glob_with()`
+#[derive(Debug, Clone, Default)]
+pub struct GlobOptions {
+    /// Match file names case-insensitively, false by default
+    pub case_insensitive: bool,
+    /// Enable `!(pattern|pattern)` for matching anything except the patterns, false by default
+    pub extended: bool,
+}
+
+/// Returns the sorted paths matching `pattern`, with the options of `Process::glob_options()`
+///
+/// The default options are used outside `Process::run()`. See `glob_with()` for the matching
+/// semantics.
+pub fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    glob_with(pattern, &Process::current_glob_options())
+}
+
+/// Returns the sorted paths matching `pattern`
+///
+/// The pattern is matched for each path component:
+/// - `*` matches any characters, `?` matches one character, `[a-z]` and `[!a-z]` match one
+///   character in (or not in) the set
+/// - `**` as a whole component matches zero or more directories recursively, without following
+///   symbolic links, and as the last component all the files and directories below too
+/// - with `extended` option, `!(a|b)` matches anything except `a` and `b`
+/// - leading `.` in file names is only matched explicitly, like in shells
+///
+/// Relative patterns are matched in `current_dir()`, which follows `set_current_dir()`, and the
+/// paths are returned relative to it, leaving out `current_dir()` itself like for a lone `**`.
+///
+/// Unlike shells, it returns an empty vector instead of the pattern itself if nothing matches,
+/// and the results can be passed to commands with `$[paths]`:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let files = glob("src/**/*.rs")?;
+/// run_cmd!(wc -l $[files])?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn glob_with(pattern: &str, options: &GlobOptions) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let mut base = PathBuf::new();
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(s) if parts.is_empty() && !is_pattern(&s.to_string_lossy()) => {
+                base.push(s)
+            }
+            Component::Normal(s) => parts.push(parse_pattern(&s.to_string_lossy(), options)?),
+            _ if parts.is_empty() => base.push(component),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid glob pattern: {}", pattern),
+                ))
+            }
+        }
+    }
+
+    // relative patterns are matched in `current_dir()`, and the paths returned relative to it
+    let root = crate::current_dir();
+    let mut ret = vec![];
+    if parts.is_empty() {
+        if root.join(&base).exists() {
+            ret.push(base);
+        }
+    } else {
+        walk(&root.join(&base), &parts, options, &mut ret)?;
+        if base.is_relative() {
+            for path in ret.iter_mut() {
+                if let Ok(relative) = path.strip_prefix(&root) {
+                    *path = relative.to_path_buf();
+                }
+            }
+            // `current_dir()` itself, matched by a leading `**` with zero directory, as bash
+            // leaves it out too
+            ret.retain(|path| !path.as_os_str().is_empty());
+        }
+    }
+    ret.sort();
+    ret.dedup();
+    Ok(ret)
+}
+
+fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '[', '!'])
+}
+
+enum Part {
+    Recursive,
+    Name(Vec<Token>),
+}
+
+enum Token {
+    Char(char),
+    AnyChar,
+    AnyChars,
+    Class(Vec<(char, char)>, bool),
+    Not(Vec<Vec<Token>>),
+}
+
+fn parse_pattern(s: &str, options: &GlobOptions) -> Result<Part> {
+    if s == "**" {
+        return Ok(Part::Recursive);
+    }
+    let chars: Vec<char> = s.chars().collect();
+    let mut pos = 0;
+    let tokens = parse_tokens(&chars, &mut pos, options, false)?;
+    Ok(Part::Name(tokens))
+}
+
+fn parse_tokens(
+    chars: &[char],
+    pos: &mut usize,
+    options: &GlobOptions,
+    in_group: bool,
+) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        if in_group && (c == '|' || c == ')') {
+            break;
+        }
+        *pos += 1;
+        match c {
+            '*' => tokens.push(Token::AnyChars),
+            '?' => tokens.push(Token::AnyChar),
+            '[' => tokens.push(parse_class(chars, pos)?),
+            '!' if options.extended && chars.get(*pos) == Some(&'(') => {
+                *pos += 1;
+                let mut alternatives = vec![];
+                loop {
+                    alternatives.push(parse_tokens(chars, pos, options, true)?);
+                    match chars.get(*pos) {
+                        Some('|') => *pos += 1,
+                        Some(')') => {
+                            *pos += 1;
+                            break;
+                        }
+                        _ => return Err(bad_pattern(chars)),
+                    }
+                }
+                tokens.push(Token::Not(alternatives));
+            }
+            _ => tokens.push(Token::Char(c)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_class(chars: &[char], pos: &mut usize) -> Result<Token> {
+    let negated = matches!(chars.get(*pos), Some('!') | Some('^'));
+    if negated {
+        *pos += 1;
+    }
+    let mut ranges = vec![];
+    let mut first = true;
+    while let Some(&c) = chars.get(*pos) {
+        *pos += 1;
+        if c == ']' && !first {
+            return Ok(Token::Class(ranges, negated));
+        }
+        first = false;
+        if chars.get(*pos) == Some(&'-') && chars.get(*pos + 1).is_some_and(|&e| e != ']') {
+            ranges.push((c, chars[*pos + 1]));
+            *pos += 2;
+        } else {
+            ranges.push((c, c));
+        }
+    }
+    Err(bad_pattern(chars))
+}
+
+fn bad_pattern(chars: &[char]) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid glob pattern: {}", chars.iter().collect::<String>()),
+    )
+}
+
+fn fold(c: char, options: &GlobOptions) -> char {
+    if options.case_insensitive {
+        c.to_lowercase().next().unwrap_or(c)
+    } else {
+        c
+    }
+}
+
+// matches with backtracking to the last `*` only, so that each `*` takes linear time instead of
+// the time growing exponentially with the number of them
+fn match_tokens(tokens: &[Token], name: &[char], options: &GlobOptions) -> bool {
+    let (mut t, mut n) = (0, 0);
+    // the token after the last `*`, and the end of the characters it matches
+    let mut star = None;
+    loop {
+        match tokens.get(t) {
+            Some(Token::AnyChars) => {
+                star = Some((t + 1, n));
+                t += 1;
+                continue;
+            }
+            Some(Token::Not(alternatives)) => {
+                if match_not(alternatives, &tokens[t + 1..], &name[n..], options) {
+                    return true;
+                }
+            }
+            Some(token) => {
+                if n < name.len() && match_char(token, name[n], options) {
+                    t += 1;
+                    n += 1;
+                    continue;
+                }
+            }
+            None => {
+                if n == name.len() {
+                    return true;
+                }
+            }
+        }
+        // retry with the last `*` matching one more character
+        match star {
+            Some((after, end)) if end < name.len() => {
+                star = Some((after, end + 1));
+                t = after;
+                n = end + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+fn match_char(token: &Token, c: char, options: &GlobOptions) -> bool {
+    match token {
+        Token::Char(expected) => fold(*expected, options) == fold(c, options),
+        Token::AnyChar => true,
+        Token::Class(ranges, negated) => {
+            let c = fold(c, options);
+            let in_class = ranges
+                .iter()
+                .any(|&(lo, hi)| fold(lo, options) <= c && c <= fold(hi, options));
+            in_class != *negated
+        }
+        Token::AnyChars | Token::Not(_) => false,
+    }
+}
+
+// matches `!(a|b)` followed by `rest`, trying every length for the part not matching `a` or `b`
+fn match_not(
+    alternatives: &[Vec<Token>],
+    rest: &[Token],
+    name: &[char],
+    options: &GlobOptions,
+) -> bool {
+    (0..=name.len()).any(|i| {
+        !alternatives
+            .iter()
+            .any(|alt| match_tokens(alt, &name[..i], options))
+            && match_tokens(rest, &name[i..], options)
+    })
+}
+
+fn match_name(tokens: &[Token], name: &str, options: &GlobOptions) -> bool {
+    // hidden files need to be matched explicitly
+    if name.starts_with('.') && !matches!(tokens.first(), Some(Token::Char('.'))) {
+        return false;
+    }
+    let name: Vec<char> = name.chars().collect();
+    match_tokens(tokens, &name, options)
+}
+
+fn read_dir_names(dir: &Path) -> Vec<(String, PathBuf)> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                (
+                    entry.file_name().to_string_lossy().to_string(),
+                    entry.path(),
+                )
+            })
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+fn walk(dir: &Path, parts: &[Part], options: &GlobOptions, ret: &mut Vec<PathBuf>) -> Result<()> {
+    let (part, rest) = match parts.split_first() {
+        None => return Ok(()),
+        Some(v) => v,
+    };
+    let base = |path: PathBuf| {
+        path.strip_prefix("./")
+            .map(Path::to_path_buf)
+            .unwrap_or(path)
+    };
+    match part {
+        Part::Recursive => {
+            // matching zero directory
+            if rest.is_empty() {
+                ret.push(dir.to_path_buf());
+            } else {
+                walk(dir, rest, options, ret)?;
+            }
+            for (name, path) in read_dir_names(dir) {
+                if name.starts_with('.') {
+                    continue;
+                }
+                let is_dir = fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir());
+                if is_dir {
+                    walk(&base(path), parts, options, ret)?;
+                } else if rest.is_empty() {
+                    // the files below are matched by a trailing `**` too
+                    ret.push(base(path));
+                }
+            }
+        }
+        Part::Name(tokens) => {
+            for (name, path) in read_dir_names(dir) {
+                if !match_name(tokens, &name, options) {
+                    continue;
+                }
+                let path = base(path);
+                if rest.is_empty() {
+                    ret.push(path);
+                } else if path.is_dir() {
+                    walk(&path, rest, options, ret)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
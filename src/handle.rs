@@ -0,0 +1,87 @@
+use crate::sys;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+/// Identity of a spawned process, telling whether it still runs even after its pid is reused
+///
+/// A pid alone may belong to another process once the spawned one exited, so the handle keeps
+/// its start time too, which is written along with the pid by `to_string()`, for checking it
+/// after a restart of the program:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let daemon = spawn!(my_daemon --foreground)?;
+/// let handle = daemon.stage_handle(0).unwrap();
+/// std::fs::write("/run/my_daemon.pid", handle.to_string())?;
+///
+/// // later, maybe in another run of the program
+/// let handle: ProcessHandle = std::fs::read_to_string("/run/my_daemon.pid")?.trim().parse()?;
+/// if !handle.is_alive() {
+///     spawn!(my_daemon --foreground)?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The start time is read from `/proc` on Linux, with `proc_pidinfo()` on macOS and with
+/// `GetProcessTimes()` on Windows. On the other platforms it is unknown, so only the pid is
+/// checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProcessHandle {
+    pid: u32,
+    // in the unit of `sys::process_start_time()`
+    started: Option<u64>,
+}
+
+impl ProcessHandle {
+    pub(crate) fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            started: sys::process_start_time(pid),
+        }
+    }
+
+    /// Returns the process id
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Whether the process still runs, with an exited process not waited for yet counted as
+    /// not running
+    pub fn is_alive(&self) -> bool {
+        match self.started {
+            Some(started) => sys::process_start_time(self.pid) == Some(started),
+            None => sys::process_running(self.pid),
+        }
+    }
+}
+
+/// Writes the handle as `PID:START`, or `PID` when the start time is unknown
+impl fmt::Display for ProcessHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.started {
+            Some(started) => write!(f, "{}:{}", self.pid, started),
+            None => write!(f, "{}", self.pid),
+        }
+    }
+}
+
+/// Reads a handle written by `to_string()`
+impl FromStr for ProcessHandle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid process handle: {:?}", s),
+            )
+        };
+        let (pid, started) = match s.split_once(':') {
+            Some((pid, started)) => (pid, Some(started.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        Ok(Self {
+            pid: pid.parse().map_err(|_| invalid())?,
+            started,
+        })
+    }
+}
@@ -1,7 +1,11 @@
 use os_pipe::*;
 use std::fs::File;
-use std::io::{Read, Result, Write};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Result, Write};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum CmdIn {
@@ -74,3 +78,152 @@ impl From<CmdOut> for Stdio {
         }
     }
 }
+
+// Relay between pipeline stages, counting the bytes or lines passing through, and the time
+// blocked writing them to the next stage
+pub(crate) struct PipeCounter {
+    pub(crate) stage: usize,
+    count: Arc<AtomicU64>,
+    blocked_nanos: Arc<AtomicU64>,
+    relay: Option<JoinHandle<()>>,
+}
+
+impl PipeCounter {
+    pub(crate) fn relay(stage: usize, pipe_in: PipeReader) -> Result<(PipeReader, Self)> {
+        Self::relay_counting(stage, pipe_in, false)
+    }
+
+    // counts the lines instead, including the last one without a newline
+    pub(crate) fn relay_lines(stage: usize, pipe_in: PipeReader) -> Result<(PipeReader, Self)> {
+        Self::relay_counting(stage, pipe_in, true)
+    }
+
+    fn relay_counting(
+        stage: usize,
+        mut pipe_in: PipeReader,
+        lines: bool,
+    ) -> Result<(PipeReader, Self)> {
+        let (pipe_reader, mut pipe_writer) = pipe()?;
+        let count = Arc::new(AtomicU64::new(0));
+        let relay_count = count.clone();
+        let blocked_nanos = Arc::new(AtomicU64::new(0));
+        let relay_blocked = blocked_nanos.clone();
+        let relay = thread::Builder::new().spawn(move || {
+            let mut buf = [0; 65536];
+            let mut in_line = false;
+            loop {
+                let n = match pipe_in.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let data = &buf[..n];
+                if lines {
+                    let newlines = data.iter().filter(|&&b| b == b'\n').count();
+                    relay_count.fetch_add(newlines as u64, Ordering::Relaxed);
+                    in_line = data[n - 1] != b'\n';
+                } else {
+                    relay_count.fetch_add(n as u64, Ordering::Relaxed);
+                }
+                // the write blocks while the pipe is full, and the upstream stage soon after
+                let started = Instant::now();
+                let written = pipe_writer.write_all(data);
+                let nanos = started.elapsed().as_nanos() as u64;
+                relay_blocked.fetch_add(nanos, Ordering::Relaxed);
+                // downstream stage is gone, close upstream pipe as well
+                if written.is_err() {
+                    break;
+                }
+            }
+            if in_line {
+                relay_count.fetch_add(1, Ordering::Relaxed);
+            }
+        })?;
+        Ok((
+            pipe_reader,
+            Self {
+                stage,
+                count,
+                blocked_nanos,
+                relay: Some(relay),
+            },
+        ))
+    }
+
+    // the count so far, updated while relaying
+    pub(crate) fn shared_count(&self) -> Arc<AtomicU64> {
+        self.count.clone()
+    }
+
+    pub(crate) fn finish(self) -> u64 {
+        self.finish_timed().0
+    }
+
+    // the count, with the time spent blocked writing to the next stage
+    pub(crate) fn finish_timed(mut self) -> (u64, Duration) {
+        if let Some(relay) = self.relay.take() {
+            let _ = relay.join();
+        }
+        let blocked = Duration::from_nanos(self.blocked_nanos.load(Ordering::Relaxed));
+        (self.count.load(Ordering::Relaxed), blocked)
+    }
+}
+
+// Reads `reader` on a thread until the end, passing the chunks to `f`. Once `f` returns false,
+// like when the receiving end of its channel is gone, the rest is read and discarded, so the
+// command writing it won't block on a full pipe. The thread returns the error reading, if any.
+pub(crate) fn drain_chunks<R, F>(
+    name: &str,
+    mut reader: R,
+    mut f: F,
+) -> Result<JoinHandle<Result<()>>>
+where
+    R: Read + Send + 'static,
+    F: FnMut(&[u8]) -> bool + Send + 'static,
+{
+    thread::Builder::new().name(name.into()).spawn(move || {
+        let mut buf = vec![0; 65536];
+        let mut forwarding = true;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if forwarding {
+                forwarding = f(&buf[..n]);
+            }
+        }
+    })
+}
+
+// Like `drain_chunks()`, passing the lines instead, with the trailing newline if any. Lines are
+// split on raw bytes, so invalid utf-8 or NUL won't stop the reading.
+pub(crate) fn drain_lines<R, F>(name: &str, reader: R, mut f: F) -> Result<JoinHandle<Result<()>>>
+where
+    R: Read + Send + 'static,
+    F: FnMut(&[u8]) -> bool + Send + 'static,
+{
+    thread::Builder::new().name(name.into()).spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut line = vec![];
+        let mut forwarding = true;
+        loop {
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+            if forwarding {
+                forwarding = f(&line);
+            }
+            line.clear();
+        }
+    })
+}
+
+// the line without the trailing newline, with invalid utf-8 replaced
+pub(crate) fn line_to_string(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
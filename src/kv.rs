@@ -0,0 +1,41 @@
+use crate::FunResult;
+use std::collections::HashMap;
+use std::io::Result;
+
+/// Parsing of `KEY=VALUE` lines, as written by tools like `systemctl show` or `git config -l`
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let props = run_fun!(systemctl show nginx --property=MainPID,ActiveState).parse_kv()?;
+/// if props.get("ActiveState").map(String::as_str) == Some("active") {
+///     println!("running as {}", props["MainPID"]);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Each line is split on its first `=`, so values can contain `=`, and keys and values are kept
+/// as they are, without trimming. When a key is repeated, the last value wins. Lines without
+/// `=`, including empty ones, are skipped.
+pub trait ParseKv {
+    type Output;
+
+    fn parse_kv(self) -> Self::Output;
+}
+
+impl ParseKv for &str {
+    type Output = HashMap<String, String>;
+
+    fn parse_kv(self) -> HashMap<String, String> {
+        self.lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+}
+
+impl ParseKv for FunResult {
+    type Output = Result<HashMap<String, String>>;
+
+    fn parse_kv(self) -> Result<HashMap<String, String>> {
+        self.map(|output| output.as_str().parse_kv())
+    }
+}
@@ -127,6 +127,11 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! Exactly one trailing newline is removed from the output if there is one, so output ending with
+//! `"\n\n"` keeps the last empty line, and output without a trailing newline is kept as it is.
+//! Output of `KEY=VALUE` lines can be parsed into a map with `ParseKv`, like
+//! `run_fun!(git config -l).parse_kv()?`.
+//!
 //! ### Abstraction without overhead
 //!
 //! Since all the macros' lexical analysis and syntactic analysis happen at compile time, it can
@@ -179,9 +184,75 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! With `${!var}`, the value of `var` is taken as the name of an environment variable, which is
+//! expanded instead, like the indirect expansion in bash. Unset environment variables expand to
+//! an empty string:
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! let name = "HOME";
+//! run_cmd!(echo ${!name} "is ${!name}")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Since values starting with `-` can be taken as options, a warning is logged when an interpolated
+//! variable starting an argument has such a value, like a file named `-rf` in `rm $file`.
+//! Options passed in `$[]` are not checked, and see `OptionGuard` for other ways to handle them.
+//!
+//! Maps can be expanded to flag arguments with `$%{}`, with each entry becoming `--key value`,
+//! sorted by key. See `Flags` for other formats like `--key=value`:
+//! ```no_run
+//! # use cmd_lib::*;
+//! # use std::collections::HashMap;
+//! let opts = HashMap::from([("region", "us-east-1"), ("profile", "ci")]);
+//! run_cmd!(aws s3 ls $%{opts})?;
+//! let flags = Flags::new(&opts).join_with("=");
+//! run_cmd!(aws s3 ls $%{flags})?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Integer arithmetic is supported with `$((...))`, and all the operations are wrapping on `i64`.
+//! Dividing by zero, a negative exponent, or a variable which is not an integer fails the command
+//! with an error of kind `InvalidInput`:
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! let n = 10;
+//! run_cmd!(seq 1 $((n * 2 + 1)))?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! All the interpolated values are evaluated once when the macro is called, from left to right,
+//! before any of the commands runs. So in a group of commands, a later command doesn't see the
+//! changes made by an earlier one, and `spawn!` has its arguments fixed when it returns. Only the
+//! `%{ ... }` statements and the closures passed to `spawn_after()` run later.
+//!
+//! Names with punctuation like `g++-12`, `python3.11` or `@scope/cli` are kept as they are
+//! written, as long as there are no spaces inside them. Since `rustfmt` formats the macros called
+//! with parentheses when their content looks like a Rust expression, like `python3.11 - V` for
+//! `run_cmd!(python3.11 -V)`, call them with braces instead, which it leaves alone:
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd! { python3.11 -V }?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Redirection and Piping
 //! Right now piping and stdin, stdout, stderr redirection are supported. Most parts are the same as in
 //! [bash scripts](https://www.gnu.org/software/bash/manual/html_node/Redirections.html#Redirections).
+//! Like `>>` for stdout, `2>>` appends stderr to a file instead of truncating it:
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd!(make > build.log 2>> errors.log)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//! Unlike bash, which silently sends nothing down the pipe, redirecting stdout of a command which
+//! is also piped to the next one is rejected at compile time, as is redirecting stdin of a piped
+//! command:
+//! ```compile_fail
+//! # use cmd_lib::run_cmd;
+//! run_cmd!(make > build.log | tail -n 5)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//! Use `tee` to write the output to a file and pass it on too.
 //!
 //! ### Logging
 //!
@@ -219,11 +290,14 @@
 //! exits the scope.
 //!
 //! Use `std::env::set_current_dir` if you want to change the current
-//! working directory for the whole program.
+//! working directory for the whole program, or `set_current_dir()` to change it only for the
+//! commands run by the current thread, which can be queried with `current_dir()`.
 //!
 //! #### ignore
 //!
-//! Ignore errors for command execution, which can be used without importing.
+//! Ignore errors for command execution, which can be used without importing. At the start of a
+//! pipeline it ignores the errors of the whole pipeline, while later in a pipeline it only
+//! ignores the errors of that stage, like `cat $file | ignore grep $pattern | wc -l`.
 //!
 //! #### echo
 //!
@@ -237,6 +311,51 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! #### env
+//!
+//! Print the environment variables an external command would get at that point, sorted and one
+//! `KEY=value` per line, including the variables set for the command like `FOO=1 env`. It also
+//! needs to be imported with `use_builtin_cmd!` macro.
+//!
+//! ```
+//! # use cmd_lib::{run_fun, use_builtin_cmd};
+//! use_builtin_cmd!(env);
+//! let vars = run_fun!(LANG=C env)?;
+//! assert!(vars.lines().any(|line| line == "LANG=C"));
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### pathmunge
+//!
+//! Prepend a directory to a PATH-like variable, or append it with `after`, for the commands after
+//! it in the same block, without adding it twice. It also needs to be imported with
+//! `use_builtin_cmd!` macro.
+//!
+//! ```no_run
+//! # use cmd_lib::{run_cmd, use_builtin_cmd};
+//! use_builtin_cmd!(pathmunge);
+//! run_cmd!(pathmunge PYTHONPATH /opt/app/lib; python3 -m app)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### grep
+//!
+//! Filter the lines of stdin, or of a file, matching a regular expression, without spawning
+//! `grep`. It needs the `grep` feature, and to be imported with `use_builtin_cmd!` macro.
+//!
+//! ```no_run
+//! # use cmd_lib::{run_fun, use_builtin_cmd};
+//! # #[cfg(feature = "grep")] {
+//! use_builtin_cmd!(grep);
+//! let errors = run_fun!(journalctl -b | grep -i "fail(ed|ure)")?;
+//! let hosts = run_fun!(grep -v "^#" /etc/hosts)?;
+//! # }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//! The pattern uses the syntax of the [regex crate](https://docs.rs/regex), and matches anywhere
+//! in the line unless anchored. Matching is case-sensitive, unless `-i` is given, and `-v`
+//! selects the lines not matching instead. Like `grep`, it fails when no line is selected.
+//!
 //! ### Macros to register your own commands
 //! Declare your function with `#[export_cmd(..)]` attribute, and import it with `use_custom_cmd!` macro:
 //!
@@ -256,6 +375,30 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! Registered commands, including the builtins imported with `use_builtin_cmd!`, take precedence
+//! over the programs with the same name in `PATH`, whether the name is written literally or
+//! interpolated, so they can also replace system commands like `echo`. Registering a name again
+//! replaces the previous command for the whole process, while commands kept in a `CmdRegistry`
+//! are only used inside `with_registry()`. A program given with a path, like
+//! `/bin/echo`, always runs the external program, and `cd` and `ignore` can't be replaced. As
+//! registered commands run inside the current process, `Process` options like `over_ssh()`,
+//! `bin_override()` and launchers don't apply to them.
+//!
+//! Rust closures can also be run between commands with `%{ ... }` statements, which get the same
+//! `CmdEnv` as custom commands, and their results are checked like other commands. Since the
+//! commands after them are built before they run, they pass values to those commands as
+//! environment variables with `CmdEnv::export()`:
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! run_cmd! {
+//!     make;
+//!     %{ |env| std::fs::metadata(env.current_dir().join("target")).map(|_| ()) };
+//!     make install;
+//! }?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Low-level process spawning macros
 //!
 //! `spawn!` macro executes the whole command as a child process, returning a handle to it. By
@@ -290,6 +433,22 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! All the wait methods wait for a pipeline the same way: the last stage is waited for first,
+//! while its output is read, then all the stages before it are reaped in pipeline order, even
+//! after the last stage failed. With pipefail, the error returned is the one of the earliest
+//! failed stage, since a failure upstream usually makes the stages after it fail too, and
+//! `CmdError::stage_index` tells which one it was. Without pipefail, only the last stage decides
+//! the result. The status of every stage is available in `stats()` either way. When reading the
+//! output stops early, like `wait_with_pipe()` returning before the end, or a closed writer in
+//! `wait_to_writer()`, the stages still running are killed instead, and their failures are not
+//! reported.
+//!
+//! If the handles returned by `spawn!` may be kept for a long time before waiting, call
+//! `enable_auto_reap()` to let a background thread reap the exited children and close their
+//! pipes early, and `wait()` will return the stashed result.
+//!
+//! To spawn commands later, use `spawn_after()` or `spawn_at()`, which can be cancelled before
+//! they start.
 //!
 //! ### Macros to define, get and set thread-local global variables
 //! - `tls_init!` to define thread local global variable
@@ -337,8 +496,17 @@
 //!
 //! ### Glob/Wildcard
 //!
-//! This library does not provide glob functions, to avoid silent errors and other surprises.
-//! You can use the [glob](https://github.com/rust-lang-nursery/glob) package instead.
+//! Commands are never globbed implicitly, to avoid silent errors and other surprises.
+//! Instead, you can expand patterns explicitly with `glob()` or `glob_with()`, which support
+//! recursive `**`, case-insensitive matching and `!(pattern)` negation, and pass the results as
+//! a vector:
+//! ```no_run
+//! # use cmd_lib::*;
+//! let options = GlobOptions { case_insensitive: true, ..Default::default() };
+//! let files = glob_with("docs/**/*.txt", &options)?;
+//! run_cmd!(cat $[files])?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
 //!
 //! ### Thread Safety
 //!
@@ -348,28 +516,97 @@
 //!
 
 pub use cmd_lib_macros::{
-    cmd_debug, cmd_die, cmd_echo, cmd_error, cmd_info, cmd_trace, cmd_warn, export_cmd, run_cmd,
-    run_fun, spawn, spawn_with_output, use_builtin_cmd, use_custom_cmd,
+    cmd_debug, cmd_die, cmd_echo, cmd_error, cmd_info, cmd_trace, cmd_warn, exec, export_cmd,
+    run_cmd, run_fun, run_fun_timed, spawn, spawn_with_output, use_builtin_cmd, use_custom_cmd,
+    validate_cmd,
 };
 /// Return type for run_fun!() macro
 pub type FunResult = std::io::Result<String>;
 /// Return type for run_cmd!() macro
 pub type CmdResult = std::io::Result<()>;
+pub use alias::{alias, alias_first};
+pub use assert::assert_output;
+#[cfg(feature = "grep")]
+#[doc(hidden)]
+pub use builtins::builtin_grep;
 pub use builtins::{
-    builtin_cat, builtin_debug, builtin_die, builtin_echo, builtin_error, builtin_info,
-    builtin_trace, builtin_warn,
+    builtin_cat, builtin_debug, builtin_die, builtin_echo, builtin_env, builtin_error,
+    builtin_info, builtin_pathmunge, builtin_trace, builtin_warn,
+};
+pub use child::{
+    CmdChildren, ExecutionRecord, FunChildren, PipelineStats, PipelineSummary, Progress,
+    StageStats, StdoutChunks, StdoutLines, StdoutLinesUntil,
 };
-pub use child::{CmdChildren, FunChildren};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use compress::Codec;
+pub use confirm::Confirm;
+pub use diagnostic::{Diagnostic, DiagnosticKind};
+pub use diff::diff_outputs;
+pub use env::Env;
+pub use error::{CmdError, PartialOutput};
+pub use executor::{DefaultExecutor, Executor};
+pub use flags::{FlagArgs, Flags};
+pub use glob::{glob, glob_with, GlobOptions};
+pub use handle::ProcessHandle;
+pub use kv::ParseKv;
 #[doc(hidden)]
 pub use log;
+pub use logfile::{LogFileSink, LogFileWriter};
 pub use logger::init_builtin_logger;
+pub use not_found::{on_command_not_found, reset_command_not_found, Fallback};
+pub use pathlike::{append_pathlike, prepend_pathlike};
 pub use process::{
-    export_cmd, set_debug, set_pipefail, AsOsStr, Cmd, CmdEnv, CmdString, Cmds, GroupCmds, Redirect,
+    arith_div, arith_pow, arith_rem, arith_result, arith_var, current_dir, env_var_indirect,
+    harden_operands, harden_operands_for, register_cmd_hook, reset_launcher, set_current_dir,
+    set_debug, set_history_expansion, set_launcher, set_max_alias_depth, set_max_cmd_len,
+    set_max_pipeline_len, set_pipefail, set_pipefail_warn, set_timeout, spawn_command,
+    spawn_command_with_output, AsOsStr, Cmd, CmdEnv, CmdString, Cmds, GroupCmds, OptionGuard,
+    ParsedCommand, Process, Redirect, RunOutput,
 };
+pub use reaper::enable_auto_reap;
+#[cfg(feature = "regex")]
+pub use regex;
+pub use registry::{export_cmd, with_registry, CmdRegistry};
+pub use retry::{retry, RetryOptions};
+pub use schedule::{spawn_after, spawn_at, ScheduledChildren};
+pub use script::{parse_cmd_line, run_script_file, ScriptOptions};
+pub use spec::CmdSpec;
+pub use stdin::{StdinOptions, StdinWriter};
+pub use transaction::{transaction, Transaction};
+pub use validate::{ValidatedCmd, ValidationError, ValidationReport};
 
+mod alias;
+mod ansi;
+mod assert;
 mod builtins;
 mod child;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+mod compress;
+pub mod config;
+mod confirm;
+mod diagnostic;
+mod diff;
+mod env;
+mod error;
+mod executor;
+mod flags;
+mod glob;
+mod handle;
 mod io;
+mod kv;
+mod logfile;
 mod logger;
+mod not_found;
+mod pathlike;
 mod process;
+mod reaper;
+mod registry;
+mod retry;
+mod schedule;
+mod script;
+mod spec;
+mod stdin;
+mod sys;
 mod thread_local;
+mod transaction;
+mod validate;
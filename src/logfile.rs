@@ -0,0 +1,217 @@
+use crate::io;
+use log::warn;
+use os_pipe::PipeReader;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// Log file with size based rotation, for `Process::stdout_log()` and `Process::stderr_log()`
+///
+/// The output is appended to `path` line by line. When a line would make it larger than
+/// `max_size`, it is renamed to `path.1`, the older `path.1` to `path.2` and so on, keeping at
+/// most `max_files` rotated files, and a new file is started:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let mut out = LogFileSink::new("/var/log/daemon.out.log");
+/// out.max_size = 1 << 20;
+/// out.max_files = 3;
+/// let mut daemon = Process::new()
+///     .stdout_log(out)
+///     .stderr_log(LogFileSink::new("/var/log/daemon.err.log"))
+///     .run(|| spawn!(my_daemon --foreground))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Since files are only rotated between lines, a line longer than `max_size` is kept whole.
+/// Failing to write the log is logged as a warning, and the rest of the output is discarded
+/// instead of blocking or killing the command.
+///
+/// The same rotation is available as a `Write` with `writer()`, e.g. for `wait_to_writer()`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LogFileSink {
+    /// Path of the current log file
+    pub path: PathBuf,
+    /// Size in bytes to rotate the file at, 10 MiB by default
+    pub max_size: u64,
+    /// Number of rotated files to keep, 5 by default, or 0 to truncate the file instead
+    pub max_files: usize,
+    /// Syncs every line to the disk, and the files before rotating them, false by default
+    pub fsync: bool,
+}
+
+impl LogFileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_size: 10 << 20,
+            max_files: 5,
+            fsync: false,
+        }
+    }
+
+    /// Returns a writer appending to the log file, rotated like for `Process::stdout_log()`
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let mut out = LogFileSink::new("/var/log/job.log");
+    /// out.max_size = 1 << 20;
+    /// spawn_with_output!(my_job --verbose)?.wait_to_writer(&mut out.writer())?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Lines are written whole, and an incomplete last line is kept until the next write
+    /// completes it, or until the writer is flushed or dropped. Unlike with
+    /// `Process::stdout_log()`, write errors are returned.
+    pub fn writer(self) -> LogFileWriter {
+        LogFileWriter {
+            log: LogFile::new(self),
+            partial: vec![],
+        }
+    }
+}
+
+/// Writer to a log file with size based rotation, returned by `LogFileSink::writer()`
+pub struct LogFileWriter {
+    log: LogFile,
+    partial: Vec<u8>,
+}
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let mut start = 0;
+        while let Some(n) = self.partial[start..].iter().position(|&b| b == b'\n') {
+            let end = start + n + 1;
+            if let Err(e) = self.log.try_write_line(&self.partial[start..end]) {
+                // the output not written yet is discarded
+                self.partial.clear();
+                return Err(e);
+            }
+            start = end;
+        }
+        self.partial.drain(..start);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.partial.is_empty() {
+            self.log.try_write_line(&self.partial)?;
+            self.partial.clear();
+        }
+        match self.log.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for LogFileWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!(
+                "Writing log file {} failed: {}",
+                self.log.sink.path.display(),
+                e
+            );
+        }
+    }
+}
+
+// log file shared by the relays of all the commands writing to it
+pub(crate) struct LogFile {
+    sink: LogFileSink,
+    file: Option<File>,
+    size: u64,
+    failed: bool,
+}
+
+impl LogFile {
+    fn new(sink: LogFileSink) -> Self {
+        LogFile {
+            sink,
+            file: None,
+            size: 0,
+            failed: false,
+        }
+    }
+
+    pub(crate) fn shared(sink: LogFileSink) -> Arc<Mutex<LogFile>> {
+        Arc::new(Mutex::new(LogFile::new(sink)))
+    }
+
+    // copies the lines from `pipe` to the log file on a thread, joined when the command is
+    // waited for, so that its output is all in the file by then
+    pub(crate) fn relay(
+        log: Arc<Mutex<LogFile>>,
+        pipe: PipeReader,
+    ) -> Result<JoinHandle<Result<()>>> {
+        io::drain_lines("cmd_lib log", pipe, move |line| {
+            log.lock().unwrap().write_line(line);
+            true
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.try_write_line(line) {
+            warn!(
+                "Writing log file {} failed: {}, discarding the rest of the output",
+                self.sink.path.display(),
+                e
+            );
+            self.failed = true;
+            self.file = None;
+        }
+    }
+
+    fn try_write_line(&mut self, line: &[u8]) -> Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.sink.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.sink.max_size {
+            self.rotate()?;
+        }
+        let file = self.file.as_mut().unwrap();
+        file.write_all(line)?;
+        if self.sink.fsync {
+            file.sync_data()?;
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            if self.sink.fsync {
+                file.sync_all()?;
+            }
+        }
+        if self.sink.max_files > 0 {
+            for i in (1..self.sink.max_files).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(i + 1))?;
+                }
+            }
+            std::fs::rename(&self.sink.path, self.rotated_path(1))?;
+        }
+        self.file = Some(File::create(&self.sink.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut path = OsString::from(&self.sink.path);
+        path.push(format!(".{}", i));
+        path.into()
+    }
+}
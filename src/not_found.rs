@@ -0,0 +1,103 @@
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex, PoisonError};
+
+// times the handler is called for a command, before it fails as not found
+const MAX_FALLBACKS: usize = 3;
+
+pub(crate) type FnNotFound = Arc<Mutex<dyn FnMut(&str) -> Option<Fallback> + Send>>;
+
+lazy_static! {
+    static ref NOT_FOUND_HANDLER: Mutex<Option<FnNotFound>> = Mutex::new(None);
+}
+
+thread_local! {
+    // set while the handler runs, and in the threads of the commands it spawns
+    static IN_HANDLER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// What to do about a command not found, returned by the handler of `on_command_not_found()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fallback {
+    /// Runs this command line instead of the program, followed by its arguments, like an alias
+    Command(Vec<String>),
+    /// Looks the program up again, like after installing it
+    Retry,
+    /// Fails the command with this message, with an error of kind `NotFound`
+    Error(String),
+}
+
+/// Sets a handler for the external commands not found, like `command_not_found_handle` in bash
+///
+/// The handler gets the program name, and returns what to do instead, or `None` for failing as
+/// usual:
+/// ```no_run
+/// # use cmd_lib::*;
+/// on_command_not_found(|name| match name {
+///     "python" => Some(Fallback::Command(vec!["python3".into()])),
+///     "jq" => {
+///         run_cmd!(sudo apt-get install -y jq).ok()?;
+///         Some(Fallback::Retry)
+///     }
+///     _ => Some(Fallback::Error(format!("{} is missing, see README.md", name))),
+/// });
+/// run_cmd!(python --version)?; // python3 --version
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The programs are looked up when the commands are spawned, in the directory they run in and in
+/// the `PATH` they get, including `PATH=dir cmd`, and not for the commands run over ssh. When the
+/// command of a `Fallback` is not found either, the handler is called again with its name, up to
+/// 3 times in all, after which the command fails as not found. The commands run by the handler
+/// itself, like installing a tool, are not passed to it again, including the commands run by the
+/// builtin and custom commands it spawns. It is not called again while it runs, so the commands
+/// on other threads wait for it, and the handler must not wait for the threads it starts itself
+/// to run commands, which would deadlock. Use `Process::on_command_not_found()` to set a handler
+/// for some commands only.
+pub fn on_command_not_found<F>(f: F)
+where
+    F: FnMut(&str) -> Option<Fallback> + Send + 'static,
+{
+    *NOT_FOUND_HANDLER.lock().unwrap() = Some(Arc::new(Mutex::new(f)));
+}
+
+/// Removes the handler set by `on_command_not_found()`
+pub fn reset_command_not_found() {
+    *NOT_FOUND_HANDLER.lock().unwrap() = None;
+}
+
+// whether the handler runs on this thread, passed to the threads running builtin commands
+pub(crate) fn in_handler() -> bool {
+    IN_HANDLER.with(Cell::get)
+}
+
+pub(crate) fn set_in_handler(in_handler: bool) {
+    IN_HANDLER.with(|cell| cell.set(in_handler));
+}
+
+// the handler of `process` or the global one, unless called from a handler
+pub(crate) fn handler(process: Option<FnNotFound>) -> Option<FnNotFound> {
+    if IN_HANDLER.with(Cell::get) {
+        return None;
+    }
+    process.or_else(|| NOT_FOUND_HANDLER.lock().unwrap().clone())
+}
+
+// calls `handler` for the program `name` found missing `attempt` times before
+pub(crate) fn fallback(handler: &FnNotFound, name: &str, attempt: usize) -> Option<Fallback> {
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            IN_HANDLER.with(|in_handler| in_handler.set(false));
+        }
+    }
+
+    if attempt >= MAX_FALLBACKS {
+        return None;
+    }
+    IN_HANDLER.with(|in_handler| in_handler.set(true));
+    let _restore = Restore;
+    // a handler which panicked before is still called
+    let mut handler = handler.lock().unwrap_or_else(PoisonError::into_inner);
+    handler(name)
+}
@@ -0,0 +1,121 @@
+use crate::process::Process;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+#[cfg(windows)]
+pub(crate) const SEPARATOR: char = ';';
+#[cfg(not(windows))]
+pub(crate) const SEPARATOR: char = ':';
+
+/// Returns the value of the PATH-like variable `name`, with `dir` appended
+///
+/// The value is the one the commands would get: from `Process::env()` inside `Process::run()`,
+/// or else from the process environment. It is joined with the platform separator, `:` on unix
+/// and `;` on Windows. If `dir` is already in the value, it is moved to the end instead of added
+/// twice. Unset variables and empty entries are taken as no entries, since an empty entry would
+/// mean the current directory for `PATH`. Set it for a command like:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let python_path = append_pathlike("PYTHONPATH", "/opt/app/lib")?;
+/// run_cmd!(PYTHONPATH=$python_path python3 -m app)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// An error is returned if `dir` contains the separator, or the value is not valid unicode.
+/// Inside a block, the `pathmunge` builtin sets the variable for the commands after it, see
+/// `builtin_pathmunge()`, and `Env::append_pathlike()` sets it in an `Env` layer.
+pub fn append_pathlike(name: &str, dir: impl AsRef<Path>) -> Result<String> {
+    munge(name, &current_value(name)?, dir.as_ref(), false, SEPARATOR)
+}
+
+/// Returns the value of the PATH-like variable `name`, with `dir` prepended
+///
+/// See `append_pathlike()` for the details.
+pub fn prepend_pathlike(name: &str, dir: impl AsRef<Path>) -> Result<String> {
+    munge(name, &current_value(name)?, dir.as_ref(), true, SEPARATOR)
+}
+
+// the value of `name` the commands would get, with unset variables taken as empty
+fn current_value(name: &str) -> Result<String> {
+    if let Some(env) = Process::current_env() {
+        return Ok(env.get(name).unwrap_or_default());
+    }
+    std::env::var_os(name)
+        .unwrap_or_default()
+        .into_string()
+        .map_err(|value| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not valid unicode: {:?}", name, value),
+            )
+        })
+}
+
+// adds `dir` to the entries of `value` separated by `sep`, which is only passed in for testing
+// the separators of all the platforms
+pub(crate) fn munge(
+    name: &str,
+    value: &str,
+    dir: &Path,
+    prepend: bool,
+    sep: char,
+) -> Result<String> {
+    let failed = |msg: String| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Setting {} to include {} failed: {}",
+                name,
+                dir.display(),
+                msg
+            ),
+        )
+    };
+    let new_dir = dir
+        .to_str()
+        .ok_or_else(|| failed("not valid unicode".into()))?;
+    if new_dir.contains(sep) {
+        return Err(failed(format!("path contains separator `{}`", sep)));
+    }
+    let mut dirs: Vec<&str> = value
+        .split(sep)
+        .filter(|entry| !entry.is_empty() && Path::new(entry) != dir)
+        .collect();
+    if prepend {
+        dirs.insert(0, new_dir);
+    } else {
+        dirs.push(new_dir);
+    }
+    Ok(dirs.join(&sep.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_munge() {
+        let munge = |value, dir, prepend, sep| munge("X", value, Path::new(dir), prepend, sep);
+        assert_eq!(munge("", "/opt/a", false, ':').unwrap(), "/opt/a");
+        assert_eq!(munge("", "/opt/a", true, ':').unwrap(), "/opt/a");
+        assert_eq!(
+            munge("/usr/lib::/opt/a:/usr/local/lib", "/opt/a", false, ':').unwrap(),
+            "/usr/lib:/usr/local/lib:/opt/a"
+        );
+        assert_eq!(
+            munge("/usr/lib:/opt/a", "/opt/b", true, ':').unwrap(),
+            "/opt/b:/usr/lib:/opt/a"
+        );
+        assert!(munge("/usr/lib", "/opt/a:/opt/b", false, ':').is_err());
+
+        // the separator of Windows, where `:` is part of the paths
+        assert_eq!(
+            munge(r"C:\lib;;C:\a", r"C:\a", false, ';').unwrap(),
+            r"C:\lib;C:\a"
+        );
+        assert_eq!(
+            munge(r"C:\lib;C:\a", r"C:\b", true, ';').unwrap(),
+            r"C:\b;C:\lib;C:\a"
+        );
+        assert!(munge("", r"C:\a;C:\b", false, ';').is_err());
+    }
+}
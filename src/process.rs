@@ -1,19 +1,46 @@
-use crate::child::{CmdChild, CmdChildHandle, CmdChildren, FunChildren};
-use crate::io::{CmdIn, CmdOut};
+use crate::alias;
+use crate::child::{
+    CmdChild, CmdChildHandle, CmdChildren, ExecutionRecord, FunChildren, StatsCollector,
+    TypedResult,
+};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::compress::{self, Codec};
+use crate::config;
+use crate::confirm::Confirm;
+use crate::env::Env;
+use crate::error;
+use crate::executor::Executor;
+use crate::glob::GlobOptions;
+use crate::io::{CmdIn, CmdOut, PipeCounter};
+use crate::logfile::{LogFile, LogFileSink};
+use crate::not_found::{self, Fallback, FnNotFound};
+use crate::registry::{self, FnFun};
+use crate::spec::{CmdSpec, StageSpec};
+use crate::stdin::{self, StdinOptions, StdinWriter};
+use crate::sys;
+use crate::validate::{
+    check_redirect, resolve_program, ValidatedCmd, ValidationError, ValidationReport,
+};
 use crate::{CmdResult, FunResult};
 use faccess::{AccessMode, PathExt};
 use lazy_static::lazy_static;
 use log::{debug, warn};
 use os_pipe::{self, PipeReader, PipeWriter};
-use std::collections::HashMap;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Read, Result, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Mutex;
-use std::thread;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 const CD_CMD: &str = "cd";
 const IGNORE_CMD: &str = "ignore";
@@ -25,7 +52,11 @@ pub struct CmdEnv {
     stderr: CmdOut,
     args: Vec<String>,
     vars: HashMap<String, String>,
+    env: Option<Env>,
     current_dir: PathBuf,
+    last_succeeded: bool,
+    exports: Exports,
+    typed_result: TypedResult,
 }
 impl CmdEnv {
     /// Returns the arguments for this command
@@ -38,11 +69,90 @@ impl CmdEnv {
         self.vars.get(key)
     }
 
+    /// Returns the value of environment variable `key`, as an external command would get it here
+    ///
+    /// Unlike `var()`, it also looks up the variables exported by the commands before in the same
+    /// block, the ones set with `Process::env()` and the ones inherited from this process.
+    pub fn env_var(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.vars.get(key) {
+            return Some(value.clone());
+        }
+        if let Some(value) = self.exports.lock().unwrap().get(key) {
+            return Some(value.clone());
+        }
+        match self.env {
+            Some(ref env) => env.get(key),
+            None => std::env::var(key).ok(),
+        }
+    }
+
+    /// Returns all the environment variables an external command would get here, sorted by name
+    pub fn env_vars(&self) -> BTreeMap<String, String> {
+        let mut vars = std::env::vars_os()
+            .map(|(k, v)| (k.to_string_lossy().into(), v.to_string_lossy().into()))
+            .collect();
+        if let Some(ref env) = self.env {
+            env.resolve(&mut vars);
+        }
+        vars.extend(self.exports.lock().unwrap().clone());
+        vars.extend(self.vars.clone());
+        vars
+    }
+
+    /// Sets the environment variable `key` to `value` for the commands after this one in the
+    /// same block, like `export` in a shell
+    ///
+    /// It is the way for a `%{ ... }` statement to pass a value it computes to the commands after
+    /// it, which are built before it runs:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// run_cmd! {
+    ///     %{ |env| { env.export("VERSION", env!("CARGO_PKG_VERSION")); Ok(()) } };
+    ///     sh -c "git tag v$$VERSION";
+    /// }?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// External commands get the exported variables in their environment, and builtin and custom
+    /// commands and closures read them with `env_var()`. Variables set for a command only, as in
+    /// `FOO=1 cmd`, still take precedence. The variables are shared by the block through a lock,
+    /// so closures running on a thread, like the last one of `run_fun!()`, can export them too.
+    ///
+    /// The other stages of the same pipeline don't see them: they are spawned together with the
+    /// closure, before it runs, so only the statements after the pipeline get them.
+    pub fn export(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.exports
+            .lock()
+            .unwrap()
+            .insert(key.into(), value.into());
+    }
+
     /// Returns the current working directory for this command
+    ///
+    /// It follows `cd` in the same block, `set_current_dir()` and `Process::scratch_dir()`, while
+    /// the working directory of this process is left alone, so relative paths in the arguments
+    /// should be resolved with `resolve_path()` rather than opened as they are.
     pub fn current_dir(&self) -> &Path {
         &self.current_dir
     }
 
+    /// Returns `path` resolved against `current_dir()` if it is relative
+    pub fn resolve_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.current_dir.join(path)
+    }
+
+    /// Returns true if the previous command in the same block succeeded, or this is the first one
+    pub fn last_succeeded(&self) -> bool {
+        self.last_succeeded
+    }
+
+    /// Sets the value returned by `FunChildren::wait_fun_typed()`, when this is the last stage
+    ///
+    /// It replaces the value set before, and is dropped when this is not the last stage or the
+    /// output is waited for otherwise.
+    pub fn set_result(&mut self, value: Box<dyn Any + Send>) {
+        *self.typed_result.lock().unwrap() = Some(value);
+    }
+
     /// Returns a new handle to the standard input for this command
     pub fn stdin(&mut self) -> impl Read + '_ {
         &mut self.stdin
@@ -57,53 +167,966 @@ impl CmdEnv {
     pub fn stderr(&mut self) -> impl Write + '_ {
         &mut self.stderr
     }
+
+    pub(crate) fn vars(&self) -> &HashMap<String, String> {
+        &self.vars
+    }
+
+    // the environment set with `Process::env()`
+    pub(crate) fn process_env(&self) -> Option<&Env> {
+        self.env.as_ref()
+    }
+
+    // takes the standard streams to pass them to a process
+    pub(crate) fn take_stdio(&mut self) -> (CmdIn, CmdOut, CmdOut) {
+        (
+            std::mem::replace(&mut self.stdin, CmdIn::Null),
+            std::mem::replace(&mut self.stdout, CmdOut::Null),
+            std::mem::replace(&mut self.stderr, CmdOut::Null),
+        )
+    }
 }
 
-type FnFun = fn(&mut CmdEnv) -> CmdResult;
+type FnCallback = Box<dyn FnOnce(&mut CmdEnv) -> CmdResult + Send>;
+
+// the variables exported by `CmdEnv::export()` to the later commands of a block
+type Exports = Arc<Mutex<HashMap<String, String>>>;
+
+type FnCmdHook = Arc<Mutex<dyn FnMut(&mut ParsedCommand) + Send>>;
 
 lazy_static! {
-    static ref CMD_MAP: Mutex<HashMap<OsString, FnFun>> = {
-        // needs explicit type, or it won't compile
-        let m: HashMap<OsString, FnFun> = HashMap::new();
-        Mutex::new(m)
-    };
+    static ref CMD_HOOKS: Mutex<Vec<FnCmdHook>> = Mutex::new(vec![]);
 }
 
-#[doc(hidden)]
-pub fn export_cmd(cmd: &'static str, func: FnFun) {
-    CMD_MAP.lock().unwrap().insert(OsString::from(cmd), func);
+thread_local! {
+    // set while the hooks run, and in the threads of the commands they spawn
+    static IN_HOOKS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A parsed command passed to the hooks registered with `register_cmd_hook()`
+#[non_exhaustive]
+pub struct ParsedCommand {
+    /// Command name and arguments, after all the variables, globs and arithmetic are expanded
+    pub args: Vec<OsString>,
+    /// Environment variables set for this command only, like `FOO=1 cmd`
+    pub vars: HashMap<String, String>,
+    /// Redirects in the order they appear
+    pub redirects: Vec<Redirect>,
+}
+
+/// Registers a hook called for every command before spawning
+///
+/// Hooks are called in the registration order, after the command is parsed and all its arguments
+/// are expanded, but before the redirects are opened, so they can rewrite the command, its
+/// arguments, environment variables or redirects. `cd` commands and `%{ }` statements are not
+/// passed to the hooks. Hooks can run commands themselves, like for collecting metrics, but those
+/// commands are not passed to the hooks again, including the commands run by the builtin and
+/// custom commands they spawn. A hook is not called again while it runs, so the commands on
+/// other threads wait for it.
+/// ```no_run
+/// # use cmd_lib::*;
+/// register_cmd_hook(|cmd| {
+///     if cmd.args.first().is_some_and(|arg| arg == "ls") {
+///         cmd.args.insert(1, "--color=never".into());
+///     }
+/// });
+/// ```
+pub fn register_cmd_hook<F>(f: F)
+where
+    F: FnMut(&mut ParsedCommand) + Send + 'static,
+{
+    CMD_HOOKS.lock().unwrap().push(Arc::new(Mutex::new(f)));
+}
+
+type FnLauncher = Arc<dyn Fn(Vec<OsString>) -> Vec<OsString> + Send + Sync>;
+
+lazy_static! {
+    static ref LAUNCHER: Mutex<Option<FnLauncher>> = Mutex::new(None);
+}
+
+/// Sets a launcher to rewrite the command line of every external command before spawning
+///
+/// The launcher gets the program and its arguments, and returns the command line to spawn
+/// instead, like running the commands in a container:
+/// ```no_run
+/// # use cmd_lib::*;
+/// set_launcher(|argv| {
+///     let mut launched = vec!["docker".into(), "exec".into(), "-i".into(), "builder".into()];
+///     launched.extend(argv);
+///     launched
+/// });
+/// run_cmd!(cargo build)?; // docker exec -i builder cargo build
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The original command line is still used for logging, errors and hooks, and the environment
+/// variables like `FOO=1 cmd` are set for the launched program. Builtin and custom commands are
+/// not launched. Use `Process::launcher()` to set a launcher for some commands only.
+pub fn set_launcher<F>(f: F)
+where
+    F: Fn(Vec<OsString>) -> Vec<OsString> + Send + Sync + 'static,
+{
+    *LAUNCHER.lock().unwrap() = Some(Arc::new(f));
+}
+
+/// Removes the launcher set by `set_launcher()`
+pub fn reset_launcher() {
+    *LAUNCHER.lock().unwrap() = None;
+}
+
+/// Spawns a `std::process::Command` built by the caller, like `spawn!()` does for the commands
+///
+/// It is the way to use the settings not available in the macros, like `pre_exec()` hooks,
+/// while keeping the stderr logging, `CmdError`, statistics and the `Process` options:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let mut cmd = std::process::Command::new("my_daemon");
+/// cmd.arg("--foreground").env_clear().env("PATH", "/usr/bin");
+/// spawn_command(cmd)?.wait()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The program, arguments, environment and working directory of `cmd` are kept, and
+/// `current_dir()` is only used when `cmd` sets no working directory. Stderr is replaced by a
+/// pipe for logging, unless `Process::interactive()` is set, while stdin and stdout are kept.
+/// Aliases, hooks, launchers, `Process::bin_override()` and `Process::over_ssh()` are not
+/// applied, since they rewrite the command line.
+pub fn spawn_command(cmd: Command) -> Result<CmdChildren> {
+    GroupCmds::from_command(cmd).spawn(false)
+}
+
+/// Spawns a `std::process::Command` built by the caller, like `spawn_with_output!()`
+///
+/// Stdout is replaced by a pipe to capture the output, and everything else is the same as
+/// `spawn_command()`.
+pub fn spawn_command_with_output(cmd: Command) -> Result<FunChildren> {
+    GroupCmds::from_command(cmd).spawn_with_output()
 }
 
 /// set debug mode or not, false by default
 ///
 /// Setting environment variable CMD_LIB_DEBUG=0|1 has the same effect
 pub fn set_debug(enable: bool) {
-    std::env::set_var("CMD_LIB_DEBUG", if enable { "1" } else { "0" });
+    config::set(|c| c.debug = Some(enable));
 }
 
 /// set pipefail or not, true by default
 ///
-/// Setting environment variable CMD_LIB_PIPEFAIL=0|1 has the same effect
+/// With pipefail, a pipeline fails with the error of its earliest failed stage, rather than the
+/// last one like bash does. Setting environment variable CMD_LIB_PIPEFAIL=0|1 has the same effect
 pub fn set_pipefail(enable: bool) {
-    std::env::set_var("CMD_LIB_PIPEFAIL", if enable { "1" } else { "0" });
+    config::set(|c| c.pipefail = Some(enable));
+}
+
+/// set the maximum length of the command strings in errors and logs, 4096 bytes by default
+///
+/// Longer command strings, like commands with thousands of file arguments, are truncated with
+/// the number of elided bytes noted, while the full arguments are still available in
+/// `CmdError::argv`. Setting environment variable CMD_LIB_MAX_CMD_LEN has the same effect.
+pub fn set_max_cmd_len(len: usize) {
+    config::set(|c| c.max_cmd_len = Some(len));
+}
+
+/// set the maximum number of commands in a pipeline, 256 by default
+///
+/// Longer pipelines fail to spawn upfront with an error of kind `InvalidInput`, like the ones
+/// needing more file descriptors than the process can still open on Linux, rather than running
+/// out of them halfway. Setting environment variable CMD_LIB_MAX_PIPELINE_LEN has the same
+/// effect.
+pub fn set_max_pipeline_len(len: usize) {
+    config::set(|c| c.max_pipeline_len = Some(len));
+}
+
+/// set the maximum number of aliases expanded in turn for a command, 8 by default
+///
+/// Alias templates starting with other aliases nested deeper fail to run with an error of kind
+/// `InvalidInput` showing the expansion chain, like the ones expanding to themselves again.
+/// Setting environment variable CMD_LIB_MAX_ALIAS_DEPTH has the same effect.
+pub fn set_max_alias_depth(depth: usize) {
+    config::set(|c| c.max_alias_depth = Some(depth));
+}
+
+/// set the default timeout of the commands, none by default
+///
+/// Like `Process::timeout()` for all the commands, which takes precedence, and zero for no
+/// timeout. Setting environment variable CMD_LIB_TIMEOUT, like CMD_LIB_TIMEOUT=10m, has the same
+/// effect.
+pub fn set_timeout(timeout: Duration) {
+    config::set(|c| c.timeout = Some(timeout));
+}
+
+/// warn about failed pipeline stages masked by disabled pipefail or not, true by default
+///
+/// With pipefail disabled, only the last stage decides the result of a pipeline, so the failed
+/// stages before it are logged as warnings and recorded in `StageStats::masked_error`. Prefix a
+/// stage with `ignore` to skip both for that stage, like `curl $url | ignore grep x | head`.
+/// Setting environment variable CMD_LIB_PIPEFAIL_WARN=0|1 has the same effect
+pub fn set_pipefail_warn(enable: bool) {
+    config::set(|c| c.pipefail_warn = Some(enable));
+}
+
+/// expand `!!` in `parse_cmd_line()` or not, false by default
+///
+/// `!!` is replaced with the last command line parsed on the same thread, for REPL like tools.
+/// Setting environment variable CMD_LIB_HISTORY_EXPANSION=0|1 has the same effect
+pub fn set_history_expansion(enable: bool) {
+    config::set(|c| c.history_expansion = Some(enable));
+}
+
+/// insert `--` before the interpolated operands of some commands or not, false by default
+///
+/// For the commands known to take `--` as the end of the options, `--` is inserted before the
+/// first interpolated argument, so that values starting with `-` are taken as operands, like file
+/// names, instead of options:
+/// ```no_run
+/// # use cmd_lib::*;
+/// harden_operands(true);
+/// let file = "-rf";
+/// run_cmd!(rm -f $file)?; // rm -f -- -rf
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The commands are `rm`, `cp`, `mv`, `chmod` and `chown`, and more can be added with
+/// `harden_operands_for()`. `git checkout` is left out, since its operands after `--` are always
+/// paths, so `git checkout $branch` would restore a file instead. Only the interpolated
+/// arguments trailing the literal ones are hardened: when a literal argument like an option
+/// follows them, which `--` would turn into an operand, or when `--` is already written, nothing
+/// is inserted, and `Process::option_guard()` applies as usual. Otherwise it doesn't apply, as the
+/// values can't be taken as options. Each insertion is logged in debug mode. Setting environment
+/// variable CMD_LIB_HARDEN_OPERANDS=0|1 has the same effect
+pub fn harden_operands(enable: bool) {
+    config::set(|c| c.harden_operands = Some(enable));
+}
+
+/// Adds a command to the ones hardened by `harden_operands()`
+///
+/// The command is given with its subcommands if any, like `&["git", "rm"]`, and matched against
+/// the literal words starting a command.
+pub fn harden_operands_for(cmd: &[&str]) {
+    let cmd = cmd.iter().map(OsString::from).collect();
+    HARDENED_CMDS.lock().unwrap().push(cmd);
+}
+
+lazy_static! {
+    static ref HARDENED_CMDS: Mutex<Vec<Vec<OsString>>> = Mutex::new(
+        ["rm", "cp", "mv", "chmod", "chown"]
+            .iter()
+            .map(|cmd| vec![OsString::from(cmd)])
+            .collect()
+    );
+}
+
+fn harden_operands_enabled() -> bool {
+    config::get(|c| c.harden_operands)
 }
 
 pub(crate) fn debug_enabled() -> bool {
-    std::env::var("CMD_LIB_DEBUG") == Ok("1".into())
+    config::get(|c| c.debug)
 }
 
 pub(crate) fn pipefail_enabled() -> bool {
-    std::env::var("CMD_LIB_PIPEFAIL") != Ok("0".into())
+    config::get(|c| c.pipefail)
 }
 
-#[doc(hidden)]
+pub(crate) fn pipefail_warn_enabled() -> bool {
+    config::get(|c| c.pipefail_warn)
+}
+
+pub(crate) fn history_expansion_enabled() -> bool {
+    config::get(|c| c.history_expansion)
+}
+
+fn max_cmd_len() -> usize {
+    config::get(|c| c.max_cmd_len)
+}
+
+// fails before spawning anything when the pipeline is too long, or would run out of fds
+fn check_pipeline_len(len: usize, with_output: bool, count_bytes: bool) -> CmdResult {
+    let max_len = config::get(|c| c.max_pipeline_len);
+    if len > max_len {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "pipeline of {} commands is longer than the limit of {}, see set_max_pipeline_len()",
+                len, max_len
+            ),
+        ));
+    }
+    // the stderr of each stage is kept open, and a few pipes while spawning each stage, with
+    // a relay thread keeping both ends of each pipe counting bytes
+    let mut needed = len + 6 + with_output as usize;
+    if count_bytes {
+        needed += 2 * (len - 1);
+    }
+    let limit = match sys::fd_limit() {
+        // counting the open fds reads a directory on every spawn, which is only worth it when
+        // the pipeline needs a good part of the limit
+        Some(limit) if needed * 16 >= limit => limit,
+        _ => return Ok(()),
+    };
+    match sys::open_fds().map(|open| limit.saturating_sub(open)) {
+        Some(available) if needed > available => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "pipeline of {} commands needs about {} file descriptors, and only {} are \
+                 available under RLIMIT_NOFILE",
+                len, needed, available
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Options for spawning processes, applied to the commands run inside `Process::run()`
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// # let config = std::fs::File::open("/etc/app.conf")?;
+/// let output = Process::new()
+///     .pass_fd(9, config.into())
+///     .run(|| run_fun!(my_tool --config-fd 9))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Builtin and custom commands are not affected by these options.
 #[derive(Default)]
+pub struct Process {
+    #[cfg(unix)]
+    fds: Vec<(RawFd, OwnedFd)>,
+    #[cfg(unix)]
+    umask: Option<u32>,
+    #[cfg(unix)]
+    process_group: bool,
+    #[cfg(unix)]
+    pipe_size: Option<usize>,
+    count_pipe_bytes: bool,
+    bin_overrides: HashMap<OsString, PathBuf>,
+    executor: Option<Arc<dyn Executor>>,
+    strip_bom: bool,
+    strip_ansi: bool,
+    ssh: Option<(OsString, Vec<OsString>)>,
+    option_guard: OptionGuard,
+    confirm: Option<Confirm>,
+    launcher: Option<FnLauncher>,
+    interactive: bool,
+    stdin_auto: Option<fn() -> bool>,
+    scratch_dir: Option<ScratchDir>,
+    env: Option<Env>,
+    stdout_log: Option<Arc<Mutex<LogFile>>>,
+    stderr_log: Option<Arc<Mutex<LogFile>>>,
+    timeout: Option<Duration>,
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    compress_output: Option<Codec>,
+    not_found: Option<FnNotFound>,
+    glob_options: Option<GlobOptions>,
+}
+
+// temp directory removed when the `Process` is dropped after running
+struct ScratchDir {
+    path: PathBuf,
+    keep_on_failure: bool,
+    // `run()` returned an error
+    failed: Cell<bool>,
+}
+
+impl ScratchDir {
+    fn create(keep_on_failure: bool) -> Result<Self> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static SCRATCH_DIR_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let path = std::env::temp_dir().join(format!(
+            "cmd_lib_scratch_{}_{:08x}_{}",
+            std::process::id(),
+            nanos,
+            SCRATCH_DIR_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir(&path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Creating scratch dir {} failed: {}", path.display(), e),
+            )
+        })?;
+        Ok(Self {
+            path,
+            keep_on_failure,
+            failed: Cell::new(false),
+        })
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if self.keep_on_failure && (thread::panicking() || self.failed.get()) {
+            warn!("Keeping scratch dir {} after failure", self.path.display());
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Removing scratch dir {} failed: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Return types of the closures run by `Process::run()`, telling whether they failed
+///
+/// A `Result` failed when it is `Err`, which keeps the directory of `Process::scratch_dir()`
+/// if asked to, while `()` never fails.
+pub trait RunOutput {
+    fn failed(&self) -> bool;
+}
+
+impl<T, E> RunOutput for std::result::Result<T, E> {
+    fn failed(&self) -> bool {
+        self.is_err()
+    }
+}
+
+impl RunOutput for () {
+    fn failed(&self) -> bool {
+        false
+    }
+}
+
+/// Guard against interpolated values being taken as options
+///
+/// A file named `-rf` interpolated into `run_cmd!(rm $file)` would be taken as an option by
+/// `rm`. Arguments of external commands starting with an interpolated variable, like `$file` or
+/// `"$file.bak"`, are guarded when their values start with `-`, unless they are a lone `-` for
+/// stdin, negative numbers or placed after a `--` argument. Vector variables in `$[]` are never
+/// guarded, which is the way to pass options on purpose:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let opts = vec!["-l", "-a"];
+/// let file = "-rf";
+/// run_cmd!(ls $[opts] -- $file)?;
+/// Process::new()
+///     .option_guard(OptionGuard::Prefix)
+///     .run(|| run_cmd!(rm $file))?; // rm ./-rf
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OptionGuard {
+    /// Passes the values as they are
+    Off,
+    /// Passes the values as they are, with a warning logged
+    #[default]
+    Warn,
+    /// Prefixes the values with `./`, for operands which are relative paths
+    Prefix,
+}
+
+thread_local! {
+    static CURRENT_PROCESS: RefCell<Option<Rc<Process>>> = const { RefCell::new(None) };
+}
+
+impl Process {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Passes `fd` to the child processes as file descriptor `child_fd`
+    ///
+    /// The fd is kept open in the children, and closed in the parent after `run()` returns.
+    #[cfg(unix)]
+    pub fn pass_fd(mut self, child_fd: RawFd, fd: OwnedFd) -> Self {
+        self.fds.push((child_fd, fd));
+        self
+    }
+
+    /// Sets the umask of the child processes, for the files they create themselves
+    ///
+    /// The permission bits set in `mask` are cleared from the files and directories the commands
+    /// create, like `umask 077` in a shell, while this process keeps its own umask:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// Process::new()
+    ///     .umask(0o077)
+    ///     .run(|| run_cmd!(ssh-keygen -q -N "" -f /tmp/deploy_key))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Files opened by redirects like `> file` are created by this process, so they are not
+    /// affected.
+    #[cfg(unix)]
+    pub fn umask(mut self, mask: u32) -> Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Starts each external command in a process group of its own, false by default
+    ///
+    /// Killing the children, directly or when they time out, kills the whole group, so that the
+    /// processes a command started itself, like the ones of a `sh -c` script, don't keep running
+    /// and holding the pipes open. Signals sent by the terminal, like the one of Ctrl-C, only
+    /// reach its foreground process group though, which the commands are no longer part of.
+    #[cfg(unix)]
+    pub fn process_group(mut self, enable: bool) -> Self {
+        self.process_group = enable;
+        self
+    }
+
+    /// Sets the capacity of the pipes between pipeline stages to at least `size` bytes
+    ///
+    /// A larger capacity lets a stage write ahead of a slower next one. Only Linux supports it,
+    /// where it fails spawning when `size` is above `/proc/sys/fs/pipe-max-size` for an
+    /// unprivileged process, while the capacity chosen by the system is kept on the other
+    /// platforms.
+    #[cfg(unix)]
+    pub fn pipe_size(mut self, size: usize) -> Self {
+        self.pipe_size = Some(size);
+        self
+    }
+
+    /// Sets the default timeout of the commands, measured from spawning each pipeline
+    ///
+    /// Pipelines still running when it expires are killed, and waiting for them fails with an
+    /// error of kind `TimedOut`:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::time::Duration;
+    /// Process::new().timeout(Duration::from_secs(30)).run(|| -> CmdResult {
+    ///     run_cmd!(apt-get update)?;
+    ///     // this one may take longer
+    ///     spawn!(apt-get upgrade -y)?.wait_timeout(Duration::from_secs(600))
+    /// })?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// It applies to `run_cmd!()`, `run_fun!()`, and to `wait()` and `wait_with_output()` of the
+    /// children spawned inside `run()`, even when they are waited for after `run()` returns. A
+    /// timeout passed to `CmdChildren::wait_timeout()` or `FunChildren::wait_with_output_timeout()`
+    /// replaces it, whether it is shorter or longer, and it replaces the global one of
+    /// `set_timeout()`. Builtin and custom commands running in threads can't be killed, so they
+    /// are still waited for after timing out.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Counts the bytes written to stdout by each pipeline stage, false by default
+    ///
+    /// The counts are available from `stats()` of the spawned children after waiting, along with
+    /// the time each stage was blocked by a slower next stage, as `StageStats::stdout_blocked`.
+    /// Since an extra relay thread is inserted between stages to count bytes, it adds one more
+    /// copy for the data passing through pipes.
+    pub fn count_pipe_bytes(mut self, enable: bool) -> Self {
+        self.count_pipe_bytes = enable;
+        self
+    }
+
+    /// Runs the program at `path` for the commands named `name`, instead of searching `PATH`
+    ///
+    /// Only the program position is affected, not the same word in arguments. The commands run
+    /// over ssh with `over_ssh()` are not affected, as the path is one on the local host, while
+    /// an override of `ssh` itself applies.
+    pub fn bin_override(mut self, name: impl Into<OsString>, path: impl Into<PathBuf>) -> Self {
+        self.bin_overrides.insert(name.into(), path.into());
+        self
+    }
+
+    /// Runs the external commands with `executor` instead of spawning them directly
+    ///
+    /// See `Executor` for mocking commands in tests.
+    pub fn executor(mut self, executor: impl Executor + 'static) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Strips the leading UTF-8 BOM from the output returned by `run_fun!()` and
+    /// `wait_with_output()`, false by default
+    ///
+    /// It is off by default to return the output as it is, but some tools, mostly on Windows,
+    /// write a BOM that needs to be stripped before parsing the output.
+    pub fn strip_bom(mut self, enable: bool) -> Self {
+        self.strip_bom = enable;
+        self
+    }
+
+    /// Strips ANSI escape sequences like colors from the captured output, false by default
+    ///
+    /// It applies to the output returned by `run_fun!()`, `wait_with_output()` and
+    /// `stdout_lines_with_summary()`, and to both stdout and stderr lines of
+    /// `wait_split_lines()`, for tools writing colors even when not attached to a terminal. CSI
+    /// sequences, which include the colors and cursor movements, OSC sequences like hyperlinks,
+    /// and the other escape sequences are stripped. The output written by `wait_to_writer()` is
+    /// not changed.
+    pub fn strip_ansi(mut self, enable: bool) -> Self {
+        self.strip_ansi = enable;
+        self
+    }
+
+    /// Runs the external commands on `host` through `ssh`, with the extra ssh options in `opts`
+    ///
+    /// Each command is spawned as `ssh <opts> <host> -- <command>`, where the command and its
+    /// arguments are shell quoted, so they reach the remote command unchanged:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let file = "my file.txt";
+    /// let size = Process::new()
+    ///     .over_ssh("build-server", &["-p", "2222"])
+    ///     .run(|| run_fun!(stat -c %s $file))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Environment variables set for a command, as in `FOO=1 cmd`, are set for the remote
+    /// command. Redirections and pipes still apply locally to the ssh process, so in
+    /// `cmd1 | cmd2 > out.txt` both commands run remotely with their data passed through the
+    /// local pipe and written to the local file. `cd` only changes the local directory.
+    pub fn over_ssh(mut self, host: impl Into<OsString>, opts: &[&str]) -> Self {
+        let opts = opts.iter().map(OsString::from).collect();
+        self.ssh = Some((host.into(), opts));
+        self
+    }
+
+    /// Sets how to guard interpolated arguments starting with `-`, `OptionGuard::Warn` by default
+    pub fn option_guard(mut self, guard: OptionGuard) -> Self {
+        self.option_guard = guard;
+        self
+    }
+
+    /// Launches the external commands with `f`, instead of the launcher set by `set_launcher()`
+    ///
+    /// With `over_ssh()`, `f` gets the command line of `ssh`.
+    pub fn launcher<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Vec<OsString>) -> Vec<OsString> + Send + Sync + 'static,
+    {
+        self.launcher = Some(Arc::new(f));
+        self
+    }
+
+    /// Handles the commands not found with `f`, instead of the handler set by
+    /// `on_command_not_found()`
+    pub fn on_command_not_found<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&str) -> Option<Fallback> + Send + 'static,
+    {
+        self.not_found = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+
+    /// Asks for confirmation before running the commands matched by `confirm`
+    ///
+    /// See `Confirm` for the details.
+    pub fn confirm(mut self, confirm: Confirm) -> Self {
+        self.confirm = Some(confirm);
+        self
+    }
+
+    /// Lets the commands interact with the user on the terminal, false by default
+    ///
+    /// Stdin is inherited as usual, and stderr is inherited too, instead of being logged, so
+    /// tools prompting on stderr can be used while their stdout is still captured:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let public_key = Process::new()
+    ///     .interactive(true)
+    ///     .run(|| run_fun!(ssh-keygen -y -f ~/.ssh/id_ed25519))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// No thread is started to log stderr, and stderr is not available in `CmdError::stderr_tail`.
+    /// Unlike the other options, it applies to builtin and custom commands too.
+    pub fn interactive(mut self, enable: bool) -> Self {
+        self.interactive = enable;
+        self
+    }
+
+    /// Inherits stdin only when it is a terminal, and gives the commands null stdin otherwise
+    ///
+    /// Tools reading stdin interactively, like prompting for a confirmation, still work on the
+    /// terminal, while in CI or cron jobs they see end of file at once instead of waiting on the
+    /// stdin of the parent forever:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// Process::new()
+    ///     .stdin_auto(true)
+    ///     .run(|| run_cmd!(apt-get remove nginx))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Pipes and redirects like `< file` take precedence. Unlike the other options, it applies to
+    /// builtin and custom commands too.
+    pub fn stdin_auto(mut self, enable: bool) -> Self {
+        if enable {
+            return self.stdin_auto_with(stdin_is_terminal);
+        }
+        self.stdin_auto = None;
+        self
+    }
+
+    // `stdin_auto()` with the terminal check given, which is replaced for testing
+    pub(crate) fn stdin_auto_with(mut self, is_terminal: fn() -> bool) -> Self {
+        self.stdin_auto = Some(is_terminal);
+        self
+    }
+
+    /// Sets the environment of the external commands with `env`
+    ///
+    /// See `Env` for composing it from several sources. Variables set for a command only, as in
+    /// `FOO=1 cmd`, still take precedence.
+    pub fn env(mut self, env: Env) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Appends the stdout of the last command in each pipeline to the rotated log file `sink`
+    ///
+    /// It is meant for long running commands started with `spawn!()`, whose output would
+    /// otherwise be lost or fill up the disk. See `LogFileSink` for the rotation. The output is
+    /// still captured by `run_fun!()` and `spawn_with_output!()`, and redirects like `> file`
+    /// take precedence. All the commands run inside `run()` share the same file, which is written
+    /// by a thread per command, joined when the command is waited for, so that all its output is
+    /// in the file by then. Unlike the other options, it applies to builtin and custom commands
+    /// too.
+    pub fn stdout_log(mut self, sink: LogFileSink) -> Self {
+        self.stdout_log = Some(LogFile::shared(sink));
+        self
+    }
+
+    /// Compresses the stdout redirected to files with `codec`, as the commands write it
+    ///
+    /// It keeps verbose output small without adding a compressing command to the pipeline:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// Process::new()
+    ///     .compress_output(Codec::Gzip)
+    ///     .run(|| run_cmd!(make V=1 > build.log.gz 2>&1))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// It applies to `> file` and `>> file`, with the files opened like without it, and to the
+    /// stderr sent there by `2>&1`, while `2> file` is left as it is. Appending adds a gzip
+    /// member or a zstd frame, which the decompressors read as the concatenated output. The
+    /// output is compressed by a thread, which the commands are waited for with, and a failure
+    /// to write the file fails the command. Unlike the other options, it applies to builtin and
+    /// custom commands too. It needs the `gzip` or `zstd` feature.
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub fn compress_output(mut self, codec: Codec) -> Self {
+        self.compress_output = Some(codec);
+        self
+    }
+
+    /// Sets the options of `glob()` called inside `run()`
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let options = GlobOptions { case_insensitive: true, ..Default::default() };
+    /// let files = Process::new()
+    ///     .glob_options(options)
+    ///     .run(|| glob("docs/**/*.TXT"))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// `glob_with()` still uses the options it is given.
+    pub fn glob_options(mut self, options: GlobOptions) -> Self {
+        self.glob_options = Some(options);
+        self
+    }
+
+    /// Appends the stderr of the commands to the rotated log file `sink`, instead of logging it
+    ///
+    /// Like `stdout_log()`, but for stderr of all the commands, which is then not available in
+    /// `CmdError::stderr_tail`. Redirects like `2> file` take precedence.
+    pub fn stderr_log(mut self, sink: LogFileSink) -> Self {
+        self.stderr_log = Some(LogFile::shared(sink));
+        self
+    }
+
+    /// Runs the commands in a new empty temp directory, which is removed after `run()` returns
+    ///
+    /// The directory is created right away in `std::env::temp_dir()`, which follows `TMPDIR` on
+    /// unix, with a unique name. It becomes `current_dir()` inside `run()`, for builtin and custom
+    /// commands too:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// Process::new().scratch_dir(true)?.run(|| -> CmdResult {
+    ///     run_cmd!(git init; touch a.txt; git add a.txt)?;
+    ///     assert_eq!(run_fun!(git status --short)?, "A  a.txt");
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// It is removed with all its contents, also when `run()` returns an error or panics. With
+    /// `keep_on_failure`, it is kept for debugging when `f` returns `Err` or panics, like a failed
+    /// `unwrap()` or assertion in tests, and its path is logged as a warning.
+    pub fn scratch_dir(mut self, keep_on_failure: bool) -> Result<Self> {
+        self.scratch_dir = Some(ScratchDir::create(keep_on_failure)?);
+        Ok(self)
+    }
+
+    /// Runs `f`, with all the commands spawned inside using these options
+    ///
+    /// `f` returns a `Result`, or nothing, see `RunOutput`.
+    pub fn run<T: RunOutput>(self, f: impl FnOnce() -> T) -> T {
+        struct Restore(Option<Rc<Process>>, Option<PathBuf>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                if let Some(dir) = self.1.take() {
+                    CURRENT_DIR.with(|d| *d.borrow_mut() = dir);
+                }
+                // drops this process, removing its scratch dir
+                let prev = self.0.take();
+                CURRENT_PROCESS.with(|p| *p.borrow_mut() = prev);
+            }
+        }
+
+        let scratch_dir = self.scratch_dir.as_ref().map(|dir| dir.path.clone());
+        let prev = CURRENT_PROCESS.with(|p| p.borrow_mut().replace(Rc::new(self)));
+        let prev_dir = scratch_dir.map(|dir| CURRENT_DIR.with(|d| d.replace(dir)));
+        let _restore = Restore(prev, prev_dir);
+        let output = f();
+        if output.failed() {
+            if let Some(dir) = Process::current()
+                .as_ref()
+                .and_then(|p| p.scratch_dir.as_ref())
+            {
+                dir.failed.set(true);
+            }
+        }
+        output
+    }
+
+    pub(crate) fn default_timeout() -> Option<Duration> {
+        Process::current().and_then(|p| p.timeout).or_else(|| {
+            let timeout = config::get(|c| c.timeout);
+            (!timeout.is_zero()).then_some(timeout)
+        })
+    }
+
+    // the environment set with `env()` for the commands run here
+    pub(crate) fn current_env() -> Option<Env> {
+        Process::current().and_then(|p| p.env.clone())
+    }
+
+    // the options of `glob()`, from `glob_options()`
+    pub(crate) fn current_glob_options() -> GlobOptions {
+        Process::current()
+            .and_then(|p| p.glob_options.clone())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn strip_bom_enabled() -> bool {
+        Process::current().is_some_and(|p| p.strip_bom)
+    }
+
+    pub(crate) fn strip_ansi_enabled() -> bool {
+        Process::current().is_some_and(|p| p.strip_ansi)
+    }
+
+    fn interactive_enabled() -> bool {
+        Process::current().is_some_and(|p| p.interactive)
+    }
+
+    // whether `stdin_auto()` replaces the inherited stdin with null
+    fn null_stdin() -> bool {
+        Process::current()
+            .and_then(|p| p.stdin_auto)
+            .is_some_and(|is_terminal| !is_terminal())
+    }
+
+    fn current() -> Option<Rc<Process>> {
+        CURRENT_PROCESS.with(|p| p.borrow().clone())
+    }
+
+    fn program(&self, name: &OsString) -> OsString {
+        self.bin_overrides
+            .get(name)
+            .map_or_else(|| name.clone(), |path| path.clone().into_os_string())
+    }
+
+    fn setup_command(&self, cmd: &mut Command) {
+        #[cfg(unix)]
+        if !self.fds.is_empty() {
+            let fds = self
+                .fds
+                .iter()
+                .map(|(child_fd, fd)| (*child_fd, fd.as_raw_fd()))
+                .collect();
+            sys::pass_fds(cmd, fds);
+        }
+        #[cfg(unix)]
+        if let Some(mask) = self.umask {
+            sys::set_umask(cmd, mask);
+        }
+        #[cfg(unix)]
+        if self.process_group {
+            sys::new_process_group(cmd);
+        }
+    }
+}
+
+fn stdin_is_terminal() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdin().is_terminal()
+}
+
+thread_local! {
+    // empty for following the process working directory
+    static CURRENT_DIR: RefCell<PathBuf> = const { RefCell::new(PathBuf::new()) };
+}
+
+/// Returns the working directory for commands run by this thread
+///
+/// It is the directory set by `set_current_dir()` in this thread, or the process working directory
+/// from `std::env::current_dir()` otherwise.
+pub fn current_dir() -> PathBuf {
+    let dir = CURRENT_DIR.with(|dir| dir.borrow().clone());
+    if dir.as_os_str().is_empty() {
+        std::env::current_dir().unwrap_or_default()
+    } else {
+        dir
+    }
+}
+
+/// Sets the working directory for commands run by this thread
+///
+/// Unlike `std::env::set_current_dir()`, it doesn't change the process working directory, so
+/// other threads and relative paths in rust code are not affected. Relative `path` is resolved
+/// against `current_dir()`. Commands blocks start from this directory, and builtin `cd` still
+/// only changes the directory within its block.
+pub fn set_current_dir<P: AsRef<Path>>(path: P) -> CmdResult {
+    let path = current_dir().join(path);
+    if !path.is_dir() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{}: No such directory", path.display()),
+        ));
+    }
+    path.access(AccessMode::EXECUTE)?;
+    CURRENT_DIR.with(|dir| *dir.borrow_mut() = path);
+    Ok(())
+}
+
+#[doc(hidden)]
 pub struct GroupCmds {
     group_cmds: Vec<Cmds>,
     current_dir: PathBuf,
+    last_failed: bool,
+    exports: Exports,
+}
+
+impl Default for GroupCmds {
+    fn default() -> Self {
+        Self {
+            group_cmds: vec![],
+            current_dir: CURRENT_DIR.with(|dir| dir.borrow().clone()),
+            last_failed: false,
+            exports: Exports::default(),
+        }
+    }
 }
 
 impl GroupCmds {
+    fn from_command(cmd: Command) -> Self {
+        let mut args = vec![cmd.get_program().to_os_string()];
+        args.extend(cmd.get_args().map(OsStr::to_os_string));
+        let cmd = Cmd {
+            in_cmd_map: false,
+            args,
+            std_cmd: Some(cmd),
+            ..Default::default()
+        };
+        let cmds = Cmds {
+            full_cmds: cmd.cmd_str(),
+            cmds: vec![Some(cmd)],
+            ..Default::default()
+        };
+        Self::default().append(cmds)
+    }
+
     pub fn append(mut self, cmds: Cmds) -> Self {
         self.group_cmds.push(cmds);
         self
@@ -111,69 +1134,324 @@ impl GroupCmds {
 
     pub fn run_cmd(&mut self) -> CmdResult {
         for cmds in self.group_cmds.iter_mut() {
+            cmds.last_succeeded = !self.last_failed;
+            cmds.exports = self.exports.clone();
+            self.last_failed = false;
             if let Err(e) = cmds.run_cmd(&mut self.current_dir) {
                 if !cmds.ignore_error {
                     return Err(e);
                 }
+                self.last_failed = true;
             }
         }
         Ok(())
     }
 
     pub fn run_fun(&mut self) -> FunResult {
+        self.run_fun_timed().map(|(output, _)| output)
+    }
+
+    /// Runs the commands with `input` as the stdin of the last pipeline, returning its stdout
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let json = br#"{"name": "cmd_lib"}"#;
+    /// let name = parse_cmd_line("jq -r .name")?.run_with_stdin(json)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The input is written while the output is read, so large input and output can't
+    /// deadlock, and stdin is closed after it, so the command sees the end of the input. Unlike
+    /// `run_fun()`, the output is returned as it is, without decoding or trimming it. The input
+    /// is written like by `feed_stdin_iter()`, so errors writing it are logged as warnings. A `<`
+    /// redirect of the first command takes precedence over `input`.
+    pub fn run_with_stdin(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        // run previous commands
+        let mut last_cmd = self.group_cmds.pop().unwrap();
+        self.run_cmd()?;
+        last_cmd.last_succeeded = !self.last_failed;
+        last_cmd.exports = self.exports.clone();
+        let ret = last_cmd.run_with_stdin(&mut self.current_dir, input);
+        if ret.is_err() && last_cmd.ignore_error {
+            return Ok(vec![]);
+        }
+        ret
+    }
+
+    pub fn run_fun_timed(&mut self) -> Result<(String, Duration)> {
+        let started = Instant::now();
         // run previous commands
         let mut last_cmd = self.group_cmds.pop().unwrap();
         self.run_cmd()?;
         // run last function command
+        last_cmd.last_succeeded = !self.last_failed;
+        last_cmd.exports = self.exports.clone();
         let ret = last_cmd.run_fun(&mut self.current_dir);
         if ret.is_err() && last_cmd.ignore_error {
-            return Ok("".into());
+            return Ok(("".into(), started.elapsed()));
         }
-        ret
+        ret.map(|output| (output, started.elapsed()))
     }
 
-    pub fn spawn(mut self, with_output: bool) -> Result<CmdChildren> {
-        assert_eq!(self.group_cmds.len(), 1);
-        let mut cmds = self.group_cmds.pop().unwrap();
-        let ret = cmds.spawn(&mut self.current_dir, with_output);
-        // spawning error contains no command information, attach it here
-        if let Err(ref e) = ret {
-            if !cmds.ignore_error {
+    /// Checks the commands would run, without running them, like `validate_cmd!`
+    ///
+    /// It is meant for command templates only known at runtime:
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # let template = "rsync -a src/ backup/";
+    /// let report = parse_cmd_line(template)?.validate()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// Programs are searched with the same lookup as spawning them, in the `PATH` the commands
+    /// would get.
+    pub fn validate(mut self) -> std::result::Result<ValidationReport, ValidationError> {
+        let process = Process::current();
+        let confirm = process.as_ref().and_then(|p| p.confirm.as_ref());
+        let mut report = ValidationReport::default();
+        let mut problems = vec![];
+        for cmds in self.group_cmds.iter() {
+            for cmd in cmds.cmds.iter().flatten() {
+                let command = cmd.cmd_str();
+                let mut program = None;
+                if cmd.arg0() == CD_CMD {
+                    if let Err(e) = cmd.run_cd_cmd(&mut self.current_dir) {
+                        problems.push(e.to_string());
+                    }
+                } else if let Some(ref std_cmd) = cmd.std_cmd {
+                    let search_path = cmd.search_path();
+                    program = resolve_program(
+                        std_cmd.get_program(),
+                        &self.current_dir,
+                        search_path.as_deref(),
+                    );
+                    if program.is_none() {
+                        problems.push(format!(
+                            "{}: program {:?} not found",
+                            command,
+                            std_cmd.get_program()
+                        ));
+                    }
+                }
+                for redirect in cmd.redirects.iter() {
+                    if let Err(e) = check_redirect(redirect, &self.current_dir) {
+                        problems.push(format!("{}: {}", command, e));
+                    }
+                }
+                let ignored = cmd.args.iter().take_while(|arg| *arg == IGNORE_CMD).count();
+                let needs_confirmation = cmd.std_cmd.is_some()
+                    && confirm.is_some_and(|confirm| confirm.matches(&cmd.args[ignored..]));
+                report.commands.push(ValidatedCmd {
+                    command,
+                    program,
+                    needs_confirmation,
+                });
+            }
+        }
+        if problems.is_empty() {
+            Ok(report)
+        } else {
+            Err(ValidationError { problems })
+        }
+    }
+
+    pub(crate) fn from_spec(spec: &CmdSpec) -> Result<Self> {
+        let mut cmds = Cmds::default();
+        for stage in spec.stages.iter() {
+            let mut cmd = Cmd {
+                args: stage.args.clone(),
+                vars: stage.vars.clone(),
+                redirects: stage.redirects.clone(),
+                ..Default::default()
+            };
+            cmd.find_cmd();
+            if stage.in_cmd_map && !cmd.in_cmd_map {
                 return Err(Error::new(
-                    e.kind(),
-                    format!("Spawning {} failed: {}", cmds.get_full_cmds(), e),
+                    ErrorKind::NotFound,
+                    format!("{:?}: custom command not found", cmd.arg0()),
                 ));
             }
+            cmds.stages.push(stage.clone());
+            cmds = cmds.push(cmd);
+            if let Some(Some(cmd)) = cmds.cmds.last_mut() {
+                cmd.ignore_error |= stage.ignore_error;
+            }
         }
-        ret
+        Ok(Self {
+            group_cmds: vec![cmds],
+            current_dir: spec.current_dir.clone(),
+            last_failed: false,
+            exports: Exports::default(),
+        })
+    }
+
+    pub fn spawn(mut self, with_output: bool) -> Result<CmdChildren> {
+        assert_eq!(self.group_cmds.len(), 1);
+        let mut cmds = self.group_cmds.pop().unwrap();
+        let spec = cmds.spec(&self.current_dir);
+        let ret = cmds
+            .spawn(&mut self.current_dir, with_output, None)
+            .map(|children| children.with_spec(spec))
+            .map(|children| {
+                if with_output {
+                    children
+                } else {
+                    children.auto_reap()
+                }
+            });
+        cmds.with_spawn_context(ret)
+    }
+
+    pub fn exec(mut self) -> Error {
+        assert_eq!(self.group_cmds.len(), 1);
+        let mut cmds = self.group_cmds.pop().unwrap();
+        if cmds.cmds.len() != 1 {
+            return Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Executing pipeline {} is not supported",
+                    cmds.get_full_cmds()
+                ),
+            );
+        }
+        let cmd = cmds.cmds.pop().unwrap().unwrap();
+        let e = cmd.exec(&self.current_dir);
+        error::with_context(e, format!("Executing {} failed", cmds.get_full_cmds()))
     }
 
     pub fn spawn_with_output(self) -> Result<FunChildren> {
         self.spawn(true).map(CmdChildren::into_fun_children)
     }
+
+    /// Spawns the commands like `spawn_with_output()`, with the items of `iter` written to their
+    /// stdin on a thread
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// let lines = (0..1_000_000).map(|i| format!("row {}\n", i));
+    /// let count = parse_cmd_line("wc -l")?.feed_stdin_iter(lines)?.wait_with_output()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The items are written as they are, so lines need their newline. They are taken from
+    /// `iter` only as fast as the commands read them, so the input is never in memory as a
+    /// whole, and stdin is closed after the last one. When the commands exit or close stdin
+    /// early, the iteration stops, and errors writing the input are logged as warnings. A `<`
+    /// redirect of the first command takes precedence over `iter`.
+    pub fn feed_stdin_iter<I>(self, iter: I) -> Result<FunChildren>
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + 'static,
+        I::Item: AsRef<[u8]>,
+    {
+        self.feed_stdin_iter_with(iter, StdinOptions::default())
+    }
+
+    /// Feeds the items of `iter` to stdin like `feed_stdin_iter()`, written in chunks and flushed
+    /// as set by `options`
+    ///
+    /// Every item is flushed by default, for commands processing the records as they come.
+    /// Without `flush_each_item`, the items are collected into chunks of `chunk_size`, for fewer
+    /// writes of small items.
+    pub fn feed_stdin_iter_with<I>(mut self, iter: I, options: StdinOptions) -> Result<FunChildren>
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + 'static,
+        I::Item: AsRef<[u8]>,
+    {
+        assert_eq!(self.group_cmds.len(), 1);
+        let mut cmds = self.group_cmds.pop().unwrap();
+        let ret = cmds.feed_stdin_iter(&mut self.current_dir, iter.into_iter(), options);
+        cmds.with_spawn_context(ret)
+    }
+
+    /// Spawns the commands like `spawn_with_output()`, returning a writer to their stdin
+    ///
+    /// ```no_run
+    /// # use cmd_lib::*;
+    /// # use std::io::Write;
+    /// let (mut children, mut stdin) =
+    ///     parse_cmd_line("sort -u")?.spawn_with_stdin(&StdinOptions::default())?;
+    /// for word in ["b", "a", "b"] {
+    ///     writeln!(stdin, "{}", word)?;
+    ///     stdin.flush()?;
+    /// }
+    /// stdin.close()?;
+    /// let sorted = children.wait_with_output()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    /// The commands see the end of the input once the writer is closed or dropped, so it should
+    /// be before waiting for them. As the output is only read when waiting, commands writing a
+    /// lot of output before reading all the input need the input written on another thread. A `<`
+    /// redirect of the first command takes precedence over the writer.
+    pub fn spawn_with_stdin(
+        mut self,
+        options: &StdinOptions,
+    ) -> Result<(FunChildren, StdinWriter)> {
+        assert_eq!(self.group_cmds.len(), 1);
+        let mut cmds = self.group_cmds.pop().unwrap();
+        let ret = cmds.spawn_with_stdin(&mut self.current_dir, options);
+        cmds.with_spawn_context(ret)
+    }
 }
 
 #[doc(hidden)]
-#[derive(Default)]
 pub struct Cmds {
     cmds: Vec<Option<Cmd>>,
+    // the commands to spawn again, if none of them has a closure
+    stages: Vec<StageSpec>,
     full_cmds: String,
     ignore_error: bool,
+    last_succeeded: bool,
+    exports: Exports,
+}
+
+impl Default for Cmds {
+    fn default() -> Self {
+        Cmds {
+            cmds: vec![],
+            stages: vec![],
+            full_cmds: String::new(),
+            ignore_error: false,
+            last_succeeded: true,
+            exports: Exports::default(),
+        }
+    }
 }
 
 impl Cmds {
-    pub fn pipe(mut self, cmd: Cmd) -> Self {
+    pub fn pipe(mut self, mut cmd: Cmd) -> Self {
+        if let Some(e) = ARITH_ERROR.with(|error| error.borrow_mut().take()) {
+            // fails when run, like a command not found
+            cmd.callback = Some(Box::new(move |_| Err(e)));
+            cmd.in_cmd_map = true;
+        }
+        cmd.insert_operands_separator();
+        cmd.resolve_alias();
+        cmd.run_hooks();
+        if cmd.callback.is_none() {
+            self.stages.push(StageSpec {
+                args: cmd.args.clone(),
+                vars: cmd.vars.clone(),
+                redirects: cmd.redirects.clone(),
+                in_cmd_map: cmd.in_cmd_map,
+                ignore_error: false,
+            });
+        }
+        self.push(cmd)
+    }
+
+    // adds the command as it is, after the rewrites of `pipe()`
+    fn push(mut self, cmd: Cmd) -> Self {
         if !self.full_cmds.is_empty() {
             self.full_cmds += " | ";
         }
         self.full_cmds += &cmd.cmd_str();
-        let (ignore_error, cmd) = cmd.gen_command();
+        let (ignore_error, mut cmd) = cmd.gen_command();
         if ignore_error {
             if self.cmds.is_empty() {
                 // first command in the pipe
                 self.ignore_error = true;
             } else {
-                warn!("Builtin \"ignore\" command at wrong position");
+                // later commands in the pipe, only ignoring their own errors
+                cmd.ignore_error = true;
             }
         }
         self.cmds.push(Some(cmd));
@@ -184,47 +1462,173 @@ impl Cmds {
         &self.full_cmds
     }
 
-    fn spawn(&mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChildren> {
+    fn spec(&self, current_dir: &Path) -> Option<CmdSpec> {
+        (self.stages.len() == self.cmds.len()).then(|| CmdSpec {
+            stages: self.stages.clone(),
+            current_dir: current_dir.to_path_buf(),
+        })
+    }
+
+    // spawns the pipeline, with `stdin` as the stdin of the first command if any
+    fn spawn(
+        &mut self,
+        current_dir: &mut PathBuf,
+        with_output: bool,
+        stdin: Option<PipeReader>,
+    ) -> Result<CmdChildren> {
         if debug_enabled() {
             debug!("Running {} ...", self.get_full_cmds());
         }
 
+        let process = Process::current();
+        if let Some(confirm) = process.as_ref().and_then(|p| p.confirm.as_ref()) {
+            let matched = self.cmds.iter().flatten().any(|cmd| {
+                let ignored = cmd.args.iter().take_while(|arg| *arg == IGNORE_CMD).count();
+                cmd.std_cmd.is_some() && confirm.matches(&cmd.args[ignored..])
+            });
+            if matched {
+                confirm.ask(&self.full_cmds)?;
+            }
+        }
+
         // spawning all the sub-processes
         let mut children: Vec<Result<CmdChild>> = Vec::new();
         let len = self.cmds.len();
-        let mut prev_pipe_in = None;
+        let mut prev_pipe_in = stdin;
+        #[cfg(unix)]
+        let pipe_size = process.as_ref().and_then(|p| p.pipe_size);
+        let count_bytes = process.is_some_and(|p| p.count_pipe_bytes);
+        check_pipeline_len(len, with_output, count_bytes)?;
+        let mut counters = vec![];
+        let full_cmds = &self.full_cmds;
         for (i, cmd_opt) in self.cmds.iter_mut().enumerate() {
             let mut cmd = cmd_opt.take().unwrap();
-            if i != len - 1 {
+            let setup = if i != len - 1 {
                 // not the last, update redirects
-                let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-                cmd.setup_redirects(&mut prev_pipe_in, Some(pipe_writer), with_output)?;
-                prev_pipe_in = Some(pipe_reader);
+                os_pipe::pipe().and_then(|(mut pipe_reader, pipe_writer)| {
+                    #[cfg(unix)]
+                    if let Some(size) = pipe_size {
+                        sys::set_pipe_size(pipe_writer.as_raw_fd(), size)?;
+                    }
+                    cmd.setup_redirects(
+                        &mut prev_pipe_in,
+                        Some(pipe_writer),
+                        with_output,
+                        current_dir,
+                    )?;
+                    if count_bytes {
+                        let (relay_reader, counter) = PipeCounter::relay(i, pipe_reader)?;
+                        pipe_reader = relay_reader;
+                        counters.push(counter);
+                    }
+                    prev_pipe_in = Some(pipe_reader);
+                    Ok(())
+                })
             } else {
-                cmd.setup_redirects(&mut prev_pipe_in, None, with_output)?;
+                cmd.setup_redirects(&mut prev_pipe_in, None, with_output, current_dir)
+            };
+            if let Err(e) = setup {
+                // the pipeline is not returned, so the stages spawned so far can't be waited for,
+                // and the pipe they write to is closed for them to stop
+                drop(prev_pipe_in.take());
+                cmd.stdin_redirect = None;
+                CmdChild::abort_all(children);
+                return Err(e);
             }
-            let child = cmd.spawn(current_dir, with_output);
+            let record = (i == len - 1).then(|| cmd.execution_record(current_dir));
+            let argv = cmd.argv();
+            let ignore_error = cmd.ignore_error;
+            let stdout_relay = cmd.stdout_relay.take();
+            let log_relays = std::mem::take(&mut cmd.log_relays);
+            let child = cmd
+                .spawn(current_dir, with_output, self.last_succeeded, &self.exports)
+                .map(|child| match record {
+                    Some(record) => child.with_record(record),
+                    None => child,
+                })
+                .map(|child| {
+                    child
+                        .in_pipeline(i, full_cmds, argv)
+                        .ignore_error(ignore_error)
+                        .with_stdout_relay(stdout_relay)
+                        .with_log_relays(log_relays)
+                });
             children.push(child);
         }
 
-        Ok(CmdChildren::new(children, self.ignore_error))
+        let stats = StatsCollector::new(len, count_bytes, counters);
+        Ok(CmdChildren::new(children, self.ignore_error).with_stats(stats))
     }
 
     fn spawn_with_output(&mut self, current_dir: &mut PathBuf) -> Result<FunChildren> {
-        self.spawn(current_dir, true)
+        self.spawn(current_dir, true, None)
             .map(CmdChildren::into_fun_children)
     }
 
     fn run_cmd(&mut self, current_dir: &mut PathBuf) -> CmdResult {
-        self.spawn(current_dir, false)?.wait()
+        self.spawn(current_dir, false, None)?.wait_result().0
     }
 
     fn run_fun(&mut self, current_dir: &mut PathBuf) -> FunResult {
         self.spawn_with_output(current_dir)?.wait_with_output()
     }
+
+    fn run_with_stdin(&mut self, current_dir: &mut PathBuf, input: &[u8]) -> Result<Vec<u8>> {
+        // the input is fed on a thread while reading the output, so that neither the children
+        // nor this thread block on a full pipe
+        let input = std::iter::once(input.to_vec());
+        let mut children = self.feed_stdin_iter(current_dir, input, StdinOptions::default())?;
+        let mut output = vec![];
+        children.wait_to_writer(&mut output)?;
+        Ok(output)
+    }
+
+    // spawns the commands with the items of `iter` written to their stdin on a thread
+    fn feed_stdin_iter<I>(
+        &mut self,
+        current_dir: &mut PathBuf,
+        iter: I,
+        options: StdinOptions,
+    ) -> Result<FunChildren>
+    where
+        I: Iterator + Send + 'static,
+        I::Item: AsRef<[u8]>,
+    {
+        let (children, writer) = self.spawn_with_stdin(current_dir, &options)?;
+        let full_cmds = self.get_full_cmds().to_string();
+        thread::Builder::new()
+            .name("cmd_lib stdin".into())
+            .spawn(move || stdin::feed(writer, iter, &options, &full_cmds))?;
+        Ok(children)
+    }
+
+    fn spawn_with_stdin(
+        &mut self,
+        current_dir: &mut PathBuf,
+        options: &StdinOptions,
+    ) -> Result<(FunChildren, StdinWriter)> {
+        let (stdin, writer) = os_pipe::pipe()?;
+        let children = self.spawn(current_dir, true, Some(stdin))?;
+        Ok((
+            children.into_fun_children(),
+            StdinWriter::new(writer, options),
+        ))
+    }
+
+    // spawning error contains no command information, attach it here
+    fn with_spawn_context<T>(&self, ret: Result<T>) -> Result<T> {
+        match ret {
+            Err(e) if !self.ignore_error => Err(error::with_context(
+                e,
+                format!("Spawning {} failed", self.get_full_cmds()),
+            )),
+            ret => ret,
+        }
+    }
 }
 
-#[doc(hidden)]
+/// Redirect of a command, the `bool` for files is true when appending
+#[derive(Clone)]
 pub enum Redirect {
     FileToStdin(PathBuf),
     StdoutToStderr,
@@ -260,9 +1664,12 @@ impl fmt::Debug for Redirect {
 pub struct Cmd {
     // for parsing
     in_cmd_map: bool,
+    // the custom command found when the name was added, as the registry may be gone when spawned
+    cmd_fn: Option<FnFun>,
     args: Vec<OsString>,
     vars: HashMap<String, String>,
     redirects: Vec<Redirect>,
+    callback: Option<FnCallback>,
 
     // for running
     std_cmd: Option<Command>,
@@ -271,27 +1678,52 @@ pub struct Cmd {
     stderr_redirect: Option<CmdOut>,
     stdout_logging: Option<PipeReader>,
     stderr_logging: Option<PipeReader>,
+    stdout_relay: Option<JoinHandle<CmdResult>>,
+    log_relays: Vec<JoinHandle<Result<()>>>,
+    ignore_error: bool,
+    hardened: Option<HardenedOperands>,
+}
+
+// where to insert `--` for `harden_operands()`, and the option-like operands to guard with
+// `Process::option_guard()` if it's not inserted after all
+struct HardenedOperands {
+    at: usize,
+    unguarded: Vec<usize>,
 }
 
 impl Default for Cmd {
     fn default() -> Self {
         Cmd {
             in_cmd_map: true,
+            cmd_fn: None,
             args: vec![],
             vars: HashMap::new(),
             redirects: vec![],
+            callback: None,
             std_cmd: None,
             stdin_redirect: None,
             stdout_redirect: None,
             stderr_redirect: None,
             stdout_logging: None,
             stderr_logging: None,
+            stdout_relay: None,
+            log_relays: vec![],
+            ignore_error: false,
+            hardened: None,
         }
     }
 }
 
 impl Cmd {
     pub fn add_arg(mut self, arg: OsString) -> Self {
+        // a literal option after the operands would be taken as an operand after `--`
+        if self.hardened.is_some() && arg.len() > 1 && arg.to_string_lossy().starts_with('-') {
+            self.unharden_operands();
+        }
+        self.push_arg(arg)
+    }
+
+    fn push_arg(mut self, arg: OsString) -> Self {
         let arg_str = arg.to_string_lossy().to_string();
         if arg_str != IGNORE_CMD && !self.args.iter().any(|cmd| *cmd != IGNORE_CMD) {
             let v: Vec<&str> = arg_str.split('=').collect();
@@ -299,24 +1731,291 @@ impl Cmd {
                 self.vars.insert(v[0].into(), v[1].into());
                 return self;
             }
-            self.in_cmd_map = CMD_MAP.lock().unwrap().contains_key(&arg);
+            self.cmd_fn = registry::find_cmd(&arg);
+            self.in_cmd_map = self.cmd_fn.is_some();
         }
         self.args.push(arg);
         self
     }
 
+    pub fn add_interpolated_arg(mut self, arg: OsString) -> Self {
+        if self.hardened.is_none() && self.hardens_operands() {
+            self.hardened = Some(HardenedOperands {
+                at: self.args.len(),
+                unguarded: vec![],
+            });
+        }
+        let value = arg.to_string_lossy();
+        // a lone `-` is the usual operand for stdin, not an option
+        let like_option = value != "-"
+            && value.strip_prefix('-').is_some_and(|rest| {
+                // not a negative number
+                !rest.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+                    || value.parse::<f64>().is_err()
+            });
+        let guarded = like_option
+            && !self.in_cmd_map
+            && self.args.iter().any(|cmd| *cmd != IGNORE_CMD)
+            && !self.args.iter().any(|arg| arg == "--");
+        if !guarded {
+            return self.push_arg(arg);
+        }
+        if let Some(ref mut hardened) = self.hardened {
+            hardened.unguarded.push(self.args.len());
+            return self.push_arg(arg);
+        }
+        let arg = self.guard_option(arg);
+        self.push_arg(arg)
+    }
+
+    fn guard_option(&self, arg: OsString) -> OsString {
+        match Process::current().map_or(OptionGuard::default(), |p| p.option_guard) {
+            OptionGuard::Off => arg,
+            OptionGuard::Warn => {
+                warn!(
+                    "Interpolated argument {:?} of {:?} starts with '-', and may be taken as an option",
+                    arg,
+                    self.arg0()
+                );
+                arg
+            }
+            OptionGuard::Prefix => {
+                let mut path = OsString::from("./");
+                path.push(&arg);
+                path
+            }
+        }
+    }
+
     pub fn add_args(mut self, args: Vec<OsString>) -> Self {
+        if self.hardened.is_none() && !args.is_empty() && self.hardens_operands() {
+            self.hardened = Some(HardenedOperands {
+                at: self.args.len(),
+                unguarded: vec![],
+            });
+        }
         for arg in args {
-            self = self.add_arg(arg);
+            self = self.push_arg(arg);
         }
         self
     }
 
+    // whether `--` is to be inserted before the interpolated operands added from now on
+    fn hardens_operands(&self) -> bool {
+        if self.in_cmd_map || self.callback.is_some() || !harden_operands_enabled() {
+            return false;
+        }
+        let ignored = self
+            .args
+            .iter()
+            .take_while(|arg| *arg == IGNORE_CMD)
+            .count();
+        let words = &self.args[ignored..];
+        !words.is_empty()
+            && !words.iter().any(|arg| arg == "--")
+            && HARDENED_CMDS
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|cmd| words.starts_with(cmd))
+    }
+
+    fn unharden_operands(&mut self) {
+        if let Some(hardened) = self.hardened.take() {
+            for i in hardened.unguarded {
+                let arg = std::mem::take(&mut self.args[i]);
+                self.args[i] = self.guard_option(arg);
+            }
+        }
+    }
+
+    fn insert_operands_separator(&mut self) {
+        if let Some(hardened) = self.hardened.take() {
+            self.args.insert(hardened.at, "--".into());
+            if debug_enabled() {
+                debug!("Inserted -- before the operands of {:?}", self.args);
+            }
+        }
+    }
+
     pub fn add_redirect(mut self, redirect: Redirect) -> Self {
         self.redirects.push(redirect);
         self
     }
 
+    pub fn add_callback<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut CmdEnv) -> CmdResult + Send + 'static,
+    {
+        self.args.push("%{...}".into());
+        self.callback = Some(Box::new(f));
+        self
+    }
+
+    fn resolve_alias(&mut self) {
+        if self.callback.is_some() {
+            return;
+        }
+        let ignored = self
+            .args
+            .iter()
+            .take_while(|arg| *arg == IGNORE_CMD)
+            .count();
+        let name = match self.args.get(ignored) {
+            Some(name) => name,
+            None => return,
+        };
+        let process = Process::current();
+        let search_path = self.search_path();
+        let exists = |program: &OsStr| {
+            registry::find_cmd(program).is_some()
+                || resolve_program(
+                    &process
+                        .as_ref()
+                        .map_or_else(|| program.into(), |p| p.program(&program.into())),
+                    Path::new(""),
+                    search_path.as_deref(),
+                )
+                .is_some()
+        };
+        match alias::resolve_alias(name, exists) {
+            None => {}
+            Some(Ok(argv)) => {
+                self.args.splice(ignored..=ignored, argv);
+                self.find_cmd();
+            }
+            Some(Err(e)) => {
+                // fails when run, like a command not found
+                self.callback = Some(Box::new(move |_| Err(e)));
+                self.in_cmd_map = true;
+            }
+        }
+    }
+
+    // asks the handler of `on_command_not_found()` about a program not found when spawning,
+    // searched in the working directory and `PATH` the program would get
+    fn handle_not_found(mut self, current_dir: &Path) -> Self {
+        let process = Process::current();
+        if self.callback.is_some()
+            || self.in_cmd_map
+            || self.arg0() == CD_CMD
+            || process.as_ref().is_some_and(|p| p.ssh.is_some())
+        {
+            return self;
+        }
+        let handler = match not_found::handler(process.as_ref().and_then(|p| p.not_found.clone())) {
+            Some(handler) => handler,
+            None => return self,
+        };
+        let ignored = self
+            .args
+            .iter()
+            .take_while(|arg| *arg == IGNORE_CMD)
+            .count();
+        let mut replaced = false;
+        for attempt in 0.. {
+            let name = self.arg0();
+            let program = process
+                .as_ref()
+                .map_or_else(|| name.clone(), |p| p.program(&name));
+            let search_path = self.search_path();
+            if self.in_cmd_map
+                || resolve_program(&program, current_dir, search_path.as_deref()).is_some()
+            {
+                break;
+            }
+            match not_found::fallback(&handler, &name.to_string_lossy(), attempt) {
+                Some(Fallback::Retry) => {}
+                Some(Fallback::Command(argv)) if !argv.is_empty() => {
+                    self.args
+                        .splice(ignored..=ignored, argv.into_iter().map(OsString::from));
+                    self.find_cmd();
+                    replaced = true;
+                }
+                Some(Fallback::Error(msg)) => {
+                    // fails when run, like a command not found
+                    self.callback =
+                        Some(Box::new(move |_| Err(Error::new(ErrorKind::NotFound, msg))));
+                    self.in_cmd_map = true;
+                    return self;
+                }
+                _ => break,
+            }
+        }
+        if replaced {
+            // the command to spawn for the new command line
+            self.std_cmd = None;
+            self = self.gen_command().1;
+        }
+        self
+    }
+
+    // the `PATH` the program is searched in, which may be set for this command only
+    fn search_path(&self) -> Option<OsString> {
+        if let Some(path) = self.vars.get("PATH") {
+            return Some(path.into());
+        }
+        Process::current()
+            .and_then(|p| p.env.as_ref().and_then(|env| env.get("PATH")))
+            .map(OsString::from)
+    }
+
+    fn run_hooks(&mut self) {
+        struct Restore;
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                IN_HOOKS.with(|in_hooks| in_hooks.set(false));
+            }
+        }
+
+        if self.callback.is_some() || self.arg0() == CD_CMD || IN_HOOKS.with(Cell::get) {
+            return;
+        }
+        // not locked while running the hooks, for the ones running commands or registering hooks
+        let hooks = CMD_HOOKS.lock().unwrap().clone();
+        if hooks.is_empty() {
+            return;
+        }
+        IN_HOOKS.with(|in_hooks| in_hooks.set(true));
+        let _restore = Restore;
+        let ignored = self
+            .args
+            .iter()
+            .take_while(|arg| *arg == IGNORE_CMD)
+            .count();
+        let mut parsed = ParsedCommand {
+            args: self.args.split_off(ignored),
+            vars: std::mem::take(&mut self.vars),
+            redirects: std::mem::take(&mut self.redirects),
+        };
+        for hook in hooks {
+            // a hook which panicked before is still called
+            let mut hook = hook.lock().unwrap_or_else(PoisonError::into_inner);
+            hook(&mut parsed);
+        }
+        self.args.append(&mut parsed.args);
+        self.vars = parsed.vars;
+        self.redirects = parsed.redirects;
+        self.find_cmd();
+    }
+
+    // looks up the custom command named by `arg0()` again, after it is rewritten
+    fn find_cmd(&mut self) {
+        self.cmd_fn = registry::find_cmd(&self.arg0());
+        self.in_cmd_map = self.cmd_fn.is_some();
+    }
+
+    fn execution_record(&self, current_dir: &Path) -> ExecutionRecord {
+        let argv = self.argv();
+        let current_dir = if current_dir.as_os_str().is_empty() {
+            std::env::current_dir().unwrap_or_default()
+        } else {
+            current_dir.to_path_buf()
+        };
+        let env = self.vars.clone().into_iter().collect();
+        ExecutionRecord::new(argv, current_dir, env)
+    }
+
     fn arg0(&self) -> OsString {
         let mut args = self.args.iter().skip_while(|cmd| *cmd == IGNORE_CMD);
         if let Some(arg) = args.next() {
@@ -340,9 +2039,27 @@ impl Cmd {
         if !extra.is_empty() {
             ret += &format!("({})", extra);
         }
+        let max_len = max_cmd_len();
+        if ret.len() > max_len {
+            let mut len = max_len;
+            while !ret.is_char_boundary(len) {
+                len -= 1;
+            }
+            let elided = ret.len() - len;
+            ret.truncate(len);
+            ret += &format!("...({} bytes elided)", elided);
+        }
         ret
     }
 
+    fn argv(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .skip_while(|cmd| *cmd == IGNORE_CMD)
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
     fn gen_command(mut self) -> (bool, Self) {
         let args: Vec<OsString> = self
             .args
@@ -350,18 +2067,86 @@ impl Cmd {
             .skip_while(|cmd| *cmd == IGNORE_CMD)
             .map(|s| s.into())
             .collect();
+        if !self.in_cmd_map && self.arg0() != CD_CMD {
+            if let Some(executor) = Process::current().and_then(|p| p.executor.clone()) {
+                // run like a custom command, so the executor gets the same environment
+                self.callback = Some(Box::new(move |env| executor.execute(env)));
+                self.in_cmd_map = true;
+            }
+        }
         if !self.in_cmd_map {
-            let mut cmd = Command::new(&args[0]);
-            cmd.args(&args[1..]);
-            for (k, v) in self.vars.iter() {
-                cmd.env(k, v);
+            let process = Process::current();
+            let (mut argv, remote_vars) = match process
+                .as_ref()
+                .and_then(|p| p.ssh.as_ref().map(|s| (p, s)))
+            {
+                Some((p, (host, opts))) => {
+                    // the remote shell sets the variables and splits the command line again,
+                    // and finds the program itself, as the local overrides are not there
+                    let mut vars: Vec<OsString> = self
+                        .vars
+                        .iter()
+                        .map(|(k, v)| {
+                            let mut var = OsString::from(format!("{}=", k));
+                            var.push(shell_quote(v.as_ref()));
+                            var
+                        })
+                        .collect();
+                    vars.sort();
+                    let words = vars
+                        .into_iter()
+                        .chain(args.iter().map(|arg| shell_quote(arg)));
+                    let mut remote = OsString::new();
+                    for (i, word) in words.enumerate() {
+                        if i > 0 {
+                            remote.push(" ");
+                        }
+                        remote.push(word);
+                    }
+                    let mut argv = vec![p.program(&"ssh".into())];
+                    argv.extend(opts.iter().cloned());
+                    argv.extend([host.clone(), "--".into(), remote]);
+                    (argv, true)
+                }
+                None => {
+                    let program = process
+                        .as_ref()
+                        .map_or_else(|| args[0].clone(), |p| p.program(&args[0]));
+                    let mut argv = vec![program];
+                    argv.extend_from_slice(&args[1..]);
+                    (argv, false)
+                }
+            };
+            let launcher = match process.as_ref().and_then(|p| p.launcher.clone()) {
+                Some(launcher) => Some(launcher),
+                None => LAUNCHER.lock().unwrap().clone(),
+            };
+            if let Some(launcher) = launcher {
+                argv = launcher(argv);
+            }
+            let mut cmd = Command::new(argv.first().cloned().unwrap_or_default());
+            cmd.args(argv.iter().skip(1));
+            if let Some(env) = process.as_ref().and_then(|p| p.env.as_ref()) {
+                env.apply(&mut cmd);
+            }
+            if !remote_vars {
+                for (k, v) in self.vars.iter() {
+                    cmd.env(k, v);
+                }
             }
             self.std_cmd = Some(cmd);
         }
         (self.args.len() > args.len(), self)
     }
 
-    fn spawn(mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChild> {
+    fn spawn(
+        mut self,
+        current_dir: &mut PathBuf,
+        with_output: bool,
+        last_succeeded: bool,
+        exports: &Exports,
+    ) -> Result<CmdChild> {
+        self = self.handle_not_found(current_dir);
         let arg0 = self.arg0();
         if arg0 == CD_CMD {
             let child = self.run_cd_cmd(current_dir)?;
@@ -374,6 +2159,17 @@ impl Cmd {
         } else if self.in_cmd_map {
             let cmd_str = self.cmd_str();
             let pipe_out = self.stdout_logging.is_none();
+            let internal_cmd: FnCallback = match (self.callback.take(), self.cmd_fn) {
+                (Some(callback), _) => callback,
+                (None, Some(cmd_fn)) => Box::new(cmd_fn),
+                (None, None) => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("{:?}: custom command not found", arg0),
+                    ))
+                }
+            };
+            let typed_result = TypedResult::default();
             let mut env = CmdEnv {
                 args: self
                     .args
@@ -382,11 +2178,15 @@ impl Cmd {
                     .map(|s| s.to_string_lossy().to_string())
                     .collect(),
                 vars: self.vars,
+                env: Process::current().and_then(|p| p.env.clone()),
                 current_dir: if current_dir.as_os_str().is_empty() {
                     std::env::current_dir()?
                 } else {
                     current_dir.clone()
                 },
+                last_succeeded,
+                exports: exports.clone(),
+                typed_result: typed_result.clone(),
                 stdin: if let Some(redirect_in) = self.stdin_redirect.take() {
                     redirect_in
                 } else {
@@ -404,15 +2204,21 @@ impl Cmd {
                 },
             };
 
-            let internal_cmd = CMD_MAP.lock().unwrap()[&arg0];
             if pipe_out || with_output {
-                let handle = thread::Builder::new().spawn(move || internal_cmd(&mut env))?;
+                let in_hooks = IN_HOOKS.with(Cell::get);
+                let in_handler = not_found::in_handler();
+                let handle = thread::Builder::new().spawn(move || {
+                    IN_HOOKS.with(|hooks| hooks.set(in_hooks));
+                    not_found::set_in_handler(in_handler);
+                    internal_cmd(&mut env)
+                })?;
                 Ok(CmdChild::new(
                     CmdChildHandle::Thread(handle),
                     cmd_str,
                     self.stdout_logging,
                     self.stderr_logging,
-                ))
+                )
+                .with_typed_result(typed_result))
             } else {
                 let child = internal_cmd(&mut env)?;
                 Ok(CmdChild::new(
@@ -420,13 +2226,21 @@ impl Cmd {
                     cmd_str,
                     self.stdout_logging,
                     self.stderr_logging,
-                ))
+                )
+                .with_typed_result(typed_result))
             }
         } else {
             let mut cmd = self.std_cmd.take().unwrap();
 
-            // setup current_dir
-            if !current_dir.as_os_str().is_empty() {
+            // set the exported variables, unless set for this command
+            for (k, v) in exports.lock().unwrap().iter() {
+                if !self.vars.contains_key(k) {
+                    cmd.env(k, v);
+                }
+            }
+
+            // setup current_dir, unless set by `spawn_command()`
+            if !current_dir.as_os_str().is_empty() && cmd.get_current_dir().is_none() {
                 cmd.current_dir(current_dir.clone());
             }
 
@@ -445,8 +2259,14 @@ impl Cmd {
                 cmd.stderr(redirect_err);
             }
 
-            // spawning process
-            let child = cmd.spawn()?;
+            if let Some(process) = Process::current() {
+                process.setup_command(&mut cmd);
+            }
+
+            // spawning process, the same way no matter whether the program name is interpolated
+            let child = cmd.spawn().map_err(|e| {
+                error::with_context(e, format!("Spawning {} failed", self.cmd_str()))
+            })?;
             Ok(CmdChild::new(
                 CmdChildHandle::Proc(child),
                 self.cmd_str(),
@@ -456,6 +2276,63 @@ impl Cmd {
         }
     }
 
+    fn exec(mut self, current_dir: &Path) -> Error {
+        let mut cmd = match self.std_cmd.take() {
+            Some(cmd) => cmd,
+            None => {
+                return Error::new(
+                    ErrorKind::InvalidInput,
+                    "only external commands can be executed",
+                )
+            }
+        };
+        // the standard streams are inherited, unless redirected
+        if let Err(e) = self.open_redirects(current_dir) {
+            return e;
+        }
+        if self.stdout_relay.is_some() {
+            return Error::new(
+                ErrorKind::InvalidInput,
+                "output can't be compressed for a command replacing this process",
+            );
+        }
+        if !current_dir.as_os_str().is_empty() {
+            cmd.current_dir(current_dir);
+        }
+        if let Some(redirect_in) = self.stdin_redirect.take() {
+            cmd.stdin(redirect_in);
+        }
+        if let Some(redirect_out) = self.stdout_redirect.take() {
+            cmd.stdout(redirect_out);
+        }
+        if let Some(redirect_err) = self.stderr_redirect.take() {
+            cmd.stderr(redirect_err);
+        }
+        if let Some(process) = Process::current() {
+            process.setup_command(&mut cmd);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            // exec() changes the directory, stdio and signals of this process before replacing
+            // it, so rule out the usual failure first, and restore them on failure
+            let search_path = self.search_path();
+            if resolve_program(cmd.get_program(), current_dir, search_path.as_deref()).is_none() {
+                return Error::new(ErrorKind::NotFound, "program not found");
+            }
+            let _restore = sys::ExecState::save();
+            cmd.exec()
+        }
+        #[cfg(not(unix))]
+        {
+            match cmd.status() {
+                Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+                Err(e) => e,
+            }
+        }
+    }
+
     fn run_cd_cmd(&self, current_dir: &mut PathBuf) -> CmdResult {
         if self.args.len() == 1 {
             return Err(Error::new(ErrorKind::Other, "cd: missing directory"));
@@ -464,7 +2341,10 @@ impl Cmd {
             return Err(Error::new(ErrorKind::Other, err_msg));
         }
 
-        let dir = PathBuf::from(&self.args[1]);
+        let mut dir = PathBuf::from(&self.args[1]);
+        if dir.is_relative() && !current_dir.as_os_str().is_empty() {
+            dir = current_dir.join(dir);
+        }
         if !dir.is_dir() {
             let err_msg = format!("cd {}: No such file or directory", dir.display());
             return Err(Error::new(ErrorKind::Other, err_msg));
@@ -493,10 +2373,13 @@ impl Cmd {
         pipe_in: &mut Option<PipeReader>,
         pipe_out: Option<PipeWriter>,
         with_output: bool,
+        current_dir: &Path,
     ) -> CmdResult {
         // set up stdin pipe
         if let Some(pipe) = pipe_in.take() {
             self.stdin_redirect = Some(CmdIn::Pipe(pipe));
+        } else if Process::null_stdin() {
+            self.stdin_redirect = Some(CmdIn::Null);
         }
         // set up stdout pipe
         if let Some(pipe) = pipe_out {
@@ -505,19 +2388,51 @@ impl Cmd {
             let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
             self.stdout_redirect = Some(CmdOut::Pipe(pipe_writer));
             self.stdout_logging = Some(pipe_reader);
+        } else if let Some(log) = Process::current().and_then(|p| p.stdout_log.clone()) {
+            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+            self.stdout_redirect = Some(CmdOut::Pipe(pipe_writer));
+            self.log_relays.push(LogFile::relay(log, pipe_reader)?);
+        }
+        // set up stderr pipe, or inherit it for interactive commands
+        if let Some(log) = Process::current().and_then(|p| p.stderr_log.clone()) {
+            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+            self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
+            self.log_relays.push(LogFile::relay(log, pipe_reader)?);
+        } else if !Process::interactive_enabled() {
+            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+            self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
+            self.stderr_logging = Some(pipe_reader);
         }
-        // set up stderr pipe
-        let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
-        self.stderr_redirect = Some(CmdOut::Pipe(pipe_writer));
-        self.stderr_logging = Some(pipe_reader);
+        self.open_redirects(current_dir)
+    }
+
+    // writes to `file` through a compressing thread with `Process::compress_output()`
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn compress_file(file: File, relay: &mut Option<JoinHandle<CmdResult>>) -> Result<CmdOut> {
+        match Process::current().and_then(|p| p.compress_output) {
+            Some(codec) => {
+                let (pipe_writer, handle) = compress::relay(codec, file)?;
+                *relay = Some(handle);
+                Ok(CmdOut::Pipe(pipe_writer))
+            }
+            None => Ok(CmdOut::File(file)),
+        }
+    }
+
+    #[cfg(not(any(feature = "gzip", feature = "zstd")))]
+    fn compress_file(file: File, _relay: &mut Option<JoinHandle<CmdResult>>) -> Result<CmdOut> {
+        Ok(CmdOut::File(file))
+    }
 
+    // opens the files, with relative paths resolved against `current_dir`
+    fn open_redirects(&mut self, current_dir: &Path) -> CmdResult {
         for redirect in self.redirects.iter() {
             match redirect {
                 Redirect::FileToStdin(path) => {
                     self.stdin_redirect = Some(if path == Path::new("/dev/null") {
                         CmdIn::Null
                     } else {
-                        CmdIn::File(Self::open_file(path, true, false)?)
+                        CmdIn::File(Self::open_file(&current_dir.join(path), true, false)?)
                     });
                 }
                 Redirect::StdoutToStderr => {
@@ -538,14 +2453,15 @@ impl Cmd {
                     self.stdout_redirect = Some(if path == Path::new("/dev/null") {
                         CmdOut::Null
                     } else {
-                        CmdOut::File(Self::open_file(path, false, *append)?)
+                        let file = Self::open_file(&current_dir.join(path), false, *append)?;
+                        Self::compress_file(file, &mut self.stdout_relay)?
                     });
                 }
                 Redirect::StderrToFile(path, append) => {
                     self.stderr_redirect = Some(if path == Path::new("/dev/null") {
                         CmdOut::Null
                     } else {
-                        CmdOut::File(Self::open_file(path, false, *append)?)
+                        CmdOut::File(Self::open_file(&current_dir.join(path), false, *append)?)
                     });
                 }
             }
@@ -554,6 +2470,113 @@ impl Cmd {
     }
 }
 
+// create an unlinked temp file, which is removed once it is closed
+#[cfg(feature = "mmap")]
+pub(crate) fn temp_file() -> Result<File> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static TEMP_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "cmd_lib_{}_{}",
+        std::process::id(),
+        TEMP_FILE_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+thread_local! {
+    // the first error of the arithmetic expanded in the arguments of the command being built
+    static ARITH_ERROR: RefCell<Option<Error>> = const { RefCell::new(None) };
+}
+
+fn arith_error(msg: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} in arithmetic expression", msg),
+    )
+}
+
+#[doc(hidden)]
+pub fn arith_result(value: Result<i64>) -> OsString {
+    match value {
+        Ok(value) => value.to_string().into(),
+        Err(e) => {
+            // failing the command when it runs, see `Cmds::pipe()`
+            ARITH_ERROR.with(|error| {
+                error.borrow_mut().get_or_insert(e);
+            });
+            OsString::new()
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn arith_var<T: ?Sized + ToString>(value: &T) -> Result<i64> {
+    let s = value.to_string();
+    s.trim()
+        .parse()
+        .map_err(|_| arith_error(format!("invalid integer {:?}", s)))
+}
+
+#[doc(hidden)]
+pub fn arith_div(lhs: i64, rhs: i64) -> Result<i64> {
+    match lhs.checked_div(rhs) {
+        Some(value) => Ok(value),
+        None if rhs == 0 => Err(arith_error("division by zero".into())),
+        // i64::MIN / -1
+        None => Ok(lhs.wrapping_div(rhs)),
+    }
+}
+
+#[doc(hidden)]
+pub fn arith_rem(lhs: i64, rhs: i64) -> Result<i64> {
+    match lhs.checked_rem(rhs) {
+        Some(value) => Ok(value),
+        None if rhs == 0 => Err(arith_error("division by zero".into())),
+        None => Ok(lhs.wrapping_rem(rhs)),
+    }
+}
+
+#[doc(hidden)]
+pub fn arith_pow(base: i64, exponent: i64) -> Result<i64> {
+    use std::convert::TryFrom;
+
+    let mut exponent = u64::try_from(exponent)
+        .map_err(|_| arith_error(format!("exponent less than 0: {}", exponent)))?;
+    if let Some(value) = u32::try_from(exponent)
+        .ok()
+        .and_then(|exponent| base.checked_pow(exponent))
+    {
+        return Ok(value);
+    }
+    // wrapping like the other operations, by squaring for the exponents beyond u32
+    let (mut base, mut value) = (base, 1i64);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            value = value.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    Ok(value)
+}
+
+#[doc(hidden)]
+pub fn env_var_indirect<T: ?Sized + ToString>(name: &T) -> OsString {
+    let name = name.to_string();
+    // unset targets and invalid names expand to nothing, like in bash
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return OsString::new();
+    }
+    std::env::var_os(name).unwrap_or_default()
+}
+
 #[doc(hidden)]
 pub trait AsOsStr {
     fn as_os_str(&self) -> OsString;
@@ -595,6 +2618,26 @@ impl fmt::Display for CmdString {
     }
 }
 
+// quotes `s` as a single word for POSIX shells, keeping bytes which are not valid UTF-8
+fn shell_quote(s: &OsStr) -> OsString {
+    let is_plain = |b: &u8| b.is_ascii_alphanumeric() || b"_-+%@:,./".contains(b);
+    let bytes = s.as_encoded_bytes();
+    if !bytes.is_empty() && bytes.iter().all(is_plain) {
+        return s.into();
+    }
+    let mut quoted = vec![b'\''];
+    for &b in bytes {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    // safety: the bytes of `s` are only split around ASCII quotes, and joined with ASCII
+    unsafe { OsString::from_encoded_bytes_unchecked(quoted) }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -657,4 +2700,98 @@ mod tests {
             .run_cmd(&mut current_dir)
             .is_ok());
     }
+
+    #[test]
+    fn test_ignore_errors_log_level() {
+        use log::{Level, Log, Metadata, Record};
+
+        static RECORDS: Mutex<Vec<(Level, String)>> = Mutex::new(vec![]);
+        struct Recorder;
+        impl Log for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &Record) {
+                let line = (record.level(), record.args().to_string());
+                RECORDS.lock().unwrap().push(line);
+            }
+            fn flush(&self) {}
+        }
+        let _ = log::set_logger(&Recorder);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let spawn = |msg: &str| {
+            let script = format!("echo {} >&2; exit 1", msg);
+            let cmd = Cmd::default().add_args(vec!["sh".into(), "-c".into(), script.into()]);
+            Cmds::default()
+                .pipe(cmd)
+                .spawn(&mut PathBuf::new(), false, None)
+                .unwrap()
+        };
+        assert!(spawn("cmd_lib_test_logged").wait().is_err());
+        assert!(spawn("cmd_lib_test_ignored").ignore_errors().wait().is_ok());
+        let level = |msg: &str| {
+            let records = RECORDS.lock().unwrap();
+            records.iter().find(|(_, line)| line == msg).map(|r| r.0)
+        };
+        assert_eq!(level("cmd_lib_test_logged"), Some(Level::Info));
+        assert_eq!(level("cmd_lib_test_ignored"), Some(Level::Debug));
+    }
+
+    #[test]
+    fn test_stdin_auto() {
+        let null_stdin = |is_terminal: fn() -> bool| {
+            let mut null = false;
+            Process::new()
+                .stdin_auto_with(is_terminal)
+                .run(|| null = Process::null_stdin());
+            null
+        };
+        assert!(!null_stdin(|| true));
+        assert!(null_stdin(|| false));
+        assert!(!Process::null_stdin());
+
+        // the stdin of the commands is checked without reading it, which could hang
+        #[cfg(target_os = "linux")]
+        {
+            let readlink = || {
+                let args = vec!["readlink".into(), "/proc/self/fd/0".into()];
+                Cmd::default().add_args(args)
+            };
+            let not_on_terminal = Process::new().stdin_auto_with(|| false).run(|| {
+                Cmds::default()
+                    .pipe(readlink())
+                    .run_fun(&mut PathBuf::new())
+            });
+            assert_eq!(not_on_terminal.unwrap(), "/dev/null");
+            // writing nothing, which could fail on the pipe readlink doesn't read
+            let first = Cmd::default().add_args(vec!["true".into()]);
+            let redirected = Process::new().stdin_auto_with(|| false).run(|| {
+                Cmds::default()
+                    .pipe(first)
+                    .pipe(readlink())
+                    .run_fun(&mut PathBuf::new())
+            });
+            assert!(redirected.unwrap().starts_with("pipe:"));
+        }
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        let quote = |s: &str| shell_quote(s.as_ref());
+        assert_eq!(quote("ls"), "ls");
+        assert_eq!(quote("/tmp/a.txt"), "/tmp/a.txt");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote("$HOME;*"), "'$HOME;*'");
+
+        // bytes which are not valid UTF-8 are kept as they are
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::{OsStrExt, OsStringExt};
+            let quoted = shell_quote(OsStr::from_bytes(b"a\xff b"));
+            assert_eq!(quoted.into_vec(), b"'a\xff b'");
+        }
+    }
 }
@@ -0,0 +1,79 @@
+use crate::child::{CmdChild, CmdChildren, ExecutionRecord, PipelineStats};
+use crate::CmdResult;
+use lazy_static::lazy_static;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref REAPABLES: Mutex<Vec<Weak<Mutex<Reapable>>>> = Mutex::new(vec![]);
+}
+static REAPER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Children registered to the background reaper, owned by `CmdChildren` until it is waited
+pub(crate) struct Reapable {
+    pub(crate) children: Vec<Result<CmdChild>>,
+    pub(crate) ignore_error: bool,
+    pub(crate) started: Instant,
+    pub(crate) result: Option<(CmdResult, Duration)>,
+    pub(crate) record: Option<ExecutionRecord>,
+    pub(crate) stats: Option<PipelineStats>,
+}
+
+/// Enables the background reaper for children spawned by `spawn!` afterwards
+///
+/// A single thread will periodically check unwaited children, and once all of them exit,
+/// it reaps them, drains and closes their stderr pipes, and stashes the result. Calling
+/// `wait()` later will return the stashed result.
+pub fn enable_auto_reap() {
+    if REAPER_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    thread::spawn(|| loop {
+        thread::sleep(REAP_INTERVAL);
+        // not locked while reaping, which may block on draining stderr, so spawning goes on
+        let reapables: Vec<_> = {
+            let mut reapables = REAPABLES.lock().unwrap();
+            reapables.retain(|reapable| reapable.strong_count() > 0);
+            reapables.iter().filter_map(Weak::upgrade).collect()
+        };
+        for reapable in reapables {
+            // the owner is waiting on it, check it next time
+            if let Ok(mut reapable) = reapable.try_lock() {
+                reap(&mut reapable);
+            }
+        }
+    });
+}
+
+pub(crate) fn auto_reap_enabled() -> bool {
+    REAPER_STARTED.load(Ordering::SeqCst)
+}
+
+pub(crate) fn register(reapable: Reapable) -> Arc<Mutex<Reapable>> {
+    let reapable = Arc::new(Mutex::new(reapable));
+    REAPABLES.lock().unwrap().push(Arc::downgrade(&reapable));
+    reapable
+}
+
+fn reap(reapable: &mut Reapable) {
+    if reapable.result.is_some() || reapable.children.is_empty() {
+        return;
+    }
+    let all_exited = reapable.children.iter_mut().all(|child| match child {
+        Ok(child) => child.has_exited(),
+        Err(_) => true,
+    });
+    if all_exited {
+        let children = std::mem::take(&mut reapable.children);
+        let mut children =
+            CmdChildren::new(children, reapable.ignore_error).started_at(reapable.started);
+        reapable.result = Some(children.wait_result());
+        reapable.record = children.take_last_record();
+        reapable.stats = Some(children.stats().clone());
+    }
+}
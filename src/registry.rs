@@ -0,0 +1,156 @@
+use crate::alias::Alias;
+use crate::{CmdEnv, CmdResult};
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+pub(crate) type FnFun = fn(&mut CmdEnv) -> CmdResult;
+
+#[derive(Default)]
+struct Entries {
+    cmds: HashMap<OsString, FnFun>,
+    aliases: HashMap<OsString, Alias>,
+}
+
+/// Custom commands and aliases, looked up by the commands run inside `with_registry()`
+///
+/// The commands exported with `#[export_cmd]` and `use_custom_cmd!`, and the aliases registered
+/// with `alias()` and `alias_first()`, are in the global registry, which is used everywhere.
+/// Components registering commands with clashing names can keep them in their own registries:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let registry = CmdRegistry::new();
+/// registry.register_cmd("deploy", |env| {
+///     let target = env.args().get(1).cloned().unwrap_or_default();
+///     cmd_info!("deploying to $target");
+///     Ok(())
+/// });
+/// registry.alias("k", "kubectl --context staging")?;
+/// with_registry(&registry, || run_cmd!(deploy staging; k get pods))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// A command name is looked up in the registry of the innermost `with_registry()`, then in the
+/// outer ones, then in the global registry, and the program is searched in `PATH` last. The first
+/// registry with either a command or an alias of that name decides, so a command in a scoped
+/// registry also hides a global alias of the same name, and the command an alias expands to is
+/// looked up again from the innermost registry. Registries are cheap to clone, with the clones
+/// sharing the same commands and aliases.
+#[derive(Clone, Default)]
+pub struct CmdRegistry {
+    entries: Arc<Mutex<Entries>>,
+}
+
+lazy_static! {
+    static ref GLOBAL: CmdRegistry = CmdRegistry::new();
+}
+
+thread_local! {
+    static SCOPED: RefCell<Vec<CmdRegistry>> = const { RefCell::new(vec![]) };
+}
+
+impl CmdRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the global registry, used outside of `with_registry()` too
+    pub fn global() -> Self {
+        GLOBAL.clone()
+    }
+
+    /// Registers `func` as the command `name`, replacing the previous one
+    pub fn register_cmd(&self, name: &str, func: fn(&mut CmdEnv) -> CmdResult) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.cmds.insert(OsString::from(name), func);
+    }
+
+    /// Registers `name` as an alias for the command line in `template`, like `alias()`
+    pub fn alias(&self, name: &str, template: &str) -> Result<()> {
+        let alias = Alias::template(name, template)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.aliases.insert(OsString::from(name), alias);
+        Ok(())
+    }
+
+    /// Registers `name` as an alias for the first of `alternatives` which exists, like
+    /// `alias_first()`
+    pub fn alias_first<I, S>(&self, name: &str, alternatives: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let alias = Alias::first(alternatives);
+        let mut entries = self.entries.lock().unwrap();
+        entries.aliases.insert(OsString::from(name), alias);
+    }
+}
+
+/// Runs `f`, with the command names looked up in `registry` before the global one
+///
+/// See `CmdRegistry` for the lookup order. It applies to the commands parsed and spawned by `f`
+/// on the current thread, and calls can be nested.
+pub fn with_registry<T>(registry: &CmdRegistry, f: impl FnOnce() -> T) -> T {
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SCOPED.with(|scoped| scoped.borrow_mut().pop());
+        }
+    }
+
+    SCOPED.with(|scoped| scoped.borrow_mut().push(registry.clone()));
+    let _restore = Restore;
+    f()
+}
+
+// the registries of `with_registry()` on the current thread, outermost first, for carrying them
+// to another one with `with_scoped()`
+pub(crate) fn scoped() -> Vec<CmdRegistry> {
+    SCOPED.with(|scoped| scoped.borrow().clone())
+}
+
+// runs `f` with the registries returned by `scoped()` in scope, like nested `with_registry()`
+pub(crate) fn with_scoped<T>(registries: Vec<CmdRegistry>, f: impl FnOnce() -> T) -> T {
+    struct Restore(Vec<CmdRegistry>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = std::mem::take(&mut self.0);
+            SCOPED.with(|scoped| *scoped.borrow_mut() = prev);
+        }
+    }
+
+    let prev = SCOPED.with(|scoped| scoped.replace(registries));
+    let _restore = Restore(prev);
+    f()
+}
+
+// the first entry of `name` in the scoped registries, innermost first, then the global one
+fn lookup<T>(name: &OsStr, f: impl Fn(&Entries) -> Option<T>) -> Option<T> {
+    let found = SCOPED.with(|scoped| {
+        scoped.borrow().iter().rev().find_map(|registry| {
+            let entries = registry.entries.lock().unwrap();
+            let known = entries.cmds.contains_key(name) || entries.aliases.contains_key(name);
+            known.then(|| f(&entries))
+        })
+    });
+    match found {
+        Some(found) => found,
+        None => f(&GLOBAL.entries.lock().unwrap()),
+    }
+}
+
+pub(crate) fn find_cmd(name: &OsStr) -> Option<FnFun> {
+    lookup(name, |entries| entries.cmds.get(name).copied())
+}
+
+pub(crate) fn find_alias(name: &OsStr) -> Option<Alias> {
+    lookup(name, |entries| entries.aliases.get(name).cloned())
+}
+
+#[doc(hidden)]
+pub fn export_cmd(cmd: &'static str, func: FnFun) {
+    GLOBAL.register_cmd(cmd, func);
+}
@@ -0,0 +1,117 @@
+use log::warn;
+use std::io::Result;
+use std::thread;
+use std::time::Duration;
+
+/// Options for `retry()`
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// Number of attempts in total, including the first one, 3 by default
+    pub attempts: usize,
+    /// Delay before the first retry, 100ms by default
+    pub base_delay: Duration,
+    /// Factor the delay grows by for each retry, 2.0 by default
+    pub multiplier: f64,
+    /// Upper bound of the delays, 10s by default
+    pub max_delay: Duration,
+    /// Waits a random delay between zero and the computed one, true by default
+    pub full_jitter: bool,
+    /// Seed for the random jitter, or `None` to seed it from the clock, `None` by default
+    pub seed: Option<u64>,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            full_jitter: true,
+            seed: None,
+        }
+    }
+}
+
+impl RetryOptions {
+    /// Returns the delays waited before each retry, `attempts - 1` of them
+    ///
+    /// Without jitter, the n-th delay is `base_delay * multiplier^n`, capped at `max_delay`. With
+    /// `full_jitter`, it is a random delay up to that, which is the same for the same `seed`.
+    pub fn delays(&self) -> impl Iterator<Item = Duration> {
+        let mut rng = SplitMix64(self.seed.unwrap_or_else(clock_seed));
+        let opts = self.clone();
+        let mut delay = opts.base_delay.min(opts.max_delay);
+        (1..opts.attempts.max(1)).map(move |_| {
+            let capped = delay;
+            let next = delay.as_secs_f64() * opts.multiplier.max(0.0);
+            delay = Duration::from_secs_f64(next.min(opts.max_delay.as_secs_f64()));
+            if opts.full_jitter {
+                capped.mul_f64(rng.next_f64())
+            } else {
+                capped
+            }
+        })
+    }
+}
+
+/// Runs `f` until it succeeds, up to `opts.attempts` times, waiting with exponential backoff
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::time::Duration;
+/// # let url = "";
+/// let opts = RetryOptions { attempts: 5, base_delay: Duration::from_secs(1), ..Default::default() };
+/// let page = retry(&opts, || run_fun!(curl -sf $url))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The commands in `f` are evaluated again for each attempt. With `full_jitter`, which is the
+/// default, the delays are random, so that many jobs failing at the same time, like on a rate
+/// limited API, don't all retry at the same time again. Failed attempts are logged as warnings,
+/// and the error of the last attempt is returned. `f` is run at least once, even with zero
+/// `attempts`.
+pub fn retry<T>(opts: &RetryOptions, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delays = opts.delays();
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(ret) => return Ok(ret),
+            Err(e) => match delays.next() {
+                Some(delay) => {
+                    warn!(
+                        "Attempt {} of {} failed: {}, retrying in {:?}",
+                        attempt, opts.attempts, e, delay
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                None => return Err(e),
+            },
+        }
+    }
+}
+
+fn clock_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    nanos ^ (u64::from(std::process::id()) << 32)
+}
+
+// small seedable generator, see https://prng.di.unimi.it/splitmix64.c
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
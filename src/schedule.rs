@@ -0,0 +1,120 @@
+use crate::child::CmdChildren;
+use crate::{config, current_dir, registry, set_current_dir};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Handle of commands scheduled to be spawned later on a timer thread
+///
+/// Calling `spawn_after()` or `spawn_at()` will return `ScheduledChildren`
+pub struct ScheduledChildren {
+    thread: JoinHandle<Result<CmdChildren>>,
+    state: Arc<(Mutex<ScheduleState>, Condvar)>,
+}
+
+#[derive(PartialEq)]
+enum ScheduleState {
+    Pending,
+    Cancelled,
+    Started,
+}
+
+impl ScheduledChildren {
+    /// Cancels the scheduled spawning, returns false if the commands were already started
+    pub fn cancel(&self) -> bool {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if *state == ScheduleState::Started {
+            return false;
+        }
+        *state = ScheduleState::Cancelled;
+        cvar.notify_one();
+        true
+    }
+
+    /// Returns true if the commands have been spawned (or are being spawned)
+    pub fn is_started(&self) -> bool {
+        *self.state.0.lock().unwrap() == ScheduleState::Started
+    }
+
+    /// Waits until the commands are spawned, returning the handle to them
+    ///
+    /// Returns an `Interrupted` error if it was cancelled before start.
+    pub fn wait(self) -> Result<CmdChildren> {
+        match self.thread.join() {
+            Ok(ret) => ret,
+            Err(e) => Err(Error::new(
+                ErrorKind::Other,
+                format!("Scheduled spawning thread joined with error: {:?}", e),
+            )),
+        }
+    }
+}
+
+/// Spawns commands returned by `f` after `delay`, on a timer thread
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::time::Duration;
+/// let handle = spawn_after(Duration::from_secs(5), || spawn!(ping -c 10 192.168.0.1));
+/// // ...
+/// handle.wait()?.wait()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn spawn_after<F>(delay: Duration, f: F) -> ScheduledChildren
+where
+    F: FnOnce() -> Result<CmdChildren> + Send + 'static,
+{
+    spawn_at(Instant::now() + delay, f)
+}
+
+/// Spawns commands returned by `f` at `start`, on a timer thread
+///
+/// `f` runs with the working directory of `set_current_dir()`, the overrides of `with_config()`
+/// and the registries of `with_registry()` of the calling thread, as they are when scheduling.
+/// The options of an enclosing `Process::run()` are not carried over to the timer thread, so
+/// `f` has to run the commands in a `Process` of its own for them:
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::time::{Duration, Instant};
+/// let handle = spawn_at(Instant::now() + Duration::from_secs(60), || {
+///     Process::new()
+///         .timeout(Duration::from_secs(10))
+///         .run(|| spawn!(curl -fsS "http://localhost:8080/health"))
+/// });
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn spawn_at<F>(start: Instant, f: F) -> ScheduledChildren
+where
+    F: FnOnce() -> Result<CmdChildren> + Send + 'static,
+{
+    let state = Arc::new((Mutex::new(ScheduleState::Pending), Condvar::new()));
+    let timer_state = state.clone();
+    let dir = current_dir();
+    let overrides = config::thread_config();
+    let registries = registry::scoped();
+    let thread = thread::spawn(move || {
+        let (lock, cvar) = &*timer_state;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if *state == ScheduleState::Cancelled {
+                return Err(Error::new(
+                    ErrorKind::Interrupted,
+                    "Scheduled spawning cancelled before start",
+                ));
+            }
+            let now = Instant::now();
+            if now >= start {
+                break;
+            }
+            state = cvar.wait_timeout(state, start - now).unwrap().0;
+        }
+        *state = ScheduleState::Started;
+        drop(state);
+        set_current_dir(&dir)?;
+        let _config = config::with_config(|config| *config = overrides);
+        registry::with_scoped(registries, f)
+    });
+    ScheduledChildren { thread, state }
+}
@@ -0,0 +1,438 @@
+use crate::process::history_expansion_enabled;
+use crate::{current_dir, set_current_dir, Cmd, CmdResult, Cmds, GroupCmds, Redirect};
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Options for `run_script_file()`
+#[derive(Debug, Clone, Default)]
+pub struct ScriptOptions {
+    /// Stops at the first failed line, without running the remaining lines, false by default
+    pub stop_on_error: bool,
+    /// Maximum number of lines running at the same time, 0 or 1 to run them one by one
+    pub jobs: usize,
+}
+
+/// Runs the commands in a script file line by line, returning each line with its result
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let opts = ScriptOptions { stop_on_error: true, ..Default::default() };
+/// for (line, res) in run_script_file("setup.cmds", &opts)? {
+///     if let Err(e) = res {
+///         eprintln!("{} failed: {}", line, e);
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// Each line is parsed with `parse_cmd_line()` and run like a separate `run_cmd!()`, so `cd`
+/// only applies to the rest of its own line. Blank lines and lines starting with `#` are
+/// skipped, and not included in the results. Lines failing to parse are reported as failed.
+///
+/// With more than one job, the lines are started in order on worker threads, which follow the
+/// directory set by `set_current_dir()`, but not the `Process` options. The results are still
+/// returned in the line order. With `stop_on_error`, the lines not started yet when a line fails
+/// are skipped, and not included in the results.
+pub fn run_script_file(
+    path: impl AsRef<Path>,
+    opts: &ScriptOptions,
+) -> Result<Vec<(String, CmdResult)>> {
+    let path = path.as_ref();
+    let script = std::fs::read_to_string(path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Reading script {} failed: {}", path.display(), e),
+        )
+    })?;
+    let lines: Vec<&str> = script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let run_line = |line: &str| parse_cmd_line(line)?.run_cmd();
+
+    if opts.jobs <= 1 {
+        let mut results = vec![];
+        for line in lines {
+            let res = run_line(line);
+            let failed = res.is_err();
+            results.push((line.to_string(), res));
+            if failed && opts.stop_on_error {
+                break;
+            }
+        }
+        return Ok(results);
+    }
+
+    let dir = current_dir();
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<CmdResult>>> = Mutex::new(lines.iter().map(|_| None).collect());
+    thread::scope(|s| {
+        for _ in 0..opts.jobs.min(lines.len()) {
+            s.spawn(|| {
+                let cd = set_current_dir(&dir);
+                loop {
+                    if opts.stop_on_error && failed.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= lines.len() {
+                        break;
+                    }
+                    let res = match cd {
+                        Ok(()) => run_line(lines[i]),
+                        Err(ref e) => Err(Error::new(e.kind(), e.to_string())),
+                    };
+                    if res.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap()[i] = Some(res);
+                }
+            });
+        }
+    });
+    Ok(lines
+        .iter()
+        .zip(results.into_inner().unwrap())
+        .filter_map(|(line, res)| Some((line.to_string(), res?)))
+        .collect())
+}
+
+/// Parses a command line at runtime, into commands which can be run like the ones from macros
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let line = std::fs::read_to_string("cmd.txt")?;
+/// parse_cmd_line(&line)?.run_cmd()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The syntax is the one of the macros, with pipes, `;`, redirects, and `FOO=1 cmd` variables,
+/// but there are no Rust variables to interpolate, so `$` is taken literally. Arguments are
+/// separated by whitespace, and can be quoted with `'...'` or `"..."`, or escaped with `\`.
+/// A `#` starting an argument starts a comment till the end of the line.
+///
+/// With `set_history_expansion(true)`, `!!` is replaced with the last line parsed by this
+/// function on the same thread, after its own expansion, like in bash. Each thread has its own
+/// last line, and lines failing to parse are not remembered. `!!` is not expanded in `'...'`,
+/// or when escaped as `\!!`, and it fails to parse without a previous line.
+pub fn parse_cmd_line(line: &str) -> Result<GroupCmds> {
+    let expanded;
+    let line = if history_expansion_enabled() {
+        expanded = expand_history(line).map_err(|msg| parse_error(line, msg))?;
+        expanded.as_str()
+    } else {
+        line
+    };
+    let mut parser = LineParser::default();
+    parser.parse(line).map_err(|msg| parse_error(line, msg))?;
+    LAST_CMD_LINE.with(|last| *last.borrow_mut() = Some(line.to_string()));
+    Ok(parser.group)
+}
+
+thread_local! {
+    // the last line parsed by `parse_cmd_line()` on this thread, for `!!`
+    static LAST_CMD_LINE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// replaces `!!` with the last line, outside of single quotes and escapes, where a `'` inside
+// double quotes starts no single quotes
+fn expand_history(line: &str) -> std::result::Result<String, String> {
+    let mut expanded = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '\\' if !in_single_quotes => {
+                expanded.push(ch);
+                match chars.next() {
+                    Some(c) => expanded.push(c),
+                    None => break,
+                }
+                continue;
+            }
+            '!' if !in_single_quotes && chars.peek() == Some(&'!') => {
+                chars.next();
+                let last = LAST_CMD_LINE.with(|last| last.borrow().clone());
+                expanded.push_str(&last.ok_or("!!: no previous command line")?);
+                continue;
+            }
+            _ => {}
+        }
+        expanded.push(ch);
+    }
+    Ok(expanded)
+}
+
+// Splits a single command into words, with the quoting of `parse_cmd_line()`, and the `$` in
+// single quotes or escaped as `\$` doubled, so they are kept when expanding the variables
+pub(crate) fn parse_words(line: &str) -> Result<Vec<String>> {
+    let mut parser = LineParser {
+        words_only: true,
+        ..Default::default()
+    };
+    parser.parse(line).map_err(|msg| parse_error(line, msg))?;
+    Ok(parser.words)
+}
+
+fn parse_error(line: &str, msg: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Parsing {:?} failed: {}", line, msg),
+    )
+}
+
+#[derive(Clone, Copy)]
+enum RedirectTarget {
+    Stdin,
+    Stdout { append: bool },
+    Stderr { append: bool },
+    StdoutErr { append: bool },
+}
+
+#[derive(Default)]
+struct LineParser {
+    group: GroupCmds,
+    cmds: Option<Cmds>,
+    cmd: Cmd,
+    // number of arguments in `cmd`, without the redirects
+    cmd_len: usize,
+    // whether `cmd` has redirects, which are not a command on their own
+    redirected: bool,
+    // whether the stdout of `cmd` is redirected, so it can't be piped too
+    stdout_redirected: bool,
+    word: String,
+    in_word: bool,
+    quoted: bool,
+    redirect: Option<RedirectTarget>,
+    // only splitting words, without pipes, `;` or redirects
+    words_only: bool,
+    words: Vec<String>,
+}
+
+impl LineParser {
+    fn parse(&mut self, line: &str) -> std::result::Result<(), String> {
+        let mut chars = line.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '|' | ';' | '<' | '>' | '&'
+                    if self.words_only && (ch != '&' || chars.peek() == Some(&'>')) =>
+                {
+                    return Err(format!("'{}' is not allowed here", ch))
+                }
+                '\'' => {
+                    self.start_quoted_word();
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some('$') => self.push_quoted_dollar(),
+                            Some(c) => self.word.push(c),
+                            None => return Err("unterminated single quote".into()),
+                        }
+                    }
+                }
+                '"' => {
+                    self.start_quoted_word();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') if matches!(chars.peek(), Some('"' | '\\')) => {
+                                self.word.push(chars.next().unwrap())
+                            }
+                            Some(c) => self.word.push(c),
+                            None => return Err("unterminated double quote".into()),
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some('$') => {
+                        self.start_quoted_word();
+                        self.push_quoted_dollar();
+                    }
+                    Some(c) => {
+                        self.start_quoted_word();
+                        self.word.push(c);
+                    }
+                    None => return Err("trailing backslash".into()),
+                },
+                '#' if !self.in_word => break,
+                '|' => {
+                    self.finish_word()?;
+                    if chars.next_if_eq(&'&').is_some() {
+                        self.add_redirect(Redirect::StderrToStdout);
+                    }
+                    if self.cmd_len == 0 {
+                        return Err("expect command before '|'".into());
+                    }
+                    if self.stdout_redirected {
+                        return Err(
+                            "conflicting stdout: already redirected, so it can't be piped to the \
+                             next command"
+                                .into(),
+                        );
+                    }
+                    self.finish_pipe()?;
+                    if matches!(chars.peek(), None | Some('|' | ';')) {
+                        return Err("expect new command after '|'".into());
+                    }
+                }
+                ';' => {
+                    self.finish_word()?;
+                    self.finish_cmds()?;
+                }
+                '<' => {
+                    self.finish_word()?;
+                    self.set_redirect(RedirectTarget::Stdin)?;
+                }
+                '>' => {
+                    // `1>` and `2>` redirect the given fd
+                    let fd =
+                        if self.in_word && !self.quoted && (self.word == "1" || self.word == "2") {
+                            let fd = if self.word == "1" { 1 } else { 2 };
+                            self.word.clear();
+                            self.in_word = false;
+                            fd
+                        } else {
+                            self.finish_word()?;
+                            1
+                        };
+                    let append = chars.next_if_eq(&'>').is_some();
+                    if chars.next_if_eq(&'&').is_some() {
+                        if append {
+                            return Err("raw fd not allowed for append redirection".into());
+                        }
+                        let redirect = match (fd, chars.next()) {
+                            (1, Some('1')) | (2, Some('2')) => None,
+                            (1, Some('2')) => Some(Redirect::StdoutToStderr),
+                            (2, Some('1')) => Some(Redirect::StderrToStdout),
+                            _ => return Err("only &1 or &2 is supported".into()),
+                        };
+                        if let Some(redirect) = redirect {
+                            self.add_redirect(redirect);
+                        }
+                    } else if fd == 1 {
+                        self.set_redirect(RedirectTarget::Stdout { append })?;
+                    } else {
+                        self.set_redirect(RedirectTarget::Stderr { append })?;
+                    }
+                }
+                '&' if chars.peek() == Some(&'>') => {
+                    self.finish_word()?;
+                    chars.next();
+                    let append = chars.next_if_eq(&'>').is_some();
+                    self.set_redirect(RedirectTarget::StdoutErr { append })?;
+                }
+                c if c.is_whitespace() => self.finish_word()?,
+                c => {
+                    self.in_word = true;
+                    self.word.push(c);
+                }
+            }
+        }
+        self.finish_word()?;
+        self.finish_cmds()
+    }
+
+    fn start_quoted_word(&mut self) {
+        self.in_word = true;
+        self.quoted = true;
+    }
+
+    fn set_redirect(&mut self, target: RedirectTarget) -> std::result::Result<(), String> {
+        if self.redirect.is_some() {
+            return Err("wrong redirection format: missing target".into());
+        }
+        self.redirect = Some(target);
+        Ok(())
+    }
+
+    fn add_redirect(&mut self, redirect: Redirect) {
+        if matches!(
+            redirect,
+            Redirect::StdoutToFile(..) | Redirect::StdoutToStderr
+        ) {
+            self.stdout_redirected = true;
+        }
+        self.cmd = std::mem::take(&mut self.cmd).add_redirect(redirect);
+        self.redirected = true;
+    }
+
+    // a `$` which is not expanded, doubled when only splitting words, as the words are expanded
+    // afterwards, see `alias()`
+    fn push_quoted_dollar(&mut self) {
+        if self.words_only {
+            self.word.push('$');
+        }
+        self.word.push('$');
+    }
+
+    fn finish_word(&mut self) -> std::result::Result<(), String> {
+        if !self.in_word {
+            return Ok(());
+        }
+        let word = std::mem::take(&mut self.word);
+        self.in_word = false;
+        self.quoted = false;
+        if self.words_only {
+            self.words.push(word);
+            return Ok(());
+        }
+        match self.redirect.take() {
+            Some(target) => {
+                let path = PathBuf::from(word);
+                match target {
+                    RedirectTarget::Stdin => self.add_redirect(Redirect::FileToStdin(path)),
+                    RedirectTarget::Stdout { append } => {
+                        self.add_redirect(Redirect::StdoutToFile(path, append))
+                    }
+                    RedirectTarget::Stderr { append } => {
+                        self.add_redirect(Redirect::StderrToFile(path, append))
+                    }
+                    RedirectTarget::StdoutErr { append } => {
+                        self.add_redirect(Redirect::StdoutToFile(path, append));
+                        self.add_redirect(Redirect::StderrToStdout);
+                    }
+                }
+            }
+            None => {
+                self.cmd = std::mem::take(&mut self.cmd).add_arg(word.into());
+                self.cmd_len += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_pipe(&mut self) -> std::result::Result<(), String> {
+        if self.redirect.is_some() {
+            return Err("wrong redirection format: missing target".into());
+        }
+        let cmd = std::mem::take(&mut self.cmd);
+        self.cmds = Some(self.cmds.take().unwrap_or_default().pipe(cmd));
+        self.cmd_len = 0;
+        self.redirected = false;
+        self.stdout_redirected = false;
+        Ok(())
+    }
+
+    fn finish_cmds(&mut self) -> std::result::Result<(), String> {
+        if self.cmd_len > 0 {
+            self.finish_pipe()?;
+        } else if self.redirect.is_some() {
+            return Err("wrong redirection format: missing target".into());
+        } else if self.redirected {
+            return Err("expect command before redirection".into());
+        } else if self.cmds.is_some() {
+            return Err("expect new command after '|'".into());
+        }
+        if let Some(cmds) = self.cmds.take() {
+            self.group = std::mem::take(&mut self.group).append(cmds);
+        }
+        Ok(())
+    }
+}
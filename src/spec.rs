@@ -0,0 +1,84 @@
+use crate::child::{CmdChildren, FunChildren};
+use crate::process::{GroupCmds, Redirect};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+/// Pipeline spawned by `spawn!()` or `spawn_with_output!()`, for spawning it again
+///
+/// It is kept by the children, and is the way to re-run a failed pipeline with a tweak, without
+/// building it again at another call site:
+/// ```no_run
+/// # use cmd_lib::*;
+/// let mut children = spawn!(make -C build test)?;
+/// if children.wait().is_err() {
+///     children.spec().unwrap().with_env("VERBOSE", "1").spawn()?.wait()?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// The command lines are kept as they were spawned, with the interpolated values copied, and
+/// the aliases and hooks already applied. Custom commands are looked up again, failing with an
+/// error of kind `NotFound` when they are gone, like outside of the `with_registry()` they were
+/// spawned in, and the options of `Process::run()` are the ones in effect where it is spawned
+/// again. Pipelines with closures, the ones spawned by `spawn_command()`, and the ones fed by
+/// `feed_stdin_iter()` can't be spawned again, so they have no spec. Commands with side effects,
+/// like deleting or sending something, run again too, so making sure that is safe is up to the
+/// caller.
+#[derive(Debug, Clone)]
+pub struct CmdSpec {
+    pub(crate) stages: Vec<StageSpec>,
+    pub(crate) current_dir: PathBuf,
+}
+
+// a command of the pipeline, before it is turned into a `std::process::Command`
+#[derive(Debug, Clone)]
+pub(crate) struct StageSpec {
+    pub(crate) args: Vec<OsString>,
+    pub(crate) vars: HashMap<String, String>,
+    pub(crate) redirects: Vec<Redirect>,
+    pub(crate) in_cmd_map: bool,
+    pub(crate) ignore_error: bool,
+}
+
+impl CmdSpec {
+    /// Sets the environment variable `key` to `value` for all the commands
+    ///
+    /// It takes precedence over the variables set for a command only, as in `FOO=1 cmd`.
+    pub fn with_env(mut self, key: &str, value: &str) -> Self {
+        for stage in self.stages.iter_mut() {
+            stage.vars.insert(key.into(), value.into());
+        }
+        self
+    }
+
+    /// Runs the pipeline in `dir`, with relative `dir` resolved against the previous one
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.current_dir = self.current_dir.join(dir);
+        self
+    }
+
+    /// Ignores the errors of the command at index `stage` in the pipeline, like
+    /// `CmdError::stage_index`
+    pub fn ignore_stage(mut self, stage: usize) -> Self {
+        if let Some(stage) = self.stages.get_mut(stage) {
+            stage.ignore_error = true;
+        }
+        self
+    }
+
+    /// Returns the number of commands in the pipeline
+    pub fn stages(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Spawns the pipeline like `spawn!()`
+    pub fn spawn(&self) -> Result<CmdChildren> {
+        GroupCmds::from_spec(self)?.spawn(false)
+    }
+
+    /// Spawns the pipeline like `spawn_with_output!()`
+    pub fn spawn_with_output(&self) -> Result<FunChildren> {
+        GroupCmds::from_spec(self)?.spawn_with_output()
+    }
+}
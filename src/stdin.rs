@@ -0,0 +1,122 @@
+use log::warn;
+use os_pipe::PipeWriter;
+use std::io::{BufWriter, ErrorKind, Result, Write};
+
+/// Options for writing the stdin of the commands, for `GroupCmds::feed_stdin_iter_with()` and
+/// `GroupCmds::spawn_with_stdin()`
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let mut options = StdinOptions::default();
+/// options.chunk_size = 4096;
+/// options.flush_each_item = false;
+/// let rows = (0..1_000_000).map(|i| format!("row {}\n", i));
+/// let count = parse_cmd_line("wc -l")?
+///     .feed_stdin_iter_with(rows, options)?
+///     .wait_with_output()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct StdinOptions {
+    /// Size in bytes of the buffer the input is collected in, and of the largest write to the
+    /// pipe, 64 KiB by default
+    pub chunk_size: usize,
+    /// Flushes the buffer after each item of the iterator, so the commands see every item as
+    /// soon as it is taken, true by default
+    pub flush_each_item: bool,
+}
+
+impl Default for StdinOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 64 << 10,
+            flush_each_item: true,
+        }
+    }
+}
+
+/// Writer to the stdin of the commands, returned by `GroupCmds::spawn_with_stdin()`
+///
+/// The input is buffered up to `StdinOptions::chunk_size`, and written when the buffer is full,
+/// or when the writer is flushed, closed or dropped. Once the commands exit or close stdin
+/// without reading all the input, the rest of it is discarded instead of failing with
+/// `BrokenPipe`, as the result of waiting for the commands tells if they failed, and
+/// `is_closed()` tells the producer to stop.
+pub struct StdinWriter {
+    inner: BufWriter<PipeWriter>,
+    chunk_size: usize,
+    closed: bool,
+}
+
+impl StdinWriter {
+    pub(crate) fn new(pipe: PipeWriter, options: &StdinOptions) -> Self {
+        let chunk_size = options.chunk_size.max(1);
+        Self {
+            inner: BufWriter::with_capacity(chunk_size, pipe),
+            chunk_size,
+            closed: false,
+        }
+    }
+
+    /// Returns true once the commands stopped reading the input
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Writes the buffered input and closes stdin, for the commands to see the end of the input
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    // the commands exited or closed stdin without reading all the input
+    fn closed_early<T>(&mut self, ret: Result<T>, discarded: T) -> Result<T> {
+        match ret {
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(discarded)
+            }
+            ret => ret,
+        }
+    }
+}
+
+impl Write for StdinWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.closed {
+            return Ok(buf.len());
+        }
+        let len = buf.len().min(self.chunk_size);
+        let ret = self.inner.write(&buf[..len]);
+        self.closed_early(ret, len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let ret = self.inner.flush();
+        self.closed_early(ret, ())
+    }
+}
+
+// writes the items to the stdin of `cmds` until they are exhausted or stdin is closed
+pub(crate) fn feed<I>(mut writer: StdinWriter, iter: I, options: &StdinOptions, cmds: &str)
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut fed = Ok(());
+    for item in iter {
+        fed = writer.write_all(item.as_ref());
+        if fed.is_ok() && options.flush_each_item {
+            fed = writer.flush();
+        }
+        if fed.is_err() || writer.is_closed() {
+            break;
+        }
+    }
+    if let Err(e) = fed.and_then(|()| writer.close()) {
+        warn!("Writing the input of {} failed: {}", cmds, e);
+    }
+}
@@ -0,0 +1,371 @@
+// Platform specific process handling, kept here so the rest of the crate has no `cfg` for the
+// differences between Linux, macOS and the BSDs, with a portable fallback for each function.
+use std::io::{Error, ErrorKind, Result};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process::Child;
+#[cfg(unix)]
+use std::process::Command;
+use std::time::Duration;
+
+// makes the child inherit each `fd` as `child_fd`
+//
+// The fds are first duplicated above all the `child_fd`s, and only then moved into place, so an
+// fd which is also the `child_fd` of another one, like when swapping two fds, is not clobbered
+// before it is duplicated.
+#[cfg(unix)]
+pub(crate) fn pass_fds(cmd: &mut Command, fds: Vec<(RawFd, RawFd)>) {
+    use std::os::unix::process::CommandExt;
+    let min_tmp = fds.iter().map(|&(child_fd, _)| child_fd).max().unwrap_or(0) + 1;
+    // allocated here, as the child may only make async-signal-safe calls
+    let mut tmp_fds = vec![-1; fds.len()];
+    // safety: only async-signal-safe calls between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            for (tmp, &(_, fd)) in tmp_fds.iter_mut().zip(fds.iter()) {
+                // closed on exec, while the duplicates made by dup2() below are not
+                *tmp = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min_tmp);
+                if *tmp < 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            for (&tmp, &(child_fd, _)) in tmp_fds.iter().zip(fds.iter()) {
+                if libc::dup2(tmp, child_fd) < 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+// makes the child create its files with `mask` cleared from the requested permissions
+#[cfg(unix)]
+pub(crate) fn set_umask(cmd: &mut Command, mask: u32) {
+    use std::os::unix::process::CommandExt;
+    // safety: umask() is async-signal-safe, and can't fail
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::umask(mask as libc::mode_t);
+            Ok(())
+        });
+    }
+}
+
+// makes the child the leader of a new process group, so that `kill_tree()` kills the processes
+// it starts too
+#[cfg(unix)]
+pub(crate) fn new_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+// kills `child`, along with the whole process group it leads when started by
+// `new_process_group()`, since its descendants may hold its pipes open after it is gone
+#[cfg(unix)]
+pub(crate) fn kill_tree(child: &mut Child) -> Result<()> {
+    let pid = child.id() as libc::pid_t;
+    // the pid is only kept from being reused until the child is reaped, which WNOWAIT doesn't
+    // safety: waitid() only fills the plain struct
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    let unreaped = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } == 0;
+    // safety: only signaling the group of our own unreaped child
+    if unreaped
+        && unsafe { libc::getpgid(pid) } == pid
+        && unsafe { libc::killpg(pid, libc::SIGKILL) } == 0
+    {
+        return Ok(());
+    }
+    child.kill()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn kill_tree(child: &mut Child) -> Result<()> {
+    child.kill()
+}
+
+// sets the capacity of the pipe `fd` to at least `size` bytes, which only Linux supports, and
+// is left to the system elsewhere
+#[cfg(target_os = "linux")]
+pub(crate) fn set_pipe_size(fd: RawFd, size: usize) -> Result<()> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    // safety: F_SETPIPE_SZ only changes the capacity of the pipe
+    if unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, size) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn set_pipe_size(_fd: RawFd, _size: usize) -> Result<()> {
+    Ok(())
+}
+
+// The state of this process which `Command::exec()` changes before replacing it: the standard
+// streams, the working directory, SIGPIPE and the signal mask. It is put back when dropped, for
+// an exec which failed after all.
+#[cfg(unix)]
+pub(crate) struct ExecState {
+    // `None` for the streams which were closed
+    stdio: Vec<(RawFd, Option<OwnedFd>)>,
+    dir: Option<PathBuf>,
+    sigpipe: libc::sigaction,
+    mask: libc::sigset_t,
+}
+
+#[cfg(unix)]
+impl ExecState {
+    pub(crate) fn save() -> Self {
+        let stdio = (0..3)
+            .map(|fd| {
+                // safety: F_DUPFD_CLOEXEC only returns a new fd, owned by the `OwnedFd`, which
+                // is not leaked to the program when exec succeeds
+                let saved = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) };
+                let saved = (saved >= 0).then(|| unsafe { OwnedFd::from_raw_fd(saved) });
+                (fd, saved)
+            })
+            .collect();
+        // safety: only querying the signal states of this thread
+        unsafe {
+            let mut sigpipe: libc::sigaction = std::mem::zeroed();
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::sigaction(libc::SIGPIPE, std::ptr::null(), &mut sigpipe);
+            libc::pthread_sigmask(libc::SIG_SETMASK, std::ptr::null(), &mut mask);
+            Self {
+                stdio,
+                dir: std::env::current_dir().ok(),
+                sigpipe,
+                mask,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ExecState {
+    fn drop(&mut self) {
+        // safety: only restoring the fds and signal states saved above
+        unsafe {
+            for (fd, saved) in self.stdio.iter() {
+                match saved {
+                    Some(saved) => libc::dup2(saved.as_raw_fd(), *fd),
+                    None => libc::close(*fd),
+                };
+            }
+            libc::sigaction(libc::SIGPIPE, &self.sigpipe, std::ptr::null_mut());
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.mask, std::ptr::null_mut());
+        }
+        if let Some(ref dir) = self.dir {
+            let _ = std::env::set_current_dir(dir);
+        }
+    }
+}
+
+// Waits up to `timeout` for the unreaped child `pid` to exit, without reaping it, returning
+// whether it exited. Fails when the platform can't wait for it, and the caller polls instead.
+#[cfg(target_os = "linux")]
+pub(crate) fn wait_exit(pid: u32, timeout: Duration) -> Result<bool> {
+    // safety: pidfd_open() only returns a new fd, owned by `pidfd` below
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    let mut pollfd = libc::pollfd {
+        fd: pidfd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // rounded up, so that it never spins on a timeout below 1ms
+    let millis = timeout.as_micros().div_ceil(1000);
+    let millis = millis.min(libc::c_int::MAX as u128) as libc::c_int;
+    // safety: polling a single valid pollfd
+    match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+        n if n < 0 => interrupted(Error::last_os_error()),
+        n => Ok(n > 0),
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd"
+))]
+pub(crate) fn wait_exit(pid: u32, timeout: Duration) -> Result<bool> {
+    // safety: kqueue() only returns a new fd owned by `kq`, and the events are plain data
+    unsafe {
+        let fd = libc::kqueue();
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let kq = OwnedFd::from_raw_fd(fd);
+        let mut change: libc::kevent = std::mem::zeroed();
+        change.ident = pid as _;
+        change.filter = libc::EVFILT_PROC as _;
+        change.flags = (libc::EV_ADD | libc::EV_ONESHOT) as _;
+        change.fflags = libc::NOTE_EXIT as _;
+        let mut event: libc::kevent = std::mem::zeroed();
+        let ts = libc::timespec {
+            tv_sec: timeout.as_secs() as _,
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+        match libc::kevent(kq.as_raw_fd(), &change, 1, &mut event, 1, &ts) {
+            n if n < 0 => interrupted(Error::last_os_error()),
+            n if n > 0 && event.flags & libc::EV_ERROR as u16 != 0 => {
+                Err(Error::from_raw_os_error(event.data as i32))
+            }
+            n => Ok(n > 0),
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd"
+)))]
+pub(crate) fn wait_exit(_pid: u32, _timeout: Duration) -> Result<bool> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "waiting for a process exit is not supported",
+    ))
+}
+
+// a signal interrupting the wait is a spurious wakeup
+#[allow(dead_code)]
+fn interrupted(e: Error) -> Result<bool> {
+    if e.kind() == ErrorKind::Interrupted {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+// the start time of the process `pid`, in a unit depending on the platform, if it runs and is
+// known: clock ticks since boot on Linux
+#[cfg(target_os = "linux")]
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+    let (state, started) = proc_stat(pid)?;
+    (state != 'Z' && state != 'X').then_some(started)
+}
+
+// microseconds since the epoch on macOS
+#[cfg(target_os = "macos")]
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+    // the status of exited processes left unreaped
+    const SZOMB: u32 = 5;
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    // safety: proc_pidinfo() fills at most `size` bytes of the plain struct
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            &mut info as *mut libc::proc_bsdinfo as *mut libc::c_void,
+            size,
+        )
+    };
+    (ret == size && info.pbi_status != SZOMB)
+        .then(|| info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+}
+
+// 100 nanosecond intervals since 1601 on Windows
+#[cfg(windows)]
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+    use winapi::shared::minwindef::{FALSE, FILETIME};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::minwinbase::STILL_ACTIVE;
+    use winapi::um::processthreadsapi::{GetExitCodeProcess, GetProcessTimes, OpenProcess};
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    // safety: the handle is checked before it's used, and closed after filling the plain structs
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if handle.is_null() {
+            return None;
+        }
+        let mut code = 0;
+        let mut times: [FILETIME; 4] = std::mem::zeroed();
+        let [created, exited, kernel, user] = &mut times;
+        let running = GetExitCodeProcess(handle, &mut code) != 0
+            && code == STILL_ACTIVE
+            && GetProcessTimes(handle, created, exited, kernel, user) != 0;
+        CloseHandle(handle);
+        running.then(|| ((times[0].dwHighDateTime as u64) << 32) | times[0].dwLowDateTime as u64)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub(crate) fn process_start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+// whether the process `pid` runs, not counting the exited ones left unreaped
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+pub(crate) fn process_running(pid: u32) -> bool {
+    process_start_time(pid).is_some()
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+pub(crate) fn process_running(pid: u32) -> bool {
+    // safety: signal 0 only checks that the process exists
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn process_running(_pid: u32) -> bool {
+    false
+}
+
+// the state and the start time of `pid` from /proc/<pid>/stat
+#[cfg(target_os = "linux")]
+fn proc_stat(pid: u32) -> Option<(char, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name in parentheses may contain spaces and parentheses
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    // the start time is the 22nd field, and the 20th after the command name
+    let started = fields.nth(18)?.parse().ok()?;
+    Some((state, started))
+}
+
+// the soft RLIMIT_NOFILE of this process, if limited
+#[cfg(unix)]
+pub(crate) fn fd_limit() -> Option<usize> {
+    // safety: getrlimit() only fills the plain struct
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(limit.rlim_cur as usize)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn fd_limit() -> Option<usize> {
+    None
+}
+
+// the number of file descriptors open in this process, if known
+#[cfg(target_os = "linux")]
+pub(crate) fn open_fds() -> Option<usize> {
+    // not counting the fd of the directory being read
+    let open = std::fs::read_dir("/proc/self/fd").ok()?.count();
+    Some(open.saturating_sub(1))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_fds() -> Option<usize> {
+    None
+}
@@ -0,0 +1,97 @@
+use crate::error;
+use crate::CmdResult;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+type StepFn<'a> = Box<dyn FnOnce() -> CmdResult + 'a>;
+
+/// Steps to run in order, with undo commands to roll back the finished steps on failure
+///
+/// Calling `transaction()` will return an empty `Transaction`
+pub struct Transaction<'a> {
+    steps: Vec<(StepFn<'a>, StepFn<'a>)>,
+    interrupted: Option<Arc<AtomicBool>>,
+}
+
+/// Creates an empty transaction
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let dir = "/tmp/app";
+/// transaction()
+///     .step(|| run_cmd!(mkdir $dir), || run_cmd!(rmdir $dir))
+///     .step(|| run_cmd!(cp app.conf $dir), || run_cmd!(rm -f $dir/app.conf))
+///     .step(|| run_cmd!(systemctl restart app), || Ok(()))
+///     .commit()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn transaction<'a>() -> Transaction<'a> {
+    Transaction {
+        steps: vec![],
+        interrupted: None,
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Adds a step, and `undo` will be called if any later step fails
+    pub fn step(
+        mut self,
+        run: impl FnOnce() -> CmdResult + 'a,
+        undo: impl FnOnce() -> CmdResult + 'a,
+    ) -> Self {
+        self.steps.push((Box::new(run), Box::new(undo)));
+        self
+    }
+
+    /// Sets a flag checked before each step, e.g. set by a Ctrl-C handler, to stop and roll back
+    ///
+    /// No signal handler is installed by the transaction, so without one of the caller's, like
+    /// with the `ctrlc` crate, Ctrl-C ends the program without rolling back. The step running
+    /// when the flag is set is not stopped, though its commands get the signal of Ctrl-C too, as
+    /// they are in the foreground process group, and the rollback starts once it returns.
+    pub fn interrupt_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupted = Some(flag);
+        self
+    }
+
+    /// Runs all the steps in order
+    ///
+    /// If one step fails, the undo commands of the previous steps are run in reverse order, and
+    /// the returned error includes both the original error and any undo failures. It has the
+    /// kind of the original error, which is its source too, so `CmdError::from_io_error()`
+    /// still finds the details of a failed command.
+    pub fn commit(self) -> CmdResult {
+        let mut undos = vec![];
+        for (i, (run, undo)) in self.steps.into_iter().enumerate() {
+            let interrupted = self
+                .interrupted
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::SeqCst));
+            let ret = if interrupted {
+                Err(Error::new(ErrorKind::Interrupted, "interrupted"))
+            } else {
+                run()
+            };
+            if let Err(e) = ret {
+                return Err(Self::rollback(i, e, undos));
+            }
+            undos.push(undo);
+        }
+        Ok(())
+    }
+
+    fn rollback(failed_step: usize, e: Error, undos: Vec<StepFn<'a>>) -> Error {
+        let mut undo_errs = vec![];
+        for (i, undo) in undos.into_iter().enumerate().rev() {
+            if let Err(undo_err) = undo() {
+                undo_errs.push(format!("undo step {} failed: {}", i, undo_err));
+            }
+        }
+        let mut context = format!("Transaction step {} failed", failed_step);
+        if !undo_errs.is_empty() {
+            context += &format!(" ({})", undo_errs.join("; "));
+        }
+        error::with_context(e, context)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::Redirect;
+use faccess::PathExt;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Result of `validate_cmd!`, with the commands checked in order
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    /// The checked commands, in the order they would run
+    pub commands: Vec<ValidatedCmd>,
+}
+
+/// A command checked by `validate_cmd!`
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ValidatedCmd {
+    /// The command, formatted as in errors and logs
+    pub command: String,
+    /// Path of the program to spawn, or `None` for builtin and custom commands and `cd`
+    pub program: Option<PathBuf>,
+    /// Whether running the command would ask for confirmation, see `Process::confirm()`
+    pub needs_confirmation: bool,
+}
+
+/// Problems found by `validate_cmd!`, which would make the commands fail to run
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ValidationError {
+    /// One message for each problem, in the order the commands would run
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Validation failed: {}", self.problems.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for Error {
+    fn from(e: ValidationError) -> Self {
+        Error::new(ErrorKind::InvalidInput, e)
+    }
+}
+
+// searches the program like execvp(3), which is what spawning does, in `search_path` if set for
+// the command, or in the `PATH` of this process
+pub(crate) fn resolve_program(
+    program: &OsStr,
+    current_dir: &Path,
+    search_path: Option<&OsStr>,
+) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        let path = if path.is_relative() && !current_dir.as_os_str().is_empty() {
+            current_dir.join(path)
+        } else {
+            path.to_path_buf()
+        };
+        return (path.is_file() && path.executable()).then_some(path);
+    }
+    let paths = match search_path {
+        Some(paths) => paths.to_os_string(),
+        None => std::env::var_os("PATH")?,
+    };
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file() && path.executable())
+}
+
+// checks the files would be opened like `Cmd::setup_redirects()`
+pub(crate) fn check_redirect(redirect: &Redirect, current_dir: &Path) -> Result<(), String> {
+    let (file, read_only) = match redirect {
+        Redirect::FileToStdin(path) => (path, true),
+        Redirect::StdoutToFile(path, _) | Redirect::StderrToFile(path, _) => (path, false),
+        Redirect::StdoutToStderr | Redirect::StderrToStdout => return Ok(()),
+    };
+    if file == Path::new("/dev/null") {
+        return Ok(());
+    }
+    let path = &current_dir.join(file);
+    let ok = if read_only {
+        path.is_file() && path.readable()
+    } else if path.exists() {
+        path.writable()
+    } else {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        dir.is_dir() && dir.writable()
+    };
+    if ok {
+        Ok(())
+    } else if read_only {
+        Err(format!("{} is not a readable file", file.display()))
+    } else {
+        Err(format!("{} can't be written", file.display()))
+    }
+}
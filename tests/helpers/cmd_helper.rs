@@ -0,0 +1,61 @@
+// Helper program for tests/test_pipelines.rs, built by the tests with rustc, so the tests don't
+// depend on the behavior of system tools.
+//
+// Usage:
+//   cmd_helper pass          copy stdin to stdout
+//   cmd_helper exit CODE     drain stdin, then exit with CODE
+//   cmd_helper emit BYTES    write BYTES bytes of `pattern()` to stdout
+//   cmd_helper spew BYTES    write BYTES bytes of `pattern()` to stderr
+//   cmd_helper sleep MS      sleep for MS milliseconds
+//   cmd_helper slow MS       sleep for MS milliseconds, then drain stdin
+//   cmd_helper fail MSG      drain stdin, write MSG to stderr, then exit with 1
+use std::io::{self, Write};
+use std::time::Duration;
+
+// all the 256 byte values, including NUL and newlines
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let arg = |i: usize| args.get(i).cloned().unwrap_or_default();
+    let code = match arg(0).as_str() {
+        "pass" => io::copy(&mut io::stdin(), &mut io::stdout())
+            .map(|_| 0)
+            .unwrap_or(1),
+        "exit" => {
+            let _ = io::copy(&mut io::stdin(), &mut io::sink());
+            arg(1).parse().unwrap()
+        }
+        "emit" => {
+            let data = pattern(arg(1).parse().unwrap());
+            io::stdout().write_all(&data).map(|_| 0).unwrap_or(1)
+        }
+        "spew" => {
+            let data = pattern(arg(1).parse().unwrap());
+            io::stderr().write_all(&data).map(|_| 0).unwrap_or(1)
+        }
+        "sleep" => {
+            std::thread::sleep(Duration::from_millis(arg(1).parse().unwrap()));
+            0
+        }
+        "slow" => {
+            std::thread::sleep(Duration::from_millis(arg(1).parse().unwrap()));
+            io::copy(&mut io::stdin(), &mut io::sink())
+                .map(|_| 0)
+                .unwrap_or(1)
+        }
+        "fail" => {
+            let _ = io::copy(&mut io::stdin(), &mut io::sink());
+            eprintln!("{}", arg(1));
+            1
+        }
+        mode => {
+            eprintln!("unknown mode: {}", mode);
+            2
+        }
+    };
+    std::process::exit(code);
+}
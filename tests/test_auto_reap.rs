@@ -0,0 +1,59 @@
+// The background reaper is process-global and can't be turned off, so its tests are kept out of
+// the other test files
+use cmd_lib::*;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_auto_reap() {
+    enable_auto_reap();
+    let mut ok = spawn!(true).unwrap();
+    let mut failed = spawn!(ls /nofile).unwrap();
+    let mut sleeping = spawn!(sleep 1).unwrap();
+    sleep(Duration::from_millis(500));
+    assert!(ok.wait().is_ok());
+    assert!(failed.wait().is_err());
+    assert!(sleeping.wait().is_ok());
+
+    // ignored after being reaped, or before
+    let reaped = spawn!(sh -c "exit 1").unwrap();
+    sleep(Duration::from_millis(500));
+    let mut reaped = reaped.ignore_errors();
+    assert!(reaped.wait().is_ok());
+    assert!(reaped.stats().stages[0].error_ignored);
+    let mut ignored = spawn!(sh -c "sleep 0.2; exit 1").unwrap().ignore_errors();
+    sleep(Duration::from_millis(600));
+    assert!(ignored.wait().is_ok());
+    assert!(ignored.stats().stages[0].error_ignored);
+
+    #[cfg(target_os = "linux")]
+    {
+        // the fds open in this process, not counting the one of the directory being read
+        let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count() - 1;
+        // the child processes of this process, including the exited ones not reaped
+        let children = || {
+            let tasks = std::fs::read_dir("/proc/self/task").unwrap();
+            tasks
+                .flatten()
+                .map(|task| std::fs::read_to_string(task.path().join("children")).unwrap())
+                .collect::<String>()
+                .split_whitespace()
+                .count()
+        };
+
+        // handles kept for later, without closing their pipes or leaving zombies meanwhile
+        let fds = open_fds();
+        let handles: Vec<_> = (0..20)
+            .map(|_| spawn!(sh -c "echo reaped >&2; exit 1").unwrap())
+            .collect();
+        let started = Instant::now();
+        while (open_fds() > fds || children() > 0) && started.elapsed() < Duration::from_secs(5) {
+            sleep(Duration::from_millis(50));
+        }
+        assert_eq!(open_fds(), fds);
+        assert_eq!(children(), 0);
+        for mut handle in handles {
+            assert!(handle.wait().is_err());
+        }
+    }
+}
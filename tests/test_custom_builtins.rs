@@ -0,0 +1,134 @@
+// Commands registered here replace system commands for the whole test process, so they are
+// kept out of the other test files
+use cmd_lib::*;
+use std::io::Write;
+
+#[export_cmd(echo)]
+fn my_echo(env: &mut CmdEnv) -> CmdResult {
+    let args = env.args()[1..].join(",");
+    writeln!(env.stdout(), "custom echo: {}", args)
+}
+
+#[test]
+fn test_custom_builtin_precedence() {
+    use_custom_cmd!(echo);
+    assert_eq!(run_fun!(echo a b).unwrap(), "custom echo: a,b");
+    let prog = "echo";
+    assert_eq!(run_fun!($prog a).unwrap(), "custom echo: a");
+    assert_eq!(run_fun!(echo x | tr a-z A-Z).unwrap(), "CUSTOM ECHO: X");
+    assert_eq!(
+        parse_cmd_line("echo from file").unwrap().run_fun().unwrap(),
+        "custom echo: from,file"
+    );
+
+    // programs with a path are still external
+    assert_eq!(run_fun!(/bin/echo a b).unwrap(), "a b");
+    assert_eq!(
+        Process::new()
+            .bin_override("echo", "/bin/false")
+            .run(|| run_fun!(echo a))
+            .unwrap(),
+        "custom echo: a"
+    );
+}
+
+#[test]
+fn test_cmd_registry() {
+    let first = CmdRegistry::new();
+    first.register_cmd("cmd_lib_test_greet", |env| writeln!(env.stdout(), "first"));
+    first
+        .alias("cmd_lib_test_hi", "cmd_lib_test_greet again")
+        .unwrap();
+    let second = CmdRegistry::new();
+    second.register_cmd("cmd_lib_test_greet", |env| writeln!(env.stdout(), "second"));
+    // hides the global custom echo, and the global alias below
+    second.register_cmd("echo", |env| writeln!(env.stdout(), "scoped echo"));
+    second.register_cmd("cmd_lib_test_global_alias", |env| {
+        writeln!(env.stdout(), "scoped command")
+    });
+    CmdRegistry::global().register_cmd("cmd_lib_test_global", |env| {
+        writeln!(env.stdout(), "global")
+    });
+    alias("cmd_lib_test_global_alias", "cmd_lib_test_global").unwrap();
+
+    // the same names resolve differently in each registry
+    assert_eq!(
+        with_registry(&first, || run_fun!(cmd_lib_test_greet)).unwrap(),
+        "first"
+    );
+    assert_eq!(
+        with_registry(&first, || run_fun!(cmd_lib_test_hi)).unwrap(),
+        "first"
+    );
+    assert_eq!(
+        with_registry(&second, || run_fun!(cmd_lib_test_greet | cat)).unwrap(),
+        "second"
+    );
+    assert!(run_fun!(cmd_lib_test_greet).is_err());
+    assert!(run_fun!(cmd_lib_test_hi).is_err());
+
+    // scoped registries, innermost first, then the global one, then PATH
+    let nested = with_registry(&first, || {
+        with_registry(&second, || {
+            [
+                run_fun!(cmd_lib_test_greet),
+                run_fun!(cmd_lib_test_hi),
+                run_fun!(cmd_lib_test_global),
+                run_fun!(printf path),
+            ]
+            .map(Result::unwrap)
+        })
+    });
+    // the expanded alias is looked up again from the innermost registry
+    assert_eq!(nested, ["second", "second", "global", "path"]);
+    assert_eq!(
+        with_registry(&second, || run_fun!(echo a)).unwrap(),
+        "scoped echo"
+    );
+    assert_eq!(
+        with_registry(&second, || run_fun!(cmd_lib_test_global_alias)).unwrap(),
+        "scoped command"
+    );
+    assert_eq!(
+        with_registry(&first, || run_fun!(cmd_lib_test_global_alias)).unwrap(),
+        "global"
+    );
+    assert_eq!(run_fun!(cmd_lib_test_global_alias).unwrap(), "global");
+
+    // the command found when parsing is run after the scope ends
+    let mut parsed = with_registry(&first, || parse_cmd_line("cmd_lib_test_greet")).unwrap();
+    assert_eq!(parsed.run_fun().unwrap(), "first");
+
+    // spawning again looks the command up again
+    let mut children = with_registry(&first, || spawn_with_output!(cmd_lib_test_greet)).unwrap();
+    assert_eq!(children.wait_with_output().unwrap(), "first");
+    let mut again = with_registry(&first, || children.respawn()).unwrap();
+    assert_eq!(again.wait_with_output().unwrap(), "first");
+    let e = children.respawn().err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_nested_commands() {
+    // builtins and hooks running commands themselves don't deadlock
+    CmdRegistry::global().register_cmd("cmd_lib_test_nested", |env| {
+        let inner = run_fun!(printf inner | cat)?;
+        write!(env.stdout(), "outer {}", inner)
+    });
+    assert_eq!(run_fun!(cmd_lib_test_nested).unwrap(), "outer inner");
+    assert_eq!(run_fun!(cmd_lib_test_nested | cat).unwrap(), "outer inner");
+
+    register_cmd_hook(|cmd| {
+        if cmd
+            .args
+            .first()
+            .is_some_and(|arg| arg == "cmd_lib_test_hooked")
+        {
+            // like recording metrics, with the commands here not passed to the hooks again
+            let measured = run_fun!(printf x | cmd_lib_test_nested).unwrap();
+            cmd.args = vec!["printf".into(), measured.into()];
+        }
+    });
+    assert_eq!(run_fun!(cmd_lib_test_hooked).unwrap(), "outer inner");
+    assert_eq!(run_fun!(cmd_lib_test_hooked | cat).unwrap(), "outer inner");
+}
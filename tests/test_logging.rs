@@ -0,0 +1,55 @@
+// A logger capturing the records is installed for the whole test process, so the tests checking
+// the logged lines are kept out of the other test files
+use cmd_lib::*;
+use log::{Level, LevelFilter, Metadata, Record};
+use std::sync::Mutex;
+
+static RECORDS: Mutex<Vec<(Level, String)>> = Mutex::new(vec![]);
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let line = record.args().to_string();
+        RECORDS.lock().unwrap().push((record.level(), line));
+    }
+
+    fn flush(&self) {}
+}
+
+// the levels the lines equal to `line` were logged at
+fn logged_levels(line: &str) -> Vec<Level> {
+    static LOGGER: CapturingLogger = CapturingLogger;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+    let records = RECORDS.lock().unwrap();
+    records
+        .iter()
+        .filter(|(_, logged)| logged == line)
+        .map(|(level, _)| *level)
+        .collect()
+}
+
+#[test]
+fn test_ignore_errors_stderr_level() {
+    logged_levels("");
+    spawn!(sh -c "echo cmd_lib_test_info >&2")
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!(logged_levels("cmd_lib_test_info"), [Level::Info]);
+
+    // stderr of spawned children is logged right away, and lowered once the errors are ignored
+    let children = spawn!(sh -c "sleep 0.2; echo cmd_lib_test_ignored >&2; exit 1").unwrap();
+    children.ignore_errors().wait().unwrap();
+    assert_eq!(logged_levels("cmd_lib_test_ignored"), [Level::Debug]);
+
+    let children = spawn_with_output!(sh -c "echo cmd_lib_test_fun_ignored >&2; exit 1").unwrap();
+    children.ignore_errors().wait_with_output().unwrap();
+    assert_eq!(logged_levels("cmd_lib_test_fun_ignored"), [Level::Debug]);
+}
@@ -76,6 +76,26 @@ fn test_non_string_args() {
     assert!(run_cmd!(sleep $a).is_ok());
 }
 
+#[test]
+fn test_map_flags() {
+    use std::collections::{BTreeMap, HashMap};
+
+    let opts = HashMap::from([("region", "us east"), ("profile", "ci")]);
+    assert_eq!(
+        run_fun!(printf "[%s]" $%{opts}).unwrap(),
+        "[--profile][ci][--region][us east]"
+    );
+    let flags = Flags::new(&opts).prefix("-").join_with("=");
+    assert_eq!(
+        run_fun!(printf "[%s]" $%{flags}).unwrap(),
+        "[-profile=ci][-region=us east]"
+    );
+    let jobs = BTreeMap::from([("jobs", 4)]);
+    assert_eq!(run_fun!(printf "[%s]" $%{jobs}).unwrap(), "[--jobs][4]");
+    let empty: BTreeMap<String, String> = BTreeMap::new();
+    assert_eq!(run_fun!(printf "[%s]" a $%{empty}).unwrap(), "[a]");
+}
+
 #[test]
 fn test_non_eng_args() {
     let msg = "你好！";
@@ -125,6 +145,102 @@ fn test_vars_in_str3() {
 /// ```
 fn test_vars_in_str4() {}
 
+#[test]
+fn test_interpolation_order() {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    // records each evaluation, and changes the value every time
+    struct Counter(Arc<Mutex<Vec<&'static str>>>, &'static str);
+    impl fmt::Display for Counter {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut log = self.0.lock().unwrap();
+            log.push(self.1);
+            write!(f, "{}{}", self.1, log.len())
+        }
+    }
+
+    let log = Arc::new(Mutex::new(vec![]));
+    let a = Counter(log.clone(), "a");
+    let b = Counter(log.clone(), "b");
+    let file = "/tmp/cmd_lib_test_interpolation_order.txt";
+    let callback_log = log.clone();
+    // the callback runs first, after all the values are evaluated
+    let output = run_fun! {
+        %{ move |_env| { callback_log.lock().unwrap().push("callback"); Ok(()) } };
+        echo $a > $file;
+        echo "$b-$a" >> $file;
+        /bin/cat $file
+    }
+    .unwrap();
+    assert_eq!(output, "a1\nb2-a3");
+    assert_eq!(*log.lock().unwrap(), ["a", "b", "a", "callback"]);
+
+    log.lock().unwrap().clear();
+    let mut child = spawn_with_output!(sh -c "sleep 0.1; echo $$1 $$2" _ $b $a).unwrap();
+    assert_eq!(*log.lock().unwrap(), ["b", "a"]);
+    assert_eq!(child.wait_with_output().unwrap(), "b1 a2");
+    assert_eq!(log.lock().unwrap().len(), 2);
+    run_cmd!(rm -f $file).unwrap();
+}
+
+#[test]
+fn test_punctuated_names() {
+    assert_eq!(
+        run_fun!(printf "%s|" g++-12 python3.11 @scope/cli clang++ docker-compose aws.cmd a.b-c+d)
+            .unwrap(),
+        "g++-12|python3.11|@scope/cli|clang++|docker-compose|aws.cmd|a.b-c+d|"
+    );
+    // tokens separated by spaces are still separate arguments
+    assert_eq!(
+        run_fun!(printf "%s|" clang ++ a. b - c).unwrap(),
+        "clang|++|a.|b|-|c|"
+    );
+
+    let names = [
+        "g++-12",
+        "python3.11",
+        "docker-compose",
+        "aws.cmd",
+        "my tool",
+    ];
+    Process::new()
+        .scratch_dir(false)
+        .unwrap()
+        .run(|| -> CmdResult {
+            let dir = current_dir();
+            run_cmd!(mkdir -p "my tool" @scope)?;
+            for name in names.iter().chain(&["my tool/run.sh", "@scope/cli"]) {
+                let script = dir.join(name);
+                if !script.is_dir() {
+                    std::fs::write(&script, "#!/bin/sh\necho \"${0##*/}\" \"$@\"\n")?;
+                    run_cmd!(chmod +x $script)?;
+                }
+            }
+            let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+            let output =
+                Process::new()
+                    .env(Env::new().set("PATH", path))
+                    .run(|| -> FunResult {
+                        Ok([
+                            run_fun!(g++-12 -O2)?,
+                            run_fun! { python3.11 -V }?,
+                            run_fun!(docker-compose up)?,
+                            run_fun!(aws.cmd s3)?,
+                            run_fun!("./my tool/run.sh" a)?,
+                            run_fun!(./@scope/cli b)?,
+                        ]
+                        .join("|"))
+                    })?;
+            assert_eq!(
+                output,
+                "g++-12 -O2|python3.11 -V|docker-compose up|aws.cmd s3|run.sh a|cli b"
+            );
+            Ok(())
+        })
+        .unwrap();
+}
+
 #[test]
 fn test_tls_set() {
     tls_init!(V, Vec<String>, vec![]);
@@ -145,9 +261,10 @@ fn test_pipe() {
     assert!(run_cmd!(false | wc).is_err());
     assert!(run_cmd!(echo xx | false | wc | wc | wc).is_err());
 
-    set_pipefail(false);
-    assert!(run_cmd!(du -ah . | sort -hr | head -n 10).is_ok());
-    set_pipefail(true);
+    {
+        let _pipefail = cmd_lib::config::with_config(|cfg| cfg.pipefail = Some(false));
+        assert!(run_cmd!(du -ah . | sort -hr | head -n 10).is_ok());
+    }
 
     let wc_cmd = "wc";
     assert!(run_cmd!(ls | $wc_cmd).is_ok());
@@ -180,6 +297,23 @@ fn test_redirect() {
     assert!(run_cmd!(rm -f $tmp_file $tmp_log).is_ok());
 }
 
+#[test]
+fn test_stderr_append_redirect() {
+    let err_log = "/tmp/cmd_lib_test_stderr_append.log";
+    let out_log = "/tmp/cmd_lib_test_stdout_append.log";
+    run_cmd!(rm -f $err_log $out_log).unwrap();
+    run_cmd! {
+        sh -c "echo out1; echo err1 >&2" > $out_log 2>> $err_log;
+        sh -c "echo out2; echo err2 >&2" > $out_log 2>> $err_log;
+    }
+    .unwrap();
+    assert_eq!(run_fun!(/bin/cat $err_log).unwrap(), "err1\nerr2");
+    assert_eq!(run_fun!(/bin/cat $out_log).unwrap(), "out2");
+    run_cmd!(sh -c "echo err3 >&2" 2> $err_log).unwrap();
+    assert_eq!(run_fun!(/bin/cat $err_log).unwrap(), "err3");
+    run_cmd!(rm -f $err_log $out_log).unwrap();
+}
+
 #[test]
 fn test_proc_env() {
     let output = run_fun!(FOO=100 printenv | grep FOO).unwrap();
@@ -233,6 +367,15 @@ fn test_current_dir() {
 /// run_cmd!(ls / /x > > /tmp/f).unwrap();
 /// run_cmd!(ls / /x >> > /tmp/f).unwrap();
 /// ```
+/// ```compile_fail
+/// run_cmd!(ls > /tmp/f | wc -l).unwrap();
+/// ```
+/// ```compile_fail
+/// run_cmd!(ls &> /tmp/f | wc -l).unwrap();
+/// ```
+/// ```compile_fail
+/// run_cmd!(ls >&2 | wc -l).unwrap();
+/// ```
 fn test_redirect_fail() {}
 
 #[test]
@@ -253,3 +396,2827 @@ fn test_path_as_var() {
     let dir2 = std::path::PathBuf::from("/");
     assert_eq!("/", run_fun!(cd $dir2; pwd).unwrap());
 }
+
+#[test]
+fn test_spawn_after() {
+    use std::path::Path;
+    use std::time::Duration;
+
+    let f = "/tmp/spawn_after_test";
+    run_cmd!(rm -f $f).unwrap();
+    let handle = spawn_after(Duration::from_millis(500), move || spawn!(touch $f));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!handle.is_started());
+    assert!(!Path::new(f).exists());
+    handle.wait().unwrap().wait().unwrap();
+    assert!(Path::new(f).exists());
+    run_cmd!(rm -f $f).unwrap();
+
+    let handle = spawn_after(Duration::from_secs(60), move || spawn!(touch $f));
+    assert!(handle.cancel());
+    assert!(handle.wait().is_err());
+    assert!(!Path::new(f).exists());
+
+    // spawned in the context of the scheduling thread
+    let registry = CmdRegistry::new();
+    registry.register_cmd("cmd_lib_test_scheduled", |env| {
+        use std::io::Write;
+        writeln!(env.stdout(), "scheduled")
+    });
+    let prev_dir = current_dir();
+    set_current_dir("/tmp").unwrap();
+    let pipefail = cmd_lib::config::with_config(|cfg| cfg.pipefail = Some(false));
+    let handle = with_registry(&registry, || {
+        spawn_after(Duration::from_millis(100), || {
+            assert_eq!(current_dir(), Path::new("/tmp"));
+            assert_eq!(run_fun!(pwd)?, "/tmp");
+            assert_eq!(run_fun!(cmd_lib_test_scheduled)?, "scheduled");
+            run_cmd!(false | true)?;
+            spawn!(true)
+        })
+    });
+    drop(pipefail);
+    set_current_dir(prev_dir).unwrap();
+    handle.wait().unwrap().wait().unwrap();
+}
+
+#[test]
+fn test_callback_statement() {
+    use std::io::Write;
+    let f = "/tmp/callback_statement_test";
+    assert!(run_cmd! {
+        cd /tmp;
+        echo xx > $f;
+        %{ move |env| {
+            assert!(env.last_succeeded());
+            assert_eq!(env.current_dir(), std::path::Path::new("/tmp"));
+            std::fs::metadata(f).map(|_| ())
+        } };
+        ignore ls /nofile;
+        %{ |env| { assert!(!env.last_succeeded()); Ok(()) } };
+        rm $f;
+    }
+    .is_ok());
+    assert!(run_cmd! {
+        %{ |_| Err(std::io::Error::new(std::io::ErrorKind::Other, "validation failed")) };
+        touch $f;
+    }
+    .is_err());
+    assert!(!std::path::Path::new(f).exists());
+    assert_eq!(
+        run_fun!(ignore false; %{ |env| {
+            let last_succeeded = env.last_succeeded();
+            writeln!(env.stdout(), "{}", last_succeeded)
+        } })
+            .unwrap(),
+        "false"
+    );
+    assert_eq!(run_fun!(date +%Y | wc -c).unwrap().trim(), "5");
+
+    // values computed by a closure reach the later commands
+    assert_eq!(
+        run_fun! {
+            %{ |env| { env.export("CMD_LIB_TEST_SUM", (1 + 2).to_string()); Ok(()) } };
+            %{ |env| { assert_eq!(env.env_var("CMD_LIB_TEST_SUM").unwrap(), "3"); Ok(()) } };
+            CMD_LIB_TEST_OTHER=4 sh -c "echo $$CMD_LIB_TEST_SUM $$CMD_LIB_TEST_OTHER"
+        }
+        .unwrap(),
+        "3 4"
+    );
+    assert!(std::env::var("CMD_LIB_TEST_SUM").is_err());
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn test_wait_to_mmap() {
+    let map = spawn_with_output!(seq 1 1000000).unwrap().wait_to_mmap().unwrap();
+    let expected: String = (1..=1000000).map(|i| format!("{}\n", i)).collect();
+    assert_eq!(map.len(), expected.len());
+    for off in [0, 7, 4096, 123457, expected.len() - 1] {
+        assert_eq!(map[off], expected.as_bytes()[off]);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_pass_fd() {
+    use std::os::unix::io::OwnedFd;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("echo")
+        .arg("hello fd")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pipe_reader = OwnedFd::from(child.stdout.take().unwrap());
+    let output = Process::new()
+        .pass_fd(9, pipe_reader)
+        .run(|| run_fun!(sh -c "cat <&9"))
+        .unwrap();
+    assert_eq!(output, "hello fd");
+    child.wait().unwrap();
+
+    // swapping two fds, each passed as the other
+    use std::os::unix::io::AsRawFd;
+    let open = |name: &str| {
+        let path = std::env::temp_dir().join(format!("cmd_lib_fd_{}_{}", name, std::process::id()));
+        std::fs::write(&path, name).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        OwnedFd::from(file)
+    };
+    let (a, b) = (open("a"), open("b"));
+    let (fd_a, fd_b) = (a.as_raw_fd(), b.as_raw_fd());
+    let script = format!("cat <&{}; cat <&{}", fd_a, fd_b);
+    let output = Process::new()
+        .pass_fd(fd_b, a)
+        .pass_fd(fd_a, b)
+        .run(|| run_fun!(sh -c $script))
+        .unwrap();
+    assert_eq!(output, "ba");
+}
+
+#[test]
+fn test_pipe_bytes_stats() {
+    let mut proc = Process::new()
+        .count_pipe_bytes(true)
+        .run(|| spawn_with_output!(seq 1 1000 | head -n 10 | wc -l))
+        .unwrap();
+    assert_eq!(proc.wait_with_output().unwrap().trim(), "10");
+    let stats = proc.stats();
+    assert_eq!(stats.stages.len(), 3);
+    assert!(stats.stages[0].stdout_bytes.unwrap() >= 21);
+    assert_eq!(stats.stages[1].stdout_bytes, Some(21));
+    assert!(stats.stages[2].stdout_bytes.unwrap() >= 3);
+
+    let mut proc = spawn_with_output!(seq 1 10 | wc -l).unwrap();
+    proc.wait_with_output().unwrap();
+    assert_eq!(proc.stats().stages[0].stdout_bytes, None);
+}
+
+#[test]
+fn test_progress() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    let reports = Arc::new(Mutex::new(vec![]));
+    let log = reports.clone();
+    let output = Process::new()
+        .count_pipe_bytes(true)
+        .run(|| spawn_with_output!(sh -c "seq 1 3; sleep 0.2; seq 4 6" | cat))
+        .unwrap()
+        .progress(Duration::ZERO, move |p| log.lock().unwrap().push(p))
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "1\n2\n3\n4\n5\n6");
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() >= 2);
+    assert!(reports.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+    let last = reports.last().unwrap();
+    assert_eq!(last.bytes, 12);
+    assert_eq!(last.stage_bytes, [12]);
+    assert!(last.elapsed >= Duration::from_millis(200));
+
+    // on the reader of wait_with_pipe(), and without stage counts
+    let reports = Arc::new(Mutex::new(vec![]));
+    let log = reports.clone();
+    spawn_with_output!(seq 1 100)
+        .unwrap()
+        .progress(Duration::from_secs(60), move |p| {
+            log.lock().unwrap().push(p)
+        })
+        .wait_with_pipe(&mut |mut stdout| {
+            std::io::copy(&mut stdout, &mut std::io::sink()).unwrap();
+        })
+        .unwrap();
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].bytes, 292);
+    assert!(reports[0].stage_bytes.is_empty());
+
+    // the last report of the other ways of reading the output
+    let seq = || spawn_with_output!(seq 1 100).unwrap();
+    let last_bytes = |children: FunChildren, read: &dyn Fn(FunChildren)| {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let log = reports.clone();
+        read(children.progress(Duration::from_secs(60), move |p| {
+            log.lock().unwrap().push(p.bytes)
+        }));
+        let reports = reports.lock().unwrap();
+        *reports.last().unwrap()
+    };
+    let timeout = Duration::from_secs(10);
+    let read_with_timeout = |mut c: FunChildren| drop(c.wait_with_output_timeout(timeout));
+    assert_eq!(last_bytes(seq(), &read_with_timeout), 292);
+    let scoped = Process::new()
+        .timeout(timeout)
+        .run(|| spawn_with_output!(seq 1 100))
+        .unwrap();
+    assert_eq!(last_bytes(scoped, &|mut c| drop(c.wait_with_output())), 292);
+    assert_eq!(last_bytes(seq(), &|mut c| drop(c.wait_split_lines())), 292);
+    let read_lines = |c: FunChildren| drop(c.stdout_lines_with_summary().unwrap().finish());
+    assert_eq!(last_bytes(seq(), &read_lines), 292);
+    let read_chunks = |c: FunChildren| drop(c.stdout_chunks(1).finish());
+    assert_eq!(last_bytes(seq(), &read_chunks), 292);
+}
+
+#[test]
+fn test_interpolated_cmd_name() {
+    #[export_cmd(interpolated_cmd)]
+    fn interpolated_cmd(env: &mut CmdEnv) -> CmdResult {
+        use std::io::Write;
+        writeln!(env.stdout(), "from custom cmd")
+    }
+    use_custom_cmd!(interpolated_cmd);
+
+    const CUSTOM: &str = "interpolated_cmd";
+    const LS: &str = "ls";
+    const NOPE: &str = "cmd_lib_no_such_program";
+    assert_eq!(run_fun!($CUSTOM).unwrap(), "from custom cmd");
+    assert_eq!(run_fun!($LS /).unwrap(), run_fun!(ls /).unwrap());
+
+    let literal_err = run_cmd!(cmd_lib_no_such_program arg).unwrap_err();
+    let interpolated_err = run_cmd!($NOPE arg).unwrap_err();
+    assert_eq!(literal_err.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(interpolated_err.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(literal_err.to_string(), interpolated_err.to_string());
+    assert!(literal_err.to_string().contains(NOPE));
+}
+
+#[test]
+fn test_arith_expansion() {
+    let a = 3;
+    let b = "4";
+    assert_eq!(run_fun!(echo $((a + b * 2))).unwrap(), "11");
+    assert_eq!(run_fun!(echo $(((a + b) * 2 ** 3 % 5))).unwrap(), "1");
+    assert_eq!(
+        run_fun!(echo $((2 ** 3 ** 2)) $((-2 ** 2)) $((7 / -2))).unwrap(),
+        "512 4 -3"
+    );
+    assert_eq!(
+        run_fun!(echo $((a < b)) $((a == b)) $((a + 1 != b))).unwrap(),
+        "1 0 0"
+    );
+    assert_eq!(run_fun!(echo file_$((a - 1)).txt).unwrap(), "file_2.txt");
+    let max = i64::MAX;
+    assert_eq!(run_fun!(echo $((max + 1))).unwrap(), i64::MIN.to_string());
+    assert_eq!(run_fun!(echo $((2 ** 64)) $((3 ** 4))).unwrap(), "0 81");
+
+    // invalid values fail the command, not the caller
+    let z = 0;
+    let word = "four";
+    let f = "/tmp/cmd_lib_arith_test";
+    for e in [
+        run_fun!(echo $((10 / z))).unwrap_err(),
+        run_fun!(echo $((10 % z))).unwrap_err(),
+        run_fun!(echo $((2 ** -1))).unwrap_err(),
+        run_cmd!(echo $((word + 1)) > $f).unwrap_err(),
+    ] {
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("in arithmetic expression"));
+    }
+    assert_eq!(run_fun!(ignore echo $((1 / z)); echo ok).unwrap(), "ok");
+    run_cmd!(rm -f $f).unwrap();
+}
+
+#[test]
+fn test_glob() {
+    use std::path::PathBuf;
+
+    let dir = "/tmp/cmd_lib_glob_test";
+    run_cmd! {
+        rm -rf $dir;
+        mkdir -p $dir/sub/deep $dir/.hidden;
+        touch $dir/a.rs $dir/sub/b.rs $dir/sub/deep/c.rs $dir/.hidden/d.rs;
+        touch $dir/README.TXT $dir/notes.txt;
+    }
+    .unwrap();
+    let paths =
+        |v: &[&str]| -> Vec<PathBuf> { v.iter().map(|p| PathBuf::from(dir).join(p)).collect() };
+
+    let rs_files = glob(&format!("{}/**/*.rs", dir)).unwrap();
+    assert_eq!(rs_files, paths(&["a.rs", "sub/b.rs", "sub/deep/c.rs"]));
+    assert_eq!(
+        glob(&format!("{}/*.TXT", dir)).unwrap(),
+        paths(&["README.TXT"])
+    );
+    let options = GlobOptions {
+        case_insensitive: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        glob_with(&format!("{}/*.TXT", dir), &options).unwrap(),
+        paths(&["README.TXT", "notes.txt"])
+    );
+    let options = GlobOptions {
+        extended: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        glob_with(&format!("{}/!(*.rs|sub)", dir), &options).unwrap(),
+        paths(&["README.TXT", "notes.txt"])
+    );
+    assert!(glob(&format!("{}/*.none", dir)).unwrap().is_empty());
+    assert_eq!(run_fun!(cat $[rs_files] | wc -c).unwrap().trim(), "0");
+
+    // a trailing `**` matches the files too
+    assert_eq!(
+        glob(&format!("{}/sub/**", dir)).unwrap(),
+        paths(&["sub", "sub/b.rs", "sub/deep", "sub/deep/c.rs"])
+    );
+    // but not the current directory itself
+    let prev_dir = current_dir();
+    set_current_dir(format!("{}/sub", dir)).unwrap();
+    let all = glob("**");
+    set_current_dir(prev_dir).unwrap();
+    assert_eq!(all.unwrap(), ["b.rs", "deep", "deep/c.rs"].map(PathBuf::from));
+    // the options for `glob()` in a scope
+    let options = GlobOptions {
+        case_insensitive: true,
+        ..Default::default()
+    };
+    let txt_files = Process::new()
+        .glob_options(options)
+        .run(|| glob(&format!("{}/*.TXT", dir)))
+        .unwrap();
+    assert_eq!(txt_files, paths(&["README.TXT", "notes.txt"]));
+
+    // many `*` don't make the matching exponential
+    let long_name = "a".repeat(40);
+    run_cmd!(touch $dir/$long_name).unwrap();
+    let started = std::time::Instant::now();
+    assert!(glob(&format!("{}/*a*a*a*a*a*a*a*a*a*a*b", dir))
+        .unwrap()
+        .is_empty());
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_transaction() {
+    use std::cell::RefCell;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    let dir = "/tmp/cmd_lib_transaction_test";
+    let undone = RefCell::new(vec![]);
+    let err = transaction()
+        .step(
+            || run_cmd!(mkdir -p $dir),
+            || {
+                undone.borrow_mut().push(0);
+                run_cmd!(rmdir $dir)
+            },
+        )
+        .step(
+            || run_cmd!(touch $dir/f),
+            || {
+                undone.borrow_mut().push(1);
+                run_cmd!(rm $dir/f)
+            },
+        )
+        .step(|| run_cmd!(ls $dir/nofile), || Ok(()))
+        .step(|| panic!("should not run"), || Ok(()))
+        .commit()
+        .unwrap_err();
+    assert!(err.to_string().starts_with("Transaction step 2 failed"));
+    // the error of the failed step is kept as the source
+    let cmd_err = CmdError::from_io_error(&err).unwrap();
+    assert_eq!(cmd_err.argv[0], "ls");
+    assert!(std::error::Error::source(err.get_ref().unwrap()).is_some());
+    assert_eq!(*undone.borrow(), vec![1, 0]);
+    assert!(!std::path::Path::new(dir).exists());
+
+    let err = transaction()
+        .step(|| Ok(()), || run_cmd!(false))
+        .step(|| run_cmd!(false), || Ok(()))
+        .commit()
+        .unwrap_err();
+    assert!(err.to_string().contains("undo step 0 failed"));
+
+    let interrupted = Arc::new(AtomicBool::new(true));
+    let err = transaction()
+        .interrupt_flag(interrupted)
+        .step(|| panic!("should not run"), || Ok(()))
+        .commit()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+}
+
+#[test]
+fn test_builtin_cat_binary() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use_builtin_cmd!(cat);
+    // a few megabytes of pseudo random bytes, including NUL, long lines and no trailing newline
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let data: Vec<u8> = (0..4 * 1024 * 1024 + 7)
+        .map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 56) as u8
+        })
+        .collect();
+    let checksum = |bytes: &[u8]| {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        hasher.finish()
+    };
+
+    let input = "/tmp/cmd_lib_binary_input";
+    let output = "/tmp/cmd_lib_binary_output";
+    std::fs::write(input, &data).unwrap();
+    assert!(run_cmd!(cat $input | cat | cat > $output).is_ok());
+    let copied = std::fs::read(output).unwrap();
+    assert_eq!(copied.len(), data.len());
+    assert_eq!(checksum(&copied), checksum(&data));
+
+    let mut buf = vec![];
+    spawn_with_output!(cat $input | cat)
+        .unwrap()
+        .wait_to_writer(&mut buf)
+        .unwrap();
+    assert_eq!(checksum(&buf), checksum(&data));
+    assert!(run_cmd!(rm -f $input $output).is_ok());
+}
+
+#[test]
+fn test_cmd_hook() {
+    register_cmd_hook(|cmd| {
+        if cmd.args.iter().any(|arg| arg == "cmd_lib_hook_test") {
+            cmd.args.push("--appended".into());
+            cmd.vars.insert("HOOK_VAR".into(), "set".into());
+        }
+    });
+    let name = "cmd_lib_hook_test";
+    assert_eq!(
+        run_fun!(echo $name).unwrap(),
+        "cmd_lib_hook_test --appended"
+    );
+    assert_eq!(
+        run_fun!(sh -c "printenv HOOK_VAR" cmd_lib_hook_test).unwrap(),
+        "set"
+    );
+}
+
+#[test]
+fn test_ignore_errors() {
+    let force = true;
+    assert!(spawn!(false).unwrap().wait().is_err());
+    let mut children = spawn!(false | cat).unwrap();
+    if force {
+        children = children.ignore_errors();
+    }
+    assert!(children.wait().is_ok());
+    assert_eq!(
+        spawn_with_output!(sh -c "echo hi; exit 1")
+            .unwrap()
+            .ignore_errors()
+            .wait_with_output()
+            .unwrap(),
+        "hi"
+    );
+}
+
+#[test]
+fn test_broken_pipe_sink() {
+    struct ClosingSink {
+        left: usize,
+    }
+    impl std::io::Write for ClosingSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.left == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.left);
+            self.left -= n;
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let err = spawn_with_output!(yes)
+        .unwrap()
+        .wait_to_writer(&mut ClosingSink { left: 1000 })
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    assert!(spawn_with_output!(yes | cat)
+        .unwrap()
+        .ignore_broken_pipe()
+        .wait_to_writer(&mut ClosingSink { left: 1000 })
+        .is_ok());
+}
+
+#[test]
+fn test_assert_output() {
+    assert_output(run_fun!(echo hello), "hello");
+    assert_output(run_fun!(printf "a\nb\n"), "a\nb");
+
+    let err = std::panic::catch_unwind(|| assert_output(run_fun!(printf "a\nc\nd"), "a\nb\nd"))
+        .unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains(" a\n-b\n+c\n d\n"), "{}", msg);
+    assert!(std::panic::catch_unwind(|| assert_output(run_fun!(false), "")).is_err());
+}
+
+#[test]
+fn test_timed() {
+    use std::time::Duration;
+
+    let (output, took) = run_fun_timed!(sleep 0.1; echo done).unwrap();
+    assert_eq!(output, "done");
+    assert!(took >= Duration::from_millis(100));
+
+    let took = spawn!(sleep 0.1).unwrap().wait_timed().unwrap();
+    assert!(took >= Duration::from_millis(100));
+    let (output, took) = spawn_with_output!(sh -c "sleep 0.1; echo done")
+        .unwrap()
+        .wait_with_output_timed()
+        .unwrap();
+    assert_eq!(output, "done");
+    assert!(took >= Duration::from_millis(100));
+
+    let err = run_cmd!(sh -c "sleep 0.1; exit 1").unwrap_err();
+    assert!(err.to_string().contains("exited with error after"));
+}
+
+#[test]
+fn test_bin_override() {
+    let output = Process::new()
+        .bin_override("cmd_lib_test_bin", "/bin/sh")
+        .run(|| run_fun!(cmd_lib_test_bin -c "echo overridden"))
+        .unwrap();
+    assert_eq!(output, "overridden");
+    let output = Process::new()
+        .bin_override("cmd_lib_test_bin", "/bin/sh")
+        .run(|| run_fun!(echo cmd_lib_test_bin))
+        .unwrap();
+    assert_eq!(output, "cmd_lib_test_bin");
+    assert!(run_cmd!(cmd_lib_test_bin -c "true").is_err());
+}
+
+#[test]
+fn test_option_guard() {
+    let file = "-rf";
+    let n = -5;
+    let opts = ["-l"];
+    let output = Process::new()
+        .option_guard(OptionGuard::Prefix)
+        .run(|| run_fun!(printf "[%s]" $file "$file.bak" x$file $n $[opts] -- $file))
+        .unwrap();
+    assert_eq!(output, "[./-rf][./-rf.bak][x-rf][-5][-l][--][-rf]");
+    let output = Process::new()
+        .option_guard(OptionGuard::Off)
+        .run(|| run_fun!(printf "[%s]" $file))
+        .unwrap();
+    assert_eq!(output, "[-rf]");
+    // only warns by default
+    assert_eq!(run_fun!(printf "[%s]" $file).unwrap(), "[-rf]");
+
+    // a lone `-` still reads stdin
+    let stdin = "-";
+    assert_eq!(run_fun!(echo hi | head -n 1 $stdin).unwrap(), "hi");
+    let output = Process::new()
+        .option_guard(OptionGuard::Prefix)
+        .run(|| run_fun!(echo hi | head -n 1 $stdin))
+        .unwrap();
+    assert_eq!(output, "hi");
+}
+
+#[test]
+fn test_confirm() {
+    use std::io::Cursor;
+
+    let confirm = |answer: &'static str| {
+        Confirm::new(|args| args[0] == "printf").input(Cursor::new(answer.as_bytes()))
+    };
+    let output = Process::new()
+        .confirm(confirm("y\n"))
+        .run(|| run_fun!(printf confirmed))
+        .unwrap();
+    assert_eq!(output, "confirmed");
+    let output = Process::new()
+        .confirm(confirm("yes\nn\n"))
+        .run(|| run_fun!(printf a; printf b));
+    assert!(output.is_err());
+    // declined by default, and unmatched commands run without asking
+    let ret = Process::new()
+        .confirm(confirm(""))
+        .run(|| run_cmd!(true | printf declined; true));
+    assert!(ret.is_err());
+    assert!(Process::new()
+        .confirm(confirm(""))
+        .run(|| run_cmd!(true))
+        .is_ok());
+}
+
+#[test]
+fn test_launcher() {
+    use std::ffi::OsString;
+
+    let launch = |argv: Vec<OsString>| {
+        let mut launched: Vec<OsString> = vec!["env".into(), "LAUNCHED=1".into()];
+        launched.extend(argv);
+        launched
+    };
+    let output = Process::new()
+        .launcher(launch)
+        .run(|| run_fun!(FOO=bar sh -c "printenv LAUNCHED FOO"))
+        .unwrap();
+    assert_eq!(output, "1\nbar");
+    // errors are reported with the original command line
+    let e = Process::new()
+        .launcher(launch)
+        .run(|| run_cmd!(sh -c "exit 2"))
+        .unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.command, r#"["sh", "-c", "exit 2"]"#);
+
+    // other tests run in parallel, so only rewrite a command unknown to them
+    set_launcher(|argv| {
+        if argv[0] == "cmd_lib_launched" {
+            vec!["echo".into(), "launched".into()]
+        } else {
+            argv
+        }
+    });
+    use_builtin_cmd!(echo);
+    let output = run_fun!(cmd_lib_launched);
+    let builtin = run_fun!(echo cmd_lib_launched);
+    reset_launcher();
+    assert_eq!(output.unwrap(), "launched");
+    assert_eq!(builtin.unwrap(), "cmd_lib_launched");
+    assert!(run_cmd!(cmd_lib_launched).is_err());
+}
+
+#[test]
+fn test_validate_cmd() {
+    let file = "/tmp/cmd_lib_test_validate.txt";
+    let report = validate_cmd! {
+        cd /tmp;
+        ls -l > $file;
+        cat < /dev/null;
+    }
+    .unwrap();
+    assert_eq!(report.commands.len(), 3);
+    assert_eq!(report.commands[0].program, None);
+    let ls = report.commands[1].program.as_ref().unwrap();
+    assert!(ls.is_absolute() && ls.ends_with("ls"));
+    assert!(!report.commands[1].needs_confirmation);
+    // nothing is run
+    assert!(!std::path::Path::new(file).exists());
+
+    let err = validate_cmd! {
+        cd /cmd_lib_no_such_dir;
+        cmd_lib_no_such_program | wc -l > /cmd_lib_no_such_dir/out.txt;
+        cat < /cmd_lib_no_such_file;
+    }
+    .unwrap_err();
+    assert_eq!(err.problems.len(), 4);
+    assert!(err.problems[1].contains("\"cmd_lib_no_such_program\" not found"));
+
+    let report = Process::new()
+        .bin_override("cmd_lib_test_bin", "/bin/sh")
+        .confirm(Confirm::new(|args| args[0] == "cmd_lib_test_bin"))
+        .run(|| validate_cmd!(cmd_lib_test_bin -c true))
+        .unwrap();
+    assert_eq!(
+        report.commands[0].program.as_deref(),
+        Some(std::path::Path::new("/bin/sh"))
+    );
+    assert!(report.commands[0].needs_confirmation);
+
+    // runtime templates, with the programs searched in the PATH they would get
+    let report = parse_cmd_line("ls -l | wc -l").unwrap().validate().unwrap();
+    assert_eq!(report.commands.len(), 2);
+    let err = parse_cmd_line("PATH=/cmd_lib_no_such_dir ls > /cmd_lib_no_such_dir/out.txt")
+        .unwrap()
+        .validate()
+        .unwrap_err();
+    assert_eq!(err.problems.len(), 2);
+    assert!(err.problems[0].contains("\"ls\" not found"));
+}
+
+#[test]
+fn test_exec() {
+    if std::env::var("CMD_LIB_TEST_EXEC").is_ok() {
+        let err = exec!(FOO=bar sh -c "echo replaced $$$$ $$FOO");
+        panic!("{}", err);
+    }
+    // run this test again in a child process, which is replaced by sh
+    let child = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_exec", "--exact", "--nocapture"])
+        .env("CMD_LIB_TEST_EXEC", "1")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // the pid is kept when the process is replaced
+    assert!(stdout
+        .trim_end()
+        .ends_with(&format!("replaced {} bar", pid)));
+
+    assert!(exec!(ls | wc).kind() == std::io::ErrorKind::InvalidInput);
+    assert!(exec!(cmd_lib_no_such_program)
+        .to_string()
+        .starts_with("Executing"));
+}
+
+#[test]
+fn test_exec_failed() {
+    if std::env::var("CMD_LIB_TEST_EXEC_FAILED").is_ok() {
+        // an argument too long for execve(), which fails after stdout and the directory are set
+        let cwd = std::env::current_dir().unwrap();
+        let dir = std::env::temp_dir();
+        let file = dir.join(format!("cmd_lib_exec_failed_{}.txt", std::process::id()));
+        let arg = "x".repeat(1 << 20);
+        set_current_dir(&dir).unwrap();
+        let err = exec!(echo $arg > $file);
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+        std::fs::remove_file(&file).unwrap();
+        std::io::Write::write_all(&mut std::io::stdout(), b"still here\n").unwrap();
+        assert!(err.to_string().starts_with("Executing"));
+        return;
+    }
+    // run this test again in a child process, which is left as it was
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_exec_failed", "--exact", "--nocapture"])
+        .env("CMD_LIB_TEST_EXEC_FAILED", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("still here"));
+}
+
+#[test]
+fn test_interactive() {
+    if std::env::var("CMD_LIB_TEST_INTERACTIVE").is_ok() {
+        let greeting = Process::new()
+            .interactive(true)
+            .run(|| run_fun!(sh -c "echo 'Name?' >&2; read name; echo hello $$name | tr a-z A-Z"));
+        println!("greeting: {}", greeting.unwrap());
+        return;
+    }
+    // run this test again in a child process, answering the prompt on its stdin
+    let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_interactive", "--exact", "--nocapture"])
+        .env("CMD_LIB_TEST_INTERACTIVE", "1")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), b"world\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    // the prompt is not logged, which discards it without a logger
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Name?"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("greeting: HELLO WORLD\n"));
+}
+
+#[test]
+fn test_spawn_command() {
+    use std::process::Command;
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo $CMD_LIB_TEST_ADOPTED; pwd"])
+        .env("CMD_LIB_TEST_ADOPTED", "kept")
+        .current_dir("/");
+    let output = spawn_command_with_output(cmd)
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "kept\n/");
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo oops >&2; exit 3"]);
+    let e = spawn_command(cmd).unwrap().wait().unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.command, r#"["sh", "-c", "echo oops >&2; exit 3"]"#);
+    assert_eq!(err.exit_code, Some(3));
+    assert_eq!(err.stderr_tail, vec!["oops"]);
+
+    let e = spawn_command(Command::new("cmd_lib_no_such_program"))
+        .unwrap()
+        .wait()
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_env_layers() {
+    use std::collections::HashMap;
+
+    std::env::set_var("CMD_LIB_TEST_ENV_INHERITED", "inherited");
+    let base = Env::new()
+        .set("CMD_LIB_TEST_ENV_A", "base")
+        .set("CMD_LIB_TEST_ENV_B", "base")
+        .set("CMD_LIB_TEST_ENV_C", "base");
+    let profile = Env::new()
+        .set("CMD_LIB_TEST_ENV_B", "profile")
+        .remove("CMD_LIB_TEST_ENV_C")
+        .remove("CMD_LIB_TEST_ENV_INHERITED")
+        .remove("CMD_LIB_TEST_ENV_A");
+    let overrides = HashMap::from([("CMD_LIB_TEST_ENV_A", "override")]);
+    let env = base.merge(&profile).with_overrides(overrides);
+    assert_eq!(env.get("CMD_LIB_TEST_ENV_B").as_deref(), Some("profile"));
+    assert_eq!(env.get("CMD_LIB_TEST_ENV_C"), None);
+
+    let script = "echo ${CMD_LIB_TEST_ENV_A-unset} ${CMD_LIB_TEST_ENV_B-unset} \
+                  ${CMD_LIB_TEST_ENV_C-unset} ${CMD_LIB_TEST_ENV_INHERITED-unset}";
+    let output = Process::new()
+        .env(env.clone())
+        .run(|| run_fun!(sh -c $script))
+        .unwrap();
+    assert_eq!(output, "override profile unset unset");
+    // variables of the command itself take precedence
+    let output = Process::new()
+        .env(env.clone())
+        .run(|| run_fun!(CMD_LIB_TEST_ENV_C=cmd sh -c $script))
+        .unwrap();
+    assert_eq!(output, "override profile cmd unset");
+
+    let cleared = env.merge(&Env::new().clear().set("CMD_LIB_TEST_ENV_D", "only"));
+    assert_eq!(cleared.get("CMD_LIB_TEST_ENV_A"), None);
+    assert_eq!(cleared.get("PATH"), None);
+    let output = Process::new()
+        .env(cleared)
+        .run(|| run_fun!(/usr/bin/env))
+        .unwrap();
+    assert_eq!(output, "CMD_LIB_TEST_ENV_D=only");
+}
+
+#[test]
+fn test_log_file_rotation() {
+    let dir = std::env::temp_dir().join(format!("cmd_lib_test_logs_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut out = LogFileSink::new(dir.join("out.log"));
+    out.max_size = 6;
+    out.max_files = 2;
+    let err = LogFileSink::new(dir.join("err.log"));
+    Process::new()
+        .stdout_log(out)
+        .stderr_log(err)
+        .run(|| run_cmd!(seq 1 10; sh -c "echo oops >&2"))
+        .unwrap();
+
+    // the relay threads are joined when the commands are waited for
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+    assert_eq!(read("out.log"), "10\n");
+    assert_eq!(read("out.log.1"), "7\n8\n9\n");
+    assert_eq!(read("out.log.2"), "4\n5\n6\n");
+    assert!(!dir.join("out.log.3").exists());
+    assert_eq!(read("err.log"), "oops\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_scratch_dir() {
+    use std::path::PathBuf;
+
+    let mut dir = PathBuf::new();
+    let output = Process::new()
+        .scratch_dir(false)
+        .unwrap()
+        .run(|| {
+            dir = current_dir();
+            run_cmd!(touch made_here)?;
+            run_fun!(ls)
+        })
+        .unwrap();
+    assert_eq!(output, "made_here");
+    assert!(dir.starts_with(std::env::temp_dir()));
+    assert!(!dir.exists());
+    assert_ne!(current_dir(), dir);
+
+    // kept when failed
+    let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        Process::new().scratch_dir(true).unwrap().run(|| {
+            dir = current_dir();
+            run_cmd!(false).unwrap();
+        })
+    }));
+    assert!(ret.is_err());
+    assert!(dir.is_dir());
+    std::fs::remove_dir(&dir).unwrap();
+
+    // kept when returning an error
+    let ret = Process::new().scratch_dir(true).unwrap().run(|| {
+        dir = current_dir();
+        run_cmd!(touch made_here; false)
+    });
+    assert!(ret.is_err());
+    assert!(dir.join("made_here").is_file());
+    std::fs::remove_dir_all(&dir).unwrap();
+    // and removed without `keep_on_failure`
+    let ret = Process::new().scratch_dir(false).unwrap().run(|| {
+        dir = current_dir();
+        run_cmd!(false)
+    });
+    assert!(ret.is_err());
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_builtin_env() {
+    use_builtin_cmd!(env);
+    let path = std::env::var("PATH").unwrap();
+    let vars = run_fun!(CMD_LIB_ENV_B=2 CMD_LIB_ENV_A=1 env).unwrap();
+    let lines: Vec<&str> = vars.lines().collect();
+    let mut sorted = lines.clone();
+    sorted.sort();
+    assert_eq!(lines, sorted);
+    assert!(lines.contains(&&*format!("PATH={}", path)));
+    let i = lines.iter().position(|l| *l == "CMD_LIB_ENV_A=1").unwrap();
+    assert_eq!(lines[i + 1], "CMD_LIB_ENV_B=2");
+    assert!(!run_fun!(env).unwrap().contains("CMD_LIB_ENV_A"));
+    assert!(run_cmd!(env - i).is_err());
+}
+
+#[test]
+fn test_run_script_file() {
+    let dir = "/tmp/cmd_lib_test_script";
+    let script = format!("{}/script.cmds", dir);
+    run_cmd!(rm -rf $dir; mkdir -p $dir).unwrap();
+    std::fs::write(
+        &script,
+        format!(
+            "# comment\n\necho 'a  b' \"c\" > {0}/out.txt\n  cat {0}/out.txt | tr a-z A-Z >> {0}/out.txt # append\nfalse\nFOO=bar sh -c 'echo $FOO' 2>&1 >{0}/foo.txt\n",
+            dir
+        ),
+    )
+    .unwrap();
+
+    let results = run_script_file(&script, &ScriptOptions::default()).unwrap();
+    let lines: Vec<&str> = results.iter().map(|(line, _)| line.as_str()).collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("cat "));
+    let ok: Vec<bool> = results.iter().map(|(_, res)| res.is_ok()).collect();
+    assert_eq!(ok, [true, true, false, true]);
+    assert_eq!(run_fun!(cat $dir/out.txt).unwrap(), "a  b c\nA  B C");
+    assert_eq!(run_fun!(cat $dir/foo.txt).unwrap(), "bar");
+
+    let opts = ScriptOptions {
+        stop_on_error: true,
+        ..Default::default()
+    };
+    assert_eq!(run_script_file(&script, &opts).unwrap().len(), 3);
+    let opts = ScriptOptions {
+        jobs: 4,
+        ..Default::default()
+    };
+    let results = run_script_file(&script, &opts).unwrap();
+    assert_eq!(results.len(), 4);
+    assert!(results[2].1.is_err());
+    assert!(run_script_file(format!("{}/none.cmds", dir), &opts).is_err());
+
+    assert!(parse_cmd_line("echo 'unterminated").is_err());
+    assert!(parse_cmd_line("echo a | | wc").is_err());
+    assert!(parse_cmd_line("echo a >").is_err());
+    // stdout can't go to both a file and the next command
+    let e = parse_cmd_line("echo hi > f | wc -c").err().unwrap();
+    assert!(e.to_string().contains("conflicting stdout"));
+    assert!(parse_cmd_line("echo hi >&2 | wc -c").is_err());
+    assert!(parse_cmd_line("echo hi 2>&1 | wc -c").is_ok());
+    // redirects alone are not a command
+    for line in ["|& echo hi", "2>&1 | cat", "> /tmp/x", "echo hi | > /tmp/x", "echo hi | "] {
+        assert!(parse_cmd_line(line).is_err(), "{}", line);
+    }
+    assert!(parse_cmd_line("< /dev/null cat").is_ok());
+    assert_eq!(
+        parse_cmd_line("sh -c 'echo $# $1' _ a\\ b\\$ c")
+            .unwrap()
+            .run_fun()
+            .unwrap(),
+        "2 a b$"
+    );
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_indirect_var() {
+    std::env::set_var("CMD_LIB_TEST_INDIRECT_TARGET", "bar");
+    let name = "CMD_LIB_TEST_INDIRECT_TARGET";
+    assert_eq!(run_fun!(echo ${!name}).unwrap(), "bar");
+    assert_eq!(
+        run_fun!(echo "${!name}/${name}").unwrap(),
+        "bar/CMD_LIB_TEST_INDIRECT_TARGET"
+    );
+
+    // unset targets and invalid names expand to an empty string
+    let name = "CMD_LIB_TEST_INDIRECT_UNSET";
+    assert_eq!(run_fun!(echo "[${!name}]").unwrap(), "[]");
+    let name = "";
+    assert_eq!(run_fun!(echo "[${!name}]").unwrap(), "[]");
+    let name = "A=B";
+    assert_eq!(run_fun!(echo x ${!name} y).unwrap(), "x  y");
+}
+
+#[test]
+fn test_wait_with_tees() {
+    let (mut a, mut b, mut c) = (vec![], vec![], vec![]);
+    let mut children = spawn_with_output!(seq 1 10000).unwrap();
+    let n = children
+        .wait_with_tees(&mut [&mut a, &mut b, &mut c])
+        .unwrap();
+    assert_eq!(n, a.len() as u64);
+    assert_eq!(
+        a,
+        format!("{}\n", run_fun!(seq 1 10000).unwrap()).into_bytes()
+    );
+    assert_eq!(a, b);
+    assert_eq!(a, c);
+
+    struct FailingSink;
+    impl std::io::Write for FailingSink {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "sink failed",
+            ))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut out = vec![];
+    let mut children = spawn_with_output!(echo hello).unwrap();
+    assert!(children
+        .wait_with_tees(&mut [&mut FailingSink, &mut out])
+        .is_err());
+    let mut children = spawn_with_output!(echo hello).unwrap().ignore_sink_errors();
+    let n = children
+        .wait_with_tees(&mut [&mut FailingSink, &mut out])
+        .unwrap();
+    assert_eq!((n, out), (6, b"hello\n".to_vec()));
+}
+
+#[test]
+fn test_strip_ansi() {
+    let colored = "\x1b[1;31merror\x1b[0m: \x1b[4mfile\x1b[24m not found";
+    assert_eq!(run_fun!(echo $colored).unwrap(), colored);
+    let output = Process::new()
+        .strip_ansi(true)
+        .run(|| run_fun!(echo $colored))
+        .unwrap();
+    assert_eq!(output, "error: file not found");
+
+    let (stdout, stderr) = Process::new()
+        .strip_ansi(true)
+        .run(|| spawn_with_output!(sh -c "echo '$colored'; echo '$colored' >&2"))
+        .unwrap()
+        .wait_split_lines()
+        .unwrap();
+    assert_eq!(stdout, ["error: file not found"]);
+    assert_eq!(stderr, ["error: file not found"]);
+}
+
+#[test]
+fn test_pathlike() {
+    let name = "CMD_LIB_TEST_PATHLIKE";
+    assert_eq!(append_pathlike(name, "/opt/a").unwrap(), "/opt/a");
+    assert_eq!(prepend_pathlike(name, "/opt/a").unwrap(), "/opt/a");
+
+    #[cfg(unix)]
+    {
+        std::env::set_var(name, "/usr/lib::/opt/a:/usr/local/lib");
+        assert_eq!(
+            append_pathlike(name, "/opt/a").unwrap(),
+            "/usr/lib:/usr/local/lib:/opt/a"
+        );
+        assert_eq!(
+            prepend_pathlike(name, "/opt/b").unwrap(),
+            "/opt/b:/usr/lib:/opt/a:/usr/local/lib"
+        );
+        assert!(append_pathlike(name, "/opt/a:/opt/b").is_err());
+        let value = append_pathlike(name, "/opt/c").unwrap();
+        assert_eq!(
+            run_fun!(CMD_LIB_TEST_PATHLIKE=$value printenv $name).unwrap(),
+            "/usr/lib:/opt/a:/usr/local/lib:/opt/c"
+        );
+    }
+    #[cfg(windows)]
+    {
+        std::env::set_var(name, r"C:\lib;;C:\a");
+        assert_eq!(append_pathlike(name, r"C:\a").unwrap(), r"C:\lib;C:\a");
+        assert_eq!(
+            prepend_pathlike(name, r"C:\b").unwrap(),
+            r"C:\b;C:\lib;C:\a"
+        );
+    }
+
+    // the value the commands would get, in a scope or an `Env` layer
+    let env = Env::new().set(name, "/scoped");
+    let scoped = Process::new()
+        .env(env.clone())
+        .run(|| append_pathlike(name, "/opt/d"));
+    let sep = if cfg!(windows) { ";" } else { ":" };
+    assert_eq!(scoped.unwrap(), format!("/scoped{}/opt/d", sep));
+    let env = env.prepend_pathlike(name, "/opt/e").unwrap();
+    assert_eq!(env.get(name).unwrap(), format!("/opt/e{}/scoped", sep));
+    let env = Env::new()
+        .remove(name)
+        .append_pathlike(name, "/opt/f")
+        .unwrap();
+    assert_eq!(env.get(name).unwrap(), "/opt/f");
+
+    #[cfg(unix)]
+    {
+        use_builtin_cmd!(pathmunge);
+        let dir = "/opt/g";
+        let value = run_fun! {
+            pathmunge CMD_LIB_TEST_PATHMUNGE $dir;
+            pathmunge CMD_LIB_TEST_PATHMUNGE /opt/h after;
+            pathmunge CMD_LIB_TEST_PATHMUNGE $dir after;
+            printenv CMD_LIB_TEST_PATHMUNGE
+        }
+        .unwrap();
+        assert_eq!(value, "/opt/h:/opt/g");
+        assert!(run_cmd!(pathmunge CMD_LIB_TEST_PATHMUNGE).is_err());
+    }
+}
+
+#[test]
+fn test_over_ssh() {
+    // show the ssh command line instead of connecting
+    let ssh = Process::new()
+        .bin_override("ssh", "/bin/echo")
+        // a local path, which the remote command doesn't get
+        .bin_override("ls", "/opt/cmd_lib_test/ls")
+        .over_ssh("example.com", &["-p", "2222"]);
+    let file = "it's a file";
+    let output = ssh.run(|| run_fun!(FOO="a b" ls -l $file)).unwrap();
+    assert_eq!(
+        output,
+        "-p 2222 example.com -- FOO='a b' ls -l 'it'\\''s a file'"
+    );
+}
+
+#[test]
+#[ignore = "needs ssh access to localhost without a password"]
+fn test_over_ssh_localhost() {
+    let output = Process::new()
+        .over_ssh("localhost", &["-o", "BatchMode=yes"])
+        .run(|| run_fun!(GREETING="hello world" printenv GREETING))
+        .unwrap();
+    assert_eq!(output, "hello world");
+}
+
+#[test]
+fn test_parse_kv() {
+    let props = run_fun!(printf "A=1\nB=x=y\n\nno separator\nA=2\nEMPTY=\n")
+        .parse_kv()
+        .unwrap();
+    assert_eq!(props.len(), 3);
+    assert_eq!(props["A"], "2");
+    assert_eq!(props["B"], "x=y");
+    assert_eq!(props["EMPTY"], "");
+    assert!(run_fun!(false).parse_kv().is_err());
+    assert_eq!("K=V".parse_kv()["K"], "V");
+}
+
+#[test]
+fn test_wait_with_diagnostics() {
+    let mut children = spawn_with_output!(
+        sh -c "echo 'error: a' >&2; echo out; echo 'warning: b' >&2"
+            | sh -c "cat; echo 'note: c' >&2; echo d >&2"
+    )
+    .unwrap();
+    let diagnostics = children.wait_with_diagnostics(|line| {
+        if line.starts_with("error:") {
+            DiagnosticKind::Error
+        } else if line.starts_with("warning:") {
+            DiagnosticKind::Warning
+        } else if line.starts_with("note:") {
+            DiagnosticKind::Note
+        } else {
+            DiagnosticKind::Other
+        }
+    });
+    assert_eq!(children.wait_with_output().unwrap(), "out");
+    let mut diagnostics: Vec<_> = diagnostics.iter().collect();
+    // the stages are read concurrently
+    diagnostics.sort_by_key(|d| d.stage_index);
+    let kinds: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.stage_index, d.kind, d.line.as_str()))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (0, DiagnosticKind::Error, "error: a"),
+            (0, DiagnosticKind::Warning, "warning: b"),
+            (1, DiagnosticKind::Note, "note: c"),
+            (1, DiagnosticKind::Other, "d"),
+        ]
+    );
+    assert!(diagnostics[0].command.contains("sh"));
+}
+
+#[test]
+fn test_wait_split_lines() {
+    let (stdout, stderr) = spawn_with_output!(
+        sh -c "echo out1; echo err1 >&2; echo; echo out2; printf err2 >&2" | cat
+    )
+    .unwrap()
+    .wait_split_lines()
+    .unwrap();
+    assert_eq!(stdout, vec!["out1", "", "out2"]);
+    assert_eq!(stderr, vec!["err1", "err2"]);
+
+    // large stderr output shouldn't block
+    let (stdout, stderr) = spawn_with_output!(sh -c "seq 100000 >&2; echo done")
+        .unwrap()
+        .wait_split_lines()
+        .unwrap();
+    assert_eq!(stdout, vec!["done"]);
+    assert_eq!(stderr.len(), 100000);
+}
+
+#[test]
+fn test_stdout_lines_with_summary() {
+    let mut lines = spawn_with_output!(
+        sh -c "echo err1 >&2; echo a; echo b; printf err2 >&2; printf c" | sh -c "cat; exit 4"
+    )
+    .unwrap()
+    .stdout_lines_with_summary()
+    .unwrap();
+    assert_eq!(lines.next().unwrap(), "a");
+    let summary = lines.finish().unwrap();
+    assert!(!summary.success);
+    assert_eq!(summary.exit_code, Some(4));
+    assert_eq!(summary.failed_stage, Some(1));
+    assert_eq!(summary.stdout_lines, 3);
+    assert_eq!(summary.stderr_lines, 2);
+
+    let mut lines = spawn_with_output!(seq 3)
+        .unwrap()
+        .stdout_lines_with_summary()
+        .unwrap();
+    assert_eq!(lines.by_ref().collect::<Vec<_>>(), vec!["1", "2", "3"]);
+    let summary = lines.finish().unwrap();
+    assert!(summary.success);
+    assert_eq!(summary.exit_code, Some(0));
+    assert_eq!(summary.stdout_lines, 3);
+    assert_eq!(summary.stderr_lines, 0);
+}
+
+#[test]
+fn test_stdout_lines_until() {
+    let started = std::time::Instant::now();
+    let mut lines = spawn_with_output!(sh -c "echo a; echo b; sleep 10; echo c")
+        .unwrap()
+        .stdout_lines_until(std::time::Duration::from_millis(500))
+        .unwrap();
+    let items: Vec<_> = lines.by_ref().collect();
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[0].as_ref().unwrap(), "a");
+    assert_eq!(items[1].as_ref().unwrap(), "b");
+    let err = items[2].as_ref().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    let summary = lines.finish().unwrap();
+    assert!(!summary.success);
+    assert_eq!(summary.stdout_lines, 2);
+
+    // the partial line read before the deadline is kept
+    let mut lines = spawn_with_output!(sh -c "echo a; printf part; exec sleep 10")
+        .unwrap()
+        .stdout_lines_until(std::time::Duration::from_millis(500))
+        .unwrap();
+    let items: Vec<_> = lines.by_ref().collect();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[1].as_ref().unwrap(), "part");
+    assert!(items[2].is_err());
+    assert_eq!(lines.finish().unwrap().stdout_lines, 2);
+
+    // ending before the deadline
+    let lines: Vec<_> = spawn_with_output!(seq 3)
+        .unwrap()
+        .stdout_lines_until(std::time::Duration::from_secs(10))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(lines, vec!["1", "2", "3"]);
+}
+
+#[test]
+fn test_cmd_error() {
+    let e = run_cmd!(echo ok | sh -c "echo oops >&2; exit 3").unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.command, r#"["sh", "-c", "echo oops >&2; exit 3"]"#);
+    assert_eq!(
+        err.pipeline,
+        r#"["echo", "ok"] | ["sh", "-c", "echo oops >&2; exit 3"]"#
+    );
+    assert_eq!(err.stage_index, 1);
+    assert_eq!(err.exit_code, Some(3));
+    assert_eq!(err.signal, None);
+    assert_eq!(err.stderr_tail, vec!["oops"]);
+    assert!(e
+        .to_string()
+        .starts_with(&format!("Running {} exited with error after", err.command)));
+    assert!(e.to_string().ends_with("; status code: 3"));
+
+    let e = run_cmd!(cmd_lib_no_such_program).unwrap_err();
+    assert!(CmdError::from_io_error(&e).is_none());
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error as _;
+    use std::io::{Error, ErrorKind};
+
+    // spawning errors keep the error from the OS as the source
+    let e = run_cmd!(cmd_lib_no_such_program).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    let source = e.source().unwrap().downcast_ref::<Error>().unwrap();
+    assert_eq!(source.kind(), ErrorKind::NotFound);
+    assert!(e.to_string().ends_with(&source.to_string()));
+    assert!(source.source().is_none());
+
+    // failed commands have the error of a custom command as the source
+    #[export_cmd(cmd_lib_test_denied)]
+    fn denied(_env: &mut CmdEnv) -> CmdResult {
+        Err(Error::new(ErrorKind::PermissionDenied, "no access"))
+    }
+    use_custom_cmd!(cmd_lib_test_denied);
+    let e = run_fun!(cmd_lib_test_denied).unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    let mut chain = vec![];
+    let mut source = err.source();
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    assert_eq!(chain, ["no access"]);
+    assert_eq!(e.source().unwrap().to_string(), "no access");
+}
+
+#[test]
+fn test_long_cmd_truncated() {
+    let files: Vec<String> = (0..2000).map(|i| format!("file{}", i)).collect();
+    let e = run_cmd!(false $[files]).unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert!(err.command.starts_with(r#"["false", "file0", "file1", "#));
+    assert!(err.command.ends_with(" bytes elided)"));
+    assert!(err.command.len() < 4200);
+    assert!(e.to_string().len() < 4400);
+    assert_eq!(err.argv.len(), 2001);
+    assert_eq!(err.argv[2000], "file1999");
+}
+
+// the json field names are stable, don't change this test without a major version bump
+#[cfg(feature = "serde")]
+#[test]
+fn test_cmd_error_json() {
+    let e = run_cmd!(sh -c "echo oops >&2; exit 3").unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    let cmd = r#"[\"sh\", \"-c\", \"echo oops >&2; exit 3\"]"#;
+    let expected = format!(
+        r#"{{"command":"{}","pipeline":"{}","stage_index":0,"exit_code":3,"signal":null,"kind":"Other","duration_ms":{},"stderr_tail":["oops"]}}"#,
+        cmd,
+        cmd,
+        err.duration.as_millis()
+    );
+    assert_eq!(err.to_json(), expected);
+}
+
+#[test]
+fn test_execution_record() {
+    let mut children = spawn!(true | FOO=bar sh -c "exit 3").unwrap();
+    assert!(children.last_execution_record().is_none());
+    assert!(children.wait().is_err());
+    let record = children.last_execution_record().unwrap();
+    assert_eq!(record.argv, vec!["sh", "-c", "exit 3"]);
+    assert_eq!(record.current_dir, std::env::current_dir().unwrap());
+    assert_eq!(record.env.get("FOO").unwrap(), "bar");
+    assert_eq!(record.env.len(), 1);
+    assert!(!record.success);
+    assert_eq!(record.exit_code, Some(3));
+    assert_eq!(record.signal, None);
+
+    let mut children = spawn!(ignore ls /).unwrap();
+    children.wait().unwrap();
+    let record = children.last_execution_record().unwrap();
+    assert_eq!(record.argv, vec!["ls", "/"]);
+    assert!(record.success);
+    assert_eq!(record.exit_code, Some(0));
+}
+
+#[test]
+fn test_run_fun_trailing_newline() {
+    use_builtin_cmd!(echo);
+    assert_eq!(run_fun!(echo abc).unwrap(), "abc");
+    assert_eq!(run_fun!(echo -n abc).unwrap(), "abc");
+    assert_eq!(run_fun!(echo "abc\n").unwrap(), "abc\n");
+    assert_eq!(run_fun!(/bin/echo abc).unwrap(), "abc");
+    assert_eq!(run_fun!(/bin/echo -n abc).unwrap(), "abc");
+    assert_eq!(run_fun!(printf "a\nb\n").unwrap(), "a\nb");
+    assert_eq!(run_fun!(printf "a\n\n").unwrap(), "a\n");
+    assert_eq!(run_fun!(printf "abc").unwrap(), "abc");
+    assert_eq!(run_fun!(printf "").unwrap(), "");
+    assert_eq!(run_fun!(printf "a\n\n" | cat).unwrap(), "a\n");
+}
+
+#[test]
+fn test_executor() {
+    use std::io::{Error, ErrorKind, Write};
+
+    use_builtin_cmd!(cat);
+    let mock = |env: &mut CmdEnv| {
+        let args = env.args().to_vec();
+        match args[0].as_str() {
+            "git" => writeln!(env.stdout(), "mocked {}", args[1..].join(" ")),
+            _ => Err(Error::new(ErrorKind::Other, "mocked failure")),
+        }
+    };
+    let process = || Process::new().executor(mock);
+    assert_eq!(
+        process().run(|| run_fun!(git status)).unwrap(),
+        "mocked status"
+    );
+    assert_eq!(
+        process().run(|| run_fun!(git log | cat)).unwrap(),
+        "mocked log"
+    );
+    let err = process().run(|| run_cmd!(make install)).unwrap_err();
+    assert!(err.to_string().contains("mocked failure"));
+
+    let output = Process::new()
+        .executor(DefaultExecutor)
+        .run(|| run_fun!(FOO=real sh -c "printenv FOO"))
+        .unwrap();
+    assert_eq!(output, "real");
+    assert!(Process::new()
+        .executor(DefaultExecutor)
+        .run(|| run_cmd!(false))
+        .is_err());
+}
+
+#[test]
+fn test_wait_with_output_timeout() {
+    use std::time::{Duration, Instant};
+
+    let started = Instant::now();
+    let e = spawn_with_output!(sh -c "echo first; printf second; exec sleep 10")
+        .unwrap()
+        .wait_with_output_timeout(Duration::from_millis(500))
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    let partial = PartialOutput::from_io_error(&e).unwrap();
+    assert_eq!(partial.output, "first\nsecond");
+
+    let output = spawn_with_output!(sh -c "echo done")
+        .unwrap()
+        .wait_with_output_timeout(Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(output, "done");
+    assert!(spawn_with_output!(false)
+        .unwrap()
+        .wait_with_output_timeout(Duration::from_secs(10))
+        .is_err());
+}
+
+#[test]
+fn test_chunk_read_timeout() {
+    use std::time::{Duration, Instant};
+
+    let started = Instant::now();
+    let e = spawn_with_output!(sh -c "echo first; exec sleep 10; echo second")
+        .unwrap()
+        .chunk_read_timeout(Duration::from_millis(500))
+        .wait_with_output()
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    let partial = PartialOutput::from_io_error(&e).unwrap();
+    assert_eq!(partial.output, "first\n");
+
+    // output keeps arriving within the timeout, for longer than the timeout in total
+    let output = spawn_with_output!(sh -c "for i in 1 2 3 4; do echo $$i; sleep 0.2; done")
+        .unwrap()
+        .chunk_read_timeout(Duration::from_secs(2))
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "1\n2\n3\n4");
+
+    let mut lines = spawn_with_output!(sh -c "echo first; exec sleep 10")
+        .unwrap()
+        .chunk_read_timeout(Duration::from_millis(500))
+        .stdout_lines_with_summary()
+        .unwrap();
+    assert_eq!(lines.next().as_deref(), Some("first"));
+    assert_eq!(lines.next(), None);
+    assert_eq!(
+        lines.finish().unwrap_err().kind(),
+        std::io::ErrorKind::TimedOut
+    );
+}
+
+#[test]
+fn test_alias_first() {
+    alias_first(
+        "cmd_lib_test_count",
+        ["cmd_lib_test_missing -x", "", "grep -c"],
+    );
+    let pattern = "a";
+    assert_eq!(
+        run_fun!(printf "a\nb\na\n" | cmd_lib_test_count $pattern).unwrap(),
+        "2"
+    );
+    assert_eq!(
+        run_fun!(ignore cmd_lib_test_count b < /dev/null).unwrap(),
+        "0"
+    );
+
+    alias_first(
+        "cmd_lib_test_none",
+        ["cmd_lib_test_missing", "cmd_lib_test_missing2 -a"],
+    );
+    let e = run_cmd!(cmd_lib_test_none x).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    assert!(e
+        .to_string()
+        .contains("tried: cmd_lib_test_missing, cmd_lib_test_missing2 -a"));
+    assert!(run_fun!(cmd_lib_test_none).is_err());
+    assert!(run_cmd!(ignore cmd_lib_test_none).is_ok());
+}
+
+#[test]
+fn test_alias() {
+    std::env::set_var("CMD_LIB_TEST_ALIAS_WORD", "hi");
+    alias(
+        "cmd_lib_test_say",
+        "printf '%s-%s|' $CMD_LIB_TEST_ALIAS_WORD ${CMD_LIB_TEST_ALIAS_WORD}x $$HOME",
+    )
+    .unwrap();
+    assert_eq!(run_fun!(cmd_lib_test_say end).unwrap(), "hi-hix|$HOME-end|");
+    // variables are expanded when run
+    std::env::set_var("CMD_LIB_TEST_ALIAS_WORD", "bye");
+    alias("cmd_lib_test_say2", "cmd_lib_test_say \"a b\"").unwrap();
+    assert_eq!(
+        run_fun!(cmd_lib_test_say2 end).unwrap(),
+        "bye-byex|$HOME-a b|end-|"
+    );
+    // not expanded in single quotes or escaped, unlike in double quotes
+    alias(
+        "cmd_lib_test_quoted",
+        r#"printf '%s|' '$CMD_LIB_TEST_ALIAS_WORD' \$HOME "$CMD_LIB_TEST_ALIAS_WORD" '$$'"#,
+    )
+    .unwrap();
+    assert_eq!(
+        run_fun!(cmd_lib_test_quoted).unwrap(),
+        "$CMD_LIB_TEST_ALIAS_WORD|$HOME|bye|$$|"
+    );
+
+    alias("cmd_lib_test_loop1", "cmd_lib_test_loop2 x").unwrap();
+    alias("cmd_lib_test_loop2", "cmd_lib_test_loop1 y").unwrap();
+    let e = run_cmd!(cmd_lib_test_loop1).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(e.to_string().contains(
+        "Recursive alias: cmd_lib_test_loop1 -> cmd_lib_test_loop2 -> cmd_lib_test_loop1"
+    ));
+    alias("cmd_lib_test_self", "cmd_lib_test_self -v").unwrap();
+    let e = run_cmd!(cmd_lib_test_self).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(e
+        .to_string()
+        .contains("Recursive alias: cmd_lib_test_self -> cmd_lib_test_self"));
+
+    // nested aliases, up to the depth limit
+    alias("cmd_lib_test_nest1", "printf '%s|' 1").unwrap();
+    alias("cmd_lib_test_nest2", "cmd_lib_test_nest1 2").unwrap();
+    alias("cmd_lib_test_nest3", "cmd_lib_test_nest2 3").unwrap();
+    assert_eq!(run_fun!(cmd_lib_test_nest3 4).unwrap(), "1|2|3|4|");
+    {
+        let _config = cmd_lib::config::with_config(|cfg| cfg.max_alias_depth = Some(3));
+        assert_eq!(run_fun!(cmd_lib_test_nest3).unwrap(), "1|2|3|");
+    }
+    {
+        let _config = cmd_lib::config::with_config(|cfg| cfg.max_alias_depth = Some(2));
+        let e = run_cmd!(cmd_lib_test_nest3).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains(
+            "limit of 2: cmd_lib_test_nest3 -> cmd_lib_test_nest2 -> cmd_lib_test_nest1"
+        ));
+    }
+
+    assert!(alias("cmd_lib_test_bad", "ls | wc").is_err());
+    assert!(alias("cmd_lib_test_bad", " ").is_err());
+}
+
+#[test]
+fn test_stdout_chunks() {
+    use std::time::Duration;
+
+    Process::new()
+        .scratch_dir(false)
+        .unwrap()
+        .run(|| -> CmdResult {
+            let progress = current_dir().join("progress");
+            let script =
+                "for i in $(seq 1 100); do head -c 65536 /dev/zero; echo $i > progress; done";
+            let mut chunks = spawn_with_output!(sh -c $script)?.stdout_chunks(2);
+            let mut total = chunks.next().unwrap().len();
+            std::thread::sleep(Duration::from_millis(500));
+            // the producer is blocked by the full channel and pipe, not done writing
+            let written: usize = std::fs::read_to_string(&progress)?.trim().parse().unwrap();
+            assert!(written < 20, "{} blocks written", written);
+            for chunk in chunks.by_ref() {
+                total += chunk.len();
+            }
+            chunks.finish()?;
+            assert_eq!(total, 100 * 65536);
+            Ok(())
+        })
+        .unwrap();
+
+    let chunks = spawn_with_output!(sh -c "echo hi; exit 3")
+        .unwrap()
+        .stdout_chunks(0);
+    assert!(chunks.finish().is_err());
+}
+
+#[test]
+fn test_wait_until_line() {
+    use std::time::Duration;
+
+    let mut server =
+        spawn_with_output!(sh -c "sleep 0.2; echo starting; echo ready; sleep 0.3; echo more")
+            .unwrap();
+    let line = server
+        .wait_until_line(|line| line.starts_with("ready"), Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(line, "ready");
+    assert!(server.wait_with_output().is_ok());
+
+    let mut slow = spawn_with_output!(sh -c "sleep 1; echo ready").unwrap();
+    let err = slow
+        .wait_until_line(|line| line == "ready", Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(slow.wait_with_output().is_ok());
+
+    let mut quiet = spawn_with_output!(echo hello).unwrap();
+    let err = quiet
+        .wait_until_line(|line| line == "ready", Duration::from_secs(5))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_set_current_dir() {
+    std::thread::spawn(|| {
+        assert_eq!(current_dir(), std::env::current_dir().unwrap());
+        assert!(set_current_dir("/cmd_lib_no_such_dir").is_err());
+        set_current_dir("/usr").unwrap();
+        assert_eq!(run_fun!(pwd).unwrap(), "/usr");
+        set_current_dir("bin").unwrap();
+        assert_eq!(current_dir(), std::path::Path::new("/usr/bin"));
+        assert_eq!(
+            run_fun! {
+                cd ..;
+                pwd;
+            }
+            .unwrap(),
+            "/usr"
+        );
+        // cd is still scoped to the block
+        assert_eq!(run_fun!(pwd).unwrap(), "/usr/bin");
+    })
+    .join()
+    .unwrap();
+    assert_eq!(current_dir(), std::env::current_dir().unwrap());
+}
+
+#[test]
+fn test_strip_bom() {
+    let text = "\u{feff}hello";
+    assert_eq!(run_fun!(printf $text).unwrap(), text);
+    let output = Process::new()
+        .strip_bom(true)
+        .run(|| run_fun!(printf $text))
+        .unwrap();
+    assert_eq!(output, "hello");
+    let output = Process::new()
+        .strip_bom(true)
+        .run(|| spawn_with_output!(printf "a\u{feff}b"))
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "a\u{feff}b");
+}
+
+#[test]
+fn test_builtins_follow_cwd() {
+    #[export_cmd(cmd_lib_test_touch)]
+    fn touch(env: &mut CmdEnv) -> CmdResult {
+        use std::io::Write;
+        let value = env.env_var("CMD_LIB_TEST_CWD_VAR").unwrap_or_default();
+        std::fs::write(env.resolve_path(&env.args()[1]), format!("{}\n", value))?;
+        writeln!(env.stdout(), "{}", value)
+    }
+    use_custom_cmd!(cmd_lib_test_touch);
+    use_builtin_cmd!(cat);
+
+    let env = Env::new().set("CMD_LIB_TEST_CWD_VAR", "from env");
+    let ret = Process::new()
+        .scratch_dir(false)
+        .unwrap()
+        .env(env)
+        .run(|| -> CmdResult {
+            run_cmd! {
+                mkdir sub;
+                cd sub;
+                cmd_lib_test_touch a.txt;
+                cmd_lib_test_touch b.txt | cat > c.txt;
+                CMD_LIB_TEST_CWD_VAR=own cmd_lib_test_touch d.txt;
+                cat a.txt > e.txt;
+            }?;
+            assert_eq!(glob("sub/*.txt")?.len(), 5);
+            let read = |name| std::fs::read_to_string(current_dir().join("sub").join(name));
+            assert_eq!(read("c.txt")?, "from env\n");
+            assert_eq!(read("d.txt")?, "own\n");
+            assert_eq!(read("e.txt")?, "from env\n");
+            assert_eq!(run_fun!(cd sub; cat d.txt)?, "own");
+            Ok(())
+        });
+    assert!(ret.is_ok());
+}
+
+#[test]
+fn test_retry_backoff() {
+    use std::time::Duration;
+
+    let ms = Duration::from_millis;
+    let opts = RetryOptions {
+        attempts: 6,
+        base_delay: ms(1),
+        multiplier: 3.0,
+        max_delay: ms(20),
+        full_jitter: false,
+        seed: Some(42),
+    };
+    let delays: Vec<Duration> = opts.delays().collect();
+    assert_eq!(delays, [ms(1), ms(3), ms(9), ms(20), ms(20)]);
+
+    // the jitter is the same for the same seed, and up to the delay without jitter
+    let jittered = RetryOptions {
+        full_jitter: true,
+        ..opts.clone()
+    };
+    let first: Vec<Duration> = jittered.delays().collect();
+    assert_eq!(first, jittered.delays().collect::<Vec<_>>());
+    assert!(first.iter().zip(&delays).all(|(j, d)| j <= d));
+    assert_ne!(first, delays);
+
+    // succeeds on the last attempt
+    let mut attempts = 0;
+    let ret = retry(&jittered, || {
+        attempts += 1;
+        let n = attempts;
+        run_fun!(test $n -ge 6; echo done)
+    });
+    assert_eq!(ret.unwrap(), "done");
+    assert_eq!(attempts, 6);
+
+    // returns the last error when all attempts fail
+    let mut attempts = 0;
+    let ret = retry(&jittered, || {
+        attempts += 1;
+        run_cmd!(false)
+    });
+    assert!(ret.is_err());
+    assert_eq!(attempts, 6);
+}
+
+#[test]
+fn test_wait_records() {
+    // the records span the writes of the command
+    let mut records = vec![];
+    let rest =
+        spawn_with_output!(sh -c "printf abc; sleep 0.1; printf defg; sleep 0.1; printf hij")
+            .unwrap()
+            .wait_records(4, &mut |record| {
+                records.push(String::from_utf8_lossy(record).to_string());
+                Ok(())
+            })
+            .unwrap();
+    assert_eq!(records, ["abcd", "efgh"]);
+    assert_eq!(rest, b"ij");
+
+    let mut count = 0;
+    let rest = spawn_with_output!(printf abcdef)
+        .unwrap()
+        .wait_records(3, &mut |_| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!((count, rest.len()), (2, 0));
+
+    // the children are killed and waited for
+    let started = std::time::Instant::now();
+    let mut children = spawn_with_output!(sleep 10).unwrap();
+    let e = children.wait_records(0, &mut |_| Ok(())).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(children.stats().stages[0].success, Some(false));
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn test_wait_records_regex() {
+    let log = "starting\n\
+               2024-01-01 info: ready\n\
+               2024-01-01 error: failed\n  at main.rs\n  at lib.rs\n\
+               2024-01-02 info: done";
+    let start = cmd_lib::regex::Regex::new("^2024-").unwrap();
+    let mut records = vec![];
+    let leading = spawn_with_output!(printf "%s" $log)
+        .unwrap()
+        .wait_records_regex(&start, &mut |record| {
+            records.push(record.to_string());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(leading, "starting\n");
+    assert_eq!(
+        records,
+        [
+            "2024-01-01 info: ready\n",
+            "2024-01-01 error: failed\n  at main.rs\n  at lib.rs\n",
+            "2024-01-02 info: done",
+        ]
+    );
+
+    // the lines span the writes of the command
+    let mut records = vec![];
+    let leading = spawn_with_output!(sh -c "printf '2024-a\n  b'; sleep 0.1; printf 'c\n2024-d\n'")
+        .unwrap()
+        .wait_records_regex(&start, &mut |record| {
+            records.push(record.to_string());
+            Ok(())
+        })
+        .unwrap();
+    assert!(leading.is_empty());
+    assert_eq!(records, ["2024-a\n  bc\n", "2024-d\n"]);
+
+    // no record at all
+    let mut count = 0;
+    let leading = spawn_with_output!(echo no match)
+        .unwrap()
+        .wait_records_regex(&start, &mut |_| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!((leading.as_str(), count), ("no match\n", 0));
+}
+
+#[test]
+fn test_process_timeout() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    let ms = Duration::from_millis;
+    let started = Instant::now();
+    let e = Process::new()
+        .timeout(ms(200))
+        .run(|| run_cmd!(sleep 5))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+    let e = Process::new()
+        .timeout(ms(200))
+        .run(|| run_fun!(sh -c "echo started; exec sleep 5"))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+    assert_eq!(
+        PartialOutput::from_io_error(&e).unwrap().output,
+        "started\n"
+    );
+    assert!(started.elapsed() < ms(3000));
+
+    // applies to the children waited for after run(), unless overridden per call
+    let mut children = Process::new()
+        .timeout(ms(100))
+        .run(|| spawn!(sleep 0.3))
+        .unwrap();
+    children.wait_timeout(Duration::from_secs(5)).unwrap();
+    let mut children = Process::new()
+        .timeout(ms(100))
+        .run(|| spawn!(sleep 5))
+        .unwrap()
+        .ignore_errors();
+    assert_eq!(children.wait().unwrap_err().kind(), ErrorKind::TimedOut);
+    let output = Process::new()
+        .timeout(ms(100))
+        .run(|| spawn_with_output!(sh -c "sleep 0.3; echo done"))
+        .unwrap()
+        .wait_with_output_timeout(Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(output, "done");
+    assert!(Process::new()
+        .timeout(Duration::from_secs(5))
+        .run(|| run_cmd!(sleep 0.1))
+        .is_ok());
+}
+
+#[test]
+#[cfg(feature = "grep")]
+fn test_builtin_grep() {
+    use_builtin_cmd!(grep);
+    let input = "error: one\nwarning: two\nERROR: three\ninfo: error four";
+    assert_eq!(run_fun!(echo $input | grep "^error").unwrap(), "error: one");
+    assert_eq!(
+        run_fun!(echo $input | grep -i "^error").unwrap(),
+        "error: one\nERROR: three"
+    );
+    assert_eq!(
+        run_fun!(echo $input | grep -v error).unwrap(),
+        "warning: two\nERROR: three"
+    );
+    assert_eq!(
+        run_fun!(echo $input | grep -iv error).unwrap(),
+        "warning: two"
+    );
+    // fails like grep when no line is selected, or with a bad pattern
+    assert!(run_fun!(echo $input | grep "^debug").is_err());
+    assert!(run_fun!(echo $input | grep "(").is_err());
+}
+
+#[test]
+fn test_wait_timeout_wakes_on_exit() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    let ms = Duration::from_millis;
+    // returns as soon as the pipeline exits, well before the timeout
+    let started = Instant::now();
+    let mut children = spawn!(sleep 0.2 | wc -c).unwrap();
+    children.wait_timeout(Duration::from_secs(10)).unwrap();
+    assert!(started.elapsed() >= ms(200));
+    assert!(started.elapsed() < ms(5000));
+
+    let mut children = spawn!(sleep 5).unwrap();
+    let e = children.wait_timeout(ms(200)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn test_log_file_writer() {
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("cmd_lib_test_writer_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut sink = LogFileSink::new(dir.join("job.log"));
+    sink.max_size = 6;
+    sink.max_files = 2;
+    let mut writer = sink.writer();
+    spawn_with_output!(seq 1 10)
+        .unwrap()
+        .wait_to_writer(&mut writer)
+        .unwrap();
+    // an incomplete line is written when the writer is dropped
+    writer.write_all(b"11\n12").unwrap();
+    drop(writer);
+
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("job.log"), "12");
+    assert_eq!(read("job.log.1"), "10\n11\n");
+    assert_eq!(read("job.log.2"), "7\n8\n9\n");
+    assert!(!dir.join("job.log.3").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_override() {
+    // overrides apply to this thread only, and nest
+    let guard = cmd_lib::config::with_config(|cfg| cfg.pipefail = Some(false));
+    assert!(run_cmd!(false | true).is_ok());
+    {
+        let _inner = cmd_lib::config::with_config(|cfg| cfg.max_cmd_len = Some(8));
+        assert!(run_cmd!(false | true).is_ok());
+    }
+    std::thread::spawn(|| assert!(run_cmd!(false | true).is_err()))
+        .join()
+        .unwrap();
+    drop(guard);
+    assert!(run_cmd!(false | true).is_err());
+
+    let snapshot = cmd_lib::config::snapshot();
+    set_debug(true);
+    assert!(cmd_lib::config::describe().contains("debug = true (set by function)\n"));
+    // not leaked to the environment of the children
+    assert_ne!(std::env::var("CMD_LIB_DEBUG").ok().as_deref(), Some("1"));
+    cmd_lib::config::restore(snapshot);
+    assert!(!cmd_lib::config::describe().contains("debug = true"));
+}
+
+#[test]
+fn test_config_env() {
+    // only values which can't disturb the tests running in parallel
+    let snapshot = cmd_lib::config::snapshot();
+    std::env::set_var("CMD_LIB_MAX_CMD_LEN", "4K");
+    std::env::set_var("CMD_LIB_PIPEFAIL_WARN", "Yes");
+    std::env::set_var("CMD_LIB_TIMEOUT", "2h");
+    std::env::set_var("CMD_LIB_DEBUG", "maybe");
+    cmd_lib::config::init();
+    let described = cmd_lib::config::describe();
+    assert!(described.contains("max_cmd_len = 4096 (CMD_LIB_MAX_CMD_LEN)\n"));
+    assert!(described.contains("pipefail_warn = true (CMD_LIB_PIPEFAIL_WARN)\n"));
+    assert!(described.contains("timeout = 7200s (CMD_LIB_TIMEOUT)"));
+    assert!(described.contains("debug = false (default)\n"));
+
+    // functions take precedence over the variables, and overrides over both
+    set_timeout(std::time::Duration::from_secs(3 * 3600));
+    assert!(cmd_lib::config::describe().contains("timeout = 10800s (set by function)"));
+    {
+        let _config = cmd_lib::config::with_config(|cfg| {
+            cfg.timeout = Some(std::time::Duration::from_millis(1500))
+        });
+        assert!(cmd_lib::config::describe().contains("timeout = 1.5s (with_config())"));
+    }
+
+    for var in [
+        "CMD_LIB_MAX_CMD_LEN",
+        "CMD_LIB_PIPEFAIL_WARN",
+        "CMD_LIB_TIMEOUT",
+        "CMD_LIB_DEBUG",
+    ] {
+        std::env::remove_var(var);
+    }
+    cmd_lib::config::restore(snapshot);
+    cmd_lib::config::init();
+    assert!(cmd_lib::config::describe().contains("timeout = 0ns (default)"));
+}
+
+#[test]
+fn test_history_expansion() {
+    // off by default
+    parse_cmd_line("echo cmd_lib_test_history").unwrap();
+    assert_eq!(parse_cmd_line("echo !!").unwrap().run_fun().unwrap(), "!!");
+
+    let _history = cmd_lib::config::with_config(|cfg| cfg.history_expansion = Some(true));
+    parse_cmd_line("echo cmd_lib_test_history").unwrap();
+    assert_eq!(
+        parse_cmd_line("!!").unwrap().run_fun().unwrap(),
+        "cmd_lib_test_history"
+    );
+    assert_eq!(
+        parse_cmd_line("!! | wc -w")
+            .unwrap()
+            .run_fun()
+            .unwrap()
+            .trim(),
+        "1"
+    );
+    assert_eq!(
+        parse_cmd_line("echo '!!' \\!!").unwrap().run_fun().unwrap(),
+        "!! !!"
+    );
+    // a single quote inside double quotes starts no single quotes
+    parse_cmd_line("echo last").unwrap();
+    assert_eq!(
+        parse_cmd_line(r#"echo "it's !!""#)
+            .unwrap()
+            .run_fun()
+            .unwrap(),
+        "it's echo last"
+    );
+    // each thread has its own last command line
+    std::thread::spawn(|| {
+        let _history = cmd_lib::config::with_config(|cfg| cfg.history_expansion = Some(true));
+        assert!(parse_cmd_line("!!").is_err());
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_run_with_stdin() {
+    // larger than the pipe buffers, so feeding and reading must not block each other
+    let input: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let output = parse_cmd_line("cat")
+        .unwrap()
+        .run_with_stdin(&input)
+        .unwrap();
+    assert_eq!(output, input);
+    let output = parse_cmd_line("tr a-z A-Z | rev")
+        .unwrap()
+        .run_with_stdin(b"abc\n")
+        .unwrap();
+    assert_eq!(output, b"CBA\n");
+    // the command may stop reading early
+    let output = parse_cmd_line("head -c 3")
+        .unwrap()
+        .run_with_stdin(&input)
+        .unwrap();
+    assert_eq!(output, &input[..3]);
+    assert!(parse_cmd_line("false")
+        .unwrap()
+        .run_with_stdin(b"x")
+        .is_err());
+}
+
+#[test]
+fn test_success_when() {
+    let soft_errors = |ret: &CmdResult, stdout_tail: &[String], stderr_tail: &[String]| {
+        let lines = stdout_tail.iter().chain(stderr_tail);
+        match ret {
+            Ok(()) if lines.clone().any(|line| line.starts_with("ERROR:")) => {
+                Err("errors reported".to_string())
+            }
+            Err(e) if CmdError::from_io_error(e).unwrap().exit_code == Some(2) => Ok(()),
+            ret => ret.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+        }
+    };
+
+    // soft failures in the output fail the last stage
+    let e = spawn_with_output!(sh -c "echo 'ERROR: disk full'; echo done")
+        .unwrap()
+        .success_when(soft_errors)
+        .wait_with_output()
+        .unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.exit_code, None);
+    assert!(e.to_string().ends_with("failed: errors reported"));
+    let e = spawn!(sh -c "echo 'ERROR: bad' >&2")
+        .unwrap()
+        .success_when(soft_errors)
+        .wait()
+        .unwrap_err();
+    assert_eq!(
+        CmdError::from_io_error(&e).unwrap().stderr_tail,
+        ["ERROR: bad"]
+    );
+
+    // and allowed failures succeed
+    let output = spawn_with_output!(sh -c "echo partial; exit 2")
+        .unwrap()
+        .success_when(soft_errors)
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "partial");
+    assert!(spawn!(sh -c "exit 2")
+        .unwrap()
+        .success_when(soft_errors)
+        .wait()
+        .is_ok());
+    let e = spawn!(sh -c "exit 3")
+        .unwrap()
+        .success_when(soft_errors)
+        .wait()
+        .unwrap_err();
+    assert_eq!(CmdError::from_io_error(&e).unwrap().exit_code, Some(3));
+
+    // the tails keep the last lines only
+    let tails = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let seen = tails.clone();
+    let output = spawn_with_output!(seq 1 20 | cat)
+        .unwrap()
+        .success_when(move |_, stdout_tail, stderr_tail| {
+            *seen.lock().unwrap() = [stdout_tail, stderr_tail].concat();
+            Ok(())
+        })
+        .wait_with_output_timeout(std::time::Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(output.lines().count(), 20);
+    let expected: Vec<String> = (11..=20).map(|i| i.to_string()).collect();
+    assert_eq!(*tails.lock().unwrap(), expected);
+}
+
+#[test]
+fn test_wait_with_output_opt() {
+    // no bytes at all is None, and a lone newline is an empty line
+    assert_eq!(
+        spawn_with_output!(true)
+            .unwrap()
+            .wait_with_output_opt()
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        spawn_with_output!(printf "")
+            .unwrap()
+            .wait_with_output_opt()
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        spawn_with_output!(echo)
+            .unwrap()
+            .wait_with_output_opt()
+            .unwrap(),
+        Some(String::new())
+    );
+    assert_eq!(
+        spawn_with_output!(echo hi)
+            .unwrap()
+            .wait_with_output_opt()
+            .unwrap(),
+        Some("hi".to_string())
+    );
+    // the same with a timeout
+    let ret = Process::new()
+        .timeout(std::time::Duration::from_secs(60))
+        .run(|| spawn_with_output!(true).unwrap().wait_with_output_opt());
+    assert_eq!(ret.unwrap(), None);
+    let ret = Process::new()
+        .timeout(std::time::Duration::from_secs(60))
+        .run(|| spawn_with_output!(echo).unwrap().wait_with_output_opt());
+    assert_eq!(ret.unwrap(), Some(String::new()));
+    // failures are still errors
+    assert!(spawn_with_output!(false)
+        .unwrap()
+        .wait_with_output_opt()
+        .is_err());
+}
+
+#[test]
+fn test_stdin_options() {
+    use std::io::Write;
+
+    let mut options = StdinOptions::default();
+    options.chunk_size = 7;
+    options.flush_each_item = false;
+    let lines = (0..10_000).map(|i| format!("line {}\n", i));
+    let count = parse_cmd_line("wc -l")
+        .unwrap()
+        .feed_stdin_iter_with(lines, options)
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(count.trim(), "10000");
+
+    // flushed input is read without closing stdin
+    let (mut children, mut stdin) = parse_cmd_line("head -n 1")
+        .unwrap()
+        .spawn_with_stdin(&StdinOptions::default())
+        .unwrap();
+    writeln!(stdin, "first").unwrap();
+    stdin.flush().unwrap();
+    assert_eq!(children.wait_with_output().unwrap(), "first");
+    // the rest of the input is discarded once it's not read
+    writeln!(stdin, "second").unwrap();
+    assert!(stdin.flush().is_ok());
+    assert!(stdin.is_closed());
+    assert!(stdin.close().is_ok());
+
+    // the status of the commands that stopped reading decides the result
+    let (mut children, mut stdin) = parse_cmd_line("sh -c \"read x; exit 3\"")
+        .unwrap()
+        .spawn_with_stdin(&StdinOptions::default())
+        .unwrap();
+    let mut input = b"x\n".to_vec();
+    input.resize(1 << 20, b'x');
+    assert!(stdin.write_all(&input).is_ok());
+    assert!(stdin.close().is_ok());
+    assert!(children.wait_with_output().is_err());
+}
+
+#[test]
+fn test_feed_stdin_iter() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let lines = (0..1_000_000).map(|i| format!("line {}\n", i));
+    let count = parse_cmd_line("wc -l")
+        .unwrap()
+        .feed_stdin_iter(lines)
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(count.trim(), "1000000");
+
+    let chunks: Vec<&[u8]> = vec![b"a", b"b\nc", b"\n"];
+    let output = parse_cmd_line("cat")
+        .unwrap()
+        .feed_stdin_iter(chunks)
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "ab\nc");
+
+    // the iteration stops once the command closes stdin
+    let taken = Arc::new(AtomicUsize::new(0));
+    let counter = taken.clone();
+    let lines = (0..).map(move |i| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        format!("{}\n", i)
+    });
+    let output = parse_cmd_line("head -n 1")
+        .unwrap()
+        .feed_stdin_iter(lines)
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "0");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    let mut last = taken.load(Ordering::SeqCst);
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let now = taken.load(Ordering::SeqCst);
+        if now == last {
+            break;
+        }
+        assert!(std::time::Instant::now() < deadline);
+        last = now;
+    }
+}
+
+#[test]
+fn test_harden_operands() {
+    let _config = config::with_config(|cfg| cfg.harden_operands = Some(true));
+    // rm doesn't take the file name for options
+    let ret = Process::new().scratch_dir(false).unwrap().run(|| {
+        let file = "-rf";
+        run_cmd!(touch ./$file keep; rm $file)?;
+        run_fun!(ls)
+    });
+    assert_eq!(ret.unwrap(), "keep");
+
+    harden_operands_for(&["printf", "%s,"]);
+    let (a, b) = ("-x", "y");
+    assert_eq!(run_fun!(printf "%s," $a $b).unwrap(), "--,-x,y,");
+    let args = ["-x", "y"];
+    assert_eq!(run_fun!(printf "%s," $[args]).unwrap(), "--,-x,y,");
+    // not inserted before a literal option, or after `--`
+    assert_eq!(run_fun!(printf "%s," $a -z).unwrap(), "-x,-z,");
+    assert_eq!(run_fun!(printf "%s," -- $a).unwrap(), "--,-x,");
+    assert_eq!(run_fun!(printf "%s|" $a).unwrap(), "-x|");
+    let guarded = Process::new()
+        .option_guard(OptionGuard::Prefix)
+        .run(|| run_fun!(printf "%s," $a $b -z));
+    assert_eq!(guarded.unwrap(), "./-x,y,-z,");
+
+    let _config = config::with_config(|cfg| cfg.harden_operands = Some(false));
+    assert_eq!(run_fun!(printf "%s," $a $b).unwrap(), "-x,y,");
+}
+
+#[test]
+fn test_respawn() {
+    let mut children = spawn!(sh -c "test \"$$CMD_LIB_TEST_VERBOSE\" = 1").unwrap();
+    assert!(children.wait().is_err());
+    assert!(children.respawn().unwrap().wait().is_err());
+    let spec = children
+        .spec()
+        .unwrap()
+        .with_env("CMD_LIB_TEST_VERBOSE", "1");
+    assert!(spec.spawn().unwrap().wait().is_ok());
+
+    // interpolated values are kept as they were spawned
+    let mut name = "first";
+    let mut children = spawn_with_output!(echo $name).unwrap();
+    name = "second";
+    assert_eq!(children.wait_with_output().unwrap(), "first");
+    let mut again = children.respawn().unwrap();
+    assert_eq!(again.wait_with_output().unwrap(), "first");
+    assert_eq!(run_fun!(echo $name).unwrap(), "second");
+
+    // ignoring the failed stage, and running in another directory
+    let mut children = spawn_with_output!(false | pwd).unwrap();
+    let e = children.wait_with_output().unwrap_err();
+    let stage = CmdError::from_io_error(&e).unwrap().stage_index;
+    let spec = children.spec().unwrap();
+    assert_eq!(spec.stages(), 2);
+    let output = spec
+        .ignore_stage(stage)
+        .current_dir("/")
+        .spawn_with_output()
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "/");
+
+    // closures and `Command`s can't be spawned again
+    let children = spawn_command(std::process::Command::new("true")).unwrap();
+    assert!(children.spec().is_none());
+    let e = children.respawn().err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_process_handle() {
+    let mut children = spawn!(sleep 10).unwrap();
+    let handle = children.stage_handle(0).unwrap();
+    assert!(children.stage_handle(1).is_none());
+    assert!(handle.is_alive());
+
+    // written and read back, as in a pidfile
+    let token = handle.to_string();
+    let parsed: ProcessHandle = token.parse().unwrap();
+    assert_eq!(parsed, handle);
+    assert_eq!(parsed.pid(), handle.pid());
+    assert!(parsed.is_alive());
+    assert!("abc".parse::<ProcessHandle>().is_err());
+    assert!("12:abc".parse::<ProcessHandle>().is_err());
+
+    // a process reusing the pid has another start time
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    {
+        assert!(token.contains(':'));
+        let reused: ProcessHandle = format!("{}:1", handle.pid()).parse().unwrap();
+        assert!(!reused.is_alive());
+    }
+
+    children.kill().unwrap();
+    let _ = children.wait();
+    assert!(!handle.is_alive());
+
+    // builtins run in this process
+    let mut children = spawn!(echo hi).unwrap();
+    assert!(children.stage_handle(0).is_none());
+    let _ = children.wait();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_umask() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("cmd_lib_umask_{}", std::process::id()));
+    let file = dir.join("file");
+    let sub_dir = dir.join("sub_dir");
+    run_cmd!(rm -rf $dir; mkdir $dir).unwrap();
+    Process::new()
+        .umask(0o077)
+        .run(|| run_cmd!(touch $file; mkdir $sub_dir))
+        .unwrap();
+    let mode = |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode();
+    assert_eq!(mode(&file) & 0o777, 0o600);
+    assert_eq!(mode(&sub_dir) & 0o777, 0o700);
+
+    // files created by redirects are not affected
+    let redirected = dir.join("redirected");
+    Process::new()
+        .umask(0o777)
+        .run(|| run_cmd!(echo hi > $redirected))
+        .unwrap();
+    assert_ne!(mode(&redirected) & 0o777, 0);
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_process_group() {
+    use std::time::{Duration, Instant};
+
+    // the background sleep holds stdout open after the shell is killed, unless killed with it
+    let mut children = Process::new()
+        .process_group(true)
+        .run(|| spawn_with_output!(sh -c "sleep 10 & wait"))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    children.kill().unwrap();
+    assert!(children.wait_with_output().is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // killing a group whose leader exited already
+    let mut children = Process::new()
+        .process_group(true)
+        .run(|| spawn!(true))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    children.kill().unwrap();
+    let _ = children.wait();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_pipe_size() {
+    let bytes = Process::new()
+        .pipe_size(1 << 20)
+        .run(|| run_fun!(head -c 3000000 /dev/zero | wc -c))
+        .unwrap();
+    assert_eq!(bytes.trim(), "3000000");
+}
+
+#[test]
+fn test_wait_with_heartbeat() {
+    use std::ops::ControlFlow;
+    use std::time::Duration;
+
+    let mut beats = 0;
+    spawn!(sleep 0.3)
+        .unwrap()
+        .wait_with_heartbeat(Duration::from_millis(50), || {
+            beats += 1;
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert!((2..=6).contains(&beats), "{} beats", beats);
+
+    // breaking leaves the children running
+    let mut children = spawn!(sleep 10).unwrap();
+    let handle = children.stage_handle(0).unwrap();
+    let e = children
+        .wait_with_heartbeat(Duration::from_millis(10), || ControlFlow::Break(()))
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::Interrupted);
+    assert!(handle.is_alive());
+    children.kill().unwrap();
+    assert!(children.wait().is_err());
+
+    // failures are still reported
+    assert!(spawn!(false)
+        .unwrap()
+        .wait_with_heartbeat(Duration::from_millis(10), || ControlFlow::Continue(()))
+        .is_err());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_max_pipeline_len() {
+    // the child processes of this process, including the exited ones not reaped
+    fn children() -> String {
+        let tasks = std::fs::read_dir("/proc/self/task").unwrap();
+        tasks
+            .flatten()
+            .map(|task| std::fs::read_to_string(task.path().join("children")).unwrap())
+            .collect()
+    }
+
+    if std::env::var("CMD_LIB_TEST_MAX_PIPELINE_LEN").is_ok() {
+        // running under `ulimit -n 16`, without other tests spawning children
+        let e = run_cmd!(
+            true | true | true | true | true | true | true | true | true | true | true | true
+        )
+        .unwrap_err();
+        println!("fds: {:?} {}", e.kind(), e);
+        println!("fds children: [{}]", children().trim());
+
+        // the stages spawned before a failed redirect are not left running
+        let e = run_cmd!(sleep 10 | sleep 10 | cat > /nonexistent/cmd_lib_test).unwrap_err();
+        println!("redirect: {:?}", e.kind());
+        println!("redirect children: [{}]", children().trim());
+
+        // custom commands can't be killed, so they are joined after closing their pipes
+        use std::sync::atomic::{AtomicBool, Ordering};
+        static FLOODED: AtomicBool = AtomicBool::new(false);
+        #[export_cmd(cmd_lib_test_flood)]
+        fn flood(env: &mut CmdEnv) -> CmdResult {
+            use std::io::Write;
+            while env.stdout().write_all(&[b'y'; 4096]).is_ok() {}
+            FLOODED.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        use_custom_cmd!(cmd_lib_test_flood);
+        let e = run_cmd!(cmd_lib_test_flood | cat > /nonexistent/cmd_lib_test).unwrap_err();
+        println!("flood: {:?}", e.kind());
+        println!("flood joined: {}", FLOODED.load(Ordering::Relaxed));
+        return;
+    }
+
+    {
+        let _config = cmd_lib::config::with_config(|cfg| cfg.max_pipeline_len = Some(2));
+        let e = run_cmd!(echo a | cat | cat).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("set_max_pipeline_len()"));
+        assert_eq!(run_fun!(echo a | cat).unwrap(), "a");
+    }
+
+    // run this test again in a child process, with fewer file descriptors
+    let exe = std::env::current_exe().unwrap();
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg("ulimit -n 16 && exec \"$0\" test_max_pipeline_len --exact --nocapture")
+        .arg(exe)
+        .env("CMD_LIB_TEST_MAX_PIPELINE_LEN", "1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("fds: InvalidInput "), "{}", stdout);
+    assert!(stdout.contains("RLIMIT_NOFILE"), "{}", stdout);
+    assert!(stdout.contains("fds children: []\n"), "{}", stdout);
+    assert!(stdout.contains("redirect: NotFound\n"), "{}", stdout);
+    assert!(stdout.contains("redirect children: []\n"), "{}", stdout);
+    assert!(stdout.contains("flood: NotFound\n"), "{}", stdout);
+    assert!(stdout.contains("flood joined: true\n"), "{}", stdout);
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+fn test_compress_output() {
+    let file = std::env::temp_dir().join(format!("cmd_lib_compress_{}.gz", std::process::id()));
+    let expected = run_fun!(seq 1 10000).unwrap();
+    Process::new()
+        .compress_output(Codec::Gzip)
+        .run(|| run_cmd!(seq 1 10000 > $file))
+        .unwrap();
+    assert_eq!(run_fun!(gzip -dc $file).unwrap(), expected);
+    assert_eq!(std::fs::read(&file).unwrap()[..2], [0x1f, 0x8b]);
+
+    // appending adds a member, and builtins are compressed too
+    Process::new()
+        .compress_output(Codec::Gzip)
+        .run(|| run_cmd!(echo appended >> $file))
+        .unwrap();
+    let output = run_fun!(gzip -dc $file).unwrap();
+    assert_eq!(output, format!("{}\nappended", expected));
+
+    // without the option, the file is written as it is
+    run_cmd!(echo plain > $file).unwrap();
+    assert_eq!(run_fun!(cat $file).unwrap(), "plain");
+    run_cmd!(rm -f $file).unwrap();
+}
+
+#[test]
+fn test_diff_outputs() {
+    let same = diff_outputs(
+        spawn_with_output!(seq 1 100000).unwrap(),
+        spawn_with_output!(seq 1 100000).unwrap(),
+    )
+    .unwrap();
+    assert!(same.is_none());
+
+    // in the format of `diff -U0`
+    let diff = diff_outputs(
+        spawn_with_output!(printf "a\nb\nc\n").unwrap(),
+        spawn_with_output!(printf "a\nB\nc\nd\n").unwrap(),
+    )
+    .unwrap();
+    assert_eq!(diff.unwrap(), "@@ -2 +2 @@\n-b\n+B\n@@ -3,0 +4 @@\n+d\n");
+    let diff = diff_outputs(
+        spawn_with_output!(printf "1\n2\n3\n").unwrap(),
+        spawn_with_output!(printf "0\n1\n3\n").unwrap(),
+    )
+    .unwrap();
+    assert_eq!(diff.unwrap(), "@@ -0,0 +1 @@\n+0\n@@ -2 +2,0 @@\n-2\n");
+
+    // only the first hunks
+    let diff = diff_outputs(
+        spawn_with_output!(seq 1 200).unwrap(),
+        spawn_with_output!(seq 1 200 | sed "s/5$$/five/").unwrap(),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(diff.matches("@@ -").count(), 10);
+    assert!(diff.starts_with("@@ -5 +5 @@\n-5\n+five\n@@ -15 +15 @@\n"));
+    assert!(diff.ends_with("@@ -95 +95 @@\n-95\n+9five\n...\n"));
+
+    // a failure is an error, not a difference
+    let e = diff_outputs(
+        spawn_with_output!(echo a).unwrap(),
+        spawn_with_output!(sh -c "echo b; exit 3").unwrap(),
+    )
+    .unwrap_err();
+    assert_eq!(CmdError::from_io_error(&e).unwrap().exit_code, Some(3));
+}
+
+#[test]
+fn test_on_command_not_found() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let output = Process::new()
+        .on_command_not_found(|name| match name {
+            "cmd_lib_test_foo" => Some(Fallback::Command(vec!["printf".into(), "%s-%s".into()])),
+            _ => None,
+        })
+        .run(|| run_fun!(cmd_lib_test_foo a b))
+        .unwrap();
+    assert_eq!(output, "a-b");
+
+    // the fallbacks not found either are limited, and the handler's own commands are not handled
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let e = Process::new()
+        .on_command_not_found(move |name| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            assert!(run_cmd!(cmd_lib_test_inner).is_err());
+            match name {
+                "cmd_lib_test_foo" => Some(Fallback::Command(vec!["cmd_lib_test_bar".into()])),
+                _ => Some(Fallback::Retry),
+            }
+        })
+        .run(|| run_cmd!(cmd_lib_test_foo))
+        .unwrap_err();
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    assert!(e.to_string().contains("cmd_lib_test_bar"));
+
+    let e = Process::new()
+        .on_command_not_found(|name| Some(Fallback::Error(format!("install {} first", name))))
+        .run(|| run_cmd!(cmd_lib_test_foo))
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    assert!(e.to_string().contains("install cmd_lib_test_foo first"));
+
+    // programs are searched where they run, and in the PATH set for them
+    let dir = std::env::temp_dir().join(format!("cmd_lib_nf_dir_{}", std::process::id()));
+    let tool = dir.join("nf_tool");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&tool, "#!/bin/sh\necho found\n").unwrap();
+    run_cmd!(chmod +x $tool).unwrap();
+    let path = format!("{}:/usr/bin:/bin", dir.display());
+    let outputs = Process::new()
+        .on_command_not_found(|name| Some(Fallback::Error(format!("{} not found", name))))
+        .run(|| -> std::io::Result<_> {
+            Ok([run_fun!(cd $dir; ./nf_tool)?, run_fun!(PATH=$path nf_tool)?])
+        });
+    assert_eq!(outputs.unwrap(), ["found", "found"]);
+    run_cmd!(rm -rf $dir).unwrap();
+}
+
+#[test]
+fn test_wait_validated() {
+    // a crude check of a JSON object, enough for the test
+    let json_object = |output: &[u8]| {
+        let text = String::from_utf8_lossy(output);
+        let text = text.trim();
+        if text.starts_with('{') && text.ends_with('}') {
+            Ok(())
+        } else {
+            Err(format!("not a JSON object: {:?}", text))
+        }
+    };
+    let output = spawn_with_output!(echo r#"{"ok": true}"#)
+        .unwrap()
+        .wait_validated(json_object)
+        .unwrap();
+    assert_eq!(output, r#"{"ok": true}"#);
+
+    let e = spawn_with_output!(echo r#"{"ok": tr"#)
+        .unwrap()
+        .wait_validated(json_object)
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert!(err.command.contains("echo"));
+    assert!(e.to_string().contains("invalid output: not a JSON object"));
+
+    // an exit status error comes first
+    let e = spawn_with_output!(sh -c "echo oops; exit 2")
+        .unwrap()
+        .wait_validated(json_object)
+        .unwrap_err();
+    assert_eq!(CmdError::from_io_error(&e).unwrap().exit_code, Some(2));
+}
+
+#[test]
+fn test_wait_fun_typed() {
+    #[derive(Debug, PartialEq)]
+    struct Summary {
+        lines: usize,
+        first: String,
+    }
+    #[export_cmd(cmd_lib_test_summary)]
+    fn summary(env: &mut CmdEnv) -> CmdResult {
+        use std::io::{Read, Write};
+        let mut input = String::new();
+        env.stdin().read_to_string(&mut input)?;
+        let summary = Summary {
+            lines: input.lines().count(),
+            first: input.lines().next().unwrap_or_default().to_string(),
+        };
+        writeln!(env.stdout(), "not the result")?;
+        env.set_result(Box::new(summary));
+        Ok(())
+    }
+    use_custom_cmd!(cmd_lib_test_summary);
+
+    let summary: Summary = spawn_with_output!(seq 3 5 | cat | cmd_lib_test_summary)
+        .unwrap()
+        .wait_fun_typed()
+        .unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            lines: 3,
+            first: "3".into()
+        }
+    );
+
+    // a value of another type
+    let e = spawn_with_output!(seq 3 | cmd_lib_test_summary)
+        .unwrap()
+        .wait_fun_typed::<String>()
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e
+        .to_string()
+        .contains("the result of the last stage is not a"));
+
+    // no value from an external command
+    let e = spawn_with_output!(echo 1)
+        .unwrap()
+        .wait_fun_typed::<Summary>()
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("no result set by the last stage"));
+
+    // failures come first
+    assert!(spawn_with_output!(false | cmd_lib_test_summary)
+        .unwrap()
+        .wait_fun_typed::<Summary>()
+        .is_err());
+}
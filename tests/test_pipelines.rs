@@ -0,0 +1,348 @@
+// End-to-end tests of pipelines, with a helper program instead of system tools
+use cmd_lib::*;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+#[path = "helpers/cmd_helper.rs"]
+mod cmd_helper;
+
+fn helper() -> &'static PathBuf {
+    static HELPER: OnceLock<PathBuf> = OnceLock::new();
+    HELPER.get_or_init(|| {
+        let src = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/helpers/cmd_helper.rs");
+        let out = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cmd_helper");
+        // rustc is installed next to cargo
+        let rustc = PathBuf::from(env!("CARGO")).with_file_name("rustc");
+        run_cmd!($rustc -O --edition 2018 -o $out $src).unwrap();
+        out
+    })
+}
+
+fn failed_stage(ret: CmdResult) -> Option<usize> {
+    let e = ret.err()?;
+    Some(CmdError::from_io_error(&e).unwrap().stage_index)
+}
+
+// the failed stage reported by each wait method for the same pipeline, checking that all the
+// stages are reaped
+fn failed_stages(spawn: impl Fn() -> FunChildren) -> Vec<Option<usize>> {
+    let reaped = |children: &FunChildren| {
+        let stages = &children.stats().stages;
+        assert!(stages.iter().all(|stage| stage.success.is_some()));
+    };
+    let mut children = spawn();
+    let output = failed_stage(children.wait_with_output().map(|_| ()));
+    reaped(&children);
+    let mut children = spawn();
+    let writer = failed_stage(children.wait_to_writer(&mut std::io::sink()));
+    reaped(&children);
+    let mut children = spawn();
+    let pipe = failed_stage(children.wait_with_pipe(&mut |mut stdout| {
+        std::io::copy(&mut stdout, &mut std::io::sink()).unwrap();
+    }));
+    reaped(&children);
+    let lines = failed_stage(spawn().wait_split_lines().map(|_| ()));
+    let timeout = std::time::Duration::from_secs(60);
+    let timed = failed_stage(spawn().wait_with_output_timeout(timeout).map(|_| ()));
+    vec![output, writer, pipe, lines, timed]
+}
+
+#[test]
+fn test_failure_positions() {
+    let h = helper();
+    for pipefail in [true, false] {
+        // on this thread only, so the other tests keep the default
+        let _pipefail = cmd_lib::config::with_config(|cfg| cfg.pipefail = Some(pipefail));
+        assert_eq!(
+            failed_stage(run_cmd!($h exit 1 < /dev/null | $h pass | $h exit 0)),
+            {
+                if pipefail {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+        );
+        assert_eq!(
+            failed_stage(run_cmd!($h emit 10 | $h exit 3 | $h exit 0)),
+            {
+                if pipefail {
+                    Some(1)
+                } else {
+                    None
+                }
+            }
+        );
+        assert_eq!(
+            failed_stage(run_cmd!($h emit 10 | $h pass | $h exit 2)),
+            Some(2)
+        );
+        assert_eq!(
+            failed_stage(run_cmd!($h emit 10 | $h pass | $h exit 0)),
+            None
+        );
+
+        // every wait method reaps all the stages and blames the earliest failed one
+        let expect = |stage| vec![stage; 5];
+        let blamed = |stage| if pipefail { Some(stage) } else { None };
+        assert_eq!(
+            failed_stages(
+                || spawn_with_output!($h exit 1 < /dev/null | $h pass | $h pass).unwrap()
+            ),
+            expect(blamed(0))
+        );
+        assert_eq!(
+            failed_stages(|| spawn_with_output!($h emit 10 | $h exit 1 | $h pass).unwrap()),
+            expect(blamed(1))
+        );
+        assert_eq!(
+            failed_stages(|| spawn_with_output!($h emit 10 | $h pass | $h exit 1).unwrap()),
+            expect(Some(2))
+        );
+        assert_eq!(
+            failed_stages(
+                || spawn_with_output!($h exit 1 < /dev/null | $h exit 2 | $h exit 3).unwrap()
+            ),
+            expect(if pipefail { Some(0) } else { Some(2) })
+        );
+        let mut children = spawn!($h exit 1 < /dev/null | $h exit 2 | $h exit 3).unwrap();
+        assert_eq!(
+            failed_stage(children.wait()),
+            if pipefail { Some(0) } else { Some(2) }
+        );
+        assert!(children
+            .stats()
+            .stages
+            .iter()
+            .all(|stage| stage.success == Some(false)));
+
+        // masked failures are recorded, unless ignored for the stage
+        let mut children = spawn!($h exit 3 < /dev/null | $h pass | $h exit 0).unwrap();
+        assert_eq!(children.wait().is_ok(), !pipefail);
+        let masked: Vec<bool> = children
+            .stats()
+            .stages
+            .iter()
+            .map(|stage| stage.masked_error.is_some())
+            .collect();
+        assert_eq!(masked, [!pipefail, false, false]);
+        let mut children = spawn!($h pass < /dev/null | ignore $h exit 3 | $h exit 0).unwrap();
+        assert!(children.wait().is_ok());
+        assert!(children.stats().stages[1].masked_error.is_none());
+    }
+
+    let e = run_cmd!($h emit 10 | $h exit 42).unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.exit_code, Some(42));
+    assert_eq!(err.signal, None);
+}
+
+#[test]
+fn test_ignore_at_each_position() {
+    let h = helper();
+    assert!(run_cmd!(ignore $h exit 1 < /dev/null | $h pass).is_ok());
+    assert!(run_cmd!(ignore $h emit 10 | $h exit 1).is_ok());
+    assert!(run_cmd!($h pass < /dev/null | ignore $h exit 1 | $h pass).is_ok());
+    assert!(run_cmd!($h pass < /dev/null | ignore $h exit 1 | $h exit 2).is_err());
+    assert_eq!(run_fun!(ignore $h exit 1 < /dev/null).unwrap(), "");
+    assert!(run_cmd! {
+        ignore $h exit 1 < /dev/null;
+        $h exit 0 < /dev/null;
+    }
+    .is_ok());
+}
+
+#[test]
+fn test_ignored_failures() {
+    let h = helper();
+    let mut children = spawn!($h pass < /dev/null | ignore $h exit 3 | $h pass).unwrap();
+    assert!(children.wait().is_ok());
+    assert!(children.had_ignored_failures());
+    let stages = &children.stats().stages;
+    assert_eq!(stages[1].success, Some(false));
+    assert_eq!(stages[1].exit_code, Some(3));
+    assert!(stages[1].error_ignored);
+    assert_eq!(stages[2].success, Some(true));
+    assert_eq!(stages[2].exit_code, Some(0));
+    assert!(!stages[2].error_ignored);
+
+    let mut children = spawn!(ignore $h emit 10 | $h exit 4).unwrap();
+    assert!(children.wait().is_ok());
+    assert!(children.had_ignored_failures());
+    assert_eq!(children.stats().stages[1].exit_code, Some(4));
+
+    let mut children = spawn!($h emit 10 | $h pass).unwrap();
+    assert!(children.wait().is_ok());
+    assert!(!children.had_ignored_failures());
+
+    let mut children = spawn_with_output!($h exit 5 < /dev/null)
+        .unwrap()
+        .ignore_errors();
+    assert_eq!(children.wait_with_output().unwrap(), "");
+    assert!(children.stats().stages[0].error_ignored);
+}
+
+#[test]
+fn test_huge_binary_output() {
+    let h = helper();
+    let len = 16 * 1024 * 1024 + 3;
+    let mut output = vec![];
+    spawn_with_output!($h emit $len | $h pass | $h pass)
+        .unwrap()
+        .wait_to_writer(&mut output)
+        .unwrap();
+    assert_eq!(output.len(), len);
+    assert!(output == cmd_helper::pattern(len));
+}
+
+#[test]
+fn test_concurrent_spawns() {
+    let h = helper();
+    let children: Vec<_> = (0..8)
+        .map(|i| {
+            let len = i * 100_000;
+            (len, spawn_with_output!($h emit $len | $h pass).unwrap())
+        })
+        .collect();
+    for (len, mut child) in children {
+        let mut output = vec![];
+        child.wait_to_writer(&mut output).unwrap();
+        assert!(output == cmd_helper::pattern(len));
+    }
+}
+
+#[test]
+fn test_kill_and_timeout() {
+    use std::time::Duration;
+
+    let h = helper();
+    let mut children = spawn!($h sleep 10000 | $h pass).unwrap();
+    children.kill().unwrap();
+    let e = children.wait().unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.exit_code, None);
+    #[cfg(unix)]
+    assert_eq!(err.signal, Some(9));
+
+    let mut server = spawn_with_output!($h sleep 10000).unwrap();
+    let err = server
+        .wait_until_line(|line| line == "ready", Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    server.kill().unwrap();
+    assert!(server.wait_with_output().is_err());
+}
+
+#[test]
+fn test_stderr_tail() {
+    let h = helper();
+    let e = run_cmd!($h emit 10 | $h fail "bad input" | $h exit 0).unwrap_err();
+    let err = CmdError::from_io_error(&e).unwrap();
+    assert_eq!(err.stage_index, 1);
+    assert_eq!(err.exit_code, Some(1));
+    assert_eq!(err.stderr_tail, vec!["bad input"]);
+}
+
+#[test]
+fn test_stderr_drained_and_joined() {
+    let h = helper();
+    // stages filling their stderr pipes must not block the others while waiting
+    let ret = Process::new()
+        .timeout(std::time::Duration::from_secs(20))
+        .run(|| run_fun!($h spew 1000000 | $h spew 1000000 | $h pass));
+    assert_eq!(ret.unwrap(), "");
+    let ret = Process::new()
+        .timeout(std::time::Duration::from_secs(20))
+        .run(|| run_cmd!($h spew 1000000 | $h exit 0));
+    assert!(ret.is_ok());
+
+    // no stderr logging thread is left running after failing pipelines
+    for _ in 0..5 {
+        assert!(run_cmd!($h fail first < /dev/null | $h fail second).is_err());
+        assert!(run_fun!($h fail first < /dev/null | $h exit 3).is_err());
+        let mut children = spawn_with_output!($h spew 100000 | $h fail last).unwrap();
+        assert!(children.wait_with_output().is_err());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let stderr_threads = || {
+            std::fs::read_dir("/proc/self/task")
+                .unwrap()
+                .flatten()
+                .filter(|task| {
+                    let comm = std::fs::read_to_string(task.path().join("comm"));
+                    comm.is_ok_and(|comm| comm.trim_end() == "cmd_lib stderr")
+                })
+                .count()
+        };
+        // other tests may still be running pipelines for a moment
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while stderr_threads() > 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(stderr_threads(), 0);
+    }
+}
+
+#[test]
+fn test_stdout_blocked() {
+    use std::time::Duration;
+
+    let h = helper();
+    let mut children = Process::new()
+        .count_pipe_bytes(true)
+        .run(|| spawn!($h emit 4000000 | $h slow 500 | $h pass))
+        .unwrap();
+    children.wait().unwrap();
+    let stages = &children.stats().stages;
+    // the producer waits for the slow stage to start reading
+    assert!(stages[0].stdout_blocked.unwrap() >= Duration::from_millis(250));
+    assert!(stages[1].stdout_blocked.unwrap() < Duration::from_millis(250));
+    assert_eq!(stages[2].stdout_blocked, None);
+
+    let mut children = spawn!($h emit 10 | $h pass).unwrap();
+    children.wait().unwrap();
+    assert_eq!(children.stats().stages[0].stdout_blocked, None);
+}
+
+#[test]
+fn test_wait_with_buf_pipe() {
+    use std::io::ErrorKind;
+
+    let h = helper();
+    let spawn = || {
+        Process::new()
+            .count_pipe_bytes(true)
+            .run(|| spawn_with_output!($h emit 1000000 | $h pass))
+            .unwrap()
+    };
+
+    let mut children = spawn();
+    let mut output = vec![];
+    children
+        .wait_with_buf_pipe(|pipe| pipe.read_to_end(&mut output).map(|_| ()))
+        .unwrap();
+    assert_eq!(output, cmd_helper::pattern(1000000));
+    assert_eq!(children.stats().stages[1].stdout_bytes, Some(1000000));
+
+    // stopping early kills the last stage instead of failing the pipeline
+    let mut children = spawn();
+    children
+        .wait_with_buf_pipe(|pipe| {
+            let mut head = [0; 100];
+            pipe.read_exact(&mut head)?;
+            let n = pipe.fill_buf()?.len().min(50);
+            pipe.consume(n);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(children.stats().stages[1].stdout_bytes, Some(150));
+
+    // the error of the closure is returned, even with the errors of the pipeline ignored
+    let e = spawn()
+        .ignore_errors()
+        .wait_with_buf_pipe(|_| Err(std::io::Error::new(ErrorKind::InvalidData, "bad input")))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "bad input");
+}